@@ -0,0 +1,17 @@
+//! Constants of WADUP's packed binary metadata format: the guest SDK's
+//! `BinaryMetadataWriter` writes it and the host decodes it.
+
+/// First bytes of every binary metadata file
+pub const MAGIC: &[u8; 4] = b"WDM\x01";
+
+/// Table definition record: name and JSON column array (empty to only
+/// assign the next table id)
+pub const RECORD_TABLE: u8 = 0x01;
+/// Row record: table id and tagged values
+pub const RECORD_ROW: u8 = 0x02;
+
+pub const VALUE_INT64: u8 = 0x00;
+pub const VALUE_FLOAT64: u8 = 0x01;
+pub const VALUE_STRING: u8 = 0x02;
+pub const VALUE_BOOLEAN: u8 = 0x03;
+pub const VALUE_TIMESTAMP: u8 = 0x04;
@@ -0,0 +1,39 @@
+//! JSON spelling of `Float64` values: finite values are numbers, and NaN
+//! and the infinities, which JSON has no numbers for (serde_json writes them
+//! as `null`), are the strings `"NaN"`, `"Infinity"` and `"-Infinity"`.
+
+/// `"NaN"`, `"Infinity"` or `"-Infinity"`; `None` for finite values
+pub fn non_finite_name(value: f64) -> Option<&'static str> {
+    if value.is_nan() {
+        Some("NaN")
+    } else if value.is_infinite() {
+        Some(if value > 0.0 { "Infinity" } else { "-Infinity" })
+    } else {
+        None
+    }
+}
+
+/// The value [`non_finite_name`] gives `name` for; `None` for any other string
+pub fn from_non_finite_name(name: &str) -> Option<f64> {
+    match name {
+        "NaN" => Some(f64::NAN),
+        "Infinity" => Some(f64::INFINITY),
+        "-Infinity" => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_finite_names_round_trip() {
+        for value in [f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(from_non_finite_name(non_finite_name(value).unwrap()), Some(value));
+        }
+        assert!(from_non_finite_name(non_finite_name(f64::NAN).unwrap()).unwrap().is_nan());
+        assert_eq!(non_finite_name(0.5), None);
+        assert_eq!(from_non_finite_name("inf"), None);
+    }
+}
@@ -0,0 +1,8 @@
+//! Conventions the WADUP host and its guests must agree on: the binary
+//! metadata format, the environment a module runs in, and how JSON spells
+//! the floats it has no numbers for. Both `wadup-core` and `wadup-guest`
+//! depend on this crate, so neither depends on the other.
+
+pub mod binary_format;
+pub mod float64;
+pub mod mode;
@@ -0,0 +1,8 @@
+//! Whether a module is running under `wadup test` or `wadup run`.
+
+/// Environment variable WADUP sets to [`MODE_TEST`] or [`MODE_RUN`].
+pub const MODE_VAR: &str = "WADUP_MODE";
+/// Value of [`MODE_VAR`] under `wadup test`
+pub const MODE_TEST: &str = "test";
+/// Value of [`MODE_VAR`] under `wadup run`
+pub const MODE_RUN: &str = "run";
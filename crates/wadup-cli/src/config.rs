@@ -0,0 +1,236 @@
+//! Configuration files for `wadup run --config`.
+//!
+//! A config file holds the same options as the `run` command line, in TOML or
+//! JSON (chosen by the `.json` extension; anything else is read as TOML):
+//!
+//! ```toml
+//! modules = "./modules"
+//! input = "./samples"
+//! threads = 8
+//! fuel = 10000000
+//! ```
+//!
+//! Options given explicitly on the command line override the file.
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use wadup_core::{Label, Mount, OutputMode, Secret, WasiProfile};
+
+use crate::RunArgs;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunConfigFile {
+    modules: Option<PathBuf>,
+    input: Option<PathBuf>,
+    input_list: Option<PathBuf>,
+    es_url: Option<String>,
+    es_index: Option<String>,
+    output_mode: Option<OutputMode>,
+    threads: Option<usize>,
+    fuel: Option<u64>,
+    fuel_ceiling: Option<u64>,
+    stall_timeout: Option<u64>,
+    max_memory: Option<usize>,
+    max_stack: Option<usize>,
+    max_open_files: Option<usize>,
+    max_recursion_depth: Option<usize>,
+    max_total_content: Option<usize>,
+    max_rows_per_table_per_content: Option<usize>,
+    entrypoint: Option<String>,
+    compress_cache: Option<bool>,
+    dump_fs_on_error: Option<PathBuf>,
+    keep_scratch: Option<bool>,
+    keep_metadata_json: Option<PathBuf>,
+    strict_schema: Option<bool>,
+    strict: Option<bool>,
+    migrate: Option<bool>,
+    summary_json: Option<PathBuf>,
+    max_db_bytes: Option<u64>,
+    no_slice_dedup: Option<bool>,
+    skip_empty: Option<bool>,
+    fail_fast: Option<bool>,
+    table_prefix: Option<String>,
+    label: Option<Vec<Label>>,
+    seed: Option<u64>,
+    content_seeded_random: Option<bool>,
+    wasi_profile: Option<WasiProfile>,
+    mount: Option<Vec<Mount>>,
+    module_arg: Option<Vec<String>>,
+    secret: Option<Vec<Secret>>,
+    secrets_file: Option<PathBuf>,
+    module_order: Option<Vec<String>>,
+    dispatch: Option<PathBuf>,
+    reuse_results: Option<bool>,
+    auto_create_tables: Option<bool>,
+    warn_empty_tables: Option<bool>,
+    passthrough_output: Option<bool>,
+    trace_wasi: Option<bool>,
+    detect_encoding: Option<bool>,
+    eager_subcontent: Option<bool>,
+    intra_content_parallel: Option<bool>,
+    profile: Option<bool>,
+    retry_failed: Option<bool>,
+    sample: Option<usize>,
+}
+
+impl RunConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+
+        if path.extension().and_then(|s| s.to_str()) == Some("json") {
+            serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse JSON config file {:?}", path))
+        } else {
+            toml::from_str(&text)
+                .with_context(|| format!("Failed to parse TOML config file {:?}", path))
+        }
+    }
+
+    /// Fill in `args` from the file for every option not given on the command line.
+    pub fn apply(self, args: &mut RunArgs, matches: &ArgMatches) {
+        fn set<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                if matches.value_source(id) != Some(ValueSource::CommandLine) {
+                    *target = value;
+                }
+            }
+        }
+
+        set(matches, "modules", &mut args.modules, self.modules.map(Some));
+        set(matches, "input", &mut args.input, self.input.map(Some));
+        set(matches, "input_list", &mut args.input_list, self.input_list.map(Some));
+        set(matches, "es_url", &mut args.es_url, self.es_url);
+        set(matches, "es_index", &mut args.es_index, self.es_index);
+        set(matches, "output_mode", &mut args.output_mode, self.output_mode);
+        set(matches, "threads", &mut args.threads, self.threads);
+        set(matches, "fuel", &mut args.fuel, self.fuel.map(Some));
+        set(matches, "fuel_ceiling", &mut args.fuel_ceiling, self.fuel_ceiling.map(Some));
+        set(matches, "stall_timeout", &mut args.stall_timeout, self.stall_timeout.map(Some));
+        set(matches, "max_memory", &mut args.max_memory, self.max_memory.map(Some));
+        set(matches, "max_stack", &mut args.max_stack, self.max_stack.map(Some));
+        set(matches, "max_open_files", &mut args.max_open_files, self.max_open_files.map(Some));
+        set(matches, "max_recursion_depth", &mut args.max_recursion_depth, self.max_recursion_depth);
+        set(matches, "max_total_content", &mut args.max_total_content, self.max_total_content.map(Some));
+        set(matches, "max_rows_per_table_per_content", &mut args.max_rows_per_table_per_content, self.max_rows_per_table_per_content.map(Some));
+        set(matches, "entrypoint", &mut args.entrypoint, self.entrypoint);
+        set(matches, "compress_cache", &mut args.compress_cache, self.compress_cache);
+        set(matches, "dump_fs_on_error", &mut args.dump_fs_on_error, self.dump_fs_on_error.map(Some));
+        set(matches, "keep_scratch", &mut args.keep_scratch, self.keep_scratch);
+        set(matches, "keep_metadata_json", &mut args.keep_metadata_json, self.keep_metadata_json.map(Some));
+        set(matches, "strict_schema", &mut args.strict_schema, self.strict_schema);
+        set(matches, "strict", &mut args.strict, self.strict);
+        set(matches, "migrate", &mut args.migrate, self.migrate);
+        set(matches, "summary_json", &mut args.summary_json, self.summary_json.map(Some));
+        set(matches, "max_db_bytes", &mut args.max_db_bytes, self.max_db_bytes.map(Some));
+        set(matches, "no_slice_dedup", &mut args.no_slice_dedup, self.no_slice_dedup);
+        set(matches, "skip_empty", &mut args.skip_empty, self.skip_empty);
+        set(matches, "fail_fast", &mut args.fail_fast, self.fail_fast);
+        set(matches, "table_prefix", &mut args.table_prefix, self.table_prefix.map(Some));
+        set(matches, "label", &mut args.label, self.label);
+        set(matches, "seed", &mut args.seed, self.seed.map(Some));
+        set(matches, "content_seeded_random", &mut args.content_seeded_random, self.content_seeded_random);
+        set(matches, "wasi_profile", &mut args.wasi_profile, self.wasi_profile);
+        set(matches, "mount", &mut args.mount, self.mount);
+        set(matches, "module_arg", &mut args.module_arg, self.module_arg);
+        set(matches, "secret", &mut args.secret, self.secret);
+        set(matches, "secrets_file", &mut args.secrets_file, self.secrets_file.map(Some));
+        set(matches, "module_order", &mut args.module_order, self.module_order);
+        set(matches, "dispatch", &mut args.dispatch, self.dispatch.map(Some));
+        set(matches, "reuse_results", &mut args.reuse_results, self.reuse_results);
+        set(matches, "auto_create_tables", &mut args.auto_create_tables, self.auto_create_tables);
+        set(matches, "warn_empty_tables", &mut args.warn_empty_tables, self.warn_empty_tables);
+        set(matches, "passthrough_output", &mut args.passthrough_output, self.passthrough_output);
+        set(matches, "trace_wasi", &mut args.trace_wasi, self.trace_wasi);
+        set(matches, "detect_encoding", &mut args.detect_encoding, self.detect_encoding);
+        set(matches, "eager_subcontent", &mut args.eager_subcontent, self.eager_subcontent);
+        set(matches, "intra_content_parallel", &mut args.intra_content_parallel, self.intra_content_parallel);
+        set(matches, "profile", &mut args.profile, self.profile);
+        set(matches, "retry_failed", &mut args.retry_failed, self.retry_failed);
+        set(matches, "sample", &mut args.sample, self.sample.map(Some));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_cli, Commands, RunArgs};
+    use std::path::{Path, PathBuf};
+    use wadup_core::OutputMode;
+
+    fn parse_run(args: &[&str]) -> RunArgs {
+        let args = std::iter::once("wadup").chain(std::iter::once("run")).chain(args.iter().copied());
+        match parse_cli(args).unwrap().command {
+            Commands::Run(run_args) => *run_args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_config(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_run_entirely_from_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_config(dir.path(), "wadup.toml", r#"
+            modules = "/opt/modules"
+            input = "/data/in"
+            es_url = "http://es:9200"
+            es_index = "cases"
+            output_mode = "per-module"
+            threads = 2
+            fuel = 1000
+            max_memory = 4096
+            max_stack = 2048
+            max_recursion_depth = 5
+            entrypoint = "run"
+        "#);
+
+        let args = parse_run(&["--config", &config]);
+        assert_eq!(args.modules, Some(PathBuf::from("/opt/modules")));
+        assert_eq!(args.input, Some(PathBuf::from("/data/in")));
+        assert_eq!(args.es_url, "http://es:9200");
+        assert_eq!(args.es_index, "cases");
+        assert_eq!(args.output_mode, OutputMode::PerModule);
+        assert_eq!(args.threads, 2);
+        assert_eq!(args.fuel, Some(1000));
+        assert_eq!(args.max_memory, Some(4096));
+        assert_eq!(args.max_stack, Some(2048));
+        assert_eq!(args.max_recursion_depth, 5);
+        assert_eq!(args.entrypoint, "run");
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_config(
+            dir.path(),
+            "wadup.json",
+            r#"{"modules": "/opt/modules", "input": "/data/in", "threads": 2, "fuel": 1000}"#,
+        );
+
+        let args = parse_run(&["--config", &config, "--threads", "16", "--input", "/other"]);
+        assert_eq!(args.threads, 16);
+        assert_eq!(args.input, Some(PathBuf::from("/other")));
+        // Values only in the file still apply
+        assert_eq!(args.modules, Some(PathBuf::from("/opt/modules")));
+        assert_eq!(args.fuel, Some(1000));
+        // Options in neither keep their defaults
+        assert_eq!(args.es_index, "wadup");
+    }
+
+    #[test]
+    fn test_unknown_config_key_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_config(dir.path(), "wadup.toml", "thread = 3\n");
+
+        let args = ["wadup", "run", "--config", config.as_str()];
+        assert!(parse_cli(args).is_err());
+    }
+}
@@ -1,8 +1,18 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use std::path::PathBuf;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 use wadup_core::*;
 
+mod config;
+
+/// Exit code of `wadup run` when `--max-db-bytes` stopped it early
+const EXIT_DB_SIZE_LIMIT: i32 = 3;
+
 #[derive(Parser)]
 #[command(name = "wadup")]
 #[command(about = "Web Assembly Data Unified Processing")]
@@ -13,13 +23,43 @@ struct Cli {
 
     #[arg(short, long, global = true, help = "Verbose output")]
     verbose: bool,
+
+    #[arg(short, long, global = true, conflicts_with = "verbose", help = "Only log warnings and errors")]
+    quiet: bool,
+
+    #[arg(long, global = true, default_value = "text", help = "Log format: 'text' or 'json' (one object per line)")]
+    log_format: LogFormat,
+
+    #[arg(long, global = true, help = "Also append logs to this file")]
+    log_file: Option<PathBuf>,
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of the event and of every
+    /// span it was logged in
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => anyhow::bail!("Unknown log format '{}' (expected 'text' or 'json')", other),
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Precompile WASM modules for faster subsequent runs
     Compile {
-        #[arg(long, help = "Directory containing WASM modules")]
+        #[arg(long, help = "Directory containing WASM modules, or a single .wasm file")]
         modules: PathBuf,
 
         #[arg(long, help = "Fuel limit (CPU) per module per content")]
@@ -30,26 +70,23 @@ enum Commands {
 
         #[arg(long, help = "Maximum stack size in bytes per module instance")]
         max_stack: Option<usize>,
-    },
 
-    /// Run WASM modules on input files
-    Run {
-        #[arg(long, help = "Directory containing WASM modules")]
-        modules: PathBuf,
+        #[arg(long, default_value = DEFAULT_ENTRYPOINT, help = "Name of the export called for each content")]
+        entrypoint: String,
 
-        #[arg(long, help = "Directory containing input files")]
-        input: PathBuf,
-
-        #[arg(long, default_value = "http://localhost:9200", help = "Elasticsearch URL")]
-        es_url: String,
+        #[arg(long, help = "Gzip the _precompiled cache files (they load either way)")]
+        compress_cache: bool,
+    },
 
-        #[arg(long, default_value = "wadup", help = "Elasticsearch index name")]
-        es_index: String,
+    /// Run WASM modules on input files
+    Run(Box<RunArgs>),
 
-        #[arg(long, default_value = "4", help = "Number of worker threads")]
-        threads: usize,
+    /// Print the tables each module declares as CREATE TABLE statements
+    Schema {
+        #[arg(long, help = "Directory containing WASM modules, or a single .wasm file")]
+        modules: PathBuf,
 
-        #[arg(long, help = "Fuel limit (CPU) per module per content")]
+        #[arg(long, help = "Fuel limit (CPU) per module")]
         fuel: Option<u64>,
 
         #[arg(long, help = "Maximum memory in bytes per module instance")]
@@ -58,8 +95,17 @@ enum Commands {
         #[arg(long, help = "Maximum stack size in bytes per module instance")]
         max_stack: Option<usize>,
 
-        #[arg(long, default_value = "100", help = "Maximum recursion depth for sub-content")]
-        max_recursion_depth: usize,
+        #[arg(long, default_value = DEFAULT_ENTRYPOINT, help = "Name of the export called for each content")]
+        entrypoint: String,
+    },
+
+    /// Check each module loads and list its entry exports, imports and manifest filters, without running it
+    ListModules {
+        #[arg(long, help = "Directory containing WASM modules, or a single .wasm file")]
+        modules: PathBuf,
+
+        #[arg(long, default_value = DEFAULT_ENTRYPOINT, help = "Name of the export called for each content")]
+        entrypoint: String,
     },
 
     /// Test a single WASM module against a sample file (outputs JSON)
@@ -81,35 +127,269 @@ enum Commands {
 
         #[arg(long, help = "Maximum stack size in bytes")]
         max_stack: Option<usize>,
+
+        #[arg(long, default_value = DEFAULT_ENTRYPOINT, help = "Name of the export called for each content")]
+        entrypoint: String,
+
+        #[arg(long, default_value_t = DEFAULT_MAX_HEX_BYTES, help = "Maximum bytes of each sub-content shown in data_hex")]
+        max_hex_bytes: usize,
+    },
+
+    /// Check a run's results for rows and contents that reference missing contents
+    Verify {
+        #[arg(long, default_value = "http://localhost:9200", help = "Elasticsearch URL")]
+        es_url: String,
+
+        #[arg(long, default_value = "wadup", help = "Elasticsearch index name")]
+        es_index: String,
     },
 }
 
+/// Options for `wadup run`
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[arg(long, help = "TOML or JSON file with run options; flags given on the command line take precedence")]
+    config: Option<PathBuf>,
+
+    #[arg(long, help = "Directory containing WASM modules, or a single .wasm file")]
+    modules: Option<PathBuf>,
+
+    #[arg(long, help = "Directory containing input files")]
+    input: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", conflicts_with = "input", help = "Read the input files listed in FILE, one path per line (optionally PATH<TAB>FILENAME), in order, instead of --input")]
+    input_list: Option<PathBuf>,
+
+    #[arg(long, default_value = "http://localhost:9200", help = "Elasticsearch URL")]
+    es_url: String,
+
+    #[arg(long, default_value = "wadup", help = "Elasticsearch index name")]
+    es_index: String,
+
+    #[arg(long, default_value = "shared", help = "Output mode: 'shared' (one index) or 'per-module' ({es_index}-{module})")]
+    output_mode: OutputMode,
+
+    #[arg(long, default_value = "4", help = "Number of worker threads")]
+    threads: usize,
+
+    #[arg(long, help = "Fuel limit (CPU) per module per content")]
+    fuel: Option<u64>,
+
+    #[arg(long, value_name = "FUEL", requires = "fuel", help = "Most fuel per content a module can reach by calling add_fuel (default: add_fuel disabled)")]
+    fuel_ceiling: Option<u64>,
+
+    #[arg(long, value_name = "SECS", help = "Fail a content when a module hasn't finished it or sent a heartbeat in this many seconds, so its worker moves on")]
+    stall_timeout: Option<u64>,
+
+    #[arg(long, help = "Maximum memory in bytes per module instance")]
+    max_memory: Option<usize>,
+
+    #[arg(long, help = "Maximum stack size in bytes per module instance")]
+    max_stack: Option<usize>,
+
+    #[arg(long, value_name = "N", help = "Most files a module instance can have open at once (default: unlimited)")]
+    max_open_files: Option<usize>,
+
+    #[arg(long, default_value = "100", help = "Maximum recursion depth for sub-content")]
+    max_recursion_depth: usize,
+
+    #[arg(long, value_name = "N", help = "Maximum sub-content items queued across the whole run (default: unlimited)")]
+    max_total_content: Option<usize>,
+
+    #[arg(long, value_name = "N", help = "Maximum rows kept per table for each content (default: unlimited)")]
+    max_rows_per_table_per_content: Option<usize>,
+
+    #[arg(long, default_value = DEFAULT_ENTRYPOINT, help = "Name of the export called for each content")]
+    entrypoint: String,
+
+    #[arg(long, help = "Gzip the _precompiled cache files written for modules (they load either way)")]
+    compress_cache: bool,
+
+    #[arg(long, help = "Dump a module's in-memory filesystem to <DIR>/<module>/<content-uuid> when it traps")]
+    dump_fs_on_error: Option<PathBuf>,
+
+    #[arg(long, help = "Keep what modules write to /tmp from one content to the next instead of clearing it")]
+    keep_scratch: bool,
+
+    #[arg(long, help = "Copy each metadata file modules write to <DIR>/<module>/<content-uuid>/ before it is consumed")]
+    keep_metadata_json: Option<PathBuf>,
+
+    #[arg(long, help = "Stop the run when two modules declare the same table with different columns")]
+    strict_schema: bool,
+
+    #[arg(long, help = "Stop the run when a module fails to instantiate, instead of running without it, or when a file in --input-list is missing")]
+    strict: bool,
+
+    #[arg(long, help = "Restamp an index written by a wadup build with a different schema version")]
+    migrate: bool,
+
+    #[arg(long, value_name = "PATH", help = "Write a JSON summary of the run's counts and errors to PATH")]
+    summary_json: Option<PathBuf>,
+
+    #[arg(long, value_name = "BYTES", help = "Stop taking new content once this many bytes of documents have been written")]
+    max_db_bytes: Option<u64>,
+
+    #[arg(long, help = "Keep sub-content slices that repeat a range already emitted for the same content")]
+    no_slice_dedup: bool,
+
+    #[arg(long, help = "Don't run modules on zero-length content or sub-content")]
+    skip_empty: bool,
+
+    #[arg(long, help = "Stop the run with an error as soon as any content fails")]
+    fail_fast: bool,
+
+    #[arg(long, value_name = "PREFIX", help = "Store each module table as {PREFIX}_{table}")]
+    table_prefix: Option<String>,
+
+    #[arg(long, value_name = "KEY=VALUE", help = "Tag the run's content documents and summary with KEY=VALUE (repeatable)")]
+    label: Vec<Label>,
+
+    #[arg(long, help = "Seed random_get so module randomness is reproducible (default: OS entropy)")]
+    seed: Option<u64>,
+
+    #[arg(long, help = "Seed random_get from each content's bytes, so the same input always sees the same random bytes")]
+    content_seeded_random: bool,
+
+    #[arg(long, default_value = "full", help = "WASI functions modules may use: 'full', 'readonly' (no filesystem writes) or 'minimal' (fd_write and proc_exit)")]
+    wasi_profile: WasiProfile,
+
+    #[arg(long, value_name = "HOST_DIR:/GUEST_PATH:ro", help = "Expose a host directory read-only to every module at GUEST_PATH (repeatable)")]
+    mount: Vec<Mount>,
+
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, help = "Pass ARG to every module after argv[0], the module name (repeatable)")]
+    module_arg: Vec<String>,
+
+    #[arg(long, value_name = "NAME=VALUE", help = "Let modules read VALUE with the get_secret host function; never exposed through the environment (repeatable)")]
+    secret: Vec<Secret>,
+
+    #[arg(long, value_name = "FILE", help = "Read secrets for get_secret from FILE, one NAME=VALUE per line")]
+    secrets_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "NAMES", value_delimiter = ',', help = "Run these modules first, in this order (comma-separated); the rest follow in name order")]
+    module_order: Vec<String>,
+
+    #[arg(long, value_name = "FILE", help = "TOML table routing contents to modules by filename glob or content type")]
+    dispatch: Option<PathBuf>,
+
+    #[arg(long, help = "Cache results by content hash, and copy cached rows instead of re-running a module on identical content")]
+    reuse_results: bool,
+
+    #[arg(long, help = "Create tables that modules write rows to without defining them, with columns inferred from the first row")]
+    auto_create_tables: bool,
+
+    #[arg(long, help = "At the end of the run, warn about tables their module defined but stored no rows in")]
+    warn_empty_tables: bool,
+
+    #[arg(long, help = "Also print module stdout/stderr to the host's, each line prefixed with [module content-uuid]")]
+    passthrough_output: bool,
+
+    #[arg(long, help = "Log each WASI file call's fd, path and errno (with --verbose), and count failed calls' errnos on module_run documents")]
+    trace_wasi: bool,
+
+    #[arg(long, help = "Guess each content's text encoding from its BOM or bytes, recorded as text_encoding and passed as WADUP_TEXT_ENCODING")]
+    detect_encoding: bool,
+
+    #[arg(long, help = "Queue sub-content as soon as a module closes its file, so other workers process it while the parent is still running")]
+    eager_subcontent: bool,
+
+    #[arg(long, help = "Run each content's modules at the same time, on a thread per module, instead of one after another")]
+    intra_content_parallel: bool,
+
+    #[arg(long, help = "Print how long loading files, loading modules, processing and each module took")]
+    profile: bool,
+
+    #[arg(long, help = "Only process input files whose content is recorded as failed in the index, e.g. after fixing a module")]
+    retry_failed: bool,
+
+    #[arg(long, value_name = "N", help = "Process only N of the input files, picked at random (the same ones for the same --seed)")]
+    sample: Option<usize>,
+}
+
+/// Parse the command line, applying any `wadup run --config` file
+fn parse_cli<I, T>(args: I) -> Result<Cli>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Cli::command().try_get_matches_from(args)?;
+    let mut cli = Cli::from_arg_matches(&matches)?;
+
+    if let (Commands::Run(run_args), Some(("run", run_matches))) = (&mut cli.command, matches.subcommand()) {
+        if let Some(path) = run_args.config.clone() {
+            let file = config::RunConfigFile::load(&path)?;
+            file.apply(run_args, run_matches);
+        }
+    }
+
+    Ok(cli)
+}
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = match parse_cli(std::env::args_os()) {
+        Ok(cli) => cli,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(clap_error) => clap_error.exit(),
+            Err(e) => return Err(e),
+        },
+    };
 
     // Set up logging
     let level = if cli.verbose {
         tracing::Level::DEBUG
+    } else if cli.quiet {
+        tracing::Level::WARN
     } else {
         tracing::Level::INFO
     };
 
-    tracing_subscriber::fmt()
-        .with_max_level(level)
-        .with_target(false)
-        .with_thread_ids(false)
-        .init();
+    match &cli.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {:?}", path))?;
+            // No colour codes, since they would end up in the file
+            log_subscriber(level, cli.log_format, false, std::io::stdout.and(std::sync::Arc::new(file))).init();
+        }
+        None => log_subscriber(level, cli.log_format, true, std::io::stdout).init(),
+    }
 
     match cli.command {
-        Commands::Compile { modules, fuel, max_memory, max_stack } => {
-            run_compile(modules, fuel, max_memory, max_stack)
+        Commands::Compile { modules, fuel, max_memory, max_stack, entrypoint, compress_cache } => {
+            run_compile(modules, fuel, max_memory, max_stack, entrypoint, compress_cache)
         }
-        Commands::Run { modules, input, es_url, es_index, threads, fuel, max_memory, max_stack, max_recursion_depth } => {
-            run_process(modules, input, es_url, es_index, threads, fuel, max_memory, max_stack, max_recursion_depth)
+        Commands::Run(args) => run_process(*args),
+        Commands::Schema { modules, fuel, max_memory, max_stack, entrypoint } => {
+            run_schema(modules, fuel, max_memory, max_stack, entrypoint)
         }
-        Commands::Test { module, sample, filename, fuel, max_memory, max_stack } => {
-            run_test_command(module, sample, filename, fuel, max_memory, max_stack)
+        Commands::ListModules { modules, entrypoint } => run_list_modules(modules, entrypoint),
+        Commands::Test { module, sample, filename, fuel, max_memory, max_stack, entrypoint, max_hex_bytes } => {
+            run_test_command(module, sample, filename, fuel, max_memory, max_stack, entrypoint, max_hex_bytes)
         }
+        Commands::Verify { es_url, es_index } => run_verify(es_url, es_index),
+    }
+}
+
+/// Subscriber writing lines at `level` and above to `writer`. Lines logged
+/// on worker threads carry the fields of their content and module spans in
+/// both formats.
+fn log_subscriber<W>(level: tracing::Level, format: LogFormat, ansi: bool, writer: W) -> Box<dyn tracing::Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    // Only events are filtered by level: the spans are INFO, and under
+    // --quiet warnings would otherwise lose them
+    let visible = tracing_subscriber::filter::filter_fn(move |metadata| metadata.is_span() || *metadata.level() <= level);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    let registry = tracing_subscriber::registry();
+    match format {
+        LogFormat::Text => Box::new(registry.with(layer.with_filter(visible))),
+        LogFormat::Json => Box::new(registry.with(layer.json().with_current_span(true).with_span_list(true).with_filter(visible))),
     }
 }
 
@@ -118,13 +398,15 @@ fn run_compile(
     fuel: Option<u64>,
     max_memory: Option<usize>,
     max_stack: Option<usize>,
+    entrypoint: String,
+    compress_cache: bool,
 ) -> Result<()> {
     tracing::info!("WADUP - Precompiling WASM Modules");
     tracing::info!("============================================");
 
     // Validate inputs
-    if !modules.exists() || !modules.is_dir() {
-        anyhow::bail!("Modules directory does not exist: {:?}", modules);
+    if !modules.exists() {
+        anyhow::bail!("Modules path does not exist: {:?}", modules);
     }
 
     // Configure resource limits (affects engine hash)
@@ -135,7 +417,7 @@ fn run_compile(
     };
 
     tracing::info!("Configuration:");
-    tracing::info!("  Modules directory: {:?}", modules);
+    tracing::info!("  Modules: {:?}", modules);
 
     if let Some(fuel) = limits.fuel {
         tracing::info!("  Fuel limit: {}", fuel);
@@ -146,10 +428,15 @@ fn run_compile(
     if let Some(stack) = limits.max_stack {
         tracing::info!("  Stack limit: {} bytes", stack);
     }
+    if compress_cache {
+        tracing::info!("  Compressed cache: on");
+    }
 
     // Create runtime and load modules (this triggers precompilation)
     tracing::info!("Precompiling WASM modules...");
     let mut runtime = WasmRuntime::new(limits)?;
+    runtime.set_entrypoint(entrypoint);
+    runtime.set_compress_cache(compress_cache);
     runtime.load_modules(&modules)?;
 
     tracing::info!("============================================");
@@ -158,122 +445,265 @@ fn run_compile(
     Ok(())
 }
 
-fn run_process(
-    modules: PathBuf,
-    input: PathBuf,
-    es_url: String,
-    es_index: String,
-    threads: usize,
-    fuel: Option<u64>,
-    max_memory: Option<usize>,
-    max_stack: Option<usize>,
-    max_recursion_depth: usize,
-) -> Result<()> {
+fn run_process(args: RunArgs) -> Result<()> {
+    let RunArgs {
+        config: _,
+        modules,
+        input,
+        input_list,
+        es_url,
+        es_index,
+        output_mode,
+        threads,
+        fuel,
+        fuel_ceiling,
+        stall_timeout,
+        max_memory,
+        max_stack,
+        max_open_files,
+        max_recursion_depth,
+        max_total_content,
+        max_rows_per_table_per_content,
+        entrypoint,
+        compress_cache,
+        dump_fs_on_error,
+        keep_scratch,
+        keep_metadata_json,
+        strict_schema,
+        strict,
+        migrate,
+        summary_json,
+        max_db_bytes,
+        no_slice_dedup,
+        skip_empty,
+        fail_fast,
+        table_prefix,
+        label,
+        seed,
+        content_seeded_random,
+        wasi_profile,
+        mount,
+        module_arg,
+        secret,
+        secrets_file,
+        module_order,
+        dispatch,
+        reuse_results,
+        auto_create_tables,
+        warn_empty_tables,
+        passthrough_output,
+        trace_wasi,
+        detect_encoding,
+        eager_subcontent,
+        intra_content_parallel,
+        profile,
+        retry_failed,
+        sample,
+    } = args;
+
     tracing::info!("WADUP - Web Assembly Data Unified Processing");
     tracing::info!("============================================");
 
-    // Validate inputs
-    if !modules.exists() || !modules.is_dir() {
-        anyhow::bail!("Modules directory does not exist: {:?}", modules);
+    let modules = modules.ok_or_else(|| anyhow::anyhow!("--modules is required (on the command line or in --config)"))?;
+    if input.is_none() && input_list.is_none() {
+        anyhow::bail!("--input or --input-list is required (on the command line or in --config)");
     }
 
-    if !input.exists() || !input.is_dir() {
-        anyhow::bail!("Input directory does not exist: {:?}", input);
+    tracing::info!("Configuration:");
+    let config = RunConfig {
+        modules,
+        input,
+        input_list,
+        inputs: Vec::new(),
+        es_url,
+        es_index,
+        output_mode,
+        threads,
+        limits: ResourceLimits {
+            fuel,
+            max_memory,
+            max_stack,
+        },
+        max_open_files,
+        max_recursion_depth,
+        max_total_content,
+        max_rows_per_table_per_content,
+        entrypoint,
+        compress_cache,
+        dump_fs_on_error,
+        keep_scratch,
+        keep_metadata_json,
+        strict_schema,
+        strict_instantiation: strict,
+        migrate,
+        summary_json,
+        max_db_bytes,
+        slice_dedup: !no_slice_dedup,
+        skip_empty,
+        fail_fast,
+        table_prefix,
+        labels: label,
+        seed,
+        content_seeded_random,
+        wasi_profile,
+        mounts: mount,
+        module_args: module_arg,
+        secrets: secret,
+        secrets_file,
+        fuel_ceiling,
+        stall_timeout: stall_timeout.map(std::time::Duration::from_secs),
+        progress: Some(std::sync::Arc::new(log_heartbeat)),
+        module_order,
+        dispatch,
+        reuse_results,
+        auto_create_tables,
+        warn_empty_tables,
+        passthrough_output,
+        trace_wasi,
+        detect_encoding,
+        eager_subcontent,
+        intra_content_parallel,
+        profile,
+        retry_failed,
+        sample,
+    };
+    let summary = wadup_core::run(&config)?;
+    if let Some(profile) = &summary.profile {
+        print!("{}", profile.render());
     }
-
-    if threads == 0 {
-        anyhow::bail!("Number of threads must be at least 1");
+    if summary.output_limit_reached {
+        tracing::error!("db size limit reached: more than {} bytes written", max_db_bytes.unwrap_or_default());
+        std::process::exit(EXIT_DB_SIZE_LIMIT);
     }
 
-    // Configure resource limits
-    let limits = ResourceLimits {
-        fuel,
-        max_memory,
-        max_stack,
-    };
+    tracing::info!("============================================");
+    tracing::info!("Processing complete! Results indexed to: {}/{}", config.es_url, config.es_index);
 
-    tracing::info!("Configuration:");
-    tracing::info!("  Modules directory: {:?}", modules);
-    tracing::info!("  Input directory: {:?}", input);
-    tracing::info!("  Elasticsearch URL: {}", es_url);
-    tracing::info!("  Elasticsearch index: {}", es_index);
-    tracing::info!("  Worker threads: {}", threads);
-    tracing::info!("  Max recursion depth: {}", max_recursion_depth);
+    Ok(())
+}
 
-    if let Some(fuel) = limits.fuel {
-        tracing::info!("  Fuel limit: {}", fuel);
-    } else {
-        tracing::info!("  Fuel limit: None (no CPU limit)");
+fn log_heartbeat(heartbeat: &Heartbeat) {
+    match heartbeat.progress_permille {
+        Some(permille) => tracing::debug!(
+            "[{} {}] {}.{}% {}", heartbeat.module, heartbeat.content_uuid, permille / 10, permille % 10, heartbeat.message
+        ),
+        None => tracing::debug!("[{} {}] {}", heartbeat.module, heartbeat.content_uuid, heartbeat.message),
     }
+}
 
-    if let Some(mem) = limits.max_memory {
-        tracing::info!("  Memory limit: {} bytes ({} MB)", mem, mem / 1024 / 1024);
-    } else {
-        tracing::info!("  Memory limit: None (wasmtime defaults)");
+fn run_schema(
+    modules: PathBuf,
+    fuel: Option<u64>,
+    max_memory: Option<usize>,
+    max_stack: Option<usize>,
+    entrypoint: String,
+) -> Result<()> {
+    if !modules.exists() {
+        anyhow::bail!("Modules path does not exist: {:?}", modules);
     }
 
-    if let Some(stack) = limits.max_stack {
-        tracing::info!("  Stack limit: {} bytes ({} KB)", stack, stack / 1024);
-    } else {
-        tracing::info!("  Stack limit: None (wasmtime defaults)");
-    }
+    let limits = ResourceLimits {
+        fuel,
+        max_memory,
+        max_stack,
+    };
 
-    // Load WASM modules (uses precompiled cache if available)
-    tracing::info!("Loading WASM modules...");
     let mut runtime = WasmRuntime::new(limits)?;
+    runtime.set_entrypoint(entrypoint);
     runtime.load_modules(&modules)?;
+    let mut instances = runtime.create_instances(MetadataStore::new_dummy())?;
 
-    // Create metadata store (connects to Elasticsearch)
-    tracing::info!("Connecting to Elasticsearch...");
-    let metadata_store = MetadataStore::new(&es_url, &es_index)?;
-
-    // Load input files
-    tracing::info!("Loading input files...");
-    let contents = load_files(&input)?;
-    tracing::info!("Found {} input files", contents.len());
-
-    // Create processor
-    let processor = ContentProcessor::new(
-        runtime,
-        metadata_store,
-        max_recursion_depth,
-    );
-
-    // Process content
-    tracing::info!("Starting processing...");
-    processor.process(contents, threads)?;
-
-    tracing::info!("============================================");
-    tracing::info!("Processing complete! Results indexed to: {}/{}", es_url, es_index);
-
+    print!("{}", render_schemas(&mut instances));
     Ok(())
 }
 
-fn load_files(input_dir: &PathBuf) -> Result<Vec<Content>> {
-    let mut contents = Vec::new();
-
-    for entry in std::fs::read_dir(input_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+fn run_list_modules(modules: PathBuf, entrypoint: String) -> Result<()> {
+    if !modules.exists() {
+        anyhow::bail!("Modules path does not exist: {:?}", modules);
+    }
 
-        if path.is_file() {
-            let filename = path.file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unknown")
-                .to_string();
+    let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory: None, max_stack: None })?;
+    runtime.set_entrypoint(entrypoint);
+    let descriptions = runtime.describe_modules(&modules)?;
+    print!("{}", render_module_list(&descriptions));
+    let failed = descriptions.iter().filter(|(_, description)| description.is_err()).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} modules failed to load", failed, descriptions.len());
+    }
+    Ok(())
+}
 
-            tracing::debug!("Loading file: {}", filename);
-            // Use memory mapping for zero-copy file loading
-            let buffer = wadup_core::shared_buffer::SharedBuffer::from_file(&path)?;
-            let content = Content::new_root(buffer, filename);
+fn run_verify(es_url: String, es_index: String) -> Result<()> {
+    let store = MetadataStore::new(&es_url, &es_index)?;
+    let report = store.verify()?;
+    print!("{}", report.render());
+    if !report.is_ok() {
+        anyhow::bail!("Verification of {}/{} found problems", es_url, es_index);
+    }
+    Ok(())
+}
 
-            contents.push(content);
+/// Run each module against empty content and render the tables it declares.
+/// A module that fails is reported as a comment so the others still print.
+fn render_schemas(instances: &mut [ModuleInstance]) -> String {
+    let mut out = String::new();
+    for instance in instances {
+        match instance.declared_schemas() {
+            Ok(schemas) => {
+                out.push_str(&format!("-- module: {}\n", instance.name()));
+                for schema in &schemas {
+                    out.push_str(&schema.to_create_table());
+                    out.push('\n');
+                }
+            }
+            Err(e) => out.push_str(&format!("-- module: {} (failed: {})\n", instance.name(), e)),
         }
+        out.push('\n');
     }
+    out
+}
 
-    Ok(contents)
+/// One block per module: its entry exports, runtime, imports and manifest
+/// filters, or why it failed to load
+fn render_module_list(descriptions: &[(String, Result<ModuleDescription>)]) -> String {
+    let mut out = String::new();
+    for (name, description) in descriptions {
+        let description = match description {
+            Ok(description) => description,
+            Err(e) => {
+                out.push_str(&format!("{} (failed: {:#})\n\n", name, e));
+                continue;
+            }
+        };
+        let list = |items: &[String]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+        let manifest = &description.manifest;
+        let mut filters = Vec::new();
+        if let Some(min) = manifest.min_size {
+            filters.push(format!("min_size={}", min));
+        }
+        if let Some(max) = manifest.max_size {
+            filters.push(format!("max_size={}", max));
+        }
+        if let Some(types) = &manifest.content_types {
+            filters.push(format!("content_types={}", types.join("|")));
+        }
+        if let Some(depth) = manifest.max_depth {
+            filters.push(format!("max_depth={}", depth));
+        }
+        out.push_str(&format!("{}\n", name));
+        out.push_str(&format!("  exports: {}\n", list(&description.entry_exports)));
+        out.push_str(&format!(
+            "  runtime: {} ({})\n",
+            description.runtime.runtime,
+            description.runtime.language.as_deref().unwrap_or("unknown language")
+        ));
+        out.push_str(&format!("  imports: {}\n", list(&description.imports)));
+        out.push_str(&format!("  filters: {}\n\n", list(&filters)));
+    }
+    out
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_test_command(
     module: PathBuf,
     sample: PathBuf,
@@ -281,18 +711,9 @@ fn run_test_command(
     fuel: Option<u64>,
     max_memory: Option<usize>,
     max_stack: Option<usize>,
+    entrypoint: String,
+    max_hex_bytes: usize,
 ) -> Result<()> {
-    use wadup_core::wasm::ModuleInstance;
-    use wadup_core::precompile::load_module_with_cache;
-
-    // Validate inputs
-    if !module.exists() {
-        anyhow::bail!("Module not found: {:?}", module);
-    }
-    if !sample.exists() {
-        anyhow::bail!("Sample not found: {:?}", sample);
-    }
-
     // Configure resource limits
     let limits = ResourceLimits {
         fuel,
@@ -300,47 +721,8 @@ fn run_test_command(
         max_stack,
     };
 
-    // Create engine with resource limits
-    let mut config = wasmtime::Config::new();
-    config.wasm_multi_memory(true);
-    config.async_support(false);
-    if limits.fuel.is_some() {
-        config.consume_fuel(true);
-    }
-    if let Some(max_stack) = limits.max_stack {
-        config.max_wasm_stack(max_stack);
-    }
-    let engine = wasmtime::Engine::new(&config)?;
-
-    // Load module
-    let wasm_module = load_module_with_cache(&engine, &module)?;
-
-    // Extract module name from path
-    let module_name = module
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("module")
-        .to_string();
-
-    // Set up environment variables (WADUP_FILENAME)
-    let env_vars = vec![
-        ("WADUP_FILENAME".to_string(), filename),
-    ];
-
-    // Create module instance with environment variables
-    let mut instance = ModuleInstance::with_env_vars(
-        &engine,
-        &wasm_module,
-        &module_name,
-        &limits,
-        env_vars,
-    )?;
-
-    // Load sample file
-    let sample_data = wadup_core::shared_buffer::SharedBuffer::from_file(&sample)?;
-
     // Run the test
-    let output = instance.process_content_for_test(sample_data);
+    let output = wadup_core::run_test(&module, &sample, &filename, &limits, &entrypoint, max_hex_bytes)?;
 
     // Output JSON to stdout
     let json = serde_json::to_string_pretty(&output)?;
@@ -353,3 +735,118 @@ fn run_test_command(
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_matches_byte_counter_table() -> Result<()> {
+        // Stand-in for examples/byte-counter: declares file_sizes(size_bytes Int64)
+        let metadata = r#"{"tables":[{"name":"file_sizes","columns":[{"name":"size_bytes","data_type":"Int64"}]}],"rows":[{"table_name":"file_sizes","values":[{"Int64":0}]}]}"#;
+        let path = "/metadata/output_0.json";
+        let guest = format!(r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "{path}")
+                (data (i32.const 2048) "{data}")
+                (func (export "process") (result i32)
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const {path_len})
+                        (i32.const 9) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+                    (i32.store (i32.const 8) (i32.const 2048))
+                    (i32.store (i32.const 12) (i32.const {data_len}))
+                    (drop (call $fd_write (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (drop (call $fd_close (i32.load (i32.const 0))))
+                    (i32.const 0)))
+        "#,
+            path = path,
+            path_len = path.len(),
+            data = metadata.replace('"', "\\\""),
+            data_len = metadata.len(),
+        );
+
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("byte_counter.wasm"), guest)?;
+        let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory: None, max_stack: None })?;
+        runtime.load_modules(dir.path())?;
+        let mut instances = runtime.create_instances(MetadataStore::new_dummy())?;
+
+        assert_eq!(
+            render_schemas(&mut instances),
+            "-- module: byte_counter\nCREATE TABLE \"file_sizes\" (\n    \"size_bytes\" INTEGER\n);\n\n"
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_list_modules_describes_byte_counter() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("byte_counter.wasm"), r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32) (i32.const 0)))
+        "#)?;
+        std::fs::write(dir.path().join("byte_counter.manifest.json"), r#"{"max_size":1024}"#)?;
+        std::fs::write(dir.path().join("no_entrypoint.wasm"), "(module)")?;
+        std::fs::write(dir.path().join("raw_socket.wasm"), r#"
+            (module (import "env" "socket" (func)) (func (export "process") (result i32) (i32.const 0)))
+        "#)?;
+
+        let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory: None, max_stack: None })?;
+        let descriptions = runtime.describe_modules(dir.path())?;
+        let listing = render_module_list(&descriptions);
+        assert!(listing.starts_with(
+            "byte_counter\n  exports: process\n  runtime: core (unknown language)\n  \
+             imports: wasi_snapshot_preview1::fd_write\n  filters: max_size=1024\n\n"
+        ), "{}", listing);
+        assert!(listing.contains("no_entrypoint (failed: Module missing required 'process' export"), "{}", listing);
+        assert!(listing.contains("raw_socket (failed: unsupported import env::socket)"), "{}", listing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_logs_carry_event_and_span_fields() -> Result<()> {
+        #[derive(Clone, Default)]
+        struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = log_subscriber(tracing::Level::WARN, LogFormat::Json, false, move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _content = tracing::info_span!("content", filename = "a.zip", depth = 1).entered();
+            let _module = tracing::info_span!("module", module = "unzip").entered();
+            tracing::info!("dropped under WARN");
+            tracing::warn!(entries = 3, "bad entry");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone())?;
+        let lines: Vec<serde_json::Value> = output.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 1, "{}", output);
+        let line = &lines[0];
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["fields"]["message"], "bad entry");
+        assert_eq!(line["fields"]["entries"], 3);
+        assert_eq!(line["span"]["module"], "unzip");
+        assert_eq!(line["spans"][0]["name"], "content");
+        assert_eq!(line["spans"][0]["filename"], "a.zip");
+        assert!(line["timestamp"].is_string());
+
+        assert!(parse_cli(["wadup", "--quiet", "--verbose", "verify"]).is_err());
+        assert!(parse_cli(["wadup", "--log-format", "xml", "verify"]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Benchmarks for the processing pipeline: `cargo bench -p wadup-core`.
+//!
+//! Everything runs in process against an in-memory metadata store, so no
+//! Elasticsearch is needed.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::io::Write;
+use wadup_core::shared_buffer::SharedBuffer;
+use wadup_core::*;
+
+const LARGE_FILE_BYTES: usize = 256 * 1024 * 1024;
+const ROWS: usize = 100_000;
+const FAN_OUT: usize = 64;
+const CHILD_BYTES: usize = 1024;
+
+/// Deterministic bytes that don't repeat, so slice dedup doesn't merge children
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn load_large_file(c: &mut Criterion) {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    let chunk = noise(1024 * 1024);
+    for _ in 0..LARGE_FILE_BYTES / chunk.len() {
+        file.write_all(&chunk).unwrap();
+    }
+    file.flush().unwrap();
+
+    let mut group = c.benchmark_group("shared_buffer");
+    group.throughput(Throughput::Bytes(LARGE_FILE_BYTES as u64));
+    group.bench_function("from_file_256mib", |b| {
+        b.iter(|| {
+            let bytes = SharedBuffer::from_file(file.path()).unwrap().to_bytes();
+            // Touch one byte per page, as a module reading the whole file would
+            bytes.iter().step_by(4096).fold(0u8, |acc, b| acc ^ b)
+        })
+    });
+    group.finish();
+}
+
+fn insert_rows(c: &mut Criterion) {
+    let schema = TableSchema {
+        name: "strings".to_string(),
+        columns: vec![Column::new("offset", DataType::Int64), Column::new("value", DataType::String)],
+        global: false,
+    };
+    let uuid = uuid::Uuid::new_v4().to_string();
+
+    let mut group = c.benchmark_group("metadata");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    group.bench_function("insert_100k_rows", |b| {
+        b.iter_batched(
+            || {
+                let store = MetadataStore::new_in_memory("wadup");
+                store.start_content(&uuid, "bench.bin", None).unwrap();
+                store.set_current_module(&uuid, "bench", None).unwrap();
+                store.define_table("bench", schema.clone()).unwrap();
+                store
+            },
+            |store| {
+                for i in 0..ROWS {
+                    let values = [Value::Int64(i as i64), Value::String(format!("string {}", i))];
+                    store.insert_row("strings", &uuid, &values).unwrap();
+                }
+                store
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// A module that slices `FAN_OUT` children of `CHILD_BYTES` out of any
+/// content large enough to hold them
+fn fan_out_guest() -> String {
+    let mut data = String::new();
+    let mut body = String::new();
+    let mut offset = 1024;
+    for i in 0..FAN_OUT {
+        let path = format!("/subcontent/metadata_{}.json", i);
+        let json = format!(r#"{{"filename":"child_{}.bin","offset":{},"length":{}}}"#, i, i * CHILD_BYTES, CHILD_BYTES);
+        data.push_str(&format!("(data (i32.const {}) \"{}{}\")\n", offset, path, json.replace('"', "\\\"")));
+        // path_open(O_CREAT | O_TRUNC) -> fd at 0, iovec at 8, nwritten at 16
+        body.push_str(&format!(
+            "(drop (call $path_open (i32.const 3) (i32.const 0) (i32.const {}) (i32.const {}) (i32.const 9) \
+                (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+             (i32.store (i32.const 8) (i32.const {}))
+             (i32.store (i32.const 12) (i32.const {}))
+             (drop (call $fd_write (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 16)))
+             (drop (call $fd_close (i32.load (i32.const 0))))\n",
+            offset, path.len(), offset + path.len(), json.len()
+        ));
+        offset += path.len() + json.len();
+    }
+    format!(r#"
+        (module
+            (import "wasi_snapshot_preview1" "path_open"
+                (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+            (memory (export "memory") 1)
+            {}
+            (func (export "process") (result i32)
+                {}
+                (i32.const 0)))
+    "#, data, body)
+}
+
+fn process_fan_out(c: &mut Criterion) {
+    let modules = tempfile::tempdir().unwrap();
+    std::fs::write(modules.path().join("fanout.wasm"), fan_out_guest()).unwrap();
+    let min_size = FAN_OUT * CHILD_BYTES;
+    std::fs::write(modules.path().join("fanout.manifest.json"), format!(r#"{{"min_size":{}}}"#, min_size)).unwrap();
+
+    let inputs: Vec<(String, Bytes)> = (0..16)
+        .map(|i| (format!("input_{}.bin", i), Bytes::from(noise(min_size + i))))
+        .collect();
+    let config = RunConfig {
+        modules: modules.path().to_path_buf(),
+        inputs,
+        threads: 4,
+        ..RunConfig::default()
+    };
+
+    let mut group = c.benchmark_group("processor");
+    group.throughput(Throughput::Elements((config.inputs.len() * (FAN_OUT + 1)) as u64));
+    group.sample_size(10);
+    group.bench_function("fan_out_16x64", |b| {
+        b.iter(|| {
+            let summary = run_with(&config, MetadataStore::new_in_memory("wadup"), Vec::new()).unwrap();
+            assert_eq!(summary.failed, 0);
+            summary
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, load_large_file, insert_rows, process_fan_out);
+criterion_main!(benches);
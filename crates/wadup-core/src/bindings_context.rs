@@ -1,13 +1,24 @@
+use bytes::Bytes;
+use std::collections::HashMap;
 use uuid::Uuid;
-use crate::bindings_types::{Value, TableSchema};
+use crate::bindings_types::{Column, Value, TableSchema};
 use crate::shared_buffer::SharedBuffer;
 
 pub struct ProcessingContext {
     pub content_uuid: Uuid,
     pub content_data: SharedBuffer,
+    /// The content this one was emitted from; `None` for root content
+    pub parent: Option<ParentInfo>,
     pub subcontent: Vec<SubContentEmission>,
+    /// Extra labeled edges from the current content to other contents
+    pub links: Vec<ContentLink>,
     pub metadata: Vec<MetadataRow>,
     pub table_schemas: Vec<TableSchema>,
+    /// Columns added to tables that are already defined
+    pub added_columns: Vec<ColumnAddition>,
+    /// Files the module wrote under `/streams`, mounted read-only for the
+    /// modules that run after it on the same content
+    pub streams: Vec<(String, Bytes)>,
     /// Captured stdout from module (None if empty)
     pub stdout: Option<String>,
     /// Captured stderr from module (None if empty)
@@ -16,6 +27,16 @@ pub struct ProcessingContext {
     pub stdout_truncated: bool,
     /// Whether stderr was truncated due to size limit
     pub stderr_truncated: bool,
+    /// Errors reported with `report_error`. They stay in the store when the
+    /// rest is taken, so they're read with `ModuleInstance::take_reported_errors`
+    /// whether or not the module then failed.
+    pub errors: Vec<GuestError>,
+    /// Why sub-content the module tried to emit was dropped, e.g. a data file
+    /// whose length doesn't match the `size` its metadata file declares
+    pub rejected_subcontent: Vec<String>,
+    /// SHA-256 of each metadata file already processed for this content, by
+    /// path, so a file written again under a reused index isn't double-counted
+    pub processed_metadata: HashMap<String, [u8; 32]>,
 }
 
 impl ProcessingContext {
@@ -23,30 +44,69 @@ impl ProcessingContext {
         Self {
             content_uuid,
             content_data,
+            parent: None,
             subcontent: Vec::new(),
+            links: Vec::new(),
             metadata: Vec::new(),
             table_schemas: Vec::new(),
+            added_columns: Vec::new(),
+            streams: Vec::new(),
             stdout: None,
             stderr: None,
             stdout_truncated: false,
             stderr_truncated: false,
+            errors: Vec::new(),
+            rejected_subcontent: Vec::new(),
+            processed_metadata: HashMap::new(),
         }
     }
 
     pub fn clear(&mut self) {
         self.subcontent.clear();
+        self.links.clear();
         self.metadata.clear();
         self.table_schemas.clear();
+        self.added_columns.clear();
+        self.streams.clear();
         self.stdout = None;
         self.stderr = None;
         self.stdout_truncated = false;
         self.stderr_truncated = false;
+        self.errors.clear();
+        self.processed_metadata.clear();
     }
 }
 
+/// What a sub-content module can learn about its parent content
+#[derive(Debug, Clone)]
+pub struct ParentInfo {
+    pub uuid: Uuid,
+    pub filename: String,
+}
+
 pub struct SubContentEmission {
+    /// UUID the sub-content will be processed under, assigned at emission so
+    /// later emissions can reference it
+    pub uuid: Uuid,
     pub data: SubContentData,
     pub filename: String,
+    pub relation: Option<String>,
+    /// Already queued by the instance's sub-content sink while the module ran
+    pub dispatched: bool,
+}
+
+/// Structured failure reported with `report_error`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestError {
+    /// Module-defined error code
+    pub code: u32,
+    pub message: String,
+}
+
+/// Edge requested with `link_content`, from the current content to `child_uuid`
+pub struct ContentLink {
+    pub child_uuid: Uuid,
+    pub relation: String,
 }
 
 pub enum SubContentData {
@@ -54,9 +114,23 @@ pub enum SubContentData {
     Bytes(bytes::Bytes),
     /// Slice of parent content (zero-copy reference)
     Slice { offset: usize, length: usize },
+    /// The bytes of another content already known to the processor
+    Reference(Uuid),
 }
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct MetadataRow {
     pub table_name: String,
     pub values: Vec<Value>,
+    /// Not tied to the content being processed (`Table::insert_global`)
+    #[serde(default)]
+    pub global: bool,
+}
+
+/// A column added to an existing table (`Table::add_column`). Rows stored
+/// before it was added, or written without it, hold null in it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ColumnAddition {
+    pub table_name: String,
+    pub column: Column,
 }
@@ -6,24 +6,297 @@ pub enum DataType {
     Float64,
     String,
     Boolean,
+    /// JSON document carried in a `Value::String`; validated on insert and
+    /// indexed as a nested object
+    Json,
+    /// Carried in a `Value::Timestamp` (or `Value::Int64`) as milliseconds
+    /// since the Unix epoch and stored as ISO-8601 text
+    Timestamp,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DataType {
+    /// SQL column type used when rendering schemas
+    pub fn sql_type(&self) -> &'static str {
+        match self {
+            DataType::Int64 => "INTEGER",
+            DataType::Float64 => "REAL",
+            DataType::String => "TEXT",
+            DataType::Boolean => "BOOLEAN",
+            DataType::Json => "TEXT",
+            // Epoch milliseconds
+            DataType::Timestamp => "INTEGER",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Column {
     pub name: String,
     pub data_type: DataType,
+    /// Part of the table's primary key. Rows repeating an existing key are ignored.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub primary_key: bool,
+    /// Rows repeating an existing value in this column are ignored.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unique: bool,
+    /// Human-readable meaning or unit, recorded in the table's schema
+    /// documents. Declarations differing only in descriptions don't conflict.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Column of another table each value must already be in; rows whose
+    /// value isn't are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub references: Option<ForeignKey>,
+}
+
+/// The referenced side of a foreign key: a primary key or unique column
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub column: String,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, data_type: DataType) -> Self {
+        Self {
+            name: name.into(),
+            data_type,
+            primary_key: false,
+            unique: false,
+            description: None,
+            references: None,
+        }
+    }
+
+    /// Same name, type and constraints, ignoring the description
+    pub fn same_definition(&self, other: &Column) -> bool {
+        self.name == other.name
+            && self.data_type == other.data_type
+            && self.primary_key == other.primary_key
+            && self.unique == other.unique
+            && self.references == other.references
+    }
+
+    /// SQL-style constraint keywords, empty if the column has none
+    pub fn constraint_label(&self) -> &'static str {
+        match (self.primary_key, self.unique) {
+            (true, _) => "PRIMARY KEY",
+            (false, true) => "UNIQUE",
+            (false, false) => "",
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TableSchema {
     pub name: String,
     pub columns: Vec<Column>,
+    /// Accepts run-global rows, stored with a null `content_uuid`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub global: bool,
+}
+
+impl TableSchema {
+    /// A schema for rows of a table that was never declared, with columns
+    /// `column_0`, `column_1`, ... typed after `values`
+    pub fn inferred(name: &str, values: &[Value], global: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            columns: values.iter()
+                .enumerate()
+                .map(|(i, value)| Column::new(format!("column_{}", i), value.data_type()))
+                .collect(),
+            global,
+        }
+    }
+
+    /// Render the schema as a `CREATE TABLE` statement, with column
+    /// descriptions as `--` comments
+    pub fn to_create_table(&self) -> String {
+        let primary_key: Vec<&Column> = self.columns.iter().filter(|c| c.primary_key).collect();
+        let mut columns: Vec<(String, Option<&str>)> = self.columns.iter()
+            .map(|c| {
+                let name = quote_identifier(&c.name);
+                let mut line = match c.data_type {
+                    DataType::Json => format!("    {} TEXT CHECK (json_valid({}))", name, name),
+                    _ => format!("    {} {}", name, c.data_type.sql_type()),
+                };
+                if c.primary_key && primary_key.len() == 1 {
+                    line.push_str(" PRIMARY KEY");
+                } else if c.unique && !c.primary_key {
+                    line.push_str(" UNIQUE");
+                }
+                if let Some(reference) = &c.references {
+                    line.push_str(&format!(
+                        " REFERENCES {} ({})",
+                        quote_identifier(&reference.table),
+                        quote_identifier(&reference.column)
+                    ));
+                }
+                (line, c.description.as_deref())
+            })
+            .collect();
+        if primary_key.len() > 1 {
+            let names: Vec<String> = primary_key.iter().map(|c| quote_identifier(&c.name)).collect();
+            columns.push((format!("    PRIMARY KEY ({})", names.join(", ")), None));
+        }
+        let last = columns.len().saturating_sub(1);
+        let lines: Vec<String> = columns.into_iter().enumerate()
+            .map(|(i, (mut line, description))| {
+                if i < last {
+                    line.push(',');
+                }
+                if let Some(description) = description {
+                    line.push_str(" -- ");
+                    line.push_str(&description.replace(['\r', '\n'], " "));
+                }
+                line
+            })
+            .collect();
+        format!("CREATE TABLE {} (\n{}\n);", quote_identifier(&self.name), lines.join("\n"))
+    }
+
+    /// Groups of columns whose values must not repeat across rows: the
+    /// primary key (all its columns together), then each unique column
+    pub fn unique_keys(&self) -> Vec<Vec<usize>> {
+        let mut keys = Vec::new();
+        let primary_key: Vec<usize> = (0..self.columns.len()).filter(|&i| self.columns[i].primary_key).collect();
+        if !primary_key.is_empty() {
+            keys.push(primary_key);
+        }
+        for (i, column) in self.columns.iter().enumerate() {
+            if column.unique && !column.primary_key {
+                keys.push(vec![i]);
+            }
+        }
+        keys
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Value {
     Int64(i64),
-    Float64(f64),
+    /// NaN and the infinities are `"NaN"`, `"Infinity"` and `"-Infinity"`
+    /// in JSON and in stored columns
+    Float64(#[serde(with = "float64")] f64),
     String(String),
     Boolean(bool),
+    /// Milliseconds since the Unix epoch, UTC
+    Timestamp(i64),
+}
+
+/// JSON encoding of `Value::Float64`, spelled as `wadup_abi::float64`
+/// describes, so host and guest rows agree
+mod float64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use wadup_abi::float64::{from_non_finite_name, non_finite_name};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match non_finite_name(*value) {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_f64(*value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Encoded {
+            Number(f64),
+            Name(String),
+        }
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Number(value) => Ok(value),
+            Encoded::Name(name) => from_non_finite_name(&name)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid Float64 value {:?}", name))),
+        }
+    }
+}
+
+impl Value {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Value::Int64(_) => DataType::Int64,
+            Value::Float64(_) => DataType::Float64,
+            Value::String(_) => DataType::String,
+            Value::Boolean(_) => DataType::Boolean,
+            Value::Timestamp(_) => DataType::Timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_table_renders_key_constraints() {
+        let mut id = Column::new("id", DataType::Int64);
+        id.primary_key = true;
+        let mut path = Column::new("path", DataType::String);
+        path.unique = true;
+        let schema = TableSchema {
+            name: "files".to_string(),
+            columns: vec![id.clone(), path],
+            global: false,
+        };
+        assert_eq!(
+            schema.to_create_table(),
+            "CREATE TABLE \"files\" (\n    \"id\" INTEGER PRIMARY KEY,\n    \"path\" TEXT UNIQUE\n);"
+        );
+        assert_eq!(schema.unique_keys(), vec![vec![0], vec![1]]);
+
+        let mut part = Column::new("part", DataType::Int64);
+        part.primary_key = true;
+        let composite = TableSchema {
+            name: "parts".to_string(),
+            columns: vec![id, part],
+            global: false,
+        };
+        assert_eq!(
+            composite.to_create_table(),
+            "CREATE TABLE \"parts\" (\n    \"id\" INTEGER,\n    \"part\" INTEGER,\n    PRIMARY KEY (\"id\", \"part\")\n);"
+        );
+        assert_eq!(composite.unique_keys(), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn test_create_table_comments_descriptions() {
+        let mut size = Column::new("size", DataType::Int64);
+        size.description = Some("File size\nin bytes".to_string());
+        let schema = TableSchema {
+            name: "files".to_string(),
+            columns: vec![size.clone(), Column::new("path", DataType::String)],
+            global: false,
+        };
+        assert_eq!(
+            schema.to_create_table(),
+            "CREATE TABLE \"files\" (\n    \"size\" INTEGER, -- File size in bytes\n    \"path\" TEXT\n);"
+        );
+        assert!(size.same_definition(&Column::new("size", DataType::Int64)));
+    }
+
+    #[test]
+    fn test_create_table_renders_references() {
+        let mut user = Column::new("user_id", DataType::Int64);
+        user.references = Some(ForeignKey { table: "users".to_string(), column: "id".to_string() });
+        let schema = TableSchema {
+            name: "orders".to_string(),
+            columns: vec![user.clone()],
+            global: false,
+        };
+        assert_eq!(
+            schema.to_create_table(),
+            "CREATE TABLE \"orders\" (\n    \"user_id\" INTEGER REFERENCES \"users\" (\"id\")\n);"
+        );
+        assert!(!user.same_definition(&Column::new("user_id", DataType::Int64)));
+    }
 }
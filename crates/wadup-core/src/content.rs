@@ -10,9 +10,30 @@ pub struct Content {
     pub data: ContentData,
     pub filename: String,
     pub parent_uuid: Option<Uuid>,
+    pub parent_filename: Option<String>,
+    /// The parent's [`Content::lineage`]; None for root content
+    pub parent_lineage: Option<String>,
+    /// Module that emitted this content; None for root content
+    pub produced_by: Option<String>,
+    /// Where a slice sub-content lies in its parent and its base; None
+    /// unless emitted as a slice
+    pub slice: Option<SliceRange>,
     pub depth: usize,
 }
 
+/// Range of a slice sub-content, both relative to its parent and absolute
+/// in its base: the nearest ancestor holding bytes of its own, which is the
+/// root content when every level in between is a slice too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct SliceRange {
+    /// Offset into the parent
+    pub offset: usize,
+    pub length: usize,
+    pub base_uuid: Uuid,
+    /// Offset into the base, composed through every slice in between
+    pub base_offset: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum ContentData {
     Owned(SharedBuffer),
@@ -21,6 +42,8 @@ pub enum ContentData {
         offset: usize,
         length: usize,
     },
+    /// Same bytes as another stored content, e.g. a duplicate a module recognized
+    Reference(Uuid),
 }
 
 impl Content {
@@ -30,28 +53,54 @@ impl Content {
             data: ContentData::Owned(buffer),
             filename,
             parent_uuid: None,
+            parent_filename: None,
+            parent_lineage: None,
+            produced_by: None,
+            slice: None,
             depth: 0,
         }
     }
 
     pub fn new_subcontent(
         parent: &Content,
+        uuid: Uuid,
         data: ContentData,
         filename: String,
+        produced_by: &str,
         max_depth: usize,
     ) -> Result<Self> {
         if parent.depth >= max_depth {
             anyhow::bail!("Max recursion depth exceeded (limit: {})", max_depth);
         }
 
+        let slice = match data {
+            ContentData::Borrowed { offset, length, .. } => Some(match parent.slice {
+                Some(range) => SliceRange { offset, length, base_uuid: range.base_uuid, base_offset: range.base_offset + offset },
+                None => SliceRange { offset, length, base_uuid: parent.uuid, base_offset: offset },
+            }),
+            _ => None,
+        };
+
         Ok(Self {
-            uuid: Uuid::new_v4(),
+            uuid,
             data,
             filename,
             parent_uuid: Some(parent.uuid),
+            parent_filename: Some(parent.filename.clone()),
+            parent_lineage: Some(parent.lineage()),
+            produced_by: Some(produced_by.to_string()),
+            slice,
             depth: parent.depth + 1,
         })
     }
+
+    /// UUIDs from the root content down to this one, joined by `/`
+    pub fn lineage(&self) -> String {
+        match &self.parent_lineage {
+            Some(parent) => format!("{}/{}", parent, self.uuid),
+            None => self.uuid.to_string(),
+        }
+    }
 }
 
 pub struct ContentStore {
@@ -76,19 +125,34 @@ impl ContentStore {
     /// Resolve content to a SharedBuffer
     ///
     /// For owned content, returns a cheap clone of the buffer.
-    /// For borrowed content, creates a zero-copy slice of the parent buffer.
+    /// For borrowed content, returns the view registered for it when it was
+    /// emitted, which keeps the parent's bytes alive on its own, so the
+    /// parent's entry isn't needed. Otherwise creates a zero-copy slice of the
+    /// parent buffer, or returns None if the range doesn't fit in it.
+    /// For referenced content, returns the referenced content's buffer.
     pub fn resolve(&self, content: &Content) -> Option<SharedBuffer> {
         match &content.data {
             ContentData::Owned(buffer) => Some(buffer.clone()),
             ContentData::Borrowed { parent_uuid, offset, length } => {
+                if let Some(view) = self.get(&content.uuid) {
+                    return Some(view);
+                }
                 let parent_buffer = self.get(parent_uuid)?;
+                let end = offset.checked_add(*length).filter(|&end| end <= parent_buffer.len())?;
                 // Zero-copy slice via Bytes::slice()
-                Some(parent_buffer.slice(*offset..*offset + *length))
+                Some(parent_buffer.slice(*offset..end))
             }
+            ContentData::Reference(uuid) => self.get(uuid),
         }
     }
 }
 
+impl Default for ContentStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for ContentStore {
     fn clone(&self) -> Self {
         Self {
@@ -96,3 +160,58 @@ impl Clone for ContentStore {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_slices_compose_offsets_to_the_base() {
+        let store = ContentStore::new();
+        let root_buffer = SharedBuffer::from_vec(b"0123456789".to_vec());
+        let root = Content::new_root(root_buffer.clone(), "root.bin".to_string());
+        store.insert(root.uuid, root_buffer.clone());
+
+        let borrowed = |parent: &Content, offset, length| ContentData::Borrowed { parent_uuid: parent.uuid, offset, length };
+        let child = Content::new_subcontent(&root, Uuid::new_v4(), borrowed(&root, 2, 6), "child".to_string(), "m", 5).unwrap();
+        store.insert(child.uuid, store.resolve(&child).unwrap());
+        let grandchild = Content::new_subcontent(&child, Uuid::new_v4(), borrowed(&child, 3, 2), "grandchild".to_string(), "m", 5).unwrap();
+
+        let slice = grandchild.slice.unwrap();
+        assert_eq!(slice, SliceRange { offset: 3, length: 2, base_uuid: root.uuid, base_offset: 5 });
+        let absolute = root_buffer.slice(slice.base_offset..slice.base_offset + slice.length);
+        assert_eq!(store.resolve(&grandchild).unwrap().as_slice(), absolute.as_slice());
+        assert_eq!(absolute.as_slice(), b"56");
+
+        // Content with bytes of its own is the base of slices below it
+        let owned = ContentData::Owned(SharedBuffer::from_vec(b"xyz".to_vec()));
+        let extracted = Content::new_subcontent(&child, Uuid::new_v4(), owned, "x".to_string(), "m", 5).unwrap();
+        assert_eq!(extracted.slice, None);
+        let inner = Content::new_subcontent(&extracted, Uuid::new_v4(), borrowed(&extracted, 1, 1), "y".to_string(), "m", 5).unwrap();
+        assert_eq!(inner.slice.map(|s| (s.base_uuid, s.base_offset)), Some((extracted.uuid, 1)));
+    }
+
+    #[test]
+    fn test_borrowed_chain_resolves_without_its_ancestors_entries() {
+        let store = ContentStore::new();
+        let mut chain = vec![Content::new_root(SharedBuffer::from_vec((0..64).collect()), "root.bin".to_string())];
+        store.insert(chain[0].uuid, store.resolve(&chain[0]).unwrap());
+        // Register each slice the way emission does, then drop every
+        // ancestor's entry as an eviction would
+        for depth in 0..8 {
+            let parent = &chain[depth];
+            let data = ContentData::Borrowed { parent_uuid: parent.uuid, offset: 2, length: 60 - 4 * depth };
+            let child = Content::new_subcontent(parent, Uuid::new_v4(), data, format!("level_{}", depth), "m", 10).unwrap();
+            let view = store.get(&parent.uuid).unwrap().slice(2..62 - 4 * depth);
+            store.insert(child.uuid, view);
+            store.store.write().unwrap().remove(&parent.uuid);
+            chain.push(child);
+        }
+
+        let leaf = chain.last().unwrap();
+        let slice = leaf.slice.unwrap();
+        assert_eq!((slice.base_uuid, slice.base_offset), (chain[0].uuid, 16));
+        let expected: Vec<u8> = (16..16 + slice.length as u8).collect();
+        assert_eq!(store.resolve(leaf).unwrap().as_slice(), &expected[..]);
+    }
+}
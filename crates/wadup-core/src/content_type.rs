@@ -0,0 +1,123 @@
+//! Content-type detection from leading magic bytes.
+
+/// Signatures checked in order: (offset, magic bytes, MIME type)
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"PK\x07\x08", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"MZ", "application/vnd.microsoft.portable-executable"),
+    (0, b"\x00asm", "application/wasm"),
+];
+
+/// Detect the MIME type of `data` from its magic bytes, or None if no
+/// known signature matches.
+pub fn detect_content_type(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES.iter()
+        .find(|(offset, magic, _)| data.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(_, _, mime)| *mime)
+}
+
+/// Leading bytes `detect_text_encoding` inspects when there is no BOM
+const ENCODING_SAMPLE: usize = 64 * 1024;
+
+/// Byte-order marks checked in order (UTF-32 before the UTF-16 prefix it shares)
+const BOMS: &[(&[u8], &str)] = &[
+    (b"\xef\xbb\xbf", "utf-8"),
+    (b"\xff\xfe\x00\x00", "utf-32le"),
+    (b"\x00\x00\xfe\xff", "utf-32be"),
+    (b"\xff\xfe", "utf-16le"),
+    (b"\xfe\xff", "utf-16be"),
+];
+
+/// Guess the text encoding of `data` from its byte-order mark or, without
+/// one, its leading bytes: `ascii` or `utf-8` for valid UTF-8 without
+/// control characters, `utf-16le`/`utf-16be` for mostly-ASCII text with
+/// every other byte zero. None for data that doesn't look like text.
+pub fn detect_text_encoding(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, encoding)) = BOMS.iter().find(|(bom, _)| data.starts_with(bom)) {
+        return Some(encoding);
+    }
+    let sample = &data[..data.len().min(ENCODING_SAMPLE)];
+    if sample.is_empty() {
+        return None;
+    }
+    let is_text = |b: u8| !b.is_ascii_control() || matches!(b, b'\t' | b'\n' | b'\r' | b'\x0c');
+
+    // Valid UTF-8, allowing a character cut off by the end of the sample
+    let valid = match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if valid && sample.iter().all(|&b| is_text(b)) {
+        return Some(if sample.is_ascii() { "ascii" } else { "utf-8" });
+    }
+
+    // UTF-16 without a BOM: ASCII code units leave one byte of each pair zero
+    if sample.len() >= 2 {
+        let pairs = sample.chunks_exact(2);
+        let count = pairs.len();
+        let (mut low, mut high) = (0, 0);
+        for pair in pairs {
+            if pair[1] == 0 && is_text(pair[0]) {
+                low += 1;
+            } else if pair[0] == 0 && is_text(pair[1]) {
+                high += 1;
+            }
+        }
+        if low * 10 >= count * 9 {
+            return Some("utf-16le");
+        }
+        if high * 10 >= count * 9 {
+            return Some("utf-16be");
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_content_type() {
+        assert_eq!(detect_content_type(b"PK\x03\x04\x14\x00"), Some("application/zip"));
+        assert_eq!(detect_content_type(b"SQLite format 3\x00\x10\x00"), Some("application/vnd.sqlite3"));
+        assert_eq!(detect_content_type(b"\x00asm\x01\x00\x00\x00"), Some("application/wasm"));
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(detect_content_type(&tar), Some("application/x-tar"));
+
+        assert_eq!(detect_content_type(b""), None);
+        assert_eq!(detect_content_type(b"PK"), None);
+        assert_eq!(detect_content_type(b"plain text"), None);
+    }
+
+    #[test]
+    fn test_detect_text_encoding() {
+        assert_eq!(detect_text_encoding(b"\xff\xfeh\x00i\x00"), Some("utf-16le"));
+        assert_eq!(detect_text_encoding(b"\xfe\xff\x00h\x00i"), Some("utf-16be"));
+        assert_eq!(detect_text_encoding(b"\xff\xfe\x00\x00h\x00\x00\x00"), Some("utf-32le"));
+        assert_eq!(detect_text_encoding(b"\xef\xbb\xbfhi"), Some("utf-8"));
+        assert_eq!(detect_text_encoding(b"line one\r\nline two\n"), Some("ascii"));
+        assert_eq!(detect_text_encoding("caf\u{e9}".as_bytes()), Some("utf-8"));
+        assert_eq!(detect_text_encoding(b"h\x00e\x00l\x00l\x00o\x00"), Some("utf-16le"));
+        assert_eq!(detect_text_encoding(b"\x00h\x00e\x00l\x00l\x00o"), Some("utf-16be"));
+
+        assert_eq!(detect_text_encoding(b""), None);
+        assert_eq!(detect_text_encoding(b"PK\x03\x04\x14\x00\x00\x00"), None);
+        assert_eq!(detect_text_encoding(b"\x7fELF\x02\x01\x01"), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Host-level routing of contents to modules (`--dispatch`).
+//!
+//! A dispatch table is a TOML file mapping filename globs and content types
+//! to the modules run on matching contents:
+//!
+//! ```toml
+//! # Modules for contents no route matches; every module when unset
+//! default = ["byte_counter"]
+//!
+//! [[route]]
+//! filename = "*.zip"
+//! modules = ["zip_extractor"]
+//!
+//! [[route]]
+//! content_type = "image/*"
+//! modules = ["exif_parser", "byte_counter"]
+//! ```
+//!
+//! A content runs the modules of every route it matches. Module manifests
+//! still apply on top, so a routed module can still skip a content its
+//! manifest doesn't accept.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DispatchTable {
+    /// Modules run on contents no route matches; every module when unset
+    #[serde(default)]
+    pub default: Option<Vec<String>>,
+    #[serde(default, rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+/// Contents matching every pattern set on the route run its `modules`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Glob (`*` and `?`) matched against the last component of the filename
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Glob matched against the detected content type (see `detect_content_type`).
+    /// Contents whose type can't be detected never match.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub modules: Vec<String>,
+}
+
+impl DispatchTable {
+    /// Load and check the table at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read dispatch table {:?}", path))?;
+        let table: Self = toml::from_str(&text)
+            .with_context(|| format!("Invalid dispatch table {:?}", path))?;
+        if let Some(index) = table.routes.iter().position(|r| r.filename.is_none() && r.content_type.is_none()) {
+            anyhow::bail!("Invalid dispatch table {:?}: route {} sets neither filename nor content_type", path, index + 1);
+        }
+        Ok(table)
+    }
+
+    /// Every module name the table refers to
+    pub fn module_names(&self) -> impl Iterator<Item = &str> {
+        self.default.iter().flatten()
+            .chain(self.routes.iter().flat_map(|route| &route.modules))
+            .map(String::as_str)
+    }
+
+    /// Whether routing needs the content's detected type
+    pub fn needs_content_type(&self) -> bool {
+        self.routes.iter().any(|route| route.content_type.is_some())
+    }
+
+    /// Modules to run on the content named `filename` whose detected type
+    /// is `content_type`, or None to run every module.
+    pub fn route(&self, filename: &str, content_type: Option<&str>) -> Option<HashSet<&str>> {
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+        let mut modules = HashSet::new();
+        let mut matched = false;
+        for route in &self.routes {
+            let filename_matches = route.filename.as_deref().is_none_or(|glob| glob_matches(glob, name));
+            let type_matches = route.content_type.as_deref()
+                .is_none_or(|glob| content_type.is_some_and(|t| glob_matches(glob, t)));
+            if filename_matches && type_matches {
+                matched = true;
+                modules.extend(route.modules.iter().map(String::as_str));
+            }
+        }
+        if matched {
+            return Some(modules);
+        }
+        self.default.as_ref().map(|default| default.iter().map(String::as_str).collect())
+    }
+}
+
+/// Match `text` against `glob`, where `*` matches any run of characters and
+/// `?` any one character
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let (glob, text): (Vec<char>, Vec<char>) = (glob.chars().collect(), text.chars().collect());
+    let (mut g, mut t) = (0, 0);
+    // Where the last `*` was and how much of `text` it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_g, star_t)) => {
+                    g = star_g + 1;
+                    t = star_t + 1;
+                    star = Some((star_g, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.zip", "a.zip"));
+        assert!(glob_matches("*.zip", ".zip"));
+        assert!(!glob_matches("*.zip", "a.zip.txt"));
+        assert!(glob_matches("a?c*", "abcdef"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(glob_matches("image/*", "image/png"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn test_route_unions_matching_routes() {
+        let table: DispatchTable = toml::from_str(r#"
+            default = ["counter"]
+
+            [[route]]
+            filename = "*.zip"
+            modules = ["unzip"]
+
+            [[route]]
+            content_type = "application/zip"
+            modules = ["zipinfo"]
+        "#).unwrap();
+
+        let zip = Some("application/zip");
+        assert_eq!(table.route("dir/a.zip", zip), Some(HashSet::from(["unzip", "zipinfo"])));
+        assert_eq!(table.route("a.zip", None), Some(HashSet::from(["unzip"])));
+        assert_eq!(table.route("a.txt", None), Some(HashSet::from(["counter"])));
+        assert_eq!(DispatchTable { default: None, ..table }.route("a.txt", None), None);
+    }
+
+    #[test]
+    fn test_load_rejects_routes_without_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dispatch.toml");
+        std::fs::write(&path, "[[route]]\nmodules = [\"m\"]\n").unwrap();
+        assert!(DispatchTable::load(&path).is_err());
+    }
+}
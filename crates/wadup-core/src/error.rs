@@ -0,0 +1,121 @@
+//! Errors returned by the library's entry points, for embedders that need to
+//! tell failures apart without matching on messages.
+
+use crate::metadata::SchemaConflict;
+use crate::wasm::{LimitExceeded, ResourceLimitError};
+
+/// Why processing failed. Internally errors are `anyhow::Error`s; they are
+/// sorted into these variants where they leave the public API.
+#[derive(Debug, thiserror::Error)]
+pub enum WadupError {
+    /// The module has no entrypoint export with a supported signature
+    #[error("Module '{module}' {entrypoint} function is missing or has unsupported signature")]
+    ModuleMissingProcess { module: String, entrypoint: String },
+
+    #[error("Module '{module}' exceeded fuel limit (CPU limit)")]
+    OutOfFuel { module: String },
+
+    #[error("Module '{module}' stack overflow")]
+    StackOverflow { module: String },
+
+    #[error("Module '{module}' memory limit exceeded")]
+    MemoryLimit { module: String },
+
+    /// The module was interrupted when `--stall-timeout` passed
+    #[error("Module '{module}' made no progress within the stall timeout")]
+    Stalled { module: String },
+
+    /// Two modules defined the same table differently under `--strict-schema`
+    #[error(transparent)]
+    SchemaMismatch(Box<SchemaConflict>),
+
+    #[error(transparent)]
+    Io(std::io::Error),
+
+    /// A request to the Elasticsearch metadata store failed
+    #[error("Metadata store request failed: {0}")]
+    Store(reqwest::Error),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl WadupError {
+    /// The resource limit that stopped the module, for limit errors
+    pub fn limit_exceeded(&self) -> Option<LimitExceeded> {
+        match self {
+            Self::OutOfFuel { .. } => Some(LimitExceeded::Fuel),
+            Self::StackOverflow { .. } => Some(LimitExceeded::Stack),
+            Self::MemoryLimit { .. } => Some(LimitExceeded::Memory),
+            Self::Stalled { .. } => Some(LimitExceeded::Stall),
+            _ => None,
+        }
+    }
+}
+
+impl From<ResourceLimitError> for WadupError {
+    fn from(error: ResourceLimitError) -> Self {
+        let module = error.module;
+        match error.limit {
+            LimitExceeded::Fuel => Self::OutOfFuel { module },
+            LimitExceeded::Stack => Self::StackOverflow { module },
+            LimitExceeded::Memory => Self::MemoryLimit { module },
+            LimitExceeded::Stall => Self::Stalled { module },
+        }
+    }
+}
+
+impl From<anyhow::Error> for WadupError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match outermost::<WadupError>(error) {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let error = match outermost::<ResourceLimitError>(error) {
+            Ok(error) => return error.into(),
+            Err(error) => error,
+        };
+        let error = match outermost::<SchemaConflict>(error) {
+            Ok(conflict) => return Self::SchemaMismatch(Box::new(conflict)),
+            Err(error) => error,
+        };
+        let error = match outermost::<std::io::Error>(error) {
+            Ok(error) => return Self::Io(error),
+            Err(error) => error,
+        };
+        match outermost::<reqwest::Error>(error) {
+            Ok(error) => Self::Store(error),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+/// Take `error` as an `E` only if no context was added on top of one, so
+/// that context stays in the message
+fn outermost<E: std::error::Error + Send + Sync + 'static>(error: anyhow::Error) -> Result<E, anyhow::Error> {
+    if error.chain().next().is_some_and(|outer| outer.is::<E>()) {
+        error.downcast::<E>()
+    } else {
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anyhow_errors_are_sorted_into_variants() {
+        let limit = anyhow::Error::from(ResourceLimitError { module: "m".to_string(), limit: LimitExceeded::Stack });
+        assert!(matches!(WadupError::from(limit), WadupError::StackOverflow { module } if module == "m"));
+
+        let io = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(matches!(WadupError::from(io), WadupError::Io(_)));
+
+        // Context is kept rather than stripped to reach the I/O error
+        let io = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound)).context("Failed to read x");
+        let error = WadupError::from(io);
+        assert!(matches!(error, WadupError::Other(_)));
+        assert_eq!(error.to_string(), "Failed to read x");
+    }
+}
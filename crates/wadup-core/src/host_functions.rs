@@ -0,0 +1,558 @@
+//! WADUP-specific host functions, imported by guests from the `wadup` module.
+//!
+//! Everything else a guest needs goes through WASI and the in-memory
+//! filesystem; these cover work that is much cheaper on the host. Modules
+//! that don't import them are unaffected.
+
+use std::io::{self, BufRead, Read};
+
+use anyhow::Result;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use sha2::{Digest, Sha256, Sha512};
+use wasmtime::{AsContext, AsContextMut, Caller, Linker, Memory};
+
+use crate::bindings_context::{ContentLink, GuestError, SubContentData, SubContentEmission};
+use crate::wasi_impl::Errno;
+use crate::wasm::{Heartbeat, StoreData};
+
+/// Import module name for WADUP host functions
+pub const HOST_MODULE: &str = "wadup";
+
+/// Every function `add_to_linker` defines under `HOST_MODULE`
+pub const HOST_FUNCTIONS: &[&str] = &[
+    "get_content_size",
+    "read_content",
+    "get_content_uuid",
+    "parent_uuid",
+    "parent_filename",
+    "hash_content_range",
+    "subcontent_uuid",
+    "emit_subcontent_ref",
+    "subcontent_exists",
+    "link_content",
+    "inflate",
+    "inflate_more",
+    "report_error",
+    "add_fuel",
+    "get_secret",
+    "heartbeat",
+];
+
+/// Longest path or name (file, relation, secret) a guest can pass: PATH_MAX
+pub const MAX_GUEST_NAME_LEN: usize = 4096;
+
+/// Longest message a guest can pass to `report_error`
+pub const MAX_GUEST_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Why bytes couldn't be read out of guest memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GuestReadError {
+    /// The length is negative or the range is outside guest memory
+    Fault,
+    /// The length is over the limit for what is being read
+    TooLong,
+}
+
+/// Copy the `len` bytes at `ptr` out of guest memory. The range is checked
+/// against the memory's size before anything is allocated, so a bogus
+/// length is rejected instead of allocating up to 2 GiB first.
+pub(crate) fn read_guest_bytes(
+    store: impl AsContext,
+    memory: Memory,
+    ptr: i32,
+    len: i32,
+    max: usize,
+) -> Result<Vec<u8>, GuestReadError> {
+    // Pointers are unsigned addresses, as in the guest
+    let ptr = ptr as u32 as usize;
+    let Ok(len) = usize::try_from(len) else {
+        return Err(GuestReadError::Fault);
+    };
+    if ptr.checked_add(len).is_none_or(|end| end > memory.data_size(&store)) {
+        return Err(GuestReadError::Fault);
+    }
+    if len > max {
+        return Err(GuestReadError::TooLong);
+    }
+    Ok(memory.data(&store)[ptr..ptr + len].to_vec())
+}
+
+/// The errno for a failed read of a name
+fn name_errno(error: GuestReadError) -> i32 {
+    match error {
+        GuestReadError::Fault => Errno::Fault as i32,
+        GuestReadError::TooLong => Errno::Nametoolong as i32,
+    }
+}
+
+/// Digest algorithms accepted by `hash_content_range`
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256 = 0,
+    Sha512 = 1,
+}
+
+impl HashAlgorithm {
+    pub fn from_id(id: i32) -> Option<Self> {
+        match id {
+            0 => Some(Self::Sha256),
+            1 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Digest size in bytes
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+pub(crate) fn add_to_linker(linker: &mut Linker<StoreData>) -> Result<()> {
+    // get_content_size - Size in bytes of the current content
+    linker.func_wrap(
+        HOST_MODULE,
+        "get_content_size",
+        |caller: Caller<StoreData>| -> i64 {
+            caller.data().processing_ctx.content_data.len() as i64
+        },
+    )?;
+
+    // read_content - Copy up to buf_len bytes of the current content, starting
+    // at offset, to buf_ptr and write the count to nread_ptr. Reading at or past
+    // the end copies nothing. Returns Inval for a negative offset or length.
+    linker.func_wrap(
+        HOST_MODULE,
+        "read_content",
+        |mut caller: Caller<StoreData>, offset: i64, buf_ptr: i32, buf_len: i32, nread_ptr: i32| -> Result<i32> {
+            let (Ok(offset), Ok(buf_len)) = (u64::try_from(offset), usize::try_from(buf_len)) else {
+                return Ok(Errno::Inval as i32);
+            };
+            // SharedBuffer clones share the underlying bytes
+            let content = caller.data().processing_ctx.content_data.clone();
+            let bytes = content.as_slice();
+            let start = usize::try_from(offset).unwrap_or(usize::MAX).min(bytes.len());
+            let chunk = &bytes[start..bytes.len().min(start.saturating_add(buf_len))];
+
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, buf_ptr as u32 as usize, chunk).is_err()
+                || memory.write(&mut caller, nread_ptr as u32 as usize, &(chunk.len() as u32).to_le_bytes()).is_err()
+            {
+                return Ok(Errno::Fault as i32);
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // get_content_uuid - Write the 16-byte UUID of the current content to out_ptr
+    linker.func_wrap(
+        HOST_MODULE,
+        "get_content_uuid",
+        |mut caller: Caller<StoreData>, out_ptr: i32| -> Result<i32> {
+            let uuid = caller.data().processing_ctx.content_uuid;
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, out_ptr as u32 as usize, uuid.as_bytes()).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // parent_uuid - Write the 16-byte UUID of the content the current one was
+    // emitted from to out_ptr. Returns Noent for root content.
+    linker.func_wrap(
+        HOST_MODULE,
+        "parent_uuid",
+        |mut caller: Caller<StoreData>, out_ptr: i32| -> Result<i32> {
+            let Some(uuid) = caller.data().processing_ctx.parent.as_ref().map(|p| p.uuid) else {
+                return Ok(Errno::Noent as i32);
+            };
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, out_ptr as u32 as usize, uuid.as_bytes()).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // parent_filename - Copy up to cap bytes of the parent content's filename
+    // to out_ptr and return its full length, so a short buffer can be retried.
+    // Returns -1 for root content and -2 if the buffer is outside guest memory.
+    linker.func_wrap(
+        HOST_MODULE,
+        "parent_filename",
+        |mut caller: Caller<StoreData>, out_ptr: i32, cap: i32| -> Result<i32> {
+            let Some(filename) = caller.data().processing_ctx.parent.as_ref().map(|p| p.filename.clone()) else {
+                return Ok(-1);
+            };
+            let copied = &filename.as_bytes()[..filename.len().min(cap.max(0) as usize)];
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, out_ptr as u32 as usize, copied).is_err() {
+                return Ok(-2);
+            }
+            Ok(filename.len() as i32)
+        },
+    )?;
+
+    // hash_content_range - Digest bytes [offset, offset + length) of /data.bin
+    // Writes the raw digest to out_ptr; out_len must be at least the digest size.
+    // Returns Inval for an unknown algorithm, a range outside the content, or a
+    // short output buffer, and Fault if the buffer is outside guest memory.
+    linker.func_wrap(
+        HOST_MODULE,
+        "hash_content_range",
+        |mut caller: Caller<StoreData>, algo: i32, offset: i64, length: i64, out_ptr: i32, out_len: i32| -> Result<i32> {
+            let Some(algorithm) = HashAlgorithm::from_id(algo) else {
+                return Ok(Errno::Inval as i32);
+            };
+            if out_len < 0 || (out_len as usize) < algorithm.digest_len() {
+                return Ok(Errno::Inval as i32);
+            }
+
+            let Some(content) = caller.data().wasi_ctx.filesystem.data_bin() else {
+                return Ok(Errno::Noent as i32);
+            };
+            let range = usize::try_from(offset).ok()
+                .zip(usize::try_from(length).ok())
+                .and_then(|(start, len)| Some(start..start.checked_add(len)?))
+                .filter(|range| range.end <= content.len());
+            let Some(range) = range else {
+                return Ok(Errno::Inval as i32);
+            };
+
+            let digest = algorithm.digest(&content[range]);
+
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, out_ptr as u32 as usize, &digest).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // subcontent_uuid - Write the 16-byte UUID of the index-th sub-content
+    // emitted for the current content to out_ptr. Returns Inval if fewer
+    // sub-contents have been emitted.
+    linker.func_wrap(
+        HOST_MODULE,
+        "subcontent_uuid",
+        |mut caller: Caller<StoreData>, index: i32, out_ptr: i32| -> Result<i32> {
+            let uuid = match usize::try_from(index).ok()
+                .and_then(|i| caller.data().processing_ctx.subcontent.get(i))
+            {
+                Some(emission) => emission.uuid,
+                None => return Ok(Errno::Inval as i32),
+            };
+            let memory = get_memory(&mut caller)?;
+            if memory.write(&mut caller, out_ptr as u32 as usize, uuid.as_bytes()).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // emit_subcontent_ref - Emit sub-content with the same bytes as the content
+    // whose 16-byte UUID is at uuid_ptr, without copying them. The processor
+    // skips the emission (and records an error document) if the UUID is not
+    // a content it knows, e.g. one from `subcontent_uuid`.
+    linker.func_wrap(
+        HOST_MODULE,
+        "emit_subcontent_ref",
+        |mut caller: Caller<StoreData>, uuid_ptr: i32, fname_ptr: i32, fname_len: i32| -> Result<i32> {
+            if fname_len < 0 {
+                return Ok(Errno::Inval as i32);
+            }
+            let memory = get_memory(&mut caller)?;
+            let mut uuid = [0u8; 16];
+            if memory.read(&caller, uuid_ptr as u32 as usize, &mut uuid).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            let filename = match read_guest_bytes(&caller, memory, fname_ptr, fname_len, MAX_GUEST_NAME_LEN) {
+                Ok(filename) => filename,
+                Err(error) => return Ok(name_errno(error)),
+            };
+            // Archive member names are often not UTF-8; keep them readable
+            let filename = String::from_utf8_lossy(&filename).into_owned();
+
+            caller.data_mut().processing_ctx.subcontent.push(SubContentEmission {
+                uuid: uuid::Uuid::new_v4(),
+                data: SubContentData::Reference(uuid::Uuid::from_bytes(uuid)),
+                filename,
+                relation: None,
+                dispatched: false,
+            });
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // subcontent_exists - Whether sub-content named by the fname_len bytes at
+    // fname_ptr (invalid UTF-8 becomes U+FFFD, as when emitting) was already
+    // emitted for the current content: 1 if so, 0 if not, -1 if the name is
+    // outside guest memory or longer than MAX_GUEST_NAME_LEN. Emissions the
+    // host rejected don't count.
+    linker.func_wrap(
+        HOST_MODULE,
+        "subcontent_exists",
+        |mut caller: Caller<StoreData>, fname_ptr: i32, fname_len: i32| -> Result<i32> {
+            let memory = get_memory(&mut caller)?;
+            let Ok(filename) = read_guest_bytes(&caller, memory, fname_ptr, fname_len, MAX_GUEST_NAME_LEN) else {
+                return Ok(-1);
+            };
+            let filename = String::from_utf8_lossy(&filename);
+            let exists = caller.data().processing_ctx.subcontent.iter().any(|s| s.filename == filename);
+            Ok(exists as i32)
+        },
+    )?;
+
+    // link_content - Record an edge labeled with the relation at relation_ptr
+    // from the current content to the content whose 16-byte UUID is at
+    // child_uuid_ptr, e.g. a second parent of an emitted sub-content. The
+    // processor records an error document instead if the UUID is unknown.
+    // Returns Inval for an empty relation.
+    linker.func_wrap(
+        HOST_MODULE,
+        "link_content",
+        |mut caller: Caller<StoreData>, child_uuid_ptr: i32, relation_ptr: i32, relation_len: i32| -> Result<i32> {
+            if relation_len <= 0 {
+                return Ok(Errno::Inval as i32);
+            }
+            let memory = get_memory(&mut caller)?;
+            let mut uuid = [0u8; 16];
+            if memory.read(&caller, child_uuid_ptr as u32 as usize, &mut uuid).is_err() {
+                return Ok(Errno::Fault as i32);
+            }
+            let relation = match read_guest_bytes(&caller, memory, relation_ptr, relation_len, MAX_GUEST_NAME_LEN) {
+                Ok(relation) => relation,
+                Err(error) => return Ok(name_errno(error)),
+            };
+            let Ok(relation) = String::from_utf8(relation) else {
+                return Ok(Errno::Ilseq as i32);
+            };
+
+            caller.data_mut().processing_ctx.links.push(ContentLink {
+                child_uuid: uuid::Uuid::from_bytes(uuid),
+                relation,
+            });
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // report_error - Record a structured failure with a module-defined code
+    // and the message at msg_ptr (invalid UTF-8 becomes U+FFFD). It is stored
+    // as an error document even if the module then fails. Returns Msgsize for
+    // messages longer than MAX_GUEST_MESSAGE_LEN.
+    linker.func_wrap(
+        HOST_MODULE,
+        "report_error",
+        |mut caller: Caller<StoreData>, code: i32, msg_ptr: i32, msg_len: i32| -> Result<i32> {
+            if msg_len < 0 {
+                return Ok(Errno::Inval as i32);
+            }
+            let memory = get_memory(&mut caller)?;
+            let message = match read_guest_bytes(&caller, memory, msg_ptr, msg_len, MAX_GUEST_MESSAGE_LEN) {
+                Ok(message) => message,
+                Err(GuestReadError::Fault) => return Ok(Errno::Fault as i32),
+                Err(GuestReadError::TooLong) => return Ok(Errno::Msgsize as i32),
+            };
+            caller.data_mut().processing_ctx.errors.push(GuestError {
+                code: code as u32,
+                message: String::from_utf8_lossy(&message).into_owned(),
+            });
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // add_fuel - Add amount to the current content's fuel and return the new
+    // remaining total. Returns -1 when fuel isn't limited, `--fuel-ceiling`
+    // isn't set, or the fuel given to this content would pass the ceiling.
+    linker.func_wrap(
+        HOST_MODULE,
+        "add_fuel",
+        |mut caller: Caller<StoreData>, amount: i64| -> i64 {
+            let (Ok(amount), Some(ceiling)) = (u64::try_from(amount), caller.data().fuel_ceiling) else {
+                return -1;
+            };
+            let Ok(remaining) = caller.get_fuel() else {
+                return -1;
+            };
+            let granted = caller.data().fuel_granted.saturating_add(amount);
+            let total = remaining.saturating_add(amount);
+            if granted > ceiling || caller.set_fuel(total).is_err() {
+                return -1;
+            }
+            caller.data_mut().fuel_granted = granted;
+            i64::try_from(total).unwrap_or(i64::MAX)
+        },
+    )?;
+
+    // heartbeat - Tell the host the module is still working on the content:
+    // progress_permille (0 to 1000, or negative when unknown) and the status
+    // message at msg_ptr go to the run's progress callback, and the module
+    // gets a fresh `--stall-timeout`. Returns Inval for progress over 1000
+    // and Msgsize for messages longer than MAX_GUEST_NAME_LEN.
+    linker.func_wrap(
+        HOST_MODULE,
+        "heartbeat",
+        |mut caller: Caller<StoreData>, progress_permille: i32, msg_ptr: i32, msg_len: i32| -> Result<i32> {
+            if progress_permille > 1000 {
+                return Ok(Errno::Inval as i32);
+            }
+            let memory = get_memory(&mut caller)?;
+            let message = match read_guest_bytes(&caller, memory, msg_ptr, msg_len, MAX_GUEST_NAME_LEN) {
+                Ok(message) => message,
+                Err(GuestReadError::Fault) => return Ok(Errno::Fault as i32),
+                Err(GuestReadError::TooLong) => return Ok(Errno::Msgsize as i32),
+            };
+            if let Some(ticks) = caller.data().stall_deadline {
+                caller.as_context_mut().set_epoch_deadline(ticks);
+            }
+            if let Some((module, callback)) = &caller.data().progress {
+                callback(&Heartbeat {
+                    module: module.clone(),
+                    content_uuid: caller.data().processing_ctx.content_uuid,
+                    progress_permille: u16::try_from(progress_permille).ok(),
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                });
+            }
+            Ok(Errno::Success as i32)
+        },
+    )?;
+
+    // get_secret - Copy up to cap bytes of the value of the secret named by
+    // the name_len bytes at name_ptr to out_ptr and return its full length,
+    // so a short buffer can be retried. Returns -1 for an unknown name
+    // (including any longer than MAX_GUEST_NAME_LEN) and -2 if a buffer is
+    // outside guest memory.
+    linker.func_wrap(
+        HOST_MODULE,
+        "get_secret",
+        |mut caller: Caller<StoreData>, name_ptr: i32, name_len: i32, out_ptr: i32, cap: i32| -> Result<i32> {
+            let memory = get_memory(&mut caller)?;
+            let name = match read_guest_bytes(&caller, memory, name_ptr, name_len, MAX_GUEST_NAME_LEN) {
+                Ok(name) => name,
+                Err(GuestReadError::Fault) => return Ok(-2),
+                Err(GuestReadError::TooLong) => return Ok(-1),
+            };
+            let secrets = std::sync::Arc::clone(&caller.data().secrets);
+            let Some(secret) = secrets.iter().rev().find(|s| s.name.as_bytes() == name) else {
+                return Ok(-1);
+            };
+            let value = secret.value().as_bytes();
+            let copied = &value[..value.len().min(cap.max(0) as usize)];
+            if memory.write(&mut caller, out_ptr as u32 as usize, copied).is_err() {
+                return Ok(-2);
+            }
+            Ok(i32::try_from(value.len()).unwrap_or(i32::MAX))
+        },
+    )?;
+
+    // inflate - Decompress the zlib stream, or gzip stream (by its 1f 8b
+    // magic), of src_len bytes at src_ptr into the out_cap bytes at out_ptr.
+    // Returns the decompressed length once the stream ends, or -4 when the
+    // buffer is full and more output remains; inflate_more then continues
+    // where it stopped. Returns -1 for a corrupt stream, -2 if a buffer is
+    // outside guest memory, and -3 past i32::MAX bytes of output.
+    linker.func_wrap(
+        HOST_MODULE,
+        "inflate",
+        |mut caller: Caller<StoreData>, src_ptr: i32, src_len: i32, out_ptr: i32, out_cap: i32| -> Result<i32> {
+            caller.data_mut().pending_inflate = None;
+            let memory = get_memory(&mut caller)?;
+            let Ok(src) = read_guest_bytes(&caller, memory, src_ptr, src_len, usize::MAX) else {
+                return Ok(-2);
+            };
+            inflate_next(&mut caller, memory, PendingInflate::new(src), out_ptr, out_cap)
+        },
+    )?;
+
+    // inflate_more - Write the next out_cap bytes of the stream the last
+    // inflate call returned -4 for, with the same return values as inflate.
+    // Returns -1 when no stream is pending.
+    linker.func_wrap(
+        HOST_MODULE,
+        "inflate_more",
+        |mut caller: Caller<StoreData>, out_ptr: i32, out_cap: i32| -> Result<i32> {
+            let Some(pending) = caller.data_mut().pending_inflate.take() else {
+                return Ok(-1);
+            };
+            let memory = get_memory(&mut caller)?;
+            inflate_next(&mut caller, memory, pending, out_ptr, out_cap)
+        },
+    )?;
+
+    Ok(())
+}
+
+/// A zlib or gzip stream `inflate` stopped decompressing at a full buffer
+pub(crate) struct PendingInflate {
+    decoder: io::BufReader<Box<dyn Read + Send>>,
+    /// Bytes decompressed so far
+    written: u64,
+}
+
+impl PendingInflate {
+    fn new(src: Vec<u8>) -> Self {
+        let decoder: Box<dyn Read + Send> = if src.starts_with(&[0x1f, 0x8b]) {
+            Box::new(MultiGzDecoder::new(io::Cursor::new(src)))
+        } else {
+            Box::new(ZlibDecoder::new(io::Cursor::new(src)))
+        };
+        Self { decoder: io::BufReader::new(decoder), written: 0 }
+    }
+}
+
+/// Decompress at most `out_cap` more bytes of `pending` to `out_ptr`. Work
+/// stops at the buffer's end, so a small stream inflating to gigabytes costs
+/// the host no more than the guest has memory for.
+fn inflate_next(
+    caller: &mut Caller<StoreData>,
+    memory: Memory,
+    mut pending: PendingInflate,
+    out_ptr: i32,
+    out_cap: i32,
+) -> Result<i32> {
+    let Ok(out_cap) = usize::try_from(out_cap) else {
+        return Ok(-2);
+    };
+    let out_end = (out_ptr as u32 as usize).checked_add(out_cap);
+    if out_end.is_none_or(|end| end > memory.data_size(&*caller)) {
+        return Ok(-2);
+    }
+
+    let limit = i32::MAX as u64;
+    let mut out = Vec::new();
+    let cap = (out_cap as u64).min(limit - pending.written);
+    let done = (&mut pending.decoder).take(cap).read_to_end(&mut out)
+        .and_then(|_| pending.decoder.fill_buf().map(|rest| rest.is_empty()));
+    let Ok(done) = done else {
+        return Ok(-1);
+    };
+    pending.written += out.len() as u64;
+    if memory.write(&mut *caller, out_ptr as u32 as usize, &out).is_err() {
+        return Ok(-2);
+    }
+    if done {
+        return Ok(out.len() as i32);
+    }
+    if pending.written == limit {
+        return Ok(-3);
+    }
+    caller.data_mut().pending_inflate = Some(pending);
+    Ok(-4)
+}
+
+fn get_memory(caller: &mut Caller<StoreData>) -> Result<wasmtime::Memory> {
+    caller.get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow::anyhow!("No memory export found"))
+}
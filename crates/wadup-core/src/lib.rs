@@ -1,5 +1,11 @@
 pub mod content;
+pub mod content_type;
+pub mod dispatch;
+pub mod error;
+pub mod manifest;
 pub mod metadata;
+pub mod module_runtime;
+pub mod metadata_binary;
 pub mod wasm;
 pub mod processor;
 pub mod memory_fs;
@@ -8,13 +14,27 @@ pub mod bindings_types;
 pub mod bindings_context;
 pub mod shared_buffer;
 pub mod precompile;
+pub mod host_functions;
 pub mod test_output;
+pub mod runner;
+pub mod secrets;
+#[cfg(test)]
+mod test_alloc;
+#[cfg(test)]
+mod test_guests;
 
 pub use content::*;
+pub use content_type::*;
+pub use dispatch::*;
+pub use error::*;
+pub use manifest::*;
 pub use metadata::*;
+pub use module_runtime::ModuleRuntime;
 pub use wasm::*;
 pub use processor::*;
 pub use bindings_types::*;
 pub use bindings_context::*;
 pub use precompile::*;
 pub use test_output::*;
+pub use runner::*;
+pub use secrets::Secret;
@@ -0,0 +1,147 @@
+//! Per-module manifests controlling which contents a module is run on.
+//!
+//! A manifest is an optional JSON file next to the module, named after it
+//! (`zip_parser.wasm` → `zip_parser.manifest.json`):
+//!
+//! ```json
+//! { "min_size": 22, "max_size": 1073741824, "content_types": ["application/zip"], "max_depth": 3, "wants_direct_content": false }
+//! ```
+//!
+//! Every field is optional; a module without a manifest runs on everything.
+//! `language` and `runtime` declare what the module is for reporting (see
+//! `ModuleRuntime`) in place of what is detected, and `stateful` keeps one
+//! instance across contents instead of a fresh one per content.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModuleManifest {
+    /// Smallest content, in bytes, the module is run on
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    /// Largest content, in bytes, the module is run on
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Detected content types (see `detect_content_type`) the module is run on.
+    /// Contents whose type can't be detected are skipped when this is set.
+    #[serde(default)]
+    pub content_types: Option<Vec<String>>,
+    /// Deepest sub-content the module is run on (roots are depth 0)
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Write each content into the buffer the module's `content_buffer`
+    /// export returns instead of having it read `/data.bin`
+    #[serde(default)]
+    pub wants_direct_content: bool,
+    /// Source language recorded for the module, e.g. `python`
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Runtime recorded for the module, e.g. `cpython`
+    #[serde(default)]
+    pub runtime: Option<String>,
+    /// Keep the instance, and whatever state the module holds in it, from
+    /// one content to the next. Modules that aren't stateful get a fresh
+    /// instance for every content.
+    #[serde(default)]
+    pub stateful: bool,
+}
+
+/// Outcome of checking a content against a module's manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    Run,
+    Skip(String),
+}
+
+impl ModuleManifest {
+    /// Sidecar path holding the manifest for the module at `module_path`
+    pub fn path_for(module_path: &Path) -> PathBuf {
+        module_path.with_extension("manifest.json")
+    }
+
+    /// Load the manifest for the module at `module_path`, or the default
+    /// (accept everything) when there is none.
+    pub fn load_for(module_path: &Path) -> Result<Self> {
+        let path = Self::path_for(module_path);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read module manifest {:?}", path))?;
+        let manifest: Self = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid module manifest {:?}", path))?;
+        if let (Some(min), Some(max)) = (manifest.min_size, manifest.max_size) {
+            if min > max {
+                anyhow::bail!("Invalid module manifest {:?}: min_size {} exceeds max_size {}", path, min, max);
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Whether the module needs the content's detected type to decide
+    pub fn needs_content_type(&self) -> bool {
+        self.content_types.is_some()
+    }
+
+    /// Decide whether to run the module on a content of `size` bytes at
+    /// `depth`, whose detected type is `content_type`.
+    pub fn dispatch(&self, size: u64, content_type: Option<&str>, depth: usize) -> Dispatch {
+        if let Some(min) = self.min_size.filter(|&min| size < min) {
+            return Dispatch::Skip(format!("size {} is below min_size {}", size, min));
+        }
+        if let Some(max) = self.max_size.filter(|&max| size > max) {
+            return Dispatch::Skip(format!("size {} is above max_size {}", size, max));
+        }
+        if let Some(max) = self.max_depth.filter(|&max| depth > max) {
+            return Dispatch::Skip(format!("depth {} is beyond max_depth {}", depth, max));
+        }
+        if let Some(types) = &self.content_types {
+            if !content_type.is_some_and(|t| types.iter().any(|accepted| accepted == t)) {
+                return Dispatch::Skip(format!(
+                    "content type {} is not one of {:?}",
+                    content_type.unwrap_or("unknown"),
+                    types
+                ));
+            }
+        }
+        Dispatch::Run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch_checks_each_filter() {
+        let manifest = ModuleManifest {
+            min_size: Some(4),
+            max_size: Some(8),
+            content_types: Some(vec!["application/zip".to_string()]),
+            max_depth: Some(1),
+            wants_direct_content: false,
+            ..ModuleManifest::default()
+        };
+        let zip = Some("application/zip");
+        assert_eq!(manifest.dispatch(4, zip, 0), Dispatch::Run);
+        assert_eq!(manifest.dispatch(8, zip, 1), Dispatch::Run);
+        assert!(matches!(manifest.dispatch(3, zip, 0), Dispatch::Skip(_)));
+        assert!(matches!(manifest.dispatch(9, zip, 0), Dispatch::Skip(_)));
+        assert!(matches!(manifest.dispatch(4, zip, 2), Dispatch::Skip(_)));
+        assert!(matches!(manifest.dispatch(4, None, 0), Dispatch::Skip(_)));
+        assert_eq!(ModuleManifest::default().dispatch(0, None, 100), Dispatch::Run);
+    }
+
+    #[test]
+    fn test_load_rejects_inverted_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("m.wasm");
+        assert_eq!(ModuleManifest::load_for(&module).unwrap(), ModuleManifest::default());
+
+        std::fs::write(dir.path().join("m.manifest.json"), r#"{"min_size": 10, "max_size": 2}"#).unwrap();
+        assert!(ModuleManifest::load_for(&module).is_err());
+    }
+}
@@ -4,6 +4,27 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 use bytes::{Bytes, BytesMut};
 
+/// Split a path into its components, ignoring empty and `.` components.
+///
+/// `..` components are rejected with `PermissionDenied` so a guest cannot
+/// escape a directory prefix such as `/metadata/`.
+pub fn path_components(path: &str) -> io::Result<Vec<&str>> {
+    let mut components = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Path traversal is not allowed",
+                ))
+            }
+            _ => components.push(part),
+        }
+    }
+    Ok(components)
+}
+
 /// File data storage - either read-only or read-write
 #[derive(Clone)]
 pub enum MemoryFileData {
@@ -19,7 +40,9 @@ pub enum MemoryFileData {
 /// This is critical for correct behavior when opening the same file multiple times.
 pub struct MemoryFile {
     data: MemoryFileData,
-    position: Arc<RwLock<usize>>,
+    /// Kept as `u64` so seeks past the end behave the same regardless of the
+    /// host's pointer width; converted to `usize` only when touching data.
+    position: Arc<RwLock<u64>>,
 }
 
 impl Clone for MemoryFile {
@@ -67,6 +90,19 @@ impl MemoryFile {
         self.len() == 0
     }
 
+    /// Whether this is a read-only view of content data
+    pub fn is_readonly(&self) -> bool {
+        matches!(self.data, MemoryFileData::ReadOnly(_))
+    }
+
+    /// Write the file's current contents to a host path
+    fn export_to(&self, path: &std::path::Path) -> io::Result<()> {
+        match &self.data {
+            MemoryFileData::ReadOnly(bytes) => std::fs::write(path, bytes),
+            MemoryFileData::ReadWrite(buf) => std::fs::write(path, &buf.read()[..]),
+        }
+    }
+
     /// Take ownership of the file data as Bytes (zero-copy for read-write files).
     ///
     /// For ReadWrite files, this freezes the BytesMut into Bytes without copying.
@@ -103,39 +139,57 @@ impl MemoryFile {
             }
         }
     }
-}
-
-impl Read for MemoryFile {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut pos = self.position.write();
 
+    /// Resize the file to `len` bytes, zero-filling when it grows and
+    /// discarding the tail when it shrinks. The position is unchanged.
+    /// Files can't be grown past 4 GiB, more than a guest can address, so a
+    /// stray size can't make the host allocate without bound.
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
         match &self.data {
-            MemoryFileData::ReadOnly(bytes) => {
-                if *pos >= bytes.len() {
-                    return Ok(0);
+            MemoryFileData::ReadOnly(_) => {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Cannot resize read-only file",
+                ))
+            }
+            MemoryFileData::ReadWrite(buf) => {
+                let mut data = buf.write();
+                if len > u64::from(u32::MAX) && len > data.len() as u64 {
+                    return Err(too_large());
                 }
-
-                let available = bytes.len() - *pos;
-                let to_read = buf.len().min(available);
-                buf[..to_read].copy_from_slice(&bytes[*pos..*pos + to_read]);
-                *pos += to_read;
-
-                Ok(to_read)
+                let len = usize::try_from(len).map_err(|_| too_large())?;
+                data.resize(len, 0);
+                Ok(())
             }
-            MemoryFileData::ReadWrite(data) => {
-                let data_guard = data.read();
+        }
+    }
+}
 
-                if *pos >= data_guard.len() {
-                    return Ok(0);
-                }
+impl Default for MemoryFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                let available = data_guard.len() - *pos;
-                let to_read = buf.len().min(available);
-                buf[..to_read].copy_from_slice(&data_guard[*pos..*pos + to_read]);
-                *pos += to_read;
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pos = self.position.write();
 
-                Ok(to_read)
-            }
+        let mut read_from = |bytes: &[u8]| -> usize {
+            // A position that doesn't fit in usize is necessarily past the end
+            let start = match usize::try_from(*pos) {
+                Ok(start) if start < bytes.len() => start,
+                _ => return 0,
+            };
+            let to_read = buf.len().min(bytes.len() - start);
+            buf[..to_read].copy_from_slice(&bytes[start..start + to_read]);
+            *pos += to_read as u64;
+            to_read
+        };
+
+        match &self.data {
+            MemoryFileData::ReadOnly(bytes) => Ok(read_from(bytes)),
+            MemoryFileData::ReadWrite(data) => Ok(read_from(&data.read())),
         }
     }
 }
@@ -153,13 +207,16 @@ impl Write for MemoryFile {
                 let mut data_guard = data.write();
                 let mut pos = self.position.write();
 
+                let start = usize::try_from(*pos).map_err(|_| too_large())?;
+                let end = start.checked_add(buf.len()).ok_or_else(too_large)?;
+
                 // Extend if writing past end
-                if *pos + buf.len() > data_guard.len() {
-                    data_guard.resize(*pos + buf.len(), 0);
+                if end > data_guard.len() {
+                    data_guard.resize(end, 0);
                 }
 
-                data_guard[*pos..*pos + buf.len()].copy_from_slice(buf);
-                *pos += buf.len();
+                data_guard[start..end].copy_from_slice(buf);
+                *pos = end as u64;
 
                 Ok(buf.len())
             }
@@ -171,29 +228,29 @@ impl Write for MemoryFile {
     }
 }
 
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "File position exceeds addressable memory")
+}
+
 impl Seek for MemoryFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let data_len = match &self.data {
-            MemoryFileData::ReadOnly(bytes) => bytes.len(),
-            MemoryFileData::ReadWrite(data) => data.read().len(),
-        };
+        let data_len = self.len() as u64;
         let mut position = self.position.write();
 
         let new_pos = match pos {
-            SeekFrom::Start(offset) => offset as i64,
-            SeekFrom::Current(offset) => *position as i64 + offset,
-            SeekFrom::End(offset) => data_len as i64 + offset,
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(offset) => data_len.checked_add_signed(offset),
         };
 
-        if new_pos < 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid seek position",
-            ));
-        }
+        // Positions past the end are fine (reads return 0, writes extend), but
+        // only up to what this host can actually address.
+        let new_pos = new_pos
+            .filter(|p| usize::try_from(*p).is_ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
 
-        *position = new_pos as usize;
-        Ok(new_pos as u64)
+        *position = new_pos;
+        Ok(new_pos)
     }
 }
 
@@ -271,6 +328,15 @@ impl MemoryDirectory {
         }
     }
 
+    /// Number of entries directly inside this directory
+    pub fn len(&self) -> usize {
+        self.entries.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().is_empty()
+    }
+
     pub fn list(&self) -> Vec<(String, bool)> {
         let entries = self.entries.read();
         entries
@@ -282,6 +348,32 @@ impl MemoryDirectory {
             .collect()
     }
 
+    /// Recursively write this directory's contents into a host directory.
+    ///
+    /// Read-only files (views of content data such as `/data.bin`) are skipped.
+    pub fn export_to(&self, path: &std::path::Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)?;
+        let entries: Vec<(String, Entry)> = self.entries.read()
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+
+        for (name, entry) in entries {
+            let target = path.join(&name);
+            match entry {
+                Entry::File(file) if file.is_readonly() => continue,
+                Entry::File(file) => file.export_to(&target)?,
+                Entry::Directory(dir) => dir.export_to(&target)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every entry in this directory
+    pub fn clear(&self) {
+        self.entries.write().clear();
+    }
+
     pub fn remove(&self, name: &str) -> io::Result<()> {
         let mut entries = self.entries.write();
         entries.remove(name).ok_or_else(|| {
@@ -314,6 +406,12 @@ impl MemoryDirectory {
     }
 }
 
+impl Default for MemoryDirectory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Root filesystem with path resolution
 pub struct MemoryFilesystem {
     root: MemoryDirectory,
@@ -328,24 +426,20 @@ impl MemoryFilesystem {
 
     /// Resolve a path and return the parent directory and filename
     fn resolve_path(&self, path: &str) -> io::Result<(MemoryDirectory, String)> {
-        let path = path.trim_start_matches('/');
+        let parts = path_components(path)?;
 
-        if path.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid path",
-            ));
-        }
-
-        let parts: Vec<&str> = path.split('/').collect();
-        let filename = parts.last().unwrap().to_string();
-
-        if parts.len() == 1 {
-            return Ok((self.root.clone(), filename));
-        }
+        let (filename, parents) = match parts.split_last() {
+            Some((filename, parents)) => (filename.to_string(), parents),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid path",
+                ))
+            }
+        };
 
         let mut current_dir = self.root.clone();
-        for &part in &parts[..parts.len() - 1] {
+        for &part in parents {
             current_dir = current_dir.get_dir(part)?;
         }
 
@@ -357,19 +451,27 @@ impl MemoryFilesystem {
         parent_dir.create_file(&filename, data)
     }
 
+    /// Create a read-only file holding `data` without copying it, creating
+    /// any missing parent directories
+    pub fn create_readonly_file(&self, path: &str, data: Bytes) -> io::Result<()> {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            if !parent.is_empty() {
+                self.create_dir_all(parent)?;
+            }
+        }
+        let (parent_dir, filename) = self.resolve_path(path)?;
+        let mut entries = parent_dir.entries.write();
+        entries.insert(filename, Entry::File(MemoryFile::with_readonly_data(data)));
+        Ok(())
+    }
+
     pub fn open_file(&self, path: &str) -> io::Result<MemoryFile> {
         let (parent_dir, filename) = self.resolve_path(path)?;
         parent_dir.get_file(&filename)
     }
 
     pub fn create_dir_all(&self, path: &str) -> io::Result<()> {
-        let path = path.trim_start_matches('/');
-
-        if path.is_empty() {
-            return Ok(());
-        }
-
-        let parts: Vec<&str> = path.split('/').collect();
+        let parts = path_components(path)?;
         let mut current_dir = self.root.clone();
 
         for &part in &parts {
@@ -389,6 +491,13 @@ impl MemoryFilesystem {
         &self.root
     }
 
+    /// Write the whole filesystem tree into a host directory, for debugging.
+    ///
+    /// Read-only files (the content at `/data.bin`) are skipped.
+    pub fn export_to(&self, path: &std::path::Path) -> io::Result<()> {
+        self.root.export_to(path)
+    }
+
     /// Create or replace /data.bin with zero-copy view
     ///
     /// This method provides a zero-copy way to update the /data.bin file
@@ -403,15 +512,62 @@ impl MemoryFilesystem {
         Ok(())
     }
 
-    /// Get directory at path
-    pub fn get_dir(&self, path: &str) -> io::Result<MemoryDirectory> {
-        let path = path.trim_start_matches('/');
+    /// Replace /streams with read-only files, one per named stream (zero-copy).
+    ///
+    /// Names must be single path components.
+    pub fn set_streams(&self, streams: &[(String, Bytes)]) -> io::Result<()> {
+        let dir = MemoryDirectory::new();
+        {
+            let mut entries = dir.entries.write();
+            for (name, data) in streams {
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid stream name '{}'", name),
+                    ));
+                }
+                entries.insert(name.clone(), Entry::File(MemoryFile::with_readonly_data(data.clone())));
+            }
+        }
+        self.root.entries.write().insert("streams".to_string(), Entry::Directory(dir));
+        Ok(())
+    }
 
-        if path.is_empty() {
-            return Ok(self.root.clone());
+    /// Remove and return the files a module wrote under /streams (zero-copy),
+    /// leaving the read-only streams mounted by `set_streams`
+    pub fn take_streams(&self) -> Vec<(String, Bytes)> {
+        let Ok(dir) = self.get_dir("/streams") else {
+            return Vec::new();
+        };
+        let mut entries = dir.entries.write();
+        let written: Vec<String> = entries.iter()
+            .filter(|(_, entry)| matches!(entry, Entry::File(file) if !file.is_readonly()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut streams: Vec<(String, Bytes)> = written.into_iter()
+            .filter_map(|name| match entries.remove(&name) {
+                Some(Entry::File(file)) => Some((name, file.take_bytes())),
+                _ => None,
+            })
+            .collect();
+        streams.sort_by(|a, b| a.0.cmp(&b.0));
+        streams
+    }
+
+    /// The content currently mounted at /data.bin (zero-copy)
+    pub fn data_bin(&self) -> Option<Bytes> {
+        match self.root.entries.read().get("data.bin") {
+            Some(Entry::File(file)) => match &file.data {
+                MemoryFileData::ReadOnly(bytes) => Some(bytes.clone()),
+                MemoryFileData::ReadWrite(_) => None,
+            },
+            _ => None,
         }
+    }
 
-        let parts: Vec<&str> = path.split('/').collect();
+    /// Get directory at path
+    pub fn get_dir(&self, path: &str) -> io::Result<MemoryDirectory> {
+        let parts = path_components(path)?;
         let mut current_dir = self.root.clone();
 
         for &part in &parts {
@@ -439,20 +595,50 @@ impl MemoryFilesystem {
     }
 }
 
+impl Default for MemoryFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_seek_beyond_4gib() {
+        const FIVE_GIB: u64 = 5 << 30;
+        let mut file = MemoryFile::with_readonly_data(Bytes::from_static(b"tail"));
+
+        if usize::BITS >= 64 {
+            assert_eq!(file.seek(SeekFrom::Start(FIVE_GIB)).unwrap(), FIVE_GIB);
+            assert_eq!(file.seek(SeekFrom::Current(1)).unwrap(), FIVE_GIB + 1);
+            assert_eq!(file.seek(SeekFrom::End(FIVE_GIB as i64)).unwrap(), FIVE_GIB + 4);
+            let mut buf = [0u8; 4];
+            assert_eq!(file.read(&mut buf).unwrap(), 0);
+            file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+            assert!(file.seek(SeekFrom::Current(1)).is_err());
+        } else {
+            let err = file.seek(SeekFrom::Start(FIVE_GIB)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // Overflow and negative positions never wrap around
+        file.seek(SeekFrom::Start(2)).unwrap();
+        assert!(file.seek(SeekFrom::Current(-3)).is_err());
+        assert_eq!(file.seek(SeekFrom::End(-4)).unwrap(), 0);
+    }
+
     #[test]
     fn test_memory_file() {
         let mut file = MemoryFile::with_data(b"Hello, World!".to_vec());
 
         let mut buf = [0u8; 5];
-        file.read(&mut buf).unwrap();
+        file.read_exact(&mut buf).unwrap();
         assert_eq!(&buf, b"Hello");
 
         file.seek(SeekFrom::Start(0)).unwrap();
-        file.write(b"Hi").unwrap();
+        file.write_all(b"Hi").unwrap();
 
         file.seek(SeekFrom::Start(0)).unwrap();
         let mut result = Vec::new();
@@ -460,6 +646,24 @@ mod tests {
         assert_eq!(result, b"Hillo, World!");
     }
 
+    #[test]
+    fn test_set_len_grows_with_zeros_and_shrinks() {
+        let mut file = MemoryFile::with_data(b"Hello".to_vec());
+        file.set_len(8).unwrap();
+        let mut result = Vec::new();
+        file.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"Hello\0\0\0");
+
+        file.set_len(2).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        result.clear();
+        file.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"He");
+
+        assert!(file.set_len(u64::MAX).is_err());
+        assert!(MemoryFile::with_readonly_data(Bytes::from_static(b"x")).set_len(0).is_err());
+    }
+
     #[test]
     fn test_memory_filesystem() {
         let fs = MemoryFilesystem::new();
@@ -473,4 +677,39 @@ mod tests {
         file.read_to_end(&mut buf).unwrap();
         assert_eq!(buf, b"test data");
     }
+
+    #[test]
+    fn test_export_to() {
+        let fs = MemoryFilesystem::new();
+        fs.set_data_bin(Bytes::from_static(b"content")).unwrap();
+        fs.create_dir_all("/tmp/nested").unwrap();
+        fs.create_dir_all("/empty").unwrap();
+        fs.create_file("/tmp/nested/scratch.txt", b"partial".to_vec()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs.export_to(dir.path()).unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("tmp/nested/scratch.txt")).unwrap(), b"partial");
+        assert!(dir.path().join("empty").is_dir());
+        assert!(!dir.path().join("data.bin").exists());
+    }
+
+    #[test]
+    fn test_path_traversal_rejected() {
+        let fs = MemoryFilesystem::new();
+        fs.create_dir_all("/metadata").unwrap();
+
+        for path in ["/metadata/../data.bin", "../etc/passwd", "/metadata/../../x"] {
+            let err = fs.create_file(path, Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}", path);
+        }
+        assert_eq!(
+            fs.create_dir_all("/tmp/../escape").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        // Empty and "." components are normalized away
+        fs.create_file("/metadata/./a.json", Vec::new()).unwrap();
+        assert!(fs.open_file("//metadata//a.json").is_ok());
+    }
 }
@@ -1,9 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
-use anyhow::Result;
-use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
-use crate::bindings_types::{TableSchema, Value};
+use crate::bindings_context::MetadataRow;
+use crate::bindings_types::{Column, DataType, ForeignKey, TableSchema, Value};
+use crate::module_runtime::ModuleRuntime;
+use crate::content::SliceRange;
+
+/// Document ID of a cached result; module names and versions may hold
+/// characters that aren't safe in a URL
+fn result_cache_id(module_name: &str, module_version: Option<&str>, content_sha256: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let key = format!("{}\0{}\0{}", module_name, module_version.unwrap_or_default(), content_sha256);
+    format!("result_{}", hex::encode(&Sha256::digest(key.as_bytes())[..16]))
+}
 
 /// Content metadata document
 #[derive(Debug, Clone, Serialize)]
@@ -12,9 +24,81 @@ pub struct ContentDoc {
     pub content_uuid: String,
     pub filename: String,
     pub parent_uuid: Option<String>,
+    /// Module that emitted this content; `None` for root content
+    pub produced_by: Option<String>,
+    /// Range of a slice sub-content in its parent and its base (see
+    /// `SliceRange`); `None` unless emitted as a slice
+    pub slice: Option<SliceRange>,
     pub processed_at: DateTime<Utc>,
+    /// When processing of this content started (milliseconds since the Unix epoch)
+    pub started_at_ms: i64,
+    /// When processing of this content finished (milliseconds since the Unix epoch)
+    pub finished_at_ms: i64,
     pub status: String,
     pub error_message: Option<String>,
+    /// Sub-content emitted by modules but dropped because this content was
+    /// already at `max_recursion_depth`
+    pub depth_limited_children: usize,
+    /// Rows dropped per table past `--max-rows-per-table-per-content`
+    pub rows_truncated: BTreeMap<String, usize>,
+    /// UUIDs from the root content down to this one, joined by `/`
+    pub lineage: Option<String>,
+    /// Encoding guessed by `--detect-encoding`, e.g. `utf-16le`; `None` for
+    /// content that doesn't look like text or when detection is off
+    pub text_encoding: Option<String>,
+    /// The run's `--label`s
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A `key=value` tag for a run (`--label`). Keys are identifiers: ASCII
+/// letters, digits and underscores, not starting with a digit.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for Label {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((key, value)) = s.split_once('=') else {
+            anyhow::bail!("Invalid label {:?}: expected KEY=VALUE", s);
+        };
+        if !is_identifier(key) {
+            anyhow::bail!(
+                "Invalid label key {:?}: use ASCII letters, digits and underscores, not starting with a digit",
+                key
+            );
+        }
+        Ok(Self { key: key.to_string(), value: value.to_string() })
+    }
+}
+
+impl TryFrom<String> for Label {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// ASCII letters, digits and underscores, not starting with a digit
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A content whose document records status `"failed"`, as read back by
+/// [`MetadataStore::failed_contents`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct FailedContent {
+    pub content_uuid: String,
+    pub filename: String,
+    pub parent_uuid: Option<String>,
 }
 
 /// Module stdout/stderr output document
@@ -23,6 +107,7 @@ pub struct ModuleOutputDoc {
     pub doc_type: &'static str,
     pub content_uuid: String,
     pub module_name: String,
+    pub module_version: Option<String>,
     pub processed_at: DateTime<Utc>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
@@ -30,27 +115,333 @@ pub struct ModuleOutputDoc {
     pub stderr_truncated: bool,
 }
 
+/// One module invocation on one content, recorded even when it emitted nothing
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleRunDoc {
+    pub doc_type: &'static str,
+    pub content_uuid: String,
+    pub module_name: String,
+    pub module_version: Option<String>,
+    /// Source language and runtime of the module (see `ModuleRuntime`)
+    pub language: Option<String>,
+    pub runtime: Option<String>,
+    pub processed_at: DateTime<Utc>,
+    /// `"success"` or `"failed"`
+    pub status: String,
+    pub emitted_rows: usize,
+    pub emitted_subcontent: usize,
+    /// Largest linear memory the module had during the run, in bytes, to
+    /// tune `--max-memory` against
+    pub peak_memory_bytes: u64,
+    /// Failed WASI file calls by errno name (e.g. `ENOENT`), under `--trace-wasi`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub wasi_errnos: BTreeMap<String, u64>,
+}
+
+/// Parent -> child relationship document, recorded when a module labels
+/// emitted sub-content with a relation
+#[derive(Debug, Clone, Serialize)]
+pub struct EdgeDoc {
+    pub doc_type: &'static str,
+    pub parent_uuid: String,
+    pub child_uuid: String,
+    pub relation: String,
+    pub processed_at: DateTime<Utc>,
+}
+
+/// One column of a table, recorded when the table is first defined
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaColumnDoc {
+    pub doc_type: &'static str,
+    pub table_name: String,
+    pub column: String,
+    pub data_type: DataType,
+    pub description: Option<String>,
+    /// Table and column the column's values must be in, for foreign keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub references: Option<ForeignKey>,
+    pub module_name: String,
+    pub processed_at: DateTime<Utc>,
+}
+
+/// Tables and rows a module produced for content with a given SHA-256,
+/// stored under `--reuse-results` so identical content can skip the module
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultCacheDoc<'a> {
+    pub doc_type: &'static str,
+    pub module_name: String,
+    pub module_version: Option<String>,
+    pub content_sha256: String,
+    pub processed_at: DateTime<Utc>,
+    pub tables: &'a [TableSchema],
+    pub rows: &'a [MetadataRow],
+}
+
+/// The tables and rows of a [`ResultCacheDoc`], as read back by
+/// [`MetadataStore::cached_result`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachedResult {
+    pub tables: Vec<TableSchema>,
+    pub rows: Vec<MetadataRow>,
+}
+
+/// Processing problem that is not tied to a single module run's stdout,
+/// e.g. a table schema conflict between modules
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorDoc {
+    pub doc_type: &'static str,
+    pub content_uuid: Option<String>,
+    pub module_name: String,
+    /// Short machine-readable category (e.g. `"schema_conflict"`)
+    pub kind: String,
+    /// Module-defined code of a `"guest_error"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u32>,
+    pub message: String,
+    pub processed_at: DateTime<Utc>,
+}
+
+/// Layout version of the documents this build writes. Bump it whenever a
+/// document type changes shape in a way existing queries would notice.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Most failed contents `failed_contents` reads in one search
+const MAX_FAILED_CONTENTS: usize = 10_000;
+
+/// Documents per page when `verify` scrolls through the indices
+const SCAN_PAGE_SIZE: usize = 5_000;
+
+/// Rows whose `content_uuid` has no content document, grouped by table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanRows {
+    pub table: String,
+    pub content_uuid: String,
+    pub count: usize,
+}
+
+/// A content document whose `parent_uuid` has no content document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingParent {
+    pub content_uuid: String,
+    pub filename: String,
+    pub parent_uuid: String,
+}
+
+/// What [`MetadataStore::verify`] found in a store's indices
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Elasticsearch cluster health (`green`, `yellow` or `red`); `None`
+    /// for in-memory stores
+    pub cluster_status: Option<String>,
+    pub contents: usize,
+    pub rows: usize,
+    pub orphan_rows: Vec<OrphanRows>,
+    pub dangling_parents: Vec<DanglingParent>,
+}
+
+impl VerifyReport {
+    /// Whether the cluster is usable and every reference resolves
+    pub fn is_ok(&self) -> bool {
+        self.cluster_status.as_deref() != Some("red") && self.orphan_rows.is_empty() && self.dangling_parents.is_empty()
+    }
+
+    /// Human-readable report, one problem per line
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if let Some(status) = &self.cluster_status {
+            out.push_str(&format!("cluster health: {}\n", status));
+        }
+        out.push_str(&format!("contents: {}\nrows: {}\n", self.contents, self.rows));
+        let orphans: usize = self.orphan_rows.iter().map(|o| o.count).sum();
+        out.push_str(&format!("orphan rows: {}\n", orphans));
+        for orphan in &self.orphan_rows {
+            out.push_str(&format!("  {} row(s) in {} reference missing content {}\n", orphan.count, orphan.table, orphan.content_uuid));
+        }
+        out.push_str(&format!("dangling parents: {}\n", self.dangling_parents.len()));
+        for dangling in &self.dangling_parents {
+            out.push_str(&format!(
+                "  content {} ({}) references missing parent {}\n",
+                dangling.content_uuid, dangling.filename, dangling.parent_uuid
+            ));
+        }
+        out
+    }
+}
+
+/// Document id of the index's schema stamp
+const META_DOC_ID: &str = "wadup_meta";
+
+/// Schema stamp written once per index, recording which layout it holds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaDoc {
+    pub doc_type: String,
+    pub schema_version: u32,
+    pub wadup_version: String,
+    pub stamped_at: DateTime<Utc>,
+}
+
 /// Table row document with flattened column values
 /// Fixed fields use underscore prefix to avoid conflicts with column names
 #[derive(Debug, Clone, Serialize)]
 pub struct RowDoc {
     pub doc_type: &'static str,
-    pub content_uuid: String,
+    /// `None` for run-global rows
+    pub content_uuid: Option<String>,
     #[serde(rename = "_module")]
     pub module_name: String,
+    #[serde(rename = "_module_version")]
+    pub module_version: Option<String>,
     #[serde(rename = "_table")]
     pub table_name: String,
+    /// `--table-prefix` the run added to the table name the module declared
+    #[serde(rename = "_table_prefix", skip_serializing_if = "Option::is_none")]
+    pub table_prefix: Option<String>,
     pub processed_at: DateTime<Utc>,
-    /// Column values flattened as key-value pairs
+    /// Column values flattened as key-value pairs, all as strings (Json
+    /// columns hold their JSON text)
     #[serde(flatten)]
     pub columns: HashMap<String, String>,
+    /// Parsed Json column documents as `_json.<table>.<column>`, so they can
+    /// be queried by path without clashing with other tables' scalar columns
+    #[serde(rename = "_json", skip_serializing_if = "HashMap::is_empty")]
+    pub json_columns: HashMap<String, HashMap<String, serde_json::Value>>,
+}
+
+/// How documents are distributed across Elasticsearch indices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    /// All documents go to the configured index
+    #[default]
+    Shared,
+    /// Each module's documents go to `{index}-{module}`, with its own copy
+    /// of the content documents for contents it processed
+    PerModule,
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "shared" => Ok(Self::Shared),
+            "per-module" => Ok(Self::PerModule),
+            other => anyhow::bail!("Unknown output mode '{}' (expected 'shared' or 'per-module')", other),
+        }
+    }
+}
+
+/// Two modules (or two runs of one module) declared the same table with
+/// different columns. The first definition is kept.
+#[derive(Debug, Clone)]
+pub struct SchemaConflict {
+    pub table: String,
+    pub existing_module: String,
+    pub existing_columns: Vec<Column>,
+    pub module: String,
+    pub columns: Vec<Column>,
+    /// True the first time this module's declaration conflicts, so callers
+    /// can record the conflict once rather than for every content
+    pub first_seen: bool,
+}
+
+impl SchemaConflict {
+    /// Column-level differences, from the existing definition to the new one
+    pub fn diff(&self) -> String {
+        let mut parts = Vec::new();
+        for old in &self.existing_columns {
+            match self.columns.iter().find(|c| c.name == old.name) {
+                None => parts.push(format!("-{} {:?}", old.name, old.data_type)),
+                Some(new) if new.data_type != old.data_type => {
+                    parts.push(format!("~{} {:?} -> {:?}", old.name, old.data_type, new.data_type))
+                }
+                Some(new) if new.constraint_label() != old.constraint_label() => {
+                    let label = |c: &Column| match c.constraint_label() {
+                        "" => "no constraint",
+                        label => label,
+                    };
+                    parts.push(format!("~{} {} -> {}", old.name, label(old), label(new)))
+                }
+                Some(new) if new.references != old.references => {
+                    let label = |c: &Column| match &c.references {
+                        Some(reference) => format!("references {}.{}", reference.table, reference.column),
+                        None => "no references".to_string(),
+                    };
+                    parts.push(format!("~{} {} -> {}", old.name, label(old), label(new)))
+                }
+                Some(_) => {}
+            }
+        }
+        for new in &self.columns {
+            if !self.existing_columns.iter().any(|c| c.name == new.name) {
+                parts.push(format!("+{} {:?}", new.name, new.data_type));
+            }
+        }
+        if parts.is_empty() {
+            parts.push("column order differs".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+impl std::fmt::Display for SchemaConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Table '{}' is defined by module '{}' as ({}) but module '{}' declares ({}): {}",
+            self.table,
+            self.existing_module,
+            describe_columns(&self.existing_columns),
+            self.module,
+            describe_columns(&self.columns),
+            self.diff()
+        )
+    }
+}
+
+impl std::error::Error for SchemaConflict {}
+
+/// A table's columns and the module that first defined it
+#[derive(Debug, Clone)]
+struct TableDefinition {
+    module: String,
+    columns: Vec<Column>,
+    /// How many of `columns` the table was defined with; the rest were added
+    /// with [`MetadataStore::add_column`]
+    declared: usize,
+    /// Column index groups from `TableSchema::unique_keys`
+    unique_keys: Vec<Vec<usize>>,
+    /// Some declaration marked the table global
+    global: bool,
+    /// Modules whose declarations conflicted with this definition
+    conflicting_modules: std::collections::HashSet<String>,
+    /// `module` has stored a row in the table
+    filled: bool,
+}
+
+/// A document captured by an in-memory store instead of being sent to Elasticsearch
+#[derive(Debug, Clone)]
+pub struct CapturedDoc {
+    pub index: String,
+    pub id: Option<String>,
+    pub doc: serde_json::Value,
 }
 
 /// Tracking state for content being processed
 struct ContentState {
     filename: String,
     parent_uuid: Option<String>,
+    produced_by: Option<String>,
+    slice: Option<SliceRange>,
     current_module: Option<String>,
+    current_module_version: Option<String>,
+    started_at_ms: i64,
+    /// Modules that have processed this content so far
+    modules: Vec<String>,
+    depth_limited_children: usize,
+    rows_truncated: BTreeMap<String, usize>,
+    lineage: Option<String>,
+    text_encoding: Option<String>,
 }
 
 pub struct MetadataStore {
@@ -59,8 +450,21 @@ pub struct MetadataStore {
     client: reqwest::blocking::Client,
     /// Content state tracking, keyed by content UUID
     content_state: Arc<Mutex<HashMap<String, ContentState>>>,
-    /// Table schemas, keyed by table name -> column names
-    table_schemas: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Table schemas, keyed by table name
+    table_schemas: Arc<Mutex<HashMap<String, TableDefinition>>>,
+    /// Primary key / unique values already inserted this run, as
+    /// `table\0key index\0values`
+    seen_keys: Arc<Mutex<std::collections::HashSet<String>>>,
+    /// Serialized size of documents written so far (tracked when a limit is set)
+    output_bytes: Arc<AtomicU64>,
+    max_output_bytes: Option<u64>,
+    output_mode: OutputMode,
+    /// Prepended as `{prefix}_` to every table name modules declare
+    table_prefix: Option<String>,
+    /// When set, documents are collected here instead of being POSTed
+    captured: Option<Arc<Mutex<Vec<CapturedDoc>>>>,
+    /// Recorded on every content document
+    labels: BTreeMap<String, String>,
 }
 
 impl MetadataStore {
@@ -95,10 +499,18 @@ impl MetadataStore {
             client,
             content_state: Arc::new(Mutex::new(HashMap::new())),
             table_schemas: Arc::new(Mutex::new(HashMap::new())),
+            seen_keys: Arc::new(Mutex::new(Default::default())),
+            output_bytes: Arc::new(AtomicU64::new(0)),
+            max_output_bytes: None,
+            output_mode: OutputMode::Shared,
+            table_prefix: None,
+            labels: BTreeMap::new(),
+            captured: None,
         })
     }
 
-    /// Create a dummy MetadataStore for test mode (no Elasticsearch connection).
+    /// Create a dummy MetadataStore for test mode (no Elasticsearch
+    /// connection). Documents written to it are dropped.
     pub fn new_dummy() -> Self {
         Self {
             es_url: String::new(),
@@ -106,6 +518,114 @@ impl MetadataStore {
             client: reqwest::blocking::Client::new(),
             content_state: Arc::new(Mutex::new(HashMap::new())),
             table_schemas: Arc::new(Mutex::new(HashMap::new())),
+            seen_keys: Arc::new(Mutex::new(Default::default())),
+            output_bytes: Arc::new(AtomicU64::new(0)),
+            max_output_bytes: None,
+            output_mode: OutputMode::Shared,
+            table_prefix: None,
+            labels: BTreeMap::new(),
+            captured: None,
+        }
+    }
+
+    /// Create a MetadataStore that keeps documents in memory instead of
+    /// sending them to Elasticsearch. Documents are indexed under `es_index`
+    /// and can be read back with `captured_documents`.
+    pub fn new_in_memory(es_index: &str) -> Self {
+        Self {
+            es_index: es_index.to_string(),
+            captured: Some(Arc::new(Mutex::new(Vec::new()))),
+            ..Self::new_dummy()
+        }
+    }
+
+    /// Documents collected by an in-memory store, in the order they were indexed
+    pub fn captured_documents(&self) -> Vec<CapturedDoc> {
+        match &self.captured {
+            Some(captured) => captured.lock().unwrap().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Choose how documents are distributed across indices
+    pub fn set_output_mode(&mut self, mode: OutputMode) {
+        self.output_mode = mode;
+    }
+
+    pub fn output_mode(&self) -> OutputMode {
+        self.output_mode
+    }
+
+    /// Limit the total serialized size of documents written. Writes are not
+    /// refused once it is exceeded; callers check `output_limit_reached` and
+    /// stop producing work.
+    pub fn set_max_output_bytes(&mut self, max: Option<u64>) {
+        self.max_output_bytes = max;
+    }
+
+    /// Whether more than the configured maximum has been written
+    pub fn output_limit_reached(&self) -> bool {
+        self.max_output_bytes
+            .is_some_and(|max| self.output_bytes.load(Ordering::SeqCst) > max)
+    }
+
+    fn note_written<T: Serialize>(&self, doc: &T) {
+        if self.max_output_bytes.is_some() {
+            let len = serde_json::to_vec(doc).map(|v| v.len()).unwrap_or(0);
+            self.output_bytes.fetch_add(len as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Namespace every table as `{prefix}_{table}`, so runs of different
+    /// pipelines can share an index. The prefix must be an identifier:
+    /// ASCII letters, digits and underscores, not starting with a digit.
+    pub fn set_table_prefix(&mut self, prefix: Option<&str>) -> Result<()> {
+        if let Some(prefix) = prefix {
+            if !is_identifier(prefix) {
+                anyhow::bail!(
+                    "Invalid table prefix {:?}: use ASCII letters, digits and underscores, not starting with a digit",
+                    prefix
+                );
+            }
+        }
+        self.table_prefix = prefix.map(|s| s.to_string());
+        Ok(())
+    }
+
+    /// Tag every content document with `labels`; a later label replaces an
+    /// earlier one with the same key
+    pub fn set_labels(&mut self, labels: &[Label]) {
+        self.labels = labels.iter().map(|label| (label.key.clone(), label.value.clone())).collect();
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// Name a module's table is stored under
+    fn stored_table_name(&self, table: &str) -> String {
+        match &self.table_prefix {
+            Some(prefix) => format!("{}_{}", prefix, table),
+            None => table.to_string(),
+        }
+    }
+
+    /// Whether a table named `table` (before the table prefix) has been defined
+    pub fn is_table_defined(&self, table: &str) -> bool {
+        self.table_schemas.lock().unwrap().contains_key(&self.stored_table_name(table))
+    }
+
+    /// Index that documents emitted by `module` are written to
+    pub fn index_for_module(&self, module: &str) -> String {
+        match self.output_mode {
+            OutputMode::Shared => self.es_index.clone(),
+            OutputMode::PerModule => {
+                // Index names must be lowercase and avoid a few reserved characters
+                let module: String = module.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_lowercase() } else { '_' })
+                    .collect();
+                format!("{}-{}", self.es_index, module)
+            }
         }
     }
 
@@ -120,80 +640,470 @@ impl MetadataStore {
         state.insert(uuid.to_string(), ContentState {
             filename: filename.to_string(),
             parent_uuid: parent_uuid.map(|s| s.to_string()),
+            produced_by: None,
+            slice: None,
             current_module: None,
+            current_module_version: None,
+            started_at_ms: Utc::now().timestamp_millis(),
+            modules: Vec::new(),
+            depth_limited_children: 0,
+            rows_truncated: BTreeMap::new(),
+            lineage: None,
+            text_encoding: None,
         });
         Ok(())
     }
 
+    /// Record that `count` children of a started content were dropped at the
+    /// recursion depth limit; reported in its ContentDoc
+    pub fn set_depth_limited_children(&self, uuid: &str, count: usize) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.depth_limited_children = count;
+        }
+    }
+
+    /// Record how many rows of each table a started content dropped at the
+    /// per-table row limit; reported in its ContentDoc
+    pub fn set_rows_truncated(&self, uuid: &str, rows_truncated: BTreeMap<String, usize>) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.rows_truncated = rows_truncated;
+        }
+    }
+
+    /// Record the text encoding detected for a started content; reported in
+    /// its ContentDoc
+    pub fn set_content_text_encoding(&self, uuid: &str, encoding: &str) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.text_encoding = Some(encoding.to_string());
+        }
+    }
+
+    /// Record the lineage of a started content (see `Content::lineage`);
+    /// reported in its ContentDoc
+    pub fn set_content_lineage(&self, uuid: &str, lineage: String) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.lineage = Some(lineage);
+        }
+    }
+
+    /// Record which module emitted a started content; reported in its
+    /// ContentDoc
+    pub fn set_content_produced_by(&self, uuid: &str, module_name: &str) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.produced_by = Some(module_name.to_string());
+        }
+    }
+
+    /// Record where a started slice content lies in its parent and base;
+    /// reported in its ContentDoc
+    pub fn set_content_slice(&self, uuid: &str, slice: SliceRange) {
+        if let Some(content) = self.content_state.lock().unwrap().get_mut(uuid) {
+            content.slice = Some(slice);
+        }
+    }
+
     /// Set the current module context for subsequent operations
-    pub fn set_current_module(&self, uuid: &str, module_name: &str) -> Result<()> {
+    pub fn set_current_module(
+        &self,
+        uuid: &str,
+        module_name: &str,
+        module_version: Option<&str>,
+    ) -> Result<()> {
         let mut state = self.content_state.lock().unwrap();
         if let Some(content) = state.get_mut(uuid) {
             content.current_module = Some(module_name.to_string());
+            content.current_module_version = module_version.map(|s| s.to_string());
+            if !content.modules.iter().any(|m| m == module_name) {
+                content.modules.push(module_name.to_string());
+            }
         }
         Ok(())
     }
 
-    /// Define a table schema - stores column names for flattening row values
-    pub fn define_table(&self, schema: TableSchema) -> Result<()> {
-        let column_names: Vec<String> = schema.columns.iter()
-            .map(|c| c.name.clone())
-            .collect();
+    /// Define a table schema - stores columns for flattening and checking row values
+    /// Register a table declared by `module`.
+    ///
+    /// Redeclaring an identical table is a no-op. A declaration whose columns
+    /// differ from the existing definition fails with a [`SchemaConflict`].
+    /// The lookup and the registration happen under one lock, so workers
+    /// declaring the same new table at once register it exactly once.
+    /// Registering a table posts a `schema_column` document per column,
+    /// carrying its type and description.
+    pub fn define_table(&self, module: &str, mut schema: TableSchema) -> Result<()> {
+        if schema.name.is_empty() {
+            anyhow::bail!("Table name must not be empty");
+        }
+        schema.name = self.stored_table_name(&schema.name);
+        for reference in schema.columns.iter_mut().filter_map(|c| c.references.as_mut()) {
+            reference.table = self.stored_table_name(&reference.table);
+        }
+        for (i, column) in schema.columns.iter().enumerate() {
+            if column.name.is_empty() {
+                anyhow::bail!(
+                    "Table '{}' ({}): column {} has an empty name",
+                    schema.name,
+                    describe_columns(&schema.columns),
+                    i
+                );
+            }
+            if schema.columns[..i].iter().any(|c| c.name == column.name) {
+                anyhow::bail!(
+                    "Table '{}' ({}): duplicate column '{}'",
+                    schema.name,
+                    describe_columns(&schema.columns),
+                    column.name
+                );
+            }
+        }
         let mut schemas = self.table_schemas.lock().unwrap();
-        schemas.insert(schema.name, column_names);
+        if let Some(existing) = schemas.get_mut(&schema.name) {
+            // Modules keep declaring a table as first defined after adding columns to it
+            let same = |columns: &[Column]| {
+                columns.len() == schema.columns.len()
+                    && columns.iter().zip(&schema.columns).all(|(a, b)| a.same_definition(b))
+            };
+            if !same(&existing.columns[..existing.declared]) && !same(&existing.columns) {
+                return Err(SchemaConflict {
+                    table: schema.name,
+                    existing_module: existing.module.clone(),
+                    existing_columns: existing.columns.clone(),
+                    module: module.to_string(),
+                    columns: schema.columns,
+                    first_seen: existing.conflicting_modules.insert(module.to_string()),
+                }.into());
+            }
+            existing.global |= schema.global;
+            return Ok(());
+        }
+        let unique_keys = schema.unique_keys();
+        schemas.insert(schema.name.clone(), TableDefinition {
+            module: module.to_string(),
+            columns: schema.columns.clone(),
+            declared: schema.columns.len(),
+            unique_keys,
+            global: schema.global,
+            conflicting_modules: Default::default(),
+            filled: false,
+        });
+        drop(schemas);
+
+        for column in schema.columns {
+            self.post_schema_column(module, &schema.name, column)?;
+        }
         Ok(())
     }
 
-    /// Insert a row - POSTs a RowDoc immediately with flattened column values
-    pub fn insert_row(&self, table: &str, uuid: &str, values: &[Value]) -> Result<()> {
-        let module_name = {
+    /// Add `column` to the table `table`, like `ALTER TABLE ADD COLUMN`.
+    /// Rows already stored hold null in it, as do rows written with only
+    /// the columns the table was defined with.
+    ///
+    /// Returns false if the table already has the column. A column of the
+    /// same name with a different type or constraints fails with a
+    /// [`SchemaConflict`]. Added columns can't be keys, since rows already
+    /// stored have no value in them.
+    pub fn add_column(&self, module: &str, table: &str, mut column: Column) -> Result<bool> {
+        let table = self.stored_table_name(table);
+        if let Some(reference) = column.references.as_mut() {
+            reference.table = self.stored_table_name(&reference.table);
+        }
+        if column.name.is_empty() {
+            anyhow::bail!("Table '{}': added column has an empty name", table);
+        }
+        let mut schemas = self.table_schemas.lock().unwrap();
+        let existing = schemas.get_mut(&table)
+            .ok_or_else(|| anyhow::anyhow!("Cannot add column '{}': no schema defined for table {}", column.name, table))?;
+        if column.primary_key || column.unique {
+            anyhow::bail!(
+                "Table '{}' ({}): added column '{}' can't be {}",
+                table,
+                describe_columns(&existing.columns),
+                column.name,
+                column.constraint_label()
+            );
+        }
+        if let Some(current) = existing.columns.iter().find(|c| c.name == column.name) {
+            if current.same_definition(&column) {
+                return Ok(false);
+            }
+            let columns = existing.columns.iter()
+                .map(|c| if c.name == column.name { column.clone() } else { c.clone() })
+                .collect();
+            return Err(SchemaConflict {
+                table,
+                existing_module: existing.module.clone(),
+                existing_columns: existing.columns.clone(),
+                module: module.to_string(),
+                columns,
+                first_seen: existing.conflicting_modules.insert(module.to_string()),
+            }.into());
+        }
+        existing.columns.push(column.clone());
+        drop(schemas);
+
+        self.post_schema_column(module, &table, column)?;
+        Ok(true)
+    }
+
+    /// Post the `schema_column` document describing `column` of `table`
+    fn post_schema_column(&self, module: &str, table: &str, column: Column) -> Result<()> {
+        use sha2::{Digest, Sha256};
+        // Names may hold characters that aren't safe in a document URL
+        let digest = Sha256::digest(format!("{}\0{}", table, column.name).as_bytes());
+        let id = format!("schema_{}", hex::encode(&digest[..16]));
+        let doc = SchemaColumnDoc {
+            doc_type: "schema_column",
+            table_name: table.to_string(),
+            column: column.name,
+            data_type: column.data_type,
+            description: column.description,
+            references: column.references,
+            module_name: module.to_string(),
+            processed_at: Utc::now(),
+        };
+        self.post_document_with_id(&self.index_for_module(module), &doc, &id)
+    }
+
+    /// Insert a row - POSTs a RowDoc immediately with flattened column values.
+    ///
+    /// Returns false, without storing anything, when the table has a primary
+    /// key or unique column and the row repeats a value already inserted
+    /// (like `INSERT OR IGNORE`). Rows of tables with a primary key are
+    /// indexed under an ID derived from the key, so a key already in the
+    /// index from an earlier run is ignored too.
+    ///
+    /// A value in a column with `references` must be a key already inserted
+    /// into the referenced table this run; a row that has one that isn't
+    /// fails, like SQL with foreign keys enforced.
+    pub fn insert_row(&self, table: &str, uuid: &str, values: &[Value]) -> Result<bool> {
+        self.insert(table, uuid, values, false)
+    }
+
+    /// Insert a run-global row, stored with a null `content_uuid`, into a
+    /// table declared global. `uuid` is the content whose module emitted it.
+    pub fn insert_global_row(&self, table: &str, uuid: &str, values: &[Value]) -> Result<bool> {
+        self.insert(table, uuid, values, true)
+    }
+
+    fn insert(&self, table: &str, uuid: &str, values: &[Value], global: bool) -> Result<bool> {
+        let table = &self.stored_table_name(table);
+        let (module_name, module_version) = {
             let state = self.content_state.lock().unwrap();
-            state.get(uuid)
+            let content = state.get(uuid);
+            let module_name = content
                 .and_then(|s| s.current_module.clone())
-                .ok_or_else(|| anyhow::anyhow!("No current module set for content {}", uuid))?
+                .ok_or_else(|| anyhow::anyhow!("No current module set for content {}", uuid))?;
+            (module_name, content.and_then(|s| s.current_module_version.clone()))
         };
 
-        // Get column names from schema
-        let column_names = {
+        // Get columns from schema
+        let (schema_columns, declared, unique_keys, table_global) = {
             let schemas = self.table_schemas.lock().unwrap();
-            schemas.get(table).cloned()
+            schemas.get(table).map(|d| (d.columns.clone(), d.declared, d.unique_keys.clone(), d.global))
                 .ok_or_else(|| anyhow::anyhow!("No schema defined for table {}", table))?
         };
+        if global && !table_global {
+            anyhow::bail!("Table '{}' ({}): global row, but the table is not global", table, describe_columns(&schema_columns));
+        }
+
+        // Added columns may be left off the end of the row, leaving them null
+        if values.len() < declared || values.len() > schema_columns.len() {
+            let expected = if declared == schema_columns.len() {
+                declared.to_string()
+            } else {
+                format!("{} to {}", declared, schema_columns.len())
+            };
+            anyhow::bail!(
+                "Table '{}' ({}): row has {} values, expected {}",
+                table,
+                describe_columns(&schema_columns),
+                values.len(),
+                expected
+            );
+        }
 
         // Build flattened column map
         let mut columns = HashMap::new();
-        for (i, value) in values.iter().enumerate() {
-            if let Some(col_name) = column_names.get(i) {
-                let string_value = match value {
-                    Value::Int64(i) => i.to_string(),
-                    Value::Float64(f) => f.to_string(),
-                    Value::String(s) => s.clone(),
-                    Value::Boolean(b) => b.to_string(),
-                };
-                columns.insert(col_name.clone(), string_value);
+        let mut json_columns = HashMap::new();
+        for (column, value) in schema_columns.iter().zip(values) {
+            let value_type = value.data_type();
+            let json_text = column.data_type == DataType::Json && value_type == DataType::String;
+            // Guests with a single number type (Python, JS) send whole numbers
+            // as Int64, and times as Int64 epoch milliseconds
+            let int_as_float = column.data_type == DataType::Float64 && value_type == DataType::Int64;
+            let int_as_time = column.data_type == DataType::Timestamp && value_type == DataType::Int64;
+            if value_type != column.data_type && !json_text && !int_as_float && !int_as_time {
+                anyhow::bail!(
+                    "Table '{}' ({}): column '{}' expects {:?}, got {:?} value {}",
+                    table,
+                    describe_columns(&schema_columns),
+                    column.name,
+                    column.data_type,
+                    value_type,
+                    redact_value(value)
+                );
+            }
+            if let (true, Value::String(s)) = (json_text, value) {
+                let document: serde_json::Value = serde_json::from_str(s).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Table '{}' ({}): column '{}' expects Json, got invalid JSON {}: {}",
+                        table,
+                        describe_columns(&schema_columns),
+                        column.name,
+                        redact_value(value),
+                        e
+                    )
+                })?;
+                json_columns.insert(column.name.clone(), document);
+            }
+            let column_value = match value {
+                Value::Int64(i) if int_as_float => (*i as f64).to_string(),
+                Value::Int64(millis) | Value::Timestamp(millis) if column.data_type == DataType::Timestamp => {
+                    timestamp_text(*millis).ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Table '{}' ({}): column '{}' timestamp {} is out of range",
+                            table,
+                            describe_columns(&schema_columns),
+                            column.name,
+                            millis
+                        )
+                    })?
+                }
+                Value::Int64(i) | Value::Timestamp(i) => i.to_string(),
+                Value::Float64(f) => float_text(*f),
+                Value::String(s) => s.clone(),
+                Value::Boolean(b) => b.to_string(),
+            };
+            columns.insert(column.name.clone(), column_value);
+        }
+
+        // Foreign key values must already be a key of the referenced table
+        let references: Vec<(&Column, &Value, String)> = schema_columns.iter().zip(values)
+            .filter_map(|(column, value)| Some((column, value, column.references.as_ref()?)))
+            .map(|(column, value, reference)| {
+                Ok((column, value, self.referenced_key(table, column, reference, &columns[&column.name])?))
+            })
+            .collect::<Result<_>>()?;
+        if !references.is_empty() {
+            let seen = self.seen_keys.lock().unwrap();
+            if let Some((column, value, _)) = references.iter().find(|(_, _, key)| !seen.contains(key)) {
+                let reference = column.references.as_ref().unwrap();
+                anyhow::bail!(
+                    "Table '{}' ({}): column '{}' value {} is not in {}.{}",
+                    table,
+                    describe_columns(&schema_columns),
+                    column.name,
+                    redact_value(value),
+                    reference.table,
+                    reference.column
+                );
             }
         }
 
+        // Check and claim every key before posting, so concurrent workers
+        // can't both insert the same key
+        let keys: Vec<String> = unique_keys.iter().enumerate()
+            .map(|(i, key)| {
+                let values: Vec<&String> = key.iter().map(|&c| &columns[&schema_columns[c].name]).collect();
+                format!("{}\0{}\0{}", table, i, serde_json::to_string(&values).unwrap_or_default())
+            })
+            .collect();
+        {
+            let mut seen = self.seen_keys.lock().unwrap();
+            if keys.iter().any(|k| seen.contains(k)) {
+                return Ok(false);
+            }
+            seen.extend(keys.iter().cloned());
+        }
+
         let doc = RowDoc {
             doc_type: "row",
-            content_uuid: uuid.to_string(),
+            content_uuid: (!global).then(|| uuid.to_string()),
             module_name,
+            module_version,
             table_name: table.to_string(),
+            table_prefix: self.table_prefix.clone(),
             processed_at: Utc::now(),
             columns,
+            json_columns: if json_columns.is_empty() {
+                HashMap::new()
+            } else {
+                HashMap::from([(table.to_string(), json_columns)])
+            },
+        };
+
+        let index = self.index_for_module(&doc.module_name);
+        let context = || format!("Failed to insert row into table '{}' ({})", table, describe_columns(&schema_columns));
+        let stored = if unique_keys.first().is_some_and(|key| key.iter().all(|&c| schema_columns[c].primary_key)) {
+            // Primary key rows get an ID derived from the key
+            use sha2::{Digest, Sha256};
+            let digest = Sha256::digest(keys[0].as_bytes());
+            let id = format!("{}_{}", table, hex::encode(&digest[..16]));
+            self.create_document(&index, &doc, &id).with_context(context)
+        } else {
+            // POST without explicit ID - let ES generate one
+            self.post_document_auto_id(&index, &doc).with_context(context).map(|()| true)
         };
 
-        // POST without explicit ID - let ES generate one
-        self.post_document_auto_id(&doc)?;
+        // Release the claimed keys if the row wasn't stored after all
+        if !matches!(stored, Ok(true)) {
+            let mut seen = self.seen_keys.lock().unwrap();
+            for key in &keys {
+                seen.remove(key);
+            }
+        } else if let Some(definition) = self.table_schemas.lock().unwrap().get_mut(table) {
+            definition.filled |= definition.module == doc.module_name;
+        }
+        stored
+    }
 
-        Ok(())
+    /// The `seen_keys` entry that the value `text` of the foreign key
+    /// `column` of `table` has to match. The referenced column must be the
+    /// primary key or a unique column of its table, of the same type.
+    fn referenced_key(&self, table: &str, column: &Column, reference: &ForeignKey, text: &String) -> Result<String> {
+        let mismatch = |why: &str| {
+            anyhow::anyhow!(
+                "Table '{}': column '{}' references {}.{}, {}",
+                table,
+                column.name,
+                reference.table,
+                reference.column,
+                why
+            )
+        };
+        let schemas = self.table_schemas.lock().unwrap();
+        let referenced = schemas.get(&reference.table).ok_or_else(|| mismatch("which is not defined"))?;
+        let index = referenced.columns.iter()
+            .position(|c| c.name == reference.column)
+            .ok_or_else(|| mismatch("which doesn't exist"))?;
+        if referenced.columns[index].data_type != column.data_type {
+            return Err(mismatch(&format!("which is {:?}", referenced.columns[index].data_type)));
+        }
+        let key = referenced.unique_keys.iter()
+            .position(|key| key == &[index])
+            .ok_or_else(|| mismatch("which is not the primary key or a unique column"))?;
+        Ok(format!("{}\0{}\0{}", reference.table, key, serde_json::to_string(&[text]).unwrap_or_default()))
+    }
+
+    /// Tables defined this run that the module defining them stored no rows
+    /// in, as table names by module
+    pub fn empty_tables(&self) -> BTreeMap<String, Vec<String>> {
+        let mut empty: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (table, definition) in self.table_schemas.lock().unwrap().iter() {
+            if !definition.filled {
+                empty.entry(definition.module.clone()).or_default().push(table.clone());
+            }
+        }
+        empty.values_mut().for_each(|tables| tables.sort());
+        empty
     }
 
     /// Record module stdout/stderr - POSTs a ModuleOutputDoc immediately
+    #[allow(clippy::too_many_arguments)]
     pub fn record_module_output(
         &self,
         content_uuid: &str,
         module_name: &str,
+        module_version: Option<&str>,
         stdout: Option<&str>,
         stderr: Option<&str>,
         stdout_truncated: bool,
@@ -208,6 +1118,7 @@ impl MetadataStore {
             doc_type: "module_output",
             content_uuid: content_uuid.to_string(),
             module_name: module_name.to_string(),
+            module_version: module_version.map(|s| s.to_string()),
             processed_at: Utc::now(),
             stdout: stdout.map(|s| s.to_string()),
             stderr: stderr.map(|s| s.to_string()),
@@ -217,65 +1128,254 @@ impl MetadataStore {
 
         // Use content_uuid + module_name as ID
         let doc_id = format!("{}_{}", content_uuid, module_name);
-        self.post_document_with_id(&doc, &doc_id)?;
+        self.post_document_with_id(&self.index_for_module(module_name), &doc, &doc_id)?;
 
         Ok(())
     }
 
-    /// Finalize a successful content - POSTs the ContentDoc
-    pub fn finalize_content_success(&self, uuid: &str) -> Result<()> {
-        let (filename, parent_uuid) = {
-            let mut state = self.content_state.lock().unwrap();
-            if let Some(content) = state.remove(uuid) {
-                (content.filename, content.parent_uuid)
-            } else {
-                return Ok(());
-            }
-        };
-
-        let doc = ContentDoc {
-            doc_type: "content",
-            content_uuid: uuid.to_string(),
-            filename,
-            parent_uuid,
+    /// Record a module invocation - POSTs a ModuleRunDoc immediately
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_module_run(
+        &self,
+        content_uuid: &str,
+        module_name: &str,
+        module_version: Option<&str>,
+        runtime: Option<&ModuleRuntime>,
+        status: &str,
+        emitted_rows: usize,
+        emitted_subcontent: usize,
+        peak_memory_bytes: u64,
+        wasi_errnos: &BTreeMap<&str, u64>,
+    ) -> Result<()> {
+        let doc = ModuleRunDoc {
+            doc_type: "module_run",
+            content_uuid: content_uuid.to_string(),
+            module_name: module_name.to_string(),
+            module_version: module_version.map(|s| s.to_string()),
+            language: runtime.and_then(|r| r.language.clone()),
+            runtime: runtime.map(|r| r.runtime.clone()),
             processed_at: Utc::now(),
-            status: "success".to_string(),
-            error_message: None,
+            status: status.to_string(),
+            emitted_rows,
+            emitted_subcontent,
+            peak_memory_bytes,
+            wasi_errnos: wasi_errnos.iter().map(|(name, count)| (name.to_string(), *count)).collect(),
         };
 
-        self.post_document_with_id(&doc, uuid)?;
-        Ok(())
+        // Use content_uuid + module_name as ID, like the module output document
+        let doc_id = format!("{}_{}_run", content_uuid, module_name);
+        self.post_document_with_id(&self.index_for_module(module_name), &doc, &doc_id)
     }
 
-    /// Finalize a failed content - POSTs the ContentDoc with error
-    pub fn finalize_content_failure(&self, uuid: &str, error: &str) -> Result<()> {
-        let (filename, parent_uuid) = {
-            let mut state = self.content_state.lock().unwrap();
-            if let Some(content) = state.remove(uuid) {
-                (content.filename, content.parent_uuid)
-            } else {
-                // Content not started, create minimal doc
-                ("unknown".to_string(), None)
-            }
+    /// Cache the tables and rows `module_name` produced for content with
+    /// SHA-256 `content_sha256` - PUTs a ResultCacheDoc
+    pub fn store_cached_result(
+        &self,
+        module_name: &str,
+        module_version: Option<&str>,
+        content_sha256: &str,
+        tables: &[TableSchema],
+        rows: &[MetadataRow],
+    ) -> Result<()> {
+        let doc = ResultCacheDoc {
+            doc_type: "result_cache",
+            module_name: module_name.to_string(),
+            module_version: module_version.map(|s| s.to_string()),
+            content_sha256: content_sha256.to_string(),
+            processed_at: Utc::now(),
+            tables,
+            rows,
+        };
+        let id = result_cache_id(module_name, module_version, content_sha256);
+        self.post_document_with_id(&self.index_for_module(module_name), &doc, &id)
+    }
+
+    /// The result cached by `store_cached_result` for this module version
+    /// and content hash, in this run or an earlier one against the same index
+    pub fn cached_result(&self, module_name: &str, module_version: Option<&str>, content_sha256: &str) -> Result<Option<CachedResult>> {
+        let index = self.index_for_module(module_name);
+        let id = result_cache_id(module_name, module_version, content_sha256);
+        let source = if let Some(captured) = &self.captured {
+            captured.lock().unwrap().iter()
+                .rev()
+                .find(|d| d.index == index && d.id.as_deref() == Some(id.as_str()))
+                .map(|d| d.doc.clone())
+        } else if self.es_url.is_empty() {
+            None
+        } else {
+            let url = format!("{}/{}/_doc/{}", self.es_url, index, id);
+            let response = self.client.get(&url).send()?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().unwrap_or_default();
+                anyhow::bail!("Failed to read cached result {}: HTTP {} - {}", id, status, body);
+            }
+            let body: serde_json::Value = response.json()?;
+            Some(body["_source"].clone())
+        };
+        source
+            .map(|doc| serde_json::from_value(doc).context("Malformed result_cache document"))
+            .transpose()
+    }
+
+    /// Record a labeled parent -> child edge found by `module_name` - POSTs an
+    /// EdgeDoc immediately
+    pub fn record_content_edge(&self, module_name: &str, parent_uuid: &str, child_uuid: &str, relation: &str) -> Result<()> {
+        let doc = EdgeDoc {
+            doc_type: "edge",
+            parent_uuid: parent_uuid.to_string(),
+            child_uuid: child_uuid.to_string(),
+            relation: relation.to_string(),
+            processed_at: Utc::now(),
         };
 
+        // Use parent_uuid + child_uuid + relation as ID
+        let doc_id = format!("{}_{}_{}", parent_uuid, child_uuid, relation);
+        self.post_document_with_id(&self.index_for_module(module_name), &doc, &doc_id)
+    }
+
+    /// Record a processing error - POSTs an ErrorDoc immediately
+    pub fn record_error(&self, content_uuid: Option<&str>, module_name: &str, kind: &str, message: &str) -> Result<()> {
+        self.post_error(content_uuid, module_name, kind, None, message)
+    }
+
+    /// Record an error a module reported with `report_error`, of kind
+    /// `"guest_error"` with the module's code
+    pub fn record_guest_error(&self, content_uuid: &str, module_name: &str, code: u32, message: &str) -> Result<()> {
+        self.post_error(Some(content_uuid), module_name, "guest_error", Some(code), message)
+    }
+
+    fn post_error(&self, content_uuid: Option<&str>, module_name: &str, kind: &str, code: Option<u32>, message: &str) -> Result<()> {
+        let doc = ErrorDoc {
+            doc_type: "error",
+            content_uuid: content_uuid.map(|s| s.to_string()),
+            module_name: module_name.to_string(),
+            kind: kind.to_string(),
+            code,
+            message: message.to_string(),
+            processed_at: Utc::now(),
+        };
+
+        self.post_document_auto_id(&self.index_for_module(module_name), &doc)
+    }
+
+    /// Finalize a successful content - POSTs the ContentDoc
+    pub fn finalize_content_success(&self, uuid: &str) -> Result<()> {
+        let (doc, modules) = match self.take_content_doc(uuid, "success", None) {
+            Some(taken) => taken,
+            None => return Ok(()),
+        };
+
+        self.post_content_doc(&doc, &modules)
+    }
+
+    /// Finalize a content no module was run on, e.g. `"skipped_empty"` - POSTs
+    /// the ContentDoc with that status
+    pub fn finalize_content_skipped(&self, uuid: &str, status: &str) -> Result<()> {
+        let (doc, modules) = match self.take_content_doc(uuid, status, None) {
+            Some(taken) => taken,
+            None => return Ok(()),
+        };
+
+        self.post_content_doc(&doc, &modules)
+    }
+
+    /// Finalize a failed content - POSTs the ContentDoc with error
+    pub fn finalize_content_failure(&self, uuid: &str, error: &str) -> Result<()> {
+        let (doc, modules) = self.take_content_doc(uuid, "failed", Some(error)).unwrap_or_else(|| {
+            // Content not started, create minimal doc
+            let now = Utc::now();
+            let doc = ContentDoc {
+                doc_type: "content",
+                content_uuid: uuid.to_string(),
+                filename: "unknown".to_string(),
+                parent_uuid: None,
+                produced_by: None,
+                slice: None,
+                processed_at: now,
+                started_at_ms: now.timestamp_millis(),
+                finished_at_ms: now.timestamp_millis(),
+                status: "failed".to_string(),
+                error_message: Some(error.to_string()),
+                depth_limited_children: 0,
+                rows_truncated: BTreeMap::new(),
+                lineage: None,
+                text_encoding: None,
+                labels: self.labels.clone(),
+            };
+            (doc, Vec::new())
+        });
+
+        self.post_content_doc(&doc, &modules)
+    }
+
+    /// POST a ContentDoc to the shared index, or in per-module mode to the
+    /// index of every module that processed the content
+    fn post_content_doc(&self, doc: &ContentDoc, modules: &[String]) -> Result<()> {
+        if self.output_mode == OutputMode::Shared || modules.is_empty() {
+            return self.post_document_with_id(&self.es_index, doc, &doc.content_uuid);
+        }
+        for module in modules {
+            self.post_document_with_id(&self.index_for_module(module), doc, &doc.content_uuid)?;
+        }
+        Ok(())
+    }
+
+    /// Stop tracking a content and build its ContentDoc, stamped with the finish time.
+    /// Also returns the modules that processed it.
+    fn take_content_doc(&self, uuid: &str, status: &str, error: Option<&str>) -> Option<(ContentDoc, Vec<String>)> {
+        let content = self.content_state.lock().unwrap().remove(uuid)?;
+        let now = Utc::now();
+
         let doc = ContentDoc {
             doc_type: "content",
             content_uuid: uuid.to_string(),
-            filename,
-            parent_uuid,
-            processed_at: Utc::now(),
-            status: "failed".to_string(),
-            error_message: Some(error.to_string()),
+            filename: content.filename,
+            parent_uuid: content.parent_uuid,
+            produced_by: content.produced_by,
+            slice: content.slice,
+            processed_at: now,
+            started_at_ms: content.started_at_ms,
+            finished_at_ms: now.timestamp_millis(),
+            status: status.to_string(),
+            error_message: error.map(|s| s.to_string()),
+            depth_limited_children: content.depth_limited_children,
+            rows_truncated: content.rows_truncated,
+            lineage: content.lineage,
+            text_encoding: content.text_encoding,
+            labels: self.labels.clone(),
         };
+        Some((doc, content.modules))
+    }
 
-        self.post_document_with_id(&doc, uuid)?;
-        Ok(())
+    /// Record a document in the in-memory capture, if enabled.
+    /// Returns true if the document was captured, or dropped by a dummy
+    /// store, and should not be POSTed.
+    fn capture<T: Serialize>(&self, index: &str, id: Option<&str>, doc: &T) -> Result<bool> {
+        match &self.captured {
+            Some(captured) => {
+                captured.lock().unwrap().push(CapturedDoc {
+                    index: index.to_string(),
+                    id: id.map(|s| s.to_string()),
+                    doc: serde_json::to_value(doc)?,
+                });
+                Ok(true)
+            }
+            None => Ok(self.es_url.is_empty()),
+        }
     }
 
     /// POST a document with auto-generated ID
-    fn post_document_auto_id<T: Serialize>(&self, doc: &T) -> Result<()> {
-        let url = format!("{}/{}/_doc", self.es_url, self.es_index);
+    fn post_document_auto_id<T: Serialize>(&self, index: &str, doc: &T) -> Result<()> {
+        self.note_written(doc);
+        if self.capture(index, None, doc)? {
+            return Ok(());
+        }
+
+        let url = format!("{}/{}/_doc", self.es_url, index);
 
         let response = self.client
             .post(&url)
@@ -293,8 +1393,13 @@ impl MetadataStore {
     }
 
     /// POST a document with explicit ID
-    fn post_document_with_id<T: Serialize>(&self, doc: &T, id: &str) -> Result<()> {
-        let url = format!("{}/{}/_doc/{}", self.es_url, self.es_index, id);
+    fn post_document_with_id<T: Serialize>(&self, index: &str, doc: &T, id: &str) -> Result<()> {
+        self.note_written(doc);
+        if self.capture(index, Some(id), doc)? {
+            return Ok(());
+        }
+
+        let url = format!("{}/{}/_doc/{}", self.es_url, index, id);
 
         let response = self.client
             .put(&url)
@@ -311,6 +1416,344 @@ impl MetadataStore {
         Ok(())
     }
 
+    /// Check the index's schema stamp against this build, writing the stamp
+    /// if the index is new. A stamp with a different `schema_version`, or
+    /// documents without any stamp (written before stamps existed), is an
+    /// error unless `migrate` is set, in which case the stamp is replaced.
+    pub fn init_schema_meta(&self, migrate: bool) -> Result<()> {
+        if let Some(existing) = self.fetch_document(&self.es_index, META_DOC_ID)? {
+            let existing: MetaDoc = serde_json::from_value(existing)
+                .context("Failed to parse the index's schema stamp")?;
+            if existing.schema_version == SCHEMA_VERSION {
+                return Ok(());
+            }
+            if !migrate {
+                anyhow::bail!(
+                    "Index '{}' was written with schema version {} (wadup {}), but this build writes version {}; \
+                     use a new index or rerun with --migrate to restamp it",
+                    self.es_index,
+                    existing.schema_version,
+                    existing.wadup_version,
+                    SCHEMA_VERSION
+                );
+            }
+            tracing::warn!(
+                "Restamping index '{}' from schema version {} to {}; existing documents keep their old layout",
+                self.es_index,
+                existing.schema_version,
+                SCHEMA_VERSION
+            );
+        } else {
+            let count = self.count_documents(&self.es_index)?;
+            if count > 0 {
+                if !migrate {
+                    anyhow::bail!(
+                        "Index '{}' holds {} documents but no schema stamp, so it was written before schema \
+                         versions were recorded; use a new index or rerun with --migrate to stamp it",
+                        self.es_index,
+                        count
+                    );
+                }
+                tracing::warn!(
+                    "Stamping unversioned index '{}' with schema version {}; existing documents keep their old layout",
+                    self.es_index,
+                    SCHEMA_VERSION
+                );
+            }
+        }
+
+        let doc = MetaDoc {
+            doc_type: "meta".to_string(),
+            schema_version: SCHEMA_VERSION,
+            wadup_version: env!("CARGO_PKG_VERSION").to_string(),
+            stamped_at: Utc::now(),
+        };
+        self.post_document_with_id(&self.es_index, &doc, META_DOC_ID)
+    }
+
+    /// Create a document with explicit ID unless one with that ID exists.
+    /// Returns whether the document was created.
+    fn create_document<T: Serialize>(&self, index: &str, doc: &T, id: &str) -> Result<bool> {
+        self.note_written(doc);
+        if self.captured.is_some() {
+            if self.fetch_document(index, id)?.is_some() {
+                return Ok(false);
+            }
+            self.capture(index, Some(id), doc)?;
+            return Ok(true);
+        }
+
+        let url = format!("{}/{}/_create/{}", self.es_url, index, id);
+
+        let response = self.client
+            .put(&url)
+            .header("Content-Type", "application/json")
+            .json(doc)
+            .send()?;
+
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to create document {}: HTTP {} - {}", id, status, body);
+        }
+
+        Ok(true)
+    }
+
+    /// GET a document's source by ID, or None if it does not exist
+    fn fetch_document(&self, index: &str, id: &str) -> Result<Option<serde_json::Value>> {
+        if let Some(captured) = &self.captured {
+            let captured = captured.lock().unwrap();
+            return Ok(captured.iter().rev()
+                .find(|d| d.index == index && d.id.as_deref() == Some(id))
+                .map(|d| d.doc.clone()));
+        }
+
+        let url = format!("{}/{}/_source/{}", self.es_url, index, id);
+        let response = self.client.get(&url).send()?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to fetch document {}: HTTP {} - {}", id, status, body);
+        }
+
+        Ok(Some(response.json()?))
+    }
+
+    /// Number of documents in `index` (0 if it doesn't exist)
+    fn count_documents(&self, index: &str) -> Result<u64> {
+        if let Some(captured) = &self.captured {
+            return Ok(captured.lock().unwrap().iter().filter(|d| d.index == index).count() as u64);
+        }
+
+        let url = format!("{}/{}/_count", self.es_url, index);
+        let response = self.client.get(&url).send()?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(0);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to count documents in {}: HTTP {} - {}", index, status, body);
+        }
+
+        let body: serde_json::Value = response.json()?;
+        body["count"].as_u64()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected _count response for {}: {}", index, body))
+    }
+
+    /// Content documents with status `"failed"` in this store's indices
+    /// (the shared index, and per-module indices), one per content
+    pub fn failed_contents(&self) -> Result<Vec<FailedContent>> {
+        let docs: Vec<serde_json::Value> = if let Some(captured) = &self.captured {
+            // Later documents replace earlier ones with the same ID
+            let mut latest = HashMap::new();
+            for d in captured.lock().unwrap().iter() {
+                let ours = d.index == self.es_index || d.index.starts_with(&format!("{}-", self.es_index));
+                if ours && d.doc["doc_type"] == "content" {
+                    latest.insert(d.doc["content_uuid"].to_string(), d.doc.clone());
+                }
+            }
+            latest.into_values().filter(|d| d["status"] == "failed").collect()
+        } else {
+            let url = format!(
+                "{}/{},{}-*/_search?ignore_unavailable=true&allow_no_indices=true",
+                self.es_url, self.es_index, self.es_index
+            );
+            let query = serde_json::json!({
+                "query": { "bool": { "filter": [
+                    { "term": { "doc_type": "content" } },
+                    { "term": { "status": "failed" } },
+                ] } },
+                "size": MAX_FAILED_CONTENTS,
+            });
+            let response = self.client.post(&url).json(&query).send()?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().unwrap_or_default();
+                anyhow::bail!("Failed to search for failed content: HTTP {} - {}", status, body);
+            }
+            let body: serde_json::Value = response.json()?;
+            let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+            if hits.len() >= MAX_FAILED_CONTENTS {
+                anyhow::bail!("At least {} failed contents: too many to read back in one search", MAX_FAILED_CONTENTS);
+            }
+            hits.into_iter().map(|hit| hit["_source"].clone()).collect()
+        };
+
+        let mut failed: Vec<FailedContent> = docs.into_iter()
+            .filter_map(|d| serde_json::from_value(d).ok())
+            .collect();
+        failed.sort_by(|a, b| (&a.filename, &a.content_uuid).cmp(&(&b.filename, &b.content_uuid)));
+        failed.dedup_by(|a, b| a.content_uuid == b.content_uuid);
+        Ok(failed)
+    }
+
+    /// Delete the `error` documents recorded for `content_uuid` in this
+    /// store's indices, so errors of an attempt that is being retried don't
+    /// outlive it
+    pub fn delete_content_errors(&self, content_uuid: &str) -> Result<()> {
+        if let Some(captured) = &self.captured {
+            captured.lock().unwrap().retain(|d| {
+                let ours = d.index == self.es_index || d.index.starts_with(&format!("{}-", self.es_index));
+                !(ours && d.doc["doc_type"] == "error" && d.doc["content_uuid"] == content_uuid)
+            });
+            return Ok(());
+        }
+        if self.es_url.is_empty() {
+            return Ok(());
+        }
+
+        let url = format!(
+            "{}/{},{}-*/_delete_by_query?ignore_unavailable=true&allow_no_indices=true&refresh=true",
+            self.es_url, self.es_index, self.es_index
+        );
+        let query = serde_json::json!({
+            "query": { "bool": { "filter": [
+                { "term": { "doc_type": "error" } },
+                { "term": { "content_uuid": content_uuid } },
+            ] } },
+        });
+        let response = self.client.post(&url).json(&query).send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to delete the errors of content {}: HTTP {} - {}", content_uuid, status, body);
+        }
+        Ok(())
+    }
+
+    /// Check that the cluster is healthy and that every row's `content_uuid`
+    /// and every content's `parent_uuid` names a content document in this
+    /// store's indices (the shared index, and per-module indices). Rows
+    /// without a content document are typically left by a run that was
+    /// interrupted before the content finished.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport { cluster_status: self.cluster_status()?, ..Default::default() };
+
+        let docs = self.scan_documents(&["content", "row"])?;
+        let (contents, rows): (Vec<_>, Vec<_>) = docs.iter().partition(|d| d["doc_type"] == "content");
+        let known: std::collections::HashSet<&str> = contents.iter()
+            .filter_map(|d| d["content_uuid"].as_str())
+            .collect();
+        report.contents = known.len();
+        report.rows = rows.len();
+
+        let mut orphans: std::collections::BTreeMap<(String, String), usize> = Default::default();
+        for row in &rows {
+            // Global rows have no content
+            let Some(uuid) = row["content_uuid"].as_str() else { continue };
+            if !known.contains(uuid) {
+                let table = row["_table"].as_str().unwrap_or("unknown").to_string();
+                *orphans.entry((table, uuid.to_string())).or_default() += 1;
+            }
+        }
+        report.orphan_rows = orphans.into_iter()
+            .map(|((table, content_uuid), count)| OrphanRows { table, content_uuid, count })
+            .collect();
+
+        for content in &contents {
+            let Some(parent) = content["parent_uuid"].as_str() else { continue };
+            if !known.contains(parent) {
+                report.dangling_parents.push(DanglingParent {
+                    content_uuid: content["content_uuid"].as_str().unwrap_or_default().to_string(),
+                    filename: content["filename"].as_str().unwrap_or_default().to_string(),
+                    parent_uuid: parent.to_string(),
+                });
+            }
+        }
+        report.dangling_parents.sort_by(|a, b| (&a.content_uuid, &a.parent_uuid).cmp(&(&b.content_uuid, &b.parent_uuid)));
+        report.dangling_parents.dedup();
+        Ok(report)
+    }
+
+    /// Status of the Elasticsearch cluster, or `None` without one
+    fn cluster_status(&self) -> Result<Option<String>> {
+        if self.captured.is_some() || self.es_url.is_empty() {
+            return Ok(None);
+        }
+        let url = format!("{}/_cluster/health", self.es_url);
+        let response = self.client.get(&url).send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to read cluster health: HTTP {} - {}", status, body);
+        }
+        let body: serde_json::Value = response.json()?;
+        Ok(body["status"].as_str().map(|s| s.to_string()))
+    }
+
+    /// Every document with one of `doc_types` in this store's indices. A
+    /// document indexed again under the same ID is returned once.
+    fn scan_documents(&self, doc_types: &[&str]) -> Result<Vec<serde_json::Value>> {
+        if let Some(captured) = &self.captured {
+            let mut docs = Vec::new();
+            let mut by_id: HashMap<(String, String), usize> = HashMap::new();
+            for d in captured.lock().unwrap().iter() {
+                let ours = d.index == self.es_index || d.index.starts_with(&format!("{}-", self.es_index));
+                if !ours || !doc_types.iter().any(|t| d.doc["doc_type"] == *t) {
+                    continue;
+                }
+                match &d.id {
+                    Some(id) => match by_id.entry((d.index.clone(), id.clone())) {
+                        std::collections::hash_map::Entry::Occupied(slot) => docs[*slot.get()] = d.doc.clone(),
+                        std::collections::hash_map::Entry::Vacant(slot) => {
+                            slot.insert(docs.len());
+                            docs.push(d.doc.clone());
+                        }
+                    },
+                    None => docs.push(d.doc.clone()),
+                }
+            }
+            return Ok(docs);
+        }
+        if self.es_url.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let url = format!(
+            "{}/{},{}-*/_search?scroll=1m&ignore_unavailable=true&allow_no_indices=true",
+            self.es_url, self.es_index, self.es_index
+        );
+        let query = serde_json::json!({
+            "query": { "terms": { "doc_type": doc_types } },
+            "_source": ["doc_type", "content_uuid", "parent_uuid", "filename", "_table"],
+            "size": SCAN_PAGE_SIZE,
+        });
+        let mut response = self.client.post(&url).json(&query).send()?;
+        let mut docs = Vec::new();
+        loop {
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().unwrap_or_default();
+                anyhow::bail!("Failed to scan documents: HTTP {} - {}", status, body);
+            }
+            let body: serde_json::Value = response.json()?;
+            let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+            let scroll_id = body["_scroll_id"].as_str().map(|s| s.to_string());
+            if hits.is_empty() || scroll_id.is_none() {
+                if let Some(id) = scroll_id {
+                    let _ = self.client.delete(format!("{}/_search/scroll", self.es_url))
+                        .json(&serde_json::json!({ "scroll_id": id }))
+                        .send();
+                }
+                return Ok(docs);
+            }
+            docs.extend(hits.into_iter().map(|hit| hit["_source"].clone()));
+            response = self.client.post(format!("{}/_search/scroll", self.es_url))
+                .json(&serde_json::json!({ "scroll": "1m", "scroll_id": scroll_id }))
+                .send()?;
+        }
+    }
+
     // Legacy compatibility methods
 
     pub fn record_content_success(
@@ -345,6 +1788,44 @@ impl MetadataStore {
     }
 }
 
+/// Format a column list as `name Type, ...` for error messages
+fn describe_columns(columns: &[Column]) -> String {
+    columns.iter()
+        .map(|c| format!("{} {:?}", c.name, c.data_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Render a value for an error message, truncating long strings
+fn redact_value(value: &Value) -> String {
+    const MAX_CHARS: usize = 32;
+    match value {
+        Value::String(s) if s.chars().count() > MAX_CHARS => {
+            let prefix: String = s.chars().take(MAX_CHARS).collect();
+            format!("{:?}...", prefix)
+        }
+        Value::String(s) => format!("{:?}", s),
+        Value::Int64(i) => i.to_string(),
+        Value::Float64(f) => float_text(*f),
+        Value::Boolean(b) => b.to_string(),
+        Value::Timestamp(millis) => timestamp_text(*millis).unwrap_or_else(|| millis.to_string()),
+    }
+}
+
+/// Stored text of a Timestamp column: ISO-8601 in UTC with milliseconds
+/// (e.g. `2024-05-01T12:30:00.250Z`), which Elasticsearch maps as a date.
+/// None past the years chrono can represent.
+fn timestamp_text(millis: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(millis).map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+/// Stored text of a Float64 column: NaN and the infinities are spelled as
+/// in JSON rows (`"NaN"`, `"Infinity"`, `"-Infinity"`) rather than Rust's
+/// `NaN` / `inf`
+fn float_text(value: f64) -> String {
+    wadup_abi::float64::non_finite_name(value).map_or_else(|| value.to_string(), str::to_string)
+}
+
 impl Clone for MetadataStore {
     fn clone(&self) -> Self {
         Self {
@@ -353,6 +1834,531 @@ impl Clone for MetadataStore {
             client: self.client.clone(),
             content_state: Arc::clone(&self.content_state),
             table_schemas: Arc::clone(&self.table_schemas),
+            seen_keys: Arc::clone(&self.seen_keys),
+            output_bytes: Arc::clone(&self.output_bytes),
+            max_output_bytes: self.max_output_bytes,
+            output_mode: self.output_mode,
+            table_prefix: self.table_prefix.clone(),
+            captured: self.captured.clone(),
+            labels: self.labels.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with_table() -> MetadataStore {
+        let store = MetadataStore::new_dummy();
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "entries".to_string(),
+            columns: vec![
+                Column::new("name", DataType::String),
+                Column::new("size", DataType::Int64),
+            ],
+            global: false,
+        }).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_insert_type_mismatch_names_table_and_column() {
+        let store = store_with_table();
+        let err = store.insert_row(
+            "entries",
+            "uuid-1",
+            &[Value::String("a".to_string()), Value::String("big".to_string())],
+        ).unwrap_err().to_string();
+
+        assert!(err.contains("'entries'"), "{}", err);
+        assert!(err.contains("column 'size' expects Int64"), "{}", err);
+        assert!(err.contains("name String, size Int64"), "{}", err);
+    }
+
+    #[test]
+    fn test_timestamps_are_stored_as_iso_8601() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "events".to_string(),
+            columns: vec![Column::new("at", DataType::Timestamp)],
+            global: false,
+        }).unwrap();
+
+        store.insert_row("events", "uuid-1", &[Value::Timestamp(1_714_566_600_250)]).unwrap();
+        // Int64 epoch milliseconds from guests without a time type
+        store.insert_row("events", "uuid-1", &[Value::Int64(-1)]).unwrap();
+        let err = store.insert_row("events", "uuid-1", &[Value::Timestamp(i64::MAX)]).unwrap_err();
+        assert!(err.to_string().contains("timestamp 9223372036854775807 is out of range"), "{}", err);
+
+        let docs = store.captured_documents();
+        let times: Vec<_> = docs.iter().filter(|d| d.doc["doc_type"] == "row").map(|d| d.doc["at"].as_str().unwrap()).collect();
+        assert_eq!(times, ["2024-05-01T12:30:00.250Z", "1969-12-31T23:59:59.999Z"]);
+        let parsed = DateTime::parse_from_rfc3339(times[0]).unwrap();
+        assert_eq!(parsed.timestamp_millis(), 1_714_566_600_250);
+        assert_eq!(DataType::Timestamp.sql_type(), "INTEGER");
+    }
+
+    #[test]
+    fn test_added_columns_widen_rows_and_reject_other_types() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        let schema = TableSchema {
+            name: "entries".to_string(),
+            columns: vec![Column::new("name", DataType::String)],
+            global: false,
+        };
+        store.define_table("module", schema.clone()).unwrap();
+        store.insert_row("entries", "uuid-1", &[Value::String("a".to_string())]).unwrap();
+
+        assert!(store.add_column("module", "entries", Column::new("size", DataType::Int64)).unwrap());
+        assert!(!store.add_column("module", "entries", Column::new("size", DataType::Int64)).unwrap());
+        let conflict = store.add_column("module", "entries", Column::new("size", DataType::String)).unwrap_err();
+        assert_eq!(conflict.downcast_ref::<SchemaConflict>().unwrap().diff(), "~size Int64 -> String");
+        // The table can still be declared as first defined
+        store.define_table("module", schema).unwrap();
+
+        store.insert_row("entries", "uuid-1", &[Value::String("b".to_string()), Value::Int64(2)]).unwrap();
+        store.insert_row("entries", "uuid-1", &[Value::String("c".to_string())]).unwrap();
+        let err = store.insert_row("entries", "uuid-1", &[]).unwrap_err().to_string();
+        assert!(err.contains("row has 0 values, expected 1 to 2"), "{}", err);
+
+        let docs = store.captured_documents();
+        let rows: Vec<_> = docs.iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .map(|d| (d.doc["name"].as_str().unwrap(), d.doc.get("size").and_then(|s| s.as_str())))
+            .collect();
+        assert_eq!(rows, [("a", None), ("b", Some("2")), ("c", None)]);
+        assert!(docs.iter().any(|d| d.doc["doc_type"] == "schema_column" && d.doc["column"] == "size"));
+    }
+
+    #[test]
+    fn test_column_descriptions_are_recorded() {
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut size = Column::new("size", DataType::Int64);
+        size.description = Some("bytes".to_string());
+        let schema = TableSchema {
+            name: "files".to_string(),
+            columns: vec![Column::new("path", DataType::String), size],
+            global: false,
+        };
+        store.define_table("alpha", schema.clone()).unwrap();
+
+        // A redeclaration differing only in descriptions is not a conflict
+        let mut redeclared = schema;
+        redeclared.columns[1].description = Some("size in bytes".to_string());
+        store.define_table("beta", redeclared).unwrap();
+
+        let columns: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "schema_column")
+            .map(|d| d.doc)
+            .collect();
+        assert_eq!(columns.len(), 2, "one document per column of the first declaration");
+        assert_eq!(columns[0]["table_name"], "files");
+        assert_eq!(columns[0]["column"], "path");
+        assert!(columns[0]["description"].is_null());
+        assert_eq!(columns[1]["column"], "size");
+        assert_eq!(columns[1]["data_type"], "Int64");
+        assert_eq!(columns[1]["description"], "bytes");
+        assert_eq!(columns[1]["module_name"], "alpha");
+    }
+
+    #[test]
+    fn test_foreign_keys_reject_rows_without_a_referenced_key() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        let mut id = Column::new("id", DataType::Int64);
+        id.primary_key = true;
+        store.define_table("module", TableSchema {
+            name: "users".to_string(),
+            columns: vec![id, Column::new("name", DataType::String)],
+            global: false,
+        }).unwrap();
+        let mut user_id = Column::new("user_id", DataType::Int64);
+        user_id.references = Some(ForeignKey { table: "users".to_string(), column: "id".to_string() });
+        let orders = TableSchema {
+            name: "orders".to_string(),
+            columns: vec![Column::new("item", DataType::String), user_id],
+            global: false,
+        };
+        store.define_table("module", orders.clone()).unwrap();
+
+        assert!(store.insert_row("users", "uuid-1", &[Value::Int64(1), Value::String("ann".to_string())]).unwrap());
+        assert!(store.insert_row("orders", "uuid-1", &[Value::String("book".to_string()), Value::Int64(1)]).unwrap());
+        let err = store.insert_row("orders", "uuid-1", &[Value::String("pen".to_string()), Value::Int64(2)]).unwrap_err();
+        assert!(err.to_string().contains("column 'user_id' value 2 is not in users.id"), "{err}");
+
+        // References must name a key column
+        let mut by_name = orders;
+        by_name.name = "by_name".to_string();
+        by_name.columns[1] = Column::new("user", DataType::String);
+        by_name.columns[1].references = Some(ForeignKey { table: "users".to_string(), column: "name".to_string() });
+        store.define_table("module", by_name).unwrap();
+        let err = store.insert_row("by_name", "uuid-1", &[Value::String("cup".to_string()), Value::String("ann".to_string())]).unwrap_err();
+        assert!(err.to_string().contains("not the primary key or a unique column"), "{err}");
+
+        let docs = store.captured_documents();
+        let items: Vec<_> = docs.iter().filter(|d| d.doc["_table"] == "orders").collect();
+        assert_eq!(items.len(), 1);
+        let schema = docs.iter().find(|d| d.doc["doc_type"] == "schema_column" && d.doc["column"] == "user_id").unwrap();
+        assert_eq!(schema.doc["references"], serde_json::json!({"table": "users", "column": "id"}));
+    }
+
+    #[test]
+    fn test_int_values_are_accepted_for_float_columns() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "ratios".to_string(),
+            columns: vec![Column::new("ratio", DataType::Float64)],
+            global: false,
+        }).unwrap();
+
+        assert!(store.insert_row("ratios", "uuid-1", &[Value::Int64(3)]).unwrap());
+        assert!(store.insert_row("ratios", "uuid-1", &[Value::String("3".to_string())]).is_err());
+
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].doc["ratio"], "3");
+    }
+
+    #[test]
+    fn test_non_finite_floats_are_stored_by_name() {
+        // Guests write them as strings, since JSON has no NaN or Infinity
+        let guest_json = serde_json::to_string(&wadup_guest::Value::Float64(f64::NAN)).unwrap();
+        assert_eq!(guest_json, r#"{"Float64":"NaN"}"#);
+        let values: Vec<Value> = serde_json::from_str(r#"[{"Float64":"NaN"},{"Float64":"Infinity"},{"Float64":"-Infinity"},{"Float64":0.5}]"#).unwrap();
+        assert!(matches!(values[..], [Value::Float64(nan), Value::Float64(inf), Value::Float64(neg), Value::Float64(half)]
+            if nan.is_nan() && inf == f64::INFINITY && neg == f64::NEG_INFINITY && half == 0.5));
+        assert!(serde_json::from_str::<Value>(r#"{"Float64":"inf"}"#).is_err());
+
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "ratios".to_string(),
+            columns: vec![Column::new("ratio", DataType::Float64)],
+            global: false,
+        }).unwrap();
+        for value in values {
+            assert!(store.insert_row("ratios", "uuid-1", &[value]).unwrap());
         }
+
+        let stored: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .map(|d| d.doc["ratio"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(stored, ["NaN", "Infinity", "-Infinity", "0.5"]);
+    }
+
+    #[test]
+    fn test_table_prefix_namespaces_tables() {
+        let mut store = MetadataStore::new_in_memory("wadup");
+        assert!(store.set_table_prefix(Some("1st")).is_err());
+        assert!(store.set_table_prefix(Some("a-b")).is_err());
+        assert!(store.set_table_prefix(Some("")).is_err());
+        store.set_table_prefix(Some("pipeline_a")).unwrap();
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "strings".to_string(),
+            columns: vec![Column::new("value", DataType::String)],
+            global: false,
+        }).unwrap();
+
+        assert!(store.insert_row("strings", "uuid-1", &[Value::String("hello".to_string())]).unwrap());
+        let schemas = store.table_schemas.lock().unwrap();
+        assert!(schemas.contains_key("pipeline_a_strings"));
+        assert!(!schemas.contains_key("strings"));
+        drop(schemas);
+
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].doc["_table"], "pipeline_a_strings");
+        assert_eq!(rows[0].doc["_table_prefix"], "pipeline_a");
+    }
+
+    #[test]
+    fn test_concurrent_define_of_one_table() {
+        let store = MetadataStore::new_in_memory("wadup");
+        let threads: Vec<_> = (0..16)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        store.define_table(&format!("module_{}", i), TableSchema {
+                            name: "shared".to_string(),
+                            columns: vec![Column::new("n", DataType::Int64)],
+                            global: false,
+                        })?;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+
+        let schemas = store.table_schemas.lock().unwrap();
+        assert_eq!(schemas.len(), 1);
+        assert!(schemas["shared"].conflicting_modules.is_empty());
+    }
+
+    #[test]
+    fn test_insert_wrong_value_count() {
+        let store = store_with_table();
+        let err = store.insert_row("entries", "uuid-1", &[Value::String("a".to_string())])
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("'entries'"), "{}", err);
+        assert!(err.contains("row has 1 values, expected 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_json_column_is_indexed_as_object() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        store.define_table("module", TableSchema {
+            name: "archives".to_string(),
+            columns: vec![
+                Column::new("name", DataType::String),
+                Column::new("summary", DataType::Json),
+            ],
+            global: false,
+        }).unwrap();
+
+        store.insert_row("archives", "uuid-1", &[
+            Value::String("a.zip".to_string()),
+            Value::String(r#"{"entries":3,"names":["x","y"]}"#.to_string()),
+        ]).unwrap();
+        let err = store.insert_row("archives", "uuid-1", &[
+            Value::String("b.zip".to_string()),
+            Value::String("{not json".to_string()),
+        ]).unwrap_err().to_string();
+        assert!(err.contains("column 'summary' expects Json, got invalid JSON"), "{}", err);
+
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .collect();
+        assert_eq!(rows.len(), 1);
+        // Path lookups work on the stored document, like json_extract would
+        let summary = &rows[0].doc["_json"]["archives"]["summary"];
+        assert_eq!(summary["entries"], 3);
+        assert_eq!(summary["names"][1], "y");
+        // The flattened column keeps the text, so it can't clash with a String column
+        assert_eq!(rows[0].doc["summary"], r#"{"entries":3,"names":["x","y"]}"#);
+        assert_eq!(rows[0].doc["name"], "a.zip");
+    }
+
+    #[test]
+    fn test_duplicate_key_rows_are_ignored() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "module", None).unwrap();
+        let mut sha256 = Column::new("sha256", DataType::String);
+        sha256.primary_key = true;
+        let mut path = Column::new("path", DataType::String);
+        path.unique = true;
+        store.define_table("module", TableSchema {
+            name: "hashes".to_string(),
+            columns: vec![sha256, path, Column::new("size", DataType::Int64)],
+            global: false,
+        }).unwrap();
+
+        let row = |hash: &str, path: &str| [Value::String(hash.to_string()), Value::String(path.to_string()), Value::Int64(1)];
+        assert!(store.insert_row("hashes", "uuid-1", &row("aa", "a.txt")).unwrap());
+        assert!(!store.insert_row("hashes", "uuid-1", &row("aa", "other.txt")).unwrap(), "repeated primary key");
+        assert!(!store.insert_row("hashes", "uuid-1", &row("bb", "a.txt")).unwrap(), "repeated unique column");
+        assert!(store.insert_row("hashes", "uuid-1", &row("bb", "b.txt")).unwrap());
+
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].doc["path"], "a.txt");
+        assert!(rows[0].id.as_deref().is_some_and(|id| id.starts_with("hashes_")));
+
+        // A key already in the index (e.g. from an earlier run) is ignored too
+        let rerun = MetadataStore {
+            seen_keys: Default::default(),
+            ..store.clone()
+        };
+        assert!(!rerun.insert_row("hashes", "uuid-1", &row("aa", "c.txt")).unwrap());
+        assert!(rerun.insert_row("hashes", "uuid-1", &row("cc", "c.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_content_timestamps_have_millisecond_resolution() {
+        let store = MetadataStore::new_dummy();
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let (doc, _) = store.take_content_doc("uuid-1", "success", None).unwrap();
+
+        assert!(doc.finished_at_ms >= doc.started_at_ms);
+        assert!(doc.finished_at_ms - doc.started_at_ms >= 5);
+        // Seconds since the epoch would be around 1.7e9; milliseconds around 1.7e12
+        assert!(doc.started_at_ms > 1_000_000_000_000);
+    }
+
+    #[test]
+    fn test_schema_meta_stamped_and_checked() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.init_schema_meta(false).unwrap();
+        store.init_schema_meta(false).unwrap();
+
+        let stamps: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.id.as_deref() == Some(META_DOC_ID))
+            .collect();
+        assert_eq!(stamps.len(), 1, "a matching stamp is not rewritten");
+        assert_eq!(stamps[0].doc["schema_version"], SCHEMA_VERSION);
+        assert_eq!(stamps[0].doc["wadup_version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_schema_meta_from_older_version_is_refused() {
+        let store = MetadataStore::new_in_memory("wadup");
+        let old = MetaDoc {
+            doc_type: "meta".to_string(),
+            schema_version: 0,
+            wadup_version: "0.0.1".to_string(),
+            stamped_at: Utc::now(),
+        };
+        store.post_document_with_id("wadup", &old, META_DOC_ID).unwrap();
+
+        let err = store.init_schema_meta(false).unwrap_err().to_string();
+        assert!(err.contains("schema version 0 (wadup 0.0.1)"), "{}", err);
+        assert!(err.contains("--migrate"), "{}", err);
+
+        store.init_schema_meta(true).unwrap();
+        store.init_schema_meta(false).unwrap();
+    }
+
+    #[test]
+    fn test_unstamped_index_with_documents_needs_migrate() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.finalize_content_success("uuid-1").unwrap();
+
+        let err = store.init_schema_meta(false).unwrap_err().to_string();
+        assert!(err.contains("no schema stamp"), "{}", err);
+        assert!(err.contains("--migrate"), "{}", err);
+
+        store.init_schema_meta(true).unwrap();
+        store.init_schema_meta(false).unwrap();
+    }
+
+    #[test]
+    fn test_per_module_output_uses_separate_indices() {
+        let mut store = MetadataStore::new_in_memory("wadup");
+        store.set_output_mode(OutputMode::PerModule);
+
+        for (module, table) in [("Alpha", "alpha_rows"), ("beta", "beta_rows")] {
+            store.define_table(module, TableSchema {
+                name: table.to_string(),
+                columns: vec![Column::new("n", DataType::Int64)],
+                global: false,
+            }).unwrap();
+            store.start_content(module, "file.bin", None).unwrap();
+            store.set_current_module(module, module, None).unwrap();
+            store.insert_row(table, module, &[Value::Int64(1)]).unwrap();
+            store.record_content_edge(module, module, "child", "member").unwrap();
+            store.finalize_content_success(module).unwrap();
+        }
+
+        let docs = store.captured_documents();
+        let tables_in = |index: &str| -> Vec<String> {
+            docs.iter()
+                .filter(|d| d.index == index && d.doc["doc_type"] == "row")
+                .map(|d| d.doc["_table"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(tables_in("wadup-alpha"), vec!["alpha_rows"]);
+        assert_eq!(tables_in("wadup-beta"), vec!["beta_rows"]);
+        assert_eq!(docs.iter().filter(|d| d.index == "wadup-alpha" && d.doc["doc_type"] == "edge").count(), 1);
+
+        // Each module index has its own content document
+        for index in ["wadup-alpha", "wadup-beta"] {
+            assert_eq!(docs.iter().filter(|d| d.index == index && d.doc["doc_type"] == "content").count(), 1);
+        }
+        assert!(docs.iter().all(|d| d.index != "wadup"));
+    }
+
+    #[test]
+    fn test_verify_flags_orphan_rows_and_dangling_parents() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.define_table("module", TableSchema {
+            name: "entries".to_string(),
+            columns: vec![Column::new("n", DataType::Int64)],
+            global: false,
+        }).unwrap();
+        store.start_content("root", "root.bin", None).unwrap();
+        store.set_current_module("root", "module", None).unwrap();
+        store.insert_row("entries", "root", &[Value::Int64(1)]).unwrap();
+        store.finalize_content_success("root").unwrap();
+        store.start_content("child", "child.bin", Some("root")).unwrap();
+        store.finalize_content_success("child").unwrap();
+        assert!(store.verify().unwrap().is_ok());
+
+        // Rows for a content that never finished, and a content whose parent is gone
+        store.start_content("lost", "lost.bin", None).unwrap();
+        store.set_current_module("lost", "module", None).unwrap();
+        store.insert_row("entries", "lost", &[Value::Int64(2)]).unwrap();
+        store.insert_row("entries", "lost", &[Value::Int64(3)]).unwrap();
+        store.start_content("stray", "stray.bin", Some("missing")).unwrap();
+        store.finalize_content_success("stray").unwrap();
+
+        let report = store.verify().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!((report.contents, report.rows), (3, 3));
+        assert_eq!(report.orphan_rows, [OrphanRows { table: "entries".to_string(), content_uuid: "lost".to_string(), count: 2 }]);
+        assert_eq!(report.dangling_parents, [DanglingParent {
+            content_uuid: "stray".to_string(),
+            filename: "stray.bin".to_string(),
+            parent_uuid: "missing".to_string(),
+        }]);
+        assert!(report.render().contains("2 row(s) in entries reference missing content lost"));
+    }
+
+    #[test]
+    fn test_shared_output_uses_one_index() {
+        let store = MetadataStore::new_in_memory("wadup");
+        store.start_content("uuid-1", "file.bin", None).unwrap();
+        store.set_current_module("uuid-1", "alpha", None).unwrap();
+        store.finalize_content_success("uuid-1").unwrap();
+
+        let docs = store.captured_documents();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].index, "wadup");
+        assert_eq!(docs[0].id.as_deref(), Some("uuid-1"));
+    }
+
+    #[test]
+    fn test_define_table_rejects_duplicate_columns() {
+        let store = MetadataStore::new_dummy();
+        let err = store.define_table("module", TableSchema {
+            name: "dups".to_string(),
+            columns: vec![
+                Column::new("a", DataType::Int64),
+                Column::new("a", DataType::String),
+            ],
+            global: false,
+        }).unwrap_err().to_string();
+
+        assert!(err.contains("'dups'"), "{}", err);
+        assert!(err.contains("duplicate column 'a'"), "{}", err);
     }
 }
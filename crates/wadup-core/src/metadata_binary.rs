@@ -0,0 +1,173 @@
+//! Compact binary metadata format for `/metadata/*.bin` files.
+//!
+//! An alternative to the JSON formats for modules emitting many rows: values
+//! are packed instead of printed, and rows name their table by a small id.
+//! All integers are little-endian.
+//!
+//! ```text
+//! file   := "WDM\x01" record*
+//! record := 0x01 table | 0x02 row
+//! table  := str name, u32 columns_len, columns_len bytes
+//! row    := u32 table_id, u16 value_count, value*
+//! value  := 0x00 i64 | 0x01 f64 | 0x02 str | 0x03 u8 (boolean)
+//!         | 0x04 i64 (timestamp, milliseconds since the Unix epoch)
+//! str    := u32 len, len bytes of UTF-8
+//! ```
+//!
+//! Table records get ids 0, 1, 2, ... in file order. `columns` is the JSON
+//! column array used by the JSON formats; an empty one only assigns the id,
+//! for rows of a table defined in an earlier file.
+//!
+//! The tag constants live in `wadup_abi::binary_format`, which the guest's
+//! `BinaryMetadataWriter` uses too, so both sides agree on them.
+
+use anyhow::{Context, Result};
+use wadup_abi::binary_format::{
+    RECORD_ROW, RECORD_TABLE, VALUE_BOOLEAN, VALUE_FLOAT64, VALUE_INT64, VALUE_STRING, VALUE_TIMESTAMP,
+};
+
+use crate::bindings_context::MetadataRow;
+use crate::bindings_types::{Column, TableSchema, Value};
+
+pub use wadup_abi::binary_format::MAGIC;
+
+/// Decode a binary metadata file into its table definitions and rows
+pub fn decode(data: &[u8]) -> Result<(Vec<TableSchema>, Vec<MetadataRow>)> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        anyhow::bail!("Binary metadata does not start with the WDM\\x01 magic");
+    }
+
+    let mut table_names = Vec::new();
+    let mut tables = Vec::new();
+    let mut rows = Vec::new();
+    while !reader.is_empty() {
+        let offset = reader.pos;
+        match reader.u8()? {
+            RECORD_TABLE => {
+                let name = reader.string()?;
+                let columns_len = reader.u32()? as usize;
+                let columns = reader.take(columns_len)?;
+                if !columns.is_empty() {
+                    let columns: Vec<Column> = serde_json::from_slice(columns)
+                        .with_context(|| format!("Invalid columns for table '{}' at byte {}", name, offset))?;
+                    tables.push(TableSchema { name: name.clone(), columns, global: false });
+                }
+                table_names.push(name);
+            }
+            RECORD_ROW => {
+                let table_id = reader.u32()? as usize;
+                let table_name = table_names.get(table_id).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("Row at byte {} uses undeclared table id {}", offset, table_id)
+                })?;
+                let count = reader.u16()? as usize;
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    values.push(reader.value()?);
+                }
+                rows.push(MetadataRow { table_name, values, global: false });
+            }
+            tag => anyhow::bail!("Unknown record tag 0x{:02x} at byte {}", tag, offset),
+        }
+    }
+
+    Ok((tables, rows))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len())
+            .ok_or_else(|| anyhow::anyhow!("Binary metadata truncated at byte {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("take returns N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let offset = self.pos;
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| anyhow::anyhow!("String at byte {} is not valid UTF-8", offset))
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let offset = self.pos;
+        Ok(match self.u8()? {
+            VALUE_INT64 => Value::Int64(i64::from_le_bytes(self.array()?)),
+            VALUE_FLOAT64 => Value::Float64(f64::from_le_bytes(self.array()?)),
+            VALUE_STRING => Value::String(self.string()?),
+            VALUE_BOOLEAN => Value::Boolean(self.u8()? != 0),
+            VALUE_TIMESTAMP => Value::Timestamp(i64::from_le_bytes(self.array()?)),
+            tag => anyhow::bail!("Unknown value tag 0x{:02x} at byte {}", tag, offset),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wadup_guest::{BinaryMetadataWriter, DataType as GuestType, Value as GuestValue};
+
+    #[test]
+    fn test_decode_tables_and_rows() {
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.define_table("files", vec![("path", GuestType::String), ("size", GuestType::Int64)]).unwrap();
+        writer.insert("files", &[GuestValue::String("a.txt".to_string()), GuestValue::Int64(-3)]).unwrap();
+        writer.insert("earlier", &[GuestValue::Float64(0.5)]).unwrap();
+        writer.insert("earlier", &[GuestValue::Boolean(true)]).unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let (tables, rows) = decode(&data).unwrap();
+        assert_eq!(tables.len(), 1, "a table record without columns only assigns an id");
+        assert_eq!(tables[0].name, "files");
+        assert_eq!(tables[0].columns[1], Column::new("size", crate::bindings_types::DataType::Int64));
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1].table_name, "earlier");
+        assert!(matches!(rows[0].values[..], [Value::String(ref s), Value::Int64(-3)] if s == "a.txt"));
+        assert!(matches!(rows[1].values[..], [Value::Float64(f)] if f == 0.5));
+        assert!(matches!(rows[2].values[..], [Value::Boolean(true)]));
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_input() {
+        assert!(decode(b"{}").is_err());
+
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.insert("t", &[GuestValue::String("abc".to_string())]).unwrap();
+        let data = writer.into_inner().unwrap();
+        let truncated = &data[..data.len() - 1];
+        assert!(decode(truncated).err().unwrap().to_string().contains("truncated"));
+
+        let mut bad_id = MAGIC.to_vec();
+        bad_id.push(RECORD_ROW);
+        bad_id.extend_from_slice(&7u32.to_le_bytes());
+        bad_id.extend_from_slice(&0u16.to_le_bytes());
+        assert!(decode(&bad_id).err().unwrap().to_string().contains("undeclared table id 7"));
+    }
+}
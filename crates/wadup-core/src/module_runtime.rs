@@ -0,0 +1,149 @@
+//! What a module was written in and how it expects to be run, recorded for
+//! reporting on mixed module sets.
+//!
+//! The language comes from the standard `producers` custom section that
+//! rustc, clang and other toolchains write; the runtime from the module's
+//! WASI entry exports. A module's manifest can declare either instead.
+
+use serde::Serialize;
+use wasmtime::Module;
+
+/// Runtime of WASI reactors, which export `_initialize`
+pub const REACTOR: &str = "reactor";
+/// Runtime of modules exporting `_start`
+pub const COMMAND: &str = "command";
+/// Runtime of modules with neither WASI entry export, only their entrypoint
+pub const CORE: &str = "core";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModuleRuntime {
+    /// Source language, lowercase (e.g. `rust`), when the module records it
+    pub language: Option<String>,
+    /// `reactor`, `command`, `core`, or the runtime the manifest declares
+    pub runtime: String,
+}
+
+impl ModuleRuntime {
+    /// Detect the runtime of `module`, compiled from the binary `wasm`.
+    pub fn detect(module: &Module, wasm: &[u8]) -> Self {
+        let exports = |name: &str| module.exports().any(|export| export.name() == name);
+        let runtime = if exports("_initialize") {
+            REACTOR
+        } else if exports("_start") {
+            COMMAND
+        } else {
+            CORE
+        };
+        Self { language: producers_language(wasm), runtime: runtime.to_string() }
+    }
+
+    /// Replace detected values with the ones `manifest` declares
+    pub fn with_manifest(mut self, manifest: &crate::manifest::ModuleManifest) -> Self {
+        if let Some(language) = &manifest.language {
+            self.language = Some(language.clone());
+        }
+        if let Some(runtime) = &manifest.runtime {
+            self.runtime = runtime.clone();
+        }
+        self
+    }
+}
+
+/// Language named in the `producers` section of `wasm`, falling back to
+/// the compiler that processed it. None for text modules or when the
+/// section is missing or malformed.
+fn producers_language(wasm: &[u8]) -> Option<String> {
+    let mut reader = Reader(wasm.strip_prefix(b"\0asm\x01\0\0\0")?);
+    while !reader.0.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.leb()?;
+        let mut section = Reader(reader.bytes(len)?);
+        if id != 0 || section.string()? != "producers" {
+            continue;
+        }
+        let mut processed_by = None;
+        for _ in 0..section.leb()? {
+            let field = section.string()?;
+            let values = (0..section.leb()?)
+                .map(|_| Some((section.string()?, section.string()?)).map(|(name, _version)| name))
+                .collect::<Option<Vec<_>>>()?;
+            match field {
+                "language" => return values.first().map(|name| name.to_lowercase()),
+                "processed-by" => processed_by = values.iter().find_map(|tool| compiler_language(tool)),
+                _ => {}
+            }
+        }
+        return processed_by.map(String::from);
+    }
+    None
+}
+
+/// Language compiled by `tool`, for toolchains that don't write `language`
+fn compiler_language(tool: &str) -> Option<&'static str> {
+    match tool {
+        "rustc" => Some("rust"),
+        "clang" => Some("c"),
+        "TinyGo" | "tinygo" => Some("go"),
+        _ => None,
+    }
+}
+
+/// Cursor over the bytes of a wasm binary
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.0.get(..len)?;
+        self.0 = &self.0[len..];
+        Some(bytes)
+    }
+
+    /// Unsigned LEB128, as used for every length in the binary format
+    fn leb(&mut self) -> Option<usize> {
+        let mut value = 0usize;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            value |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        let len = self.leb()?;
+        std::str::from_utf8(self.bytes(len)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Engine;
+
+    fn detect(wat: &str) -> ModuleRuntime {
+        let wasm = wat::parse_str(wat).unwrap();
+        ModuleRuntime::detect(&Module::new(&Engine::default(), &wasm).unwrap(), &wasm)
+    }
+
+    #[test]
+    fn test_detect_language_and_runtime() {
+        let rust = detect(r#"(module (@producers (language "Rust" "") (processed-by "rustc" "1.80.0"))
+            (func (export "process") (result i32) (i32.const 0)))"#);
+        assert_eq!(rust, ModuleRuntime { language: Some("rust".to_string()), runtime: CORE.to_string() });
+
+        let reactor = detect(r#"(module (@producers (processed-by "clang" "18.1.0"))
+            (func (export "_initialize")) (func (export "process") (result i32) (i32.const 0)))"#);
+        assert_eq!(reactor, ModuleRuntime { language: Some("c".to_string()), runtime: REACTOR.to_string() });
+
+        let bare = detect(r#"(module (func (export "_start")))"#);
+        assert_eq!(bare, ModuleRuntime { language: None, runtime: COMMAND.to_string() });
+    }
+}
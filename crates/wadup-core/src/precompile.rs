@@ -7,8 +7,17 @@
 //! Cache validity is determined by:
 //! - Engine compatibility hash (ensures same wasmtime config)
 //! - Source file modification time (detects source changes)
+//!
+//! The serialized module after the header can be gzip-compressed
+//! (`--compress-cache`); the header records which, so either kind loads.
+//!
+//! [`ModuleCache`] additionally keeps recently loaded modules in memory so
+//! reloading an unchanged module skips the disk cache entirely.
 
 use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::VecDeque;
 use std::fs::{self, File};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{Read, Write};
@@ -44,10 +53,16 @@ pub fn get_file_mtime(path: &Path) -> Result<u64> {
     Ok(duration.as_secs())
 }
 
+/// Bit of the header's mtime word set when the serialized module is
+/// gzip-compressed. Mtimes in seconds never reach it, and cache files
+/// written before compression was supported have it clear.
+const COMPRESSED: u64 = 1 << 63;
+
 /// Header stored at the beginning of cache files.
 struct CacheHeader {
     engine_hash: u64,
     mtime: u64,
+    compressed: bool,
 }
 
 /// Read the header from a cache file.
@@ -66,7 +81,7 @@ fn read_cache_header(cache_path: &Path) -> Result<Option<CacheHeader>> {
     let engine_hash = u64::from_le_bytes(header[0..8].try_into().unwrap());
     let mtime = u64::from_le_bytes(header[8..16].try_into().unwrap());
 
-    Ok(Some(CacheHeader { engine_hash, mtime }))
+    Ok(Some(CacheHeader { engine_hash, mtime: mtime & !COMPRESSED, compressed: mtime & COMPRESSED != 0 }))
 }
 
 /// Check if a cache file is valid for the current engine and source file.
@@ -79,23 +94,51 @@ pub fn is_cache_valid(cache_path: &Path, current_engine_hash: u64, current_mtime
     }
 }
 
-/// Write a precompiled module to the cache.
+/// Write a precompiled module to the cache, gzip-compressing it after the
+/// header if `compress` is set.
+///
+/// The cache is written to a uniquely named temporary file in the same
+/// directory and renamed into place, so concurrent readers and writers
+/// never see a partially written cache file.
 fn write_precompiled_cache(
     cache_path: &Path,
     engine_hash: u64,
     mtime: u64,
     serialized_module: &[u8],
+    compress: bool,
 ) -> Result<()> {
-    let mut file = File::create(cache_path)?;
+    let file_name = cache_path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("cache");
+    let tmp_path = cache_path.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+
+    let result = (|| -> Result<()> {
+        let mut file = File::create(&tmp_path)?;
 
-    // Write header
-    file.write_all(&engine_hash.to_le_bytes())?;
-    file.write_all(&mtime.to_le_bytes())?;
+        // Write header
+        file.write_all(&engine_hash.to_le_bytes())?;
+        let flags = if compress { COMPRESSED } else { 0 };
+        file.write_all(&(mtime | flags).to_le_bytes())?;
 
-    // Write serialized module
-    file.write_all(serialized_module)?;
+        // Write serialized module
+        if compress {
+            let mut encoder = GzEncoder::new(&mut file, flate2::Compression::default());
+            encoder.write_all(serialized_module)?;
+            encoder.finish()?;
+        } else {
+            file.write_all(serialized_module)?;
+        }
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, cache_path)?;
+        Ok(())
+    })();
 
-    Ok(())
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
 }
 
 /// Load a WASM module, using cache if available and valid.
@@ -103,22 +146,30 @@ fn write_precompiled_cache(
 /// If the cache is valid, deserializes the precompiled module.
 /// If the cache is invalid or missing, compiles from source and writes cache.
 pub fn load_module_with_cache(engine: &Engine, wasm_path: &Path) -> Result<Module> {
+    load_module_from_disk(engine, wasm_path, false).map(|(module, _)| module)
+}
+
+/// [`load_module_with_cache`], also returning whether the module came from
+/// the precompiled cache rather than being compiled. With `compress`, caches
+/// are written compressed, and an uncompressed cache that loads is rewritten
+/// compressed.
+fn load_module_from_disk(engine: &Engine, wasm_path: &Path, compress: bool) -> Result<(Module, bool)> {
     let cache_path = get_cache_path(wasm_path);
     let engine_hash = compute_engine_hash(engine);
     let current_mtime = get_file_mtime(wasm_path)?;
 
     // Try loading from cache
-    if is_cache_valid(&cache_path, engine_hash, current_mtime) {
-        tracing::debug!("Loading precompiled module from cache: {:?}", cache_path);
-
-        let cache_data = fs::read(&cache_path)?;
-        if cache_data.len() > 16 {
-            let serialized_data = &cache_data[16..]; // Skip header
-
-            // SAFETY: We only deserialize data we serialized ourselves.
-            // Cache validity is checked via engine hash and mtime.
-            match unsafe { Module::deserialize(engine, serialized_data) } {
-                Ok(module) => return Ok(module),
+    if let Ok(Some(header)) = read_cache_header(&cache_path) {
+        if header.engine_hash == engine_hash && header.mtime == current_mtime {
+            tracing::debug!("Loading precompiled module from cache: {:?}", cache_path);
+            match read_cached_module(engine, &cache_path, header.compressed) {
+                Ok(Some(module)) => {
+                    if compress && !header.compressed {
+                        write_cache(&cache_path, engine_hash, current_mtime, &module, true);
+                    }
+                    return Ok((module, true));
+                }
+                Ok(None) => {}
                 Err(e) => {
                     tracing::warn!("Failed to deserialize cached module: {}", e);
                     // Fall through to recompile
@@ -131,12 +182,37 @@ pub fn load_module_with_cache(engine: &Engine, wasm_path: &Path) -> Result<Modul
     tracing::debug!("Compiling module from source: {:?}", wasm_path);
     let module = Module::from_file(engine, wasm_path)?;
 
-    // Write to cache
+    write_cache(&cache_path, engine_hash, current_mtime, &module, compress);
+    Ok((module, false))
+}
+
+/// Deserialize the module in a valid cache file, or None if the file has
+/// nothing after its header
+fn read_cached_module(engine: &Engine, cache_path: &Path, compressed: bool) -> Result<Option<Module>> {
+    let cache_data = fs::read(cache_path)?;
+    if cache_data.len() <= 16 {
+        return Ok(None);
+    }
+    let body = &cache_data[16..]; // Skip header
+    let serialized_data = if compressed {
+        let mut serialized = Vec::new();
+        GzDecoder::new(body).read_to_end(&mut serialized)?;
+        std::borrow::Cow::Owned(serialized)
+    } else {
+        std::borrow::Cow::Borrowed(body)
+    };
+
+    // SAFETY: We only deserialize data we serialized ourselves.
+    // Cache validity is checked via engine hash and mtime.
+    unsafe { Module::deserialize(engine, &serialized_data) }.map(Some)
+}
+
+/// Serialize `module` into its cache file, logging rather than failing,
+/// since the cache only speeds up later loads
+fn write_cache(cache_path: &Path, engine_hash: u64, mtime: u64, module: &Module, compress: bool) {
     match module.serialize() {
         Ok(serialized) => {
-            if let Err(e) =
-                write_precompiled_cache(&cache_path, engine_hash, current_mtime, &serialized)
-            {
+            if let Err(e) = write_precompiled_cache(cache_path, engine_hash, mtime, &serialized, compress) {
                 tracing::warn!("Failed to write precompiled cache: {}", e);
             } else {
                 tracing::debug!("Wrote precompiled cache: {:?}", cache_path);
@@ -146,6 +222,270 @@ pub fn load_module_with_cache(engine: &Engine, wasm_path: &Path) -> Result<Modul
             tracing::warn!("Failed to serialize module: {}", e);
         }
     }
+}
 
-    Ok(module)
+/// Key identifying a compiled module in the in-memory cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ModuleCacheKey {
+    path: PathBuf,
+    mtime: u64,
+    engine_hash: u64,
+}
+
+/// Bounded in-memory cache of compiled modules.
+///
+/// Entries are keyed by source path, source mtime and engine hash, so a
+/// changed module or engine configuration misses. When full, the least
+/// recently used entry is evicted.
+pub struct ModuleCache {
+    capacity: usize,
+    /// Most recently used entry first
+    entries: VecDeque<(ModuleCacheKey, Module)>,
+    hits: u64,
+    misses: u64,
+    /// Misses served from a precompiled cache file, and compiled from source
+    precompiled_hits: u64,
+    compiled: u64,
+    /// Write precompiled cache files gzip-compressed
+    compress: bool,
+}
+
+impl ModuleCache {
+    /// Default number of modules kept in memory.
+    pub const DEFAULT_CAPACITY: usize = 32;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+            precompiled_hits: 0,
+            compiled: 0,
+            compress: false,
+        }
+    }
+
+    /// Write precompiled cache files gzip-compressed (`--compress-cache`).
+    /// Cache files load whether or not they are compressed.
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// Load a module, returning the in-memory copy if it is unchanged and
+    /// falling back to [`load_module_with_cache`] otherwise.
+    pub fn load(&mut self, engine: &Engine, wasm_path: &Path) -> Result<Module> {
+        let key = ModuleCacheKey {
+            path: fs::canonicalize(wasm_path).unwrap_or_else(|_| wasm_path.to_path_buf()),
+            mtime: get_file_mtime(wasm_path)?,
+            engine_hash: compute_engine_hash(engine),
+        };
+
+        if let Some(index) = self.entries.iter().position(|(k, _)| *k == key) {
+            self.hits += 1;
+            let entry = self.entries.remove(index).unwrap();
+            let module = entry.1.clone();
+            self.entries.push_front(entry);
+            tracing::debug!("Loaded module from memory cache: {:?}", wasm_path);
+            return Ok(module);
+        }
+
+        self.misses += 1;
+        let (module, precompiled) = load_module_from_disk(engine, wasm_path, self.compress)?;
+        if precompiled {
+            self.precompiled_hits += 1;
+        } else {
+            self.compiled += 1;
+        }
+
+        if self.capacity > 0 {
+            // Drop any stale entry for the same path before inserting
+            self.entries.retain(|(k, _)| k.path != key.path);
+            self.entries.truncate(self.capacity - 1);
+            self.entries.push_front((key, module.clone()));
+        }
+
+        Ok(module)
+    }
+
+    /// Number of loads served from memory.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of loads that went to disk.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Loads that went to disk and found a valid precompiled cache file.
+    pub fn precompiled_hits(&self) -> u64 {
+        self.precompiled_hits
+    }
+
+    /// Loads that went to disk and compiled the module from source.
+    pub fn compiled(&self) -> u64 {
+        self.compiled
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for ModuleCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &str = r#"(module (func (export "process") (result i32) (i32.const 0)))"#;
+
+    #[test]
+    fn test_concurrent_loads_leave_intact_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("shared.wasm");
+        fs::write(&path, MODULE)?;
+
+        let engine = Engine::default();
+        let barrier = std::sync::Barrier::new(4);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| {
+                    barrier.wait();
+                    load_module_with_cache(&engine, &path)
+                }))
+                .collect();
+            for handle in handles {
+                let module = handle.join().unwrap().unwrap();
+                assert!(module.get_export("process").is_some());
+            }
+        });
+
+        // The final cache is complete and loads without recompiling
+        let cache_path = get_cache_path(&path);
+        assert!(is_cache_valid(&cache_path, compute_engine_hash(&engine), get_file_mtime(&path)?));
+        let data = fs::read(&cache_path)?;
+        unsafe { Module::deserialize(&engine, &data[16..]) }?;
+
+        // No temporary files are left behind
+        let leftovers: Vec<_> = fs::read_dir(dir.path())?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_cache_is_recompiled() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("m.wasm");
+        fs::write(&path, MODULE)?;
+
+        let engine = Engine::default();
+        load_module_with_cache(&engine, &path)?;
+
+        // Simulate a torn write from an older version: valid header, truncated body
+        let cache_path = get_cache_path(&path);
+        let data = fs::read(&cache_path)?;
+        fs::write(&cache_path, &data[..data.len() / 2])?;
+
+        let module = load_module_with_cache(&engine, &path)?;
+        assert!(module.get_export("process").is_some());
+        assert_eq!(fs::read(&cache_path)?.len(), data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_cache_loads_on_the_next_run() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("m.wasm");
+        fs::write(&path, MODULE)?;
+        let engine = Engine::default();
+        let cache_path = get_cache_path(&path);
+
+        // An uncompressed cache is rewritten compressed once it loads
+        load_module_with_cache(&engine, &path)?;
+        let uncompressed = fs::read(&cache_path)?;
+        let mut cache = ModuleCache::new(0);
+        cache.set_compress(true);
+        cache.load(&engine, &path)?;
+        assert_eq!(cache.precompiled_hits(), 1);
+
+        let header = read_cache_header(&cache_path)?.unwrap();
+        assert!(header.compressed);
+        assert_eq!(header.mtime, get_file_mtime(&path)?);
+        assert!(is_cache_valid(&cache_path, compute_engine_hash(&engine), header.mtime));
+        let compressed = fs::read(&cache_path)?;
+        assert!(compressed.len() < uncompressed.len(), "{} >= {}", compressed.len(), uncompressed.len());
+
+        // Later loads deserialize it, with or without --compress-cache
+        for compress in [true, false] {
+            let mut cache = ModuleCache::new(0);
+            cache.set_compress(compress);
+            let module = cache.load(&engine, &path)?;
+            assert!(module.get_export("process").is_some());
+            assert_eq!((cache.precompiled_hits(), cache.compiled()), (1, 0));
+        }
+        assert_eq!(fs::read(&cache_path)?, compressed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged_module_hits_memory_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("m.wasm");
+        fs::write(&path, MODULE)?;
+
+        let engine = Engine::default();
+        let mut cache = ModuleCache::new(4);
+        cache.load(&engine, &path)?;
+        cache.load(&engine, &path)?;
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // A newer mtime misses and replaces the stale entry
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options().write(true).open(&path)?.set_modified(later)?;
+        cache.load(&engine, &path)?;
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_cache_evicts_least_recently_used() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let paths: Vec<PathBuf> = (0..3).map(|i| dir.path().join(format!("m{}.wasm", i))).collect();
+        for path in &paths {
+            fs::write(path, MODULE)?;
+        }
+
+        let engine = Engine::default();
+        let mut cache = ModuleCache::new(2);
+        cache.load(&engine, &paths[0])?;
+        cache.load(&engine, &paths[1])?;
+        cache.load(&engine, &paths[0])?; // m0 is now most recent
+        cache.load(&engine, &paths[2])?; // evicts m1
+        assert_eq!(cache.len(), 2);
+
+        let misses = cache.misses();
+        cache.load(&engine, &paths[0])?;
+        assert_eq!(cache.misses(), misses, "m0 should still be cached");
+        cache.load(&engine, &paths[1])?;
+        assert_eq!(cache.misses(), misses + 1, "m1 should have been evicted");
+        Ok(())
+    }
 }
@@ -1,15 +1,144 @@
 use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
-use crossbeam_deque::{Worker, Stealer, Steal};
+use std::time::{Duration, Instant};
+use crossbeam::utils::Backoff;
+use crossbeam_deque::{Injector, Worker, Stealer, Steal};
 use crate::content::{Content, ContentData, ContentStore};
-use crate::wasm::{WasmRuntime, ModuleInstance};
-use crate::metadata::MetadataStore;
-use crate::bindings_context::SubContentData;
+use crate::error::WadupError;
+use crate::wasm::{WasmRuntime, ModuleInstance, InstantiationError, SubcontentSink};
+use crate::metadata::{CachedResult, MetadataStore, SchemaConflict};
+use crate::bindings_context::{MetadataRow, ParentInfo, ProcessingContext, SubContentData, SubContentEmission};
+use crate::shared_buffer::SharedBuffer;
+use crate::bindings_types::TableSchema;
+use crate::manifest::Dispatch;
+use crate::module_runtime::ModuleRuntime;
+use crate::dispatch::DispatchTable;
+use crate::runner::{RunProfile, Sample};
+
+/// Sub-content beyond this many items in a worker's local queue goes to the
+/// shared injector, so one content that fans out widely is spread across
+/// every worker instead of waiting to be stolen from one.
+const LOCAL_QUEUE_LIMIT: usize = 16;
+
+/// Counts from a finished `ContentProcessor::process` run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessSummary {
+    /// Number of root contents the run started with
+    pub input_count: usize,
+    /// Contents (roots and sub-content) every module processed successfully
+    pub processed: usize,
+    /// Contents no module processed successfully
+    pub failed: usize,
+    /// Contents some modules processed and others failed on
+    pub partial: usize,
+    /// Zero-length contents no module was run on (`--skip-empty`)
+    pub skipped_empty: usize,
+    /// Sub-content emissions dropped because their parent was already at
+    /// the maximum recursion depth
+    pub depth_limited: usize,
+    /// Rows dropped because their table already had
+    /// `--max-rows-per-table-per-content` rows for the content
+    pub rows_truncated: usize,
+    /// Rows successfully inserted across all tables
+    pub total_rows: usize,
+    pub per_table_counts: BTreeMap<String, usize>,
+    pub duration_ms: u64,
+    /// One message per failed or partial content, as `filename (uuid): error`
+    pub errors: Vec<String>,
+    /// The run stopped early because the metadata store's output size limit
+    /// was reached
+    pub output_limit_reached: bool,
+    /// Sub-content was dropped because the run had already queued
+    /// `--max-total-content` sub-content items
+    pub content_limit_reached: bool,
+    /// The error that stopped the run early (e.g. a `--strict-schema`
+    /// conflict); the counts cover the content processed before it
+    pub fatal_error: Option<String>,
+    /// One message per module that failed to instantiate and was left out
+    /// of the run
+    pub instantiation_failures: Vec<String>,
+    /// Loaded modules in the order they run on each content
+    pub module_order: Vec<String>,
+    /// Source language and runtime of each loaded module, by name
+    pub module_runtimes: BTreeMap<String, ModuleRuntime>,
+    /// Number of content items each worker thread processed, by thread id
+    #[serde(skip)]
+    pub processed_per_worker: Vec<usize>,
+    /// Time spent in each module's entrypoint, summed over workers
+    #[serde(skip)]
+    pub module_time: BTreeMap<String, Duration>,
+    /// The run's `--label`s
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// With `--warn-empty-tables`, tables the module that defined them
+    /// stored no rows in, by module
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub empty_tables: BTreeMap<String, Vec<String>>,
+    /// Phase timings, with `--profile`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<RunProfile>,
+    /// How the root contents were sampled, with `--sample`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample: Option<Sample>,
+}
+
+impl ProcessSummary {
+    fn merge(&mut self, other: ProcessSummary) {
+        self.processed += other.processed;
+        self.failed += other.failed;
+        self.partial += other.partial;
+        self.skipped_empty += other.skipped_empty;
+        self.depth_limited += other.depth_limited;
+        self.rows_truncated += other.rows_truncated;
+        self.total_rows += other.total_rows;
+        for (table, count) in other.per_table_counts {
+            *self.per_table_counts.entry(table).or_default() += count;
+        }
+        for (module, time) in other.module_time {
+            *self.module_time.entry(module).or_default() += time;
+        }
+        self.errors.extend(other.errors);
+    }
+}
+
+/// What `ContentProcessor::process_one` produced for one content
+#[derive(Debug, Clone)]
+pub struct ContentResult {
+    pub outcome: ContentOutcome,
+    /// Rows inserted into the metadata store, in module order
+    pub rows: Vec<MetadataRow>,
+    /// Sub-content the modules emitted, with its own copy of its bytes so
+    /// it can be passed to `process_one` in turn. It isn't processed.
+    pub subcontent: Vec<Content>,
+    /// One message per module that failed
+    pub errors: Vec<String>,
+}
 
 pub struct ContentProcessor {
     runtime: WasmRuntime,
     metadata_store: MetadataStore,
     max_recursion_depth: usize,
+    strict_schema: bool,
+    strict_instantiation: bool,
+    slice_dedup: bool,
+    skip_empty: bool,
+    fail_fast: bool,
+    max_total_content: Option<usize>,
+    reuse_results: bool,
+    auto_create_tables: bool,
+    detect_encoding: bool,
+    eager_subcontent: bool,
+    intra_content_parallel: bool,
+    dispatch_table: Option<Arc<DispatchTable>>,
+    max_rows_per_table: Option<usize>,
+    warn_empty_tables: bool,
+    passthrough_output: bool,
+    /// Instances `process_one` reuses from one call to the next
+    one_shot_instances: Mutex<Vec<ModuleInstance>>,
 }
 
 impl ContentProcessor {
@@ -22,15 +151,244 @@ impl ContentProcessor {
             runtime,
             metadata_store,
             max_recursion_depth,
+            strict_schema: false,
+            strict_instantiation: false,
+            slice_dedup: true,
+            skip_empty: false,
+            fail_fast: false,
+            max_total_content: None,
+            reuse_results: false,
+            auto_create_tables: false,
+            detect_encoding: false,
+            eager_subcontent: false,
+            intra_content_parallel: false,
+            dispatch_table: None,
+            max_rows_per_table: None,
+            warn_empty_tables: false,
+            passthrough_output: false,
+            one_shot_instances: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stop the whole run at the first table schema conflict between modules,
+    /// instead of recording it and carrying on.
+    pub fn set_strict_schema(&mut self, strict: bool) {
+        self.strict_schema = strict;
+    }
+
+    /// Stop the run when any module fails to instantiate, instead of
+    /// recording the failure and running the other modules.
+    pub fn set_strict_instantiation(&mut self, strict: bool) {
+        self.strict_instantiation = strict;
+    }
+
+    /// Collapse sub-content slices that repeat an `(offset, length)` range
+    /// already emitted for the same content into one child (on by default).
+    pub fn set_slice_dedup(&mut self, dedup: bool) {
+        self.slice_dedup = dedup;
+    }
+
+    /// Record zero-length content as `skipped_empty` without running any
+    /// module on it, and drop zero-length sub-content emissions.
+    pub fn set_skip_empty(&mut self, skip: bool) {
+        self.skip_empty = skip;
+    }
+
+    /// Stop the run, with that content's error, as soon as any module fails
+    /// on any content. Content already being processed by other workers
+    /// finishes; queued content is left unprocessed.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Queue at most `max` sub-content items across the whole run, however
+    /// shallow. Emissions past the limit are dropped and the summary's
+    /// `content_limit_reached` is set.
+    pub fn set_max_total_content(&mut self, max: Option<usize>) {
+        self.max_total_content = max;
+    }
+
+    /// Cache each module's tables and rows by content SHA-256 (and module
+    /// version), and on content whose hash is already cached, insert the
+    /// cached rows for it instead of running the module. Results with
+    /// sub-content, links, streams or reported errors aren't cached.
+    pub fn set_reuse_results(&mut self, reuse: bool) {
+        self.reuse_results = reuse;
+    }
+
+    /// Define a table with columns `column_0`, `column_1`, ... typed after the
+    /// first row a module writes to it without defining it, instead of
+    /// skipping its rows and recording an `undefined_table` error.
+    pub fn set_auto_create_tables(&mut self, auto_create: bool) {
+        self.auto_create_tables = auto_create;
+    }
+
+    /// Guess each content's text encoding, recording it as `text_encoding`
+    /// on its content document and showing it to modules as
+    /// `WADUP_TEXT_ENCODING`.
+    pub fn set_detect_encoding(&mut self, detect: bool) {
+        self.detect_encoding = detect;
+    }
+
+    /// At the end of a run, warn about tables the module that defined them
+    /// stored no rows in, which usually means its data path is broken, and
+    /// list them in the summary's `empty_tables`.
+    pub fn set_warn_empty_tables(&mut self, warn: bool) {
+        self.warn_empty_tables = warn;
+    }
+
+    /// Also write what modules print to the host's stdout and stderr, each
+    /// line prefixed with `[module content-uuid]`. Either way it is recorded
+    /// in `module_output` documents; by default it goes nowhere else.
+    pub fn set_passthrough_output(&mut self, passthrough: bool) {
+        self.passthrough_output = passthrough;
+    }
+
+    /// Queue sub-content as soon as the module closes its file, so other
+    /// workers can process it while the parent's modules are still running,
+    /// instead of after they have all returned. Children queued this way are
+    /// kept even if the module emitting them fails afterwards.
+    pub fn set_eager_subcontent(&mut self, eager: bool) {
+        self.eager_subcontent = eager;
+    }
+
+    /// Run a content's modules at the same time, each on a thread of its
+    /// own, instead of one after the other. Their results are still applied
+    /// in module order, so the output is the same, but modules don't see
+    /// the streams earlier modules published.
+    pub fn set_intra_content_parallel(&mut self, parallel: bool) {
+        self.intra_content_parallel = parallel;
+    }
+
+    /// Run on each content only the modules `table` routes it to, on top of
+    /// their manifests. Fails if the table names a module that isn't loaded.
+    pub fn set_dispatch_table(&mut self, table: DispatchTable) -> Result<()> {
+        let loaded = self.runtime.module_names();
+        if let Some(unknown) = table.module_names().find(|name| !loaded.contains(name)) {
+            anyhow::bail!("Dispatch table names unknown module '{}' (loaded: {})", unknown, loaded.join(", "));
+        }
+        self.dispatch_table = Some(Arc::new(table));
+        Ok(())
+    }
+
+    /// Keep at most `max` rows of each table per content, across all
+    /// modules; further rows are dropped and counted in the content's
+    /// `rows_truncated`. None keeps every row.
+    pub fn set_max_rows_per_table(&mut self, max: Option<usize>) {
+        self.max_rows_per_table = max;
+    }
+
+    pub fn process(&self, initial_contents: Vec<Content>, num_threads: usize) -> Result<ProcessSummary, WadupError> {
+        let (summary, result) = self.process_with_summary(initial_contents, num_threads);
+        result.map(|()| summary)
+    }
+
+    /// Run every module on `content` alone, on the calling thread, and
+    /// return what they produced. Its documents are written to the metadata
+    /// store as in `process`, but its sub-content is returned instead of
+    /// processed. The module instances are created on the first call and
+    /// reused by later ones.
+    pub fn process_one(&self, content: Content) -> Result<ContentResult, WadupError> {
+        let mut instances = self.one_shot_instances.lock().unwrap_or_else(PoisonError::into_inner);
+        if instances.is_empty() {
+            let (created, failures) = self.runtime.try_create_instances(self.metadata_store.clone());
+            let mut summary = ProcessSummary::default();
+            for failure in failures {
+                if self.strict_instantiation {
+                    return Err(anyhow::Error::from(failure).into());
+                }
+                self.record_instantiation_failure(failure, &mut summary);
+            }
+            if created.is_empty() {
+                return Err(anyhow::anyhow!("No module could be instantiated").into());
+            }
+            *instances = created;
+        }
+
+        let mut worker_thread = WorkerThread {
+            id: 0,
+            worker: Worker::new_fifo(),
+            injector: Arc::new(Injector::new()),
+            stealers: Vec::new(),
+            pending: Arc::new(AtomicUsize::new(1)),
+            content_limit: Arc::new(ContentLimit::new(None)),
+            content_store: ContentStore::new(),
+            metadata_store: self.metadata_store.clone(),
+            max_recursion_depth: self.max_recursion_depth,
+            strict_schema: self.strict_schema,
+            slice_dedup: self.slice_dedup,
+            skip_empty: self.skip_empty,
+            fail_fast: false,
+            reuse_results: self.reuse_results,
+            auto_create_tables: self.auto_create_tables,
+            detect_encoding: self.detect_encoding,
+            eager_subcontent: self.eager_subcontent,
+            intra_content_parallel: self.intra_content_parallel,
+            dispatch_table: self.dispatch_table.clone(),
+            max_rows_per_table: self.max_rows_per_table,
+            passthrough_output: self.passthrough_output,
+            abort: Arc::new(AtomicBool::new(false)),
+            instances: std::mem::take(&mut *instances),
+            stats: ProcessSummary::default(),
+            collected: Some(Collected::default()),
+        };
+        let result = worker_thread.process_content(content);
+        *instances = std::mem::take(&mut worker_thread.instances);
+        let outcome = result?;
+
+        // Children queued locally or, when emitted eagerly or past the local
+        // queue limit, on the injector
+        let mut subcontent = Vec::new();
+        while let Some(child) = worker_thread.worker.pop().or_else(|| worker_thread.injector.steal().success()) {
+            match worker_thread.content_store.resolve(&child) {
+                Some(data) => subcontent.push(Content { data: ContentData::Owned(data), ..child }),
+                None => tracing::warn!("Sub-content data not found for '{}' (UUID: {})", child.filename, child.uuid),
+            }
+        }
+        let collected = worker_thread.collected.take().unwrap_or_default();
+        Ok(ContentResult { outcome, rows: collected.rows, subcontent, errors: collected.errors })
+    }
+
+    /// Like `process`, but also returns the counts when the run fails, so
+    /// callers can report how far it got.
+    pub fn process_with_summary(&self, initial_contents: Vec<Content>, num_threads: usize) -> (ProcessSummary, Result<(), WadupError>) {
+        let mut summary = ProcessSummary {
+            input_count: initial_contents.len(),
+            module_order: self.runtime.module_names().into_iter().map(String::from).collect(),
+            module_runtimes: self.runtime.module_runtimes(),
+            labels: self.metadata_store.labels().clone(),
+            ..ProcessSummary::default()
+        };
+        let started = Instant::now();
+        let result = self.run_workers(initial_contents, num_threads, &mut summary);
+        if let Err(e) = &result {
+            summary.fatal_error = Some(format!("{:#}", e));
+        }
+
+        summary.duration_ms = started.elapsed().as_millis() as u64;
+        if self.warn_empty_tables {
+            summary.empty_tables = self.metadata_store.empty_tables();
+            for (module, tables) in &summary.empty_tables {
+                tracing::warn!("Module '{}' defined tables it stored no rows in: {}", module, tables.join(", "));
+            }
         }
+        if self.metadata_store.output_limit_reached() {
+            tracing::warn!("Output size limit reached; stopped taking new content");
+            summary.output_limit_reached = true;
+        }
+        (summary, result.map_err(WadupError::from))
     }
 
-    pub fn process(&self, initial_contents: Vec<Content>, num_threads: usize) -> Result<()> {
+    fn run_workers(&self, initial_contents: Vec<Content>, num_threads: usize, summary: &mut ProcessSummary) -> Result<()> {
         tracing::info!("Starting processing with {} threads", num_threads);
         tracing::info!("Initial content count: {}", initial_contents.len());
         tracing::info!("Max recursion depth: {}", self.max_recursion_depth);
 
         let content_store = ContentStore::new();
+        let abort = Arc::new(AtomicBool::new(false));
+        let injector = Arc::new(Injector::new());
+        let pending = Arc::new(AtomicUsize::new(initial_contents.len()));
+        let content_limit = Arc::new(ContentLimit::new(self.max_total_content));
 
         // Store initial content data
         for content in &initial_contents {
@@ -49,11 +407,9 @@ impl ContentProcessor {
             workers.push(worker);
         }
 
-        // Add initial contents to first worker
-        if !workers.is_empty() {
-            for content in initial_contents {
-                workers[0].push(content);
-            }
+        // Initial contents are shared by every worker
+        for content in initial_contents {
+            injector.push(content);
         }
 
         // Spawn worker threads
@@ -69,19 +425,62 @@ impl ContentProcessor {
             let content_store = content_store.clone();
             let metadata_store = self.metadata_store.clone();
             let max_recursion_depth = self.max_recursion_depth;
+            let strict_schema = self.strict_schema;
+            let slice_dedup = self.slice_dedup;
+            let skip_empty = self.skip_empty;
+            let fail_fast = self.fail_fast;
+            let reuse_results = self.reuse_results;
+            let auto_create_tables = self.auto_create_tables;
+            let detect_encoding = self.detect_encoding;
+            let eager_subcontent = self.eager_subcontent;
+            let intra_content_parallel = self.intra_content_parallel;
+            let passthrough_output = self.passthrough_output;
+            let dispatch_table = self.dispatch_table.clone();
+            let max_rows_per_table = self.max_rows_per_table;
+            let abort = Arc::clone(&abort);
+            let injector = Arc::clone(&injector);
+            let pending = Arc::clone(&pending);
+            let content_limit = Arc::clone(&content_limit);
 
             // Create module instances for this thread
-            let instances = self.runtime.create_instances(metadata_store.clone())?;
+            let (instances, failures) = self.runtime.try_create_instances(metadata_store.clone());
+            for failure in failures {
+                if self.strict_instantiation {
+                    return Err(failure.into());
+                }
+                self.record_instantiation_failure(failure, summary);
+            }
+            if instances.is_empty() {
+                anyhow::bail!("No module could be instantiated");
+            }
 
-            let handle = thread::spawn(move || -> Result<()> {
+            let handle = thread::spawn(move || -> (ProcessSummary, Result<()>) {
                 let mut worker_thread = WorkerThread {
                     id: thread_id,
                     worker,
+                    injector,
                     stealers: thread_stealers,
+                    pending,
+                    content_limit,
                     content_store,
                     metadata_store,
                     max_recursion_depth,
+                    strict_schema,
+                    slice_dedup,
+                    skip_empty,
+                    fail_fast,
+                    reuse_results,
+                    auto_create_tables,
+                    detect_encoding,
+                    eager_subcontent,
+                    intra_content_parallel,
+                    passthrough_output,
+                    dispatch_table,
+                    max_rows_per_table,
+                    abort,
                     instances,
+                    stats: ProcessSummary::default(),
+                    collected: None,
                 };
 
                 worker_thread.run()
@@ -90,39 +489,295 @@ impl ContentProcessor {
             handles.push(handle);
         }
 
-        // Wait for all threads to complete
+        // Wait for every thread, keeping the counts of those that failed,
+        // and report the first failure once all have stopped
+        let mut first_error = None;
         for (i, handle) in handles.into_iter().enumerate() {
-            match handle.join() {
-                Ok(Ok(())) => {
-                    tracing::debug!("Worker thread {} completed successfully", i);
-                }
-                Ok(Err(e)) => {
+            let (stats, result) = match handle.join() {
+                Ok(joined) => joined,
+                Err(_) => (ProcessSummary::default(), Err(anyhow::anyhow!("Worker thread {} panicked", i))),
+            };
+            summary.processed_per_worker.push(stats.processed + stats.failed + stats.partial);
+            summary.merge(stats);
+            match result {
+                Ok(()) => tracing::debug!("Worker thread {} completed successfully", i),
+                Err(e) => {
                     tracing::error!("Worker thread {} failed: {}", i, e);
-                    return Err(e);
-                }
-                Err(_) => {
-                    anyhow::bail!("Worker thread {} panicked", i);
+                    first_error.get_or_insert(e);
                 }
             }
         }
 
+        summary.content_limit_reached = content_limit.reached.load(Ordering::SeqCst);
+
+        if let Some(e) = first_error {
+            return Err(e);
+        }
         tracing::info!("Processing complete");
         Ok(())
     }
+
+    /// Log and record a module left out of the run, once however many
+    /// workers failed to instantiate it
+    fn record_instantiation_failure(&self, failure: InstantiationError, summary: &mut ProcessSummary) {
+        let message = failure.to_string();
+        if summary.instantiation_failures.contains(&message) {
+            return;
+        }
+        tracing::error!("{}; continuing without it", message);
+        if let Err(e) = self.metadata_store.record_error(None, &failure.module, "instantiation_failed", &message) {
+            tracing::warn!("Failed to record instantiation error: {}", e);
+        }
+        summary.instantiation_failures.push(message);
+    }
+}
+
+/// Run-wide cap on queued sub-content (`--max-total-content`)
+struct ContentLimit {
+    max: Option<usize>,
+    queued: AtomicUsize,
+    reached: AtomicBool,
+}
+
+impl ContentLimit {
+    fn new(max: Option<usize>) -> Self {
+        Self { max, queued: AtomicUsize::new(0), reached: AtomicBool::new(false) }
+    }
+
+    /// Claim a slot for one more sub-content; false once the cap is used up
+    fn try_claim(&self) -> bool {
+        let Some(max) = self.max else {
+            return true;
+        };
+        let claimed = self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .is_ok();
+        if !claimed && !self.reached.swap(true, Ordering::SeqCst) {
+            tracing::warn!("Max total content reached (limit: {}); dropping further sub-content", max);
+        }
+        claimed
+    }
+}
+
+/// What turning an emission into queued sub-content needs, shared by the
+/// worker and, under `--eager-subcontent`, the sinks of its instances
+#[derive(Clone)]
+struct SubcontentQueue {
+    injector: Arc<Injector<Content>>,
+    pending: Arc<AtomicUsize>,
+    content_limit: Arc<ContentLimit>,
+    content_store: ContentStore,
+    metadata_store: MetadataStore,
+    max_recursion_depth: usize,
+    slice_dedup: bool,
+    skip_empty: bool,
+}
+
+impl SubcontentQueue {
+    /// Turn `emission` by `module_name` into a child of `parent`, counted in
+    /// `pending`, for the caller to push. None when it is skipped or dropped.
+    fn prepare(
+        &self,
+        parent: &Content,
+        parent_data: &SharedBuffer,
+        module_name: &str,
+        emission: &SubContentEmission,
+        seen_slices: &mut HashSet<(usize, usize)>,
+    ) -> Option<Content> {
+        let parent_uuid = parent.uuid.to_string();
+        let empty = match &emission.data {
+            SubContentData::Bytes(bytes) => bytes.is_empty(),
+            SubContentData::Slice { length, .. } => *length == 0,
+            SubContentData::Reference(target) => self.content_store.get(target).is_some_and(|b| b.is_empty()),
+        };
+        if self.skip_empty && empty {
+            tracing::debug!("Skipping empty sub-content '{}' of {}", emission.filename, parent.filename);
+            return None;
+        }
+        let data = match &emission.data {
+            SubContentData::Bytes(bytes) => {
+                // Zero-copy: SharedBuffer wraps the Bytes directly
+                let buffer = SharedBuffer::from_bytes(bytes.clone());
+                self.content_store.insert(emission.uuid, buffer.clone());
+                ContentData::Owned(buffer)
+            }
+            &SubContentData::Slice { offset, length } => {
+                // Register the slice so later emissions can reference it
+                if offset.checked_add(length).is_some_and(|end| end <= parent_data.len()) {
+                    self.content_store.insert(emission.uuid, parent_data.slice(offset..offset + length));
+                }
+                // The duplicate stays registered above, so references to it still resolve
+                if self.slice_dedup && !seen_slices.insert((offset, length)) {
+                    tracing::debug!(
+                        "Skipping sub-content '{}': repeats slice {}+{} of {}",
+                        emission.filename, offset, length, parent.filename
+                    );
+                    return None;
+                }
+                ContentData::Borrowed {
+                    parent_uuid: parent.uuid,
+                    offset,
+                    length,
+                }
+            }
+            &SubContentData::Reference(target) => {
+                if self.content_store.get(&target).is_none() {
+                    let message = format!(
+                        "Sub-content '{}' references unknown content {}",
+                        emission.filename, target
+                    );
+                    tracing::warn!("{}", message);
+                    if let Err(e) = self.metadata_store.record_error(
+                        Some(&parent_uuid),
+                        module_name,
+                        "dangling_reference",
+                        &message,
+                    ) {
+                        tracing::warn!("Failed to record dangling reference: {}", e);
+                    }
+                    return None;
+                }
+                ContentData::Reference(target)
+            }
+        };
+
+        match Content::new_subcontent(
+            parent,
+            emission.uuid,
+            data,
+            emission.filename.clone(),
+            module_name,
+            self.max_recursion_depth,
+        ) {
+            Ok(subcontent) => {
+                if !self.content_limit.try_claim() {
+                    tracing::debug!("Dropping sub-content '{}' of {}: max total content reached", subcontent.filename, parent.filename);
+                    return None;
+                }
+                if let Some(relation) = &emission.relation {
+                    if let Err(e) = self.metadata_store.record_content_edge(
+                        module_name,
+                        &parent_uuid,
+                        &subcontent.uuid.to_string(),
+                        relation,
+                    ) {
+                        tracing::warn!("Failed to record content edge '{}': {}", relation, e);
+                    }
+                }
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                Some(subcontent)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to create sub-content: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Sink queuing `module_name`'s children of `parent` on the shared
+    /// injector as they are emitted, so idle workers start on them while the
+    /// module is still running
+    fn sink(
+        &self,
+        parent: &Content,
+        parent_data: &SharedBuffer,
+        module_name: &str,
+        seen_slices: &Arc<Mutex<HashSet<(usize, usize)>>>,
+    ) -> SubcontentSink {
+        let queue = self.clone();
+        let parent = parent.clone();
+        let parent_data = parent_data.clone();
+        let module_name = module_name.to_string();
+        let seen_slices = Arc::clone(seen_slices);
+        Box::new(move |emission| {
+            let mut seen_slices = seen_slices.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(subcontent) = queue.prepare(&parent, &parent_data, &module_name, emission, &mut seen_slices) {
+                tracing::debug!("Enqueuing sub-content before its parent finished: {} (depth: {})", subcontent.filename, subcontent.depth);
+                queue.injector.push(subcontent);
+            }
+            true
+        })
+    }
 }
 
 struct WorkerThread {
     id: usize,
     worker: Worker<Content>,
+    /// Queue shared by all workers: initial contents and fan-out overflow
+    injector: Arc<Injector<Content>>,
     stealers: Vec<Stealer<Content>>,
+    /// Content queued or being processed anywhere; workers exit once it is zero
+    pending: Arc<AtomicUsize>,
+    content_limit: Arc<ContentLimit>,
     content_store: ContentStore,
     metadata_store: MetadataStore,
     max_recursion_depth: usize,
+    strict_schema: bool,
+    slice_dedup: bool,
+    skip_empty: bool,
+    fail_fast: bool,
+    reuse_results: bool,
+    auto_create_tables: bool,
+    detect_encoding: bool,
+    eager_subcontent: bool,
+    intra_content_parallel: bool,
+    dispatch_table: Option<Arc<DispatchTable>>,
+    max_rows_per_table: Option<usize>,
+    passthrough_output: bool,
+    /// Set when a worker hits a fatal error; every worker stops taking work
+    abort: Arc<AtomicBool>,
     instances: Vec<ModuleInstance>,
+    /// Outcome counts for the content this worker processed
+    stats: ProcessSummary,
+    /// What `process_content` produced, kept for `process_one`
+    collected: Option<Collected>,
+}
+
+/// Rows and module errors of one content, for `ContentResult`
+#[derive(Default)]
+struct Collected {
+    rows: Vec<MetadataRow>,
+    errors: Vec<String>,
+}
+
+/// The message a panic was raised with, when it is a string
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// A module's entrypoint result on one content and how long the call took
+type EntrypointRun = (Result<ProcessingContext, WadupError>, Duration);
+
+/// How one content's module runs went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentOutcome {
+    /// Every module that ran succeeded
+    Success,
+    /// Some modules succeeded and others failed
+    Partial,
+    /// No module succeeded
+    Failed,
+    /// Zero-length content under `--skip-empty`
+    SkippedEmpty,
 }
 
 impl WorkerThread {
-    fn run(&mut self) -> Result<()> {
+    fn subcontent_queue(&self) -> SubcontentQueue {
+        SubcontentQueue {
+            injector: Arc::clone(&self.injector),
+            pending: Arc::clone(&self.pending),
+            content_limit: Arc::clone(&self.content_limit),
+            content_store: self.content_store.clone(),
+            metadata_store: self.metadata_store.clone(),
+            max_recursion_depth: self.max_recursion_depth,
+            slice_dedup: self.slice_dedup,
+            skip_empty: self.skip_empty,
+        }
+    }
+
+    fn run(&mut self) -> (ProcessSummary, Result<()>) {
         let mut processed_count = 0;
 
         loop {
@@ -131,31 +786,87 @@ impl WorkerThread {
                 None => break,
             };
 
-            match self.process_content(content) {
-                Ok(()) => {
+            // Sub-content is counted as it is queued, so `pending` only
+            // reaches zero once nothing is left anywhere
+            let label = format!("{} ({})", content.filename, content.uuid);
+            // A panic stops the run, but `pending` must still drop or the
+            // other workers would wait for this content forever
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.process_content(content)))
+                .unwrap_or_else(|panic| {
+                    self.abort.store(true, Ordering::SeqCst);
+                    Err(anyhow::anyhow!("panicked: {}", panic_message(panic.as_ref())))
+                });
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+
+            match result {
+                Ok(outcome) => {
                     processed_count += 1;
+                    match outcome {
+                        ContentOutcome::Success => self.stats.processed += 1,
+                        ContentOutcome::Partial => self.stats.partial += 1,
+                        ContentOutcome::Failed => self.stats.failed += 1,
+                        ContentOutcome::SkippedEmpty => self.stats.skipped_empty += 1,
+                    }
+                    if self.fail_fast && matches!(outcome, ContentOutcome::Partial | ContentOutcome::Failed) {
+                        self.abort.store(true, Ordering::SeqCst);
+                        let error = self.stats.errors.last().cloned().unwrap_or(label);
+                        return (std::mem::take(&mut self.stats), Err(anyhow::anyhow!("Stopped at the first failed content: {}", error)));
+                    }
                 }
                 Err(e) => {
                     tracing::error!("Failed to process content: {}", e);
+                    self.stats.failed += 1;
+                    self.stats.errors.push(format!("{}: {}", label, e));
+                    if self.abort.load(Ordering::SeqCst) {
+                        return (std::mem::take(&mut self.stats), Err(e));
+                    }
                 }
             }
         }
 
         tracing::debug!("Worker {} processed {} items", self.id, processed_count);
-        Ok(())
+        (std::mem::take(&mut self.stats), Ok(()))
     }
 
+    /// Take the next content, waiting while other workers may still queue
+    /// more. Returns None once all work is done or the run is aborted.
     fn get_work(&self) -> Option<Content> {
-        // Try local queue first (LIFO for depth-first)
+        let backoff = Backoff::new();
+        loop {
+            if self.abort.load(Ordering::SeqCst) || self.metadata_store.output_limit_reached() {
+                return None;
+            }
+            if let Some(content) = self.find_work() {
+                return Some(content);
+            }
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            if backoff.is_completed() {
+                thread::sleep(Duration::from_millis(1));
+            } else {
+                backoff.snooze();
+            }
+        }
+    }
+
+    fn find_work(&self) -> Option<Content> {
+        // Try local queue first
         if let Some(content) = self.worker.pop() {
             return Some(content);
         }
 
-        // Try stealing from others (FIFO from their bottom)
+        // Then the shared queue, then stealing from others (FIFO from their bottom)
         loop {
             let mut retry = false;
             let mut found = None;
 
+            match self.injector.steal_batch_and_pop(&self.worker) {
+                Steal::Success(content) => return Some(content),
+                Steal::Empty => {}
+                Steal::Retry => retry = true,
+            }
+
             for stealer in &self.stealers {
                 match stealer.steal() {
                     Steal::Success(content) => {
@@ -181,7 +892,14 @@ impl WorkerThread {
         None
     }
 
-    fn process_content(&mut self, content: Content) -> Result<()> {
+    fn process_content(&mut self, content: Content) -> Result<ContentOutcome> {
+        // Every log line for this content, from any module, carries which content it is about
+        let _span = tracing::info_span!(
+            "content",
+            content_uuid = %content.uuid,
+            filename = %content.filename,
+            depth = content.depth,
+        ).entered();
         tracing::debug!(
             "Worker {} processing content: {} (depth: {})",
             self.id,
@@ -189,9 +907,11 @@ impl WorkerThread {
             content.depth
         );
 
-        // Resolve content data
-        let data = self.content_store.resolve(&content)
-            .ok_or_else(|| anyhow::anyhow!("Content data not found for UUID: {}", content.uuid))?;
+        // Resolve content data, so slices of a parent reach /data.bin as
+        // their own bytes
+        let data = self.content_store.resolve(&content).ok_or_else(|| {
+            anyhow::anyhow!("Content data not found for '{}' (UUID: {})", content.filename, content.uuid)
+        })?;
 
         // Store in content store if owned
         if let ContentData::Owned(ref owned_data) = content.data {
@@ -208,36 +928,259 @@ impl WorkerThread {
             &content.filename,
             parent_uuid_ref,
         )?;
+        self.metadata_store.set_content_lineage(&content_uuid_str, content.lineage());
+        if let Some(module) = &content.produced_by {
+            self.metadata_store.set_content_produced_by(&content_uuid_str, module);
+        }
+        if let Some(slice) = content.slice {
+            self.metadata_store.set_content_slice(&content_uuid_str, slice);
+        }
+
+        if self.skip_empty && data.is_empty() {
+            tracing::debug!("Skipping empty content: {}", content.filename);
+            self.metadata_store.finalize_content_skipped(&content_uuid_str, "skipped_empty")?;
+            return Ok(ContentOutcome::SkippedEmpty);
+        }
 
         let mut all_subcontent = Vec::new();
+        let mut all_links = Vec::new();
         let mut processing_errors = Vec::new();
+        let mut fatal_error = None;
+        let mut succeeded_modules = 0;
+        // Rows inserted and dropped per table, over all modules, for the
+        // per-table row limit
+        let mut table_rows: HashMap<String, usize> = HashMap::new();
+        let mut rows_truncated: BTreeMap<String, usize> = BTreeMap::new();
+
+        // Only sniff the content type if some module's manifest or the
+        // dispatch table filters on it
+        let content_type = if self.instances.iter().any(|i| i.manifest().needs_content_type())
+            || self.dispatch_table.as_ref().is_some_and(|table| table.needs_content_type())
+        {
+            crate::content_type::detect_content_type(data.as_slice())
+        } else {
+            None
+        };
+        let routed = self.dispatch_table.as_ref().and_then(|table| table.route(&content.filename, content_type));
+        let dispatch = |instance: &ModuleInstance| match &routed {
+            Some(routed) if !routed.contains(instance.name()) => {
+                Dispatch::Skip("not routed to it by the dispatch table".to_string())
+            }
+            _ => instance.manifest().dispatch(data.len() as u64, content_type, content.depth),
+        };
+
+        if self.detect_encoding {
+            let encoding = crate::content_type::detect_text_encoding(data.as_slice());
+            if let Some(encoding) = encoding {
+                self.metadata_store.set_content_text_encoding(&content_uuid_str, encoding);
+            }
+            for instance in &mut self.instances {
+                instance.set_text_encoding(encoding);
+            }
+        }
+
+        let parent = content.parent_uuid.zip(content.parent_filename.clone())
+            .map(|(uuid, filename)| ParentInfo { uuid, filename });
+        // Children of content below the depth limit are queued as they are
+        // emitted under `--eager-subcontent`
+        let eager_queue = (self.eager_subcontent && content.depth < self.max_recursion_depth)
+            .then(|| self.subcontent_queue());
+        let eager_seen_slices = Arc::new(Mutex::new(HashSet::new()));
+        // Streams published under /streams so far, mounted for later modules
+        let mut streams: Vec<(String, bytes::Bytes)> = Vec::new();
+        // Hashed once for every module: to look up reused results and to
+        // seed each module's `random_get`
+        let content_digest: Option<[u8; 32]> = (self.reuse_results || self.instances.iter().any(|i| i.is_seeded()))
+            .then(|| {
+                use sha2::{Digest, Sha256};
+                Sha256::digest(data.as_slice()).into()
+            });
+        let content_sha256 = content_digest.filter(|_| self.reuse_results).map(hex::encode);
+
+        // Under `--intra-content-parallel`, cached results are looked up and
+        // every other module that will run is run up front, each on its own
+        // thread; the loop below then applies the results in module order
+        let mut cached_results: Vec<Option<CachedResult>> = Vec::new();
+        let mut parallel_runs: Vec<Option<EntrypointRun>> = Vec::new();
+        if self.intra_content_parallel {
+            let dispatched: Vec<bool> = self.instances.iter()
+                .map(|instance| matches!(dispatch(instance), Dispatch::Run))
+                .collect();
+            cached_results = self.instances.iter().zip(&dispatched)
+                .map(|(instance, &dispatched)| {
+                    dispatched.then(|| cached_result(&self.metadata_store, instance, content_sha256.as_deref())).flatten()
+                })
+                .collect();
+            parallel_runs = thread::scope(|scope| {
+                let handles: Vec<_> = self.instances.iter_mut().zip(dispatched.iter().zip(&cached_results))
+                    .map(|(instance, (&dispatched, cached))| (dispatched && cached.is_none()).then(|| {
+                        instance.set_parent(parent.clone());
+                        instance.set_content_digest(content_digest);
+                        instance.set_streams(Vec::new());
+                        if let Some(queue) = &eager_queue {
+                            instance.set_subcontent_sink(Some(queue.sink(&content, &data, instance.name(), &eager_seen_slices)));
+                        }
+                        let module_span = tracing::info_span!("module", module = %instance.name());
+                        let (uuid, data) = (content.uuid, data.clone());
+                        scope.spawn(move || {
+                            let _module_span = module_span.entered();
+                            let started = Instant::now();
+                            let result = instance.process_content(uuid, data);
+                            instance.set_subcontent_sink(None);
+                            (result, started.elapsed())
+                        })
+                    }))
+                    .collect();
+                handles.into_iter()
+                    .map(|handle| handle.map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))))
+                    .collect()
+            });
+        }
 
         // Process through each module
-        for instance in &mut self.instances {
+        for (index, instance) in self.instances.iter_mut().enumerate() {
+            if let Dispatch::Skip(reason) = dispatch(instance) {
+                tracing::debug!("Skipping module '{}' for {}: {}", instance.name(), content.filename, reason);
+                continue;
+            }
+
+            let _module_span = tracing::info_span!("module", module = %instance.name()).entered();
+
             // Set current module context for metadata accumulation
-            self.metadata_store.set_current_module(&content_uuid_str, instance.name())?;
+            self.metadata_store.set_current_module(&content_uuid_str, instance.name(), instance.version())?;
+
+            let cached = if self.intra_content_parallel {
+                cached_results[index].take()
+            } else {
+                cached_result(&self.metadata_store, instance, content_sha256.as_deref())
+            };
+            let reused = cached.is_some();
+
+            instance.set_parent(parent.clone());
+            instance.set_content_digest(content_digest);
+            instance.set_streams(streams.clone());
+            let (ctx, error) = match cached {
+                Some(cached) => {
+                    tracing::debug!("Reusing cached result of '{}' for {}", instance.name(), content.filename);
+                    let mut ctx = ProcessingContext::new(content.uuid, data.clone());
+                    ctx.table_schemas = cached.tables;
+                    ctx.metadata = cached.rows;
+                    (Some(ctx), None)
+                }
+                None => {
+                    let (result, elapsed) = match parallel_runs.get_mut(index).and_then(Option::take) {
+                        Some(run) => run,
+                        None => {
+                            if let Some(queue) = &eager_queue {
+                                instance.set_subcontent_sink(Some(queue.sink(&content, &data, instance.name(), &eager_seen_slices)));
+                            }
+                            let started = Instant::now();
+                            let result = instance.process_content(content.uuid, data.clone());
+                            if eager_queue.is_some() {
+                                instance.set_subcontent_sink(None);
+                            }
+                            (result, started.elapsed())
+                        }
+                    };
+                    *self.stats.module_time.entry(instance.name().to_string()).or_default() += elapsed;
+                    match result {
+                        Ok(ctx) => (Some(ctx), None),
+                        // Rows and sub-content emitted before fuel ran out are kept
+                        Err(e) => (instance.take_partial_context(), Some(e)),
+                    }
+                }
+            };
+            let salvaged = ctx.is_some() && error.is_some();
+            let reported_errors = instance.take_reported_errors();
+            let mut cacheable = error.is_none() && reported_errors.is_empty();
+            for reported in reported_errors {
+                if let Err(e) = self.metadata_store.record_guest_error(&content_uuid_str, instance.name(), reported.code, &reported.message) {
+                    tracing::warn!("Failed to record error reported by '{}': {}", instance.name(), e);
+                }
+            }
+
+            if let Some(ctx) = ctx {
+                for reason in &ctx.rejected_subcontent {
+                    cacheable = false;
+                    if let Err(e) = self.metadata_store.record_error(Some(&content_uuid_str), instance.name(), "subcontent_rejected", reason) {
+                        tracing::warn!("Failed to record rejected sub-content of '{}': {}", instance.name(), e);
+                    }
+                }
 
-            match instance.process_content(content.uuid, data.clone()) {
-                Ok(ctx) => {
-                    // First, define any tables requested by the module
-                    for table_schema in &ctx.table_schemas {
-                        if let Err(e) = instance.metadata_store().define_table(table_schema.clone()) {
+                // First, define any tables requested by the module, then add
+                // the columns it added to them
+                let store = instance.metadata_store();
+                let definitions = ctx.table_schemas.iter()
+                    .map(|schema| (&schema.name, store.define_table(instance.name(), schema.clone())))
+                    .chain(ctx.added_columns.iter().map(|added| {
+                        (&added.table_name, store.add_column(instance.name(), &added.table_name, added.column.clone()).map(drop))
+                    }));
+                for (table_name, defined) in definitions {
+                    if let Err(e) = defined {
+                        if let Some(conflict) = e.downcast_ref::<SchemaConflict>() {
+                            if conflict.first_seen {
+                                tracing::error!("{}", conflict);
+                                if let Err(record_err) = self.metadata_store.record_error(
+                                    Some(&content_uuid_str),
+                                    instance.name(),
+                                    "schema_conflict",
+                                    &conflict.to_string(),
+                                ) {
+                                    tracing::warn!("Failed to record schema conflict: {}", record_err);
+                                }
+                            }
+                            if self.strict_schema {
+                                processing_errors.push(conflict.to_string());
+                                self.abort.store(true, Ordering::SeqCst);
+                                fatal_error = Some(e);
+                                break;
+                            }
+                        } else {
                             tracing::warn!(
                                 "Failed to define table '{}' for module '{}': {}",
-                                table_schema.name,
+                                table_name,
                                 instance.name(),
                                 e
                             );
                         }
                     }
+                }
+                if fatal_error.is_some() {
+                    break;
+                }
 
-                    // Handle metadata
-                    for metadata_row in &ctx.metadata {
-                        if let Err(e) = instance.metadata_store().insert_row(
-                            &metadata_row.table_name,
-                            &content.uuid.to_string(),
-                            &metadata_row.values,
-                        ) {
+                // Handle metadata
+                let mut undefined_tables = HashSet::new();
+                for metadata_row in &ctx.metadata {
+                    let store = instance.metadata_store();
+                    if !store.is_table_defined(&metadata_row.table_name)
+                        && !define_undefined_table(instance, &content_uuid_str, metadata_row, self.auto_create_tables, &mut undefined_tables)
+                    {
+                        continue;
+                    }
+                    let inserted = table_rows.entry(metadata_row.table_name.clone()).or_default();
+                    if self.max_rows_per_table.is_some_and(|max| *inserted >= max) {
+                        *rows_truncated.entry(metadata_row.table_name.clone()).or_default() += 1;
+                        continue;
+                    }
+                    let insert = if metadata_row.global { MetadataStore::insert_global_row } else { MetadataStore::insert_row };
+                    match insert(store, &metadata_row.table_name, &content.uuid.to_string(), &metadata_row.values) {
+                        Ok(true) => {
+                            *inserted += 1;
+                            if let Some(collected) = &mut self.collected {
+                                collected.rows.push(metadata_row.clone());
+                            }
+                            self.stats.total_rows += 1;
+                            *self.stats.per_table_counts.entry(metadata_row.table_name.clone()).or_default() += 1;
+                        }
+                        Ok(false) => {
+                            tracing::debug!(
+                                "Ignored row repeating a key in table '{}' from module '{}'",
+                                metadata_row.table_name,
+                                instance.name()
+                            );
+                        }
+                        Err(e) => {
                             tracing::warn!(
                                 "Failed to insert row for module '{}': {}",
                                 instance.name(),
@@ -245,34 +1188,135 @@ impl WorkerThread {
                             );
                         }
                     }
+                }
 
-                    // Record module stdout/stderr output
-                    if let Err(e) = self.metadata_store.record_module_output(
-                        &content.uuid.to_string(),
+                // Record module stdout/stderr output
+                if let Err(e) = self.metadata_store.record_module_output(
+                    &content.uuid.to_string(),
+                    instance.name(),
+                    instance.version(),
+                    ctx.stdout.as_deref(),
+                    ctx.stderr.as_deref(),
+                    ctx.stdout_truncated,
+                    ctx.stderr_truncated,
+                ) {
+                    tracing::warn!(
+                        "Failed to record module output for '{}': {}",
                         instance.name(),
-                        ctx.stdout.as_deref(),
-                        ctx.stderr.as_deref(),
-                        ctx.stdout_truncated,
-                        ctx.stderr_truncated,
-                    ) {
-                        tracing::warn!(
-                            "Failed to record module output for '{}': {}",
+                        e
+                    );
+                }
+                if self.passthrough_output {
+                    let prefix = format!("[{} {}] ", instance.name(), content.uuid);
+                    if let Some(stdout) = &ctx.stdout {
+                        let _ = write_prefixed(&mut std::io::stdout().lock(), &prefix, stdout, ctx.stdout_truncated);
+                    }
+                    if let Some(stderr) = &ctx.stderr {
+                        let _ = write_prefixed(&mut std::io::stderr().lock(), &prefix, stderr, ctx.stderr_truncated);
+                    }
+                }
+
+                if let Err(e) = self.metadata_store.record_module_run(
+                    &content_uuid_str,
+                    instance.name(),
+                    instance.version(),
+                    instance.runtime(),
+                    if reused { "reused" } else if error.is_some() { "partial" } else { "success" },
+                    ctx.metadata.len(),
+                    ctx.subcontent.len(),
+                    instance.peak_memory() as u64,
+                    instance.wasi_errnos(),
+                ) {
+                    tracing::warn!("Failed to record module run for '{}': {}", instance.name(), e);
+                }
+
+                succeeded_modules += 1;
+
+                if let Some(sha256) = &content_sha256 {
+                    if cacheable && !reused && ctx.added_columns.is_empty() && ctx.subcontent.is_empty() && ctx.links.is_empty() && ctx.streams.is_empty() {
+                        if let Err(e) = self.metadata_store.store_cached_result(
                             instance.name(),
-                            e
-                        );
+                            instance.version(),
+                            sha256,
+                            &ctx.table_schemas,
+                            &ctx.metadata,
+                        ) {
+                            tracing::warn!("Failed to cache result of '{}': {}", instance.name(), e);
+                        }
                     }
+                }
+
+                // Collect sub-content, remembering which module emitted it
+                let module_name = instance.name().to_string();
+                all_subcontent.extend(ctx.subcontent.into_iter().map(|s| (module_name.clone(), s)));
+                all_links.extend(ctx.links.into_iter().map(|l| (module_name.clone(), l)));
+                streams.extend(ctx.streams);
+            }
 
-                    // Collect sub-content
-                    all_subcontent.extend(ctx.subcontent);
+            if let Some(e) = error {
+                if !salvaged {
+                    if let Err(e) = self.metadata_store.record_module_run(
+                        &content_uuid_str,
+                        instance.name(),
+                        instance.version(),
+                        instance.runtime(),
+                        "failed",
+                        0,
+                        0,
+                        instance.peak_memory() as u64,
+                        instance.wasi_errnos(),
+                    ) {
+                        tracing::warn!("Failed to record module run for '{}': {}", instance.name(), e);
+                    }
                 }
-                Err(e) => {
-                    let error_msg = format!("Module '{}' failed: {}", instance.name(), e);
-                    tracing::warn!("{}", error_msg);
-                    processing_errors.push(error_msg);
+
+                if let Some(limit) = e.limit_exceeded() {
+                    if let Err(record_err) = self.metadata_store.record_error(
+                        Some(&content_uuid_str),
+                        instance.name(),
+                        limit.kind(),
+                        &e.to_string(),
+                    ) {
+                        tracing::warn!("Failed to record resource limit error: {}", record_err);
+                    }
                 }
+
+                let error_msg = format!("Module '{}' failed: {}", instance.name(), e);
+                tracing::warn!("{}", error_msg);
+                processing_errors.push(error_msg);
             }
         }
 
+        // Children of content at the depth limit are never created; say so in
+        // its content document
+        if content.depth >= self.max_recursion_depth && !all_subcontent.is_empty() {
+            tracing::warn!(
+                "Dropping {} sub-content of {}: max recursion depth reached (limit: {})",
+                all_subcontent.len(),
+                content.filename,
+                self.max_recursion_depth
+            );
+            self.metadata_store.set_depth_limited_children(&content_uuid_str, all_subcontent.len());
+            self.stats.depth_limited += all_subcontent.len();
+            all_subcontent.clear();
+        }
+
+        if !rows_truncated.is_empty() {
+            let dropped: usize = rows_truncated.values().sum();
+            tracing::warn!(
+                "Dropped {} rows of {}: max rows per table reached (limit: {})",
+                dropped,
+                content.filename,
+                self.max_rows_per_table.unwrap_or_default()
+            );
+            self.metadata_store.set_rows_truncated(&content_uuid_str, rows_truncated);
+            self.stats.rows_truncated += dropped;
+        }
+
+        if let Some(collected) = &mut self.collected {
+            collected.errors = processing_errors.clone();
+        }
+
         // Finalize content document and POST to Elasticsearch
         if processing_errors.is_empty() {
             self.metadata_store.finalize_content_success(&content_uuid_str)?;
@@ -281,46 +1325,2014 @@ impl WorkerThread {
             self.metadata_store.finalize_content_failure(&content_uuid_str, &error_summary)?;
         }
 
+        if let Some(e) = fatal_error {
+            return Err(e);
+        }
+
+        let outcome = if processing_errors.is_empty() {
+            ContentOutcome::Success
+        } else {
+            self.stats.errors.push(format!("{} ({}): {}", content.filename, content.uuid, processing_errors.join("; ")));
+            if succeeded_modules > 0 {
+                ContentOutcome::Partial
+            } else {
+                ContentOutcome::Failed
+            }
+        };
+
+        // Parent ranges already emitted as a child of this content
+        let mut seen_slices = HashSet::new();
+        let queue = self.subcontent_queue();
+
         // Process sub-content (depth-first)
-        for subcontent_emission in all_subcontent {
-            let subcontent_data = match subcontent_emission.data {
-                SubContentData::Bytes(bytes) => {
-                    // Zero-copy: SharedBuffer wraps the Bytes directly
-                    let buffer = crate::shared_buffer::SharedBuffer::from_bytes(bytes);
-                    let uuid = uuid::Uuid::new_v4();
-                    self.content_store.insert(uuid, buffer.clone());
-                    ContentData::Owned(buffer)
-                }
-                SubContentData::Slice { offset, length } => {
-                    ContentData::Borrowed {
-                        parent_uuid: content.uuid,
-                        offset,
-                        length,
-                    }
-                }
+        for (module_name, subcontent_emission) in all_subcontent {
+            if subcontent_emission.dispatched {
+                continue;
+            }
+            let Some(subcontent) = queue.prepare(&content, &data, &module_name, &subcontent_emission, &mut seen_slices) else {
+                continue;
             };
+            tracing::debug!(
+                "Worker {} enqueuing sub-content: {} (depth: {})",
+                self.id,
+                subcontent.filename,
+                subcontent.depth
+            );
+            if self.worker.len() < LOCAL_QUEUE_LIMIT {
+                self.worker.push(subcontent);
+            } else {
+                self.injector.push(subcontent);
+            }
+        }
 
-            match Content::new_subcontent(
-                &content,
-                subcontent_data,
-                subcontent_emission.filename,
-                self.max_recursion_depth,
-            ) {
-                Ok(subcontent) => {
-                    tracing::debug!(
-                        "Worker {} enqueuing sub-content: {} (depth: {})",
-                        self.id,
-                        subcontent.filename,
-                        subcontent.depth
-                    );
-                    self.worker.push(subcontent);
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to create sub-content: {}", e);
-                }
+        // Explicit links, checked after this content's own children are stored
+        for (module_name, link) in all_links {
+            let result = if self.content_store.get(&link.child_uuid).is_some() {
+                self.metadata_store.record_content_edge(
+                    &module_name,
+                    &content_uuid_str,
+                    &link.child_uuid.to_string(),
+                    &link.relation,
+                )
+            } else {
+                let message = format!("Link '{}' references unknown content {}", link.relation, link.child_uuid);
+                tracing::warn!("{}", message);
+                self.metadata_store.record_error(Some(&content_uuid_str), &module_name, "dangling_reference", &message)
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to record content link '{}': {}", link.relation, e);
+            }
+        }
+
+        Ok(outcome)
+    }
+}
+
+/// `instance`'s cached result for content hashing to `content_sha256`, if
+/// results are reused (`content_sha256` is set) and one is cached
+/// Write `text` to `out` a line at a time, each line starting with `prefix`.
+/// The caller holds the stream's lock, so one content's lines stay together.
+fn write_prefixed(out: &mut impl std::io::Write, prefix: &str, text: &str, truncated: bool) -> std::io::Result<()> {
+    for line in text.lines() {
+        writeln!(out, "{}{}", prefix, line)?;
+    }
+    if truncated {
+        writeln!(out, "{}(output truncated)", prefix)?;
+    }
+    Ok(())
+}
+
+fn cached_result(store: &MetadataStore, instance: &ModuleInstance, content_sha256: Option<&str>) -> Option<CachedResult> {
+    let sha256 = content_sha256?;
+    match store.cached_result(instance.name(), instance.version(), sha256) {
+        Ok(cached) => cached,
+        Err(e) => {
+            tracing::warn!("Failed to look up cached result for '{}': {}", instance.name(), e);
+            None
+        }
+    }
+}
+
+/// Handle a row for a table the module never defined: with `auto_create`,
+/// define it from the row's values, otherwise record an `undefined_table`
+/// error (once per table in `reported`). Returns whether to insert the row.
+fn define_undefined_table(
+    instance: &ModuleInstance,
+    content_uuid: &str,
+    row: &MetadataRow,
+    auto_create: bool,
+    reported: &mut HashSet<String>,
+) -> bool {
+    let store = instance.metadata_store();
+    if auto_create {
+        let schema = TableSchema::inferred(&row.table_name, &row.values, row.global);
+        return match store.define_table(instance.name(), schema) {
+            Ok(()) => {
+                tracing::warn!(
+                    "Module '{}' wrote to undefined table '{}'; created it with {} inferred column(s)",
+                    instance.name(),
+                    row.table_name,
+                    row.values.len()
+                );
+                true
+            }
+            Err(e) => {
+                tracing::warn!("Failed to create table '{}' for module '{}': {}", row.table_name, instance.name(), e);
+                false
             }
+        };
+    }
+    if reported.insert(row.table_name.clone()) {
+        let message = format!(
+            "Module '{}' wrote a row for table '{}', which it never defined",
+            instance.name(),
+            row.table_name
+        );
+        tracing::warn!("{}; skipping its rows", message);
+        if let Err(e) = store.record_error(Some(content_uuid), instance.name(), "undefined_table", &message) {
+            tracing::warn!("Failed to record undefined table error: {}", e);
         }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::CapturedDoc;
+    use crate::shared_buffer::SharedBuffer;
+    use crate::test_guests::{file_writing_guest, file_writing_guest_with_imports};
+    use crate::wasm::{Heartbeat, ResourceLimits};
+
+    fn no_limits() -> ResourceLimits {
+        ResourceLimits { fuel: None, max_memory: None, max_stack: None }
+    }
+
+    /// Two modules that both declare table `shared`, with different column types
+    fn conflicting_modules() -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        for (name, data_type) in [("alpha", "Int64"), ("beta", "String")] {
+            let metadata = format!(
+                r#"{{"tables":[{{"name":"shared","columns":[{{"name":"n","data_type":"{}"}}]}}],"rows":[]}}"#,
+                data_type
+            );
+            std::fs::write(
+                dir.path().join(format!("{}.wasm", name)),
+                file_writing_guest(&[("/metadata/output_0.json", &metadata)], true, "(i32.const 0)"),
+            )?;
+        }
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_manifest_size_bounds_gate_module() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("gated.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+        std::fs::write(dir.path().join("gated.manifest.json"), r#"{"min_size": 4, "max_size": 8}"#)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+
+        let contents: Vec<_> = [3usize, 4, 8, 9]
+            .iter()
+            .map(|&len| Content::new_root(SharedBuffer::from_vec(vec![0; len]), format!("{}.bin", len)))
+            .collect();
+        let uuids: Vec<String> = contents.iter().map(|c| c.uuid.to_string()).collect();
+        let summary = processor.process(contents, 1)?;
+        assert_eq!(summary.processed, 4, "skipped modules don't fail the content");
+        assert_eq!(summary.total_rows, 2);
+
+        let runs: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .map(|d| d.doc["content_uuid"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(runs.len(), 2);
+        assert!(runs.contains(&uuids[1]) && runs.contains(&uuids[2]), "only 4 and 8 byte inputs are in bounds");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch_table_routes_zips_to_the_zip_extractor() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["zip_extractor", "byte_counter"] {
+            std::fs::write(dir.path().join(format!("{}.wasm", name)), file_writing_guest(&[], true, "(i32.const 0)"))?;
+        }
+        let table: DispatchTable = toml::from_str(r#"
+            default = ["byte_counter"]
+
+            [[route]]
+            filename = "*.zip"
+            modules = ["zip_extractor"]
+        "#)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let unknown: DispatchTable = toml::from_str("default = [\"unzip\"]")?;
+        assert!(processor.set_dispatch_table(unknown).is_err(), "unknown module names are rejected");
+        processor.set_dispatch_table(table)?;
+
+        let zip = Content::new_root(SharedBuffer::from_vec(b"PK\x03\x04".to_vec()), "a.zip".to_string());
+        let text = Content::new_root(SharedBuffer::from_vec(b"hello".to_vec()), "b.txt".to_string());
+        let (zip_uuid, text_uuid) = (zip.uuid.to_string(), text.uuid.to_string());
+        processor.process(vec![zip, text], 1)?;
+
+        let runs: Vec<(String, String)> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .map(|d| (d.doc["content_uuid"].as_str().unwrap().to_string(), d.doc["module_name"].as_str().unwrap().to_string()))
+            .collect();
+        assert_eq!(runs.len(), 2);
+        assert!(runs.contains(&(zip_uuid, "zip_extractor".to_string())));
+        assert!(runs.contains(&(text_uuid, "byte_counter".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_subcontent_is_readable_from_data_bin() -> Result<()> {
+        // Emits the slice 2+3 and echoes /data.bin to stdout; fd_read stores
+        // the count over the iovec length, so fd_write sends what was read
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("echo.wasm"),
+            file_writing_guest_with_imports(
+                &[("/subcontent/metadata_0.json", r#"{"filename":"slice.bin","offset":2,"length":3}"#)],
+                true,
+                r#"(import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                   (data (i32.const 512) "/data.bin")"#,
+                "(if (call $path_open (i32.const 3) (i32.const 0) (i32.const 512) (i32.const 9) (i32.const 0)
+                     (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)) (then (return (i32.const 1))))
+                 (i32.store (i32.const 8) (i32.const 2048))
+                 (i32.store (i32.const 12) (i32.const 64))
+                 (drop (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 12)))
+                 (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                 (i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let summary = processor.process(vec![Content::new_root(SharedBuffer::from_vec(b"abcdefgh".to_vec()), "root.bin".to_string())], 1)?;
+        assert_eq!(summary.processed, 2);
+
+        let docs = store.captured_documents();
+        let child_doc = docs.iter()
+            .find(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "slice.bin")
+            .unwrap();
+        let child = child_doc.doc["content_uuid"].clone();
+        assert_eq!(child_doc.doc["slice"]["offset"], 2);
+        assert_eq!(child_doc.doc["slice"]["base_uuid"], child_doc.doc["parent_uuid"]);
+        let stdout = docs.iter()
+            .find(|d| d.doc["doc_type"] == "module_output" && d.doc["content_uuid"] == child)
+            .map(|d| d.doc["stdout"].clone());
+        assert_eq!(stdout, Some("cde".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_output_is_captured_and_passed_through_only_on_request() -> Result<()> {
+        // Prints two lines to stdout
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("printer.wasm"),
+            file_writing_guest_with_imports(
+                &[],
+                true,
+                r#"(data (i32.const 512) "one\ntwo\n")"#,
+                "(i32.store (i32.const 8) (i32.const 512))
+                 (i32.store (i32.const 12) (i32.const 8))
+                 (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                 (i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        assert!(!processor.passthrough_output);
+        processor.process(vec![Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x".to_string())], 1)?;
+        let output = store.captured_documents().into_iter()
+            .find(|d| d.doc["doc_type"] == "module_output")
+            .expect("module_output document");
+        assert_eq!(output.doc["stdout"], "one\ntwo\n");
+
+        let mut passed = Vec::new();
+        write_prefixed(&mut passed, "[printer u] ", "one\ntwo\n", true)?;
+        assert_eq!(String::from_utf8(passed)?, "[printer u] one\n[printer u] two\n[printer u] (output truncated)\n");
+        Ok(())
+    }
+
+    /// Module emitting the slice 0+2 twice and the slice 1+2 once
+    fn repeated_slice_module() -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("scanner.wasm"),
+            file_writing_guest(
+                &[
+                    ("/subcontent/metadata_0.json", r#"{"filename":"a.bin","offset":0,"length":2}"#),
+                    ("/subcontent/metadata_1.json", r#"{"filename":"b.bin","offset":0,"length":2}"#),
+                    ("/subcontent/metadata_2.json", r#"{"filename":"c.bin","offset":1,"length":2}"#),
+                ],
+                true,
+                "(i32.const 0)",
+            ),
+        )?;
+        Ok(dir)
+    }
+
+    fn child_filenames(store: &MetadataStore, parent: &str) -> Vec<String> {
+        let mut names: Vec<String> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content" && d.doc["parent_uuid"] == parent)
+            .map(|d| d.doc["filename"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_repeated_slice_is_collapsed() -> Result<()> {
+        let dir = repeated_slice_module()?;
+        // Depth 1: only the root emits, so the children don't slice themselves
+        let run = |dedup: bool| -> Result<Vec<String>> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 1);
+            processor.set_slice_dedup(dedup);
+            let root = Content::new_root(SharedBuffer::from_vec(b"abcd".to_vec()), "root.bin".to_string());
+            let root_uuid = root.uuid.to_string();
+            processor.process(vec![root], 1)?;
+            Ok(child_filenames(&store, &root_uuid))
+        };
+
+        assert_eq!(run(true)?, ["a.bin", "c.bin"]);
+        assert_eq!(run(false)?, ["a.bin", "b.bin", "c.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detected_text_encoding_is_recorded_and_passed_to_modules() -> Result<()> {
+        // Writes its whole environment block to stdout
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("env.wasm"), r#"
+            (module
+                (import "wasi_snapshot_preview1" "environ_sizes_get" (func $sizes (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_get" (func $get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (drop (call $sizes (i32.const 0) (i32.const 4)))
+                    (drop (call $get (i32.const 64) (i32.const 1024)))
+                    (i32.store (i32.const 8) (i32.const 1024))
+                    (i32.store (i32.const 12) (i32.load (i32.const 4)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.const 0)))
+        "#)?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut processor = ContentProcessor::new(runtime, store.clone(), 1);
+        processor.set_detect_encoding(true);
+        let text = Content::new_root(SharedBuffer::from_vec(b"\xff\xfeh\x00i\x00".to_vec()), "text.txt".to_string());
+        let binary = Content::new_root(SharedBuffer::from_vec(b"\x7fELF\x02\x01".to_vec()), "binary.elf".to_string());
+        let (text_uuid, binary_uuid) = (text.uuid.to_string(), binary.uuid.to_string());
+        processor.process(vec![text, binary], 1)?;
+
+        let docs = store.captured_documents();
+        let doc = |doc_type: &str, uuid: &str| docs.iter()
+            .find(|d| d.doc["doc_type"] == doc_type && d.doc["content_uuid"] == uuid)
+            .map(|d| d.doc.clone())
+            .unwrap();
+        assert_eq!(doc("content", &text_uuid)["text_encoding"], "utf-16le");
+        assert!(doc("content", &binary_uuid)["text_encoding"].is_null());
+        assert!(doc("module_output", &text_uuid)["stdout"].as_str().unwrap().contains("WADUP_TEXT_ENCODING=utf-16le"));
+        assert!(!doc("module_output", &binary_uuid)["stdout"].as_str().unwrap().contains("WADUP_TEXT_ENCODING"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_lineage_lists_ancestors_from_the_root() -> Result<()> {
+        // Every content emits one child, down to the grandchild
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("nesting.wasm"),
+            file_writing_guest(
+                &[
+                    ("/subcontent/data_0.bin", "child"),
+                    ("/subcontent/metadata_0.json", r#"{"filename":"child.bin"}"#),
+                ],
+                true,
+                "(i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 2);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        processor.process(vec![root], 1)?;
+
+        let contents: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .map(|d| d.doc)
+            .collect();
+        assert_eq!(contents.len(), 3);
+        let lineage = |doc: &serde_json::Value| doc["lineage"].as_str().unwrap().to_string();
+        let root_doc = contents.iter().find(|d| d["content_uuid"] == root_uuid.as_str()).unwrap();
+        let child = contents.iter().find(|d| d["parent_uuid"] == root_uuid.as_str()).unwrap();
+        let leaf = contents.iter().find(|d| d["parent_uuid"] == child["content_uuid"]).unwrap();
+        assert_eq!(lineage(root_doc), root_uuid);
+        assert_eq!(
+            lineage(leaf),
+            format!("{}/{}/{}", root_uuid, child["content_uuid"].as_str().unwrap(), leaf["content_uuid"].as_str().unwrap())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_depth_limited_children_are_recorded() -> Result<()> {
+        // Every content emits two children
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("nesting.wasm"),
+            file_writing_guest(
+                &[
+                    ("/subcontent/data_0.bin", "a"),
+                    ("/subcontent/metadata_0.json", r#"{"filename":"a.bin"}"#),
+                    ("/subcontent/data_1.bin", "b"),
+                    ("/subcontent/metadata_1.json", r#"{"filename":"b.bin"}"#),
+                ],
+                true,
+                "(i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 2);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let summary = processor.process(vec![root], 1)?;
+
+        assert_eq!(summary.processed, 7, "root, 2 children, 4 grandchildren");
+        assert_eq!(summary.depth_limited, 8);
+        let mut limited: Vec<u64> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .map(|d| d.doc["depth_limited_children"].as_u64().unwrap())
+            .collect();
+        limited.sort();
+        assert_eq!(limited, [0, 0, 0, 2, 2, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_total_content_stops_enqueuing() -> Result<()> {
+        // Every content emits four children, without limit on depth
+        let dir = tempfile::tempdir()?;
+        let files: Vec<(String, String)> = (0..4)
+            .flat_map(|i| [
+                (format!("/subcontent/data_{}.bin", i), i.to_string()),
+                (format!("/subcontent/metadata_{}.json", i), format!(r#"{{"filename":"{}.bin"}}"#, i)),
+            ])
+            .collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+        std::fs::write(dir.path().join("fanout.wasm"), file_writing_guest(&files, true, "(i32.const 0)"))?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut processor = ContentProcessor::new(runtime, store.clone(), 100);
+        processor.set_max_total_content(Some(10));
+        let roots = (0..2)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i]), format!("root_{}.bin", i)))
+            .collect();
+        let summary = processor.process(roots, 2)?;
+
+        assert!(summary.content_limit_reached);
+        assert_eq!(summary.processed, 12, "2 roots and 10 sub-content");
+        let contents = store.captured_documents().into_iter().filter(|d| d.doc["doc_type"] == "content").count();
+        assert_eq!(contents, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rows_for_undefined_table_are_reported_or_auto_created() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[],"rows":[
+            {"table_name":"loose","values":[{"Int64":3},{"String":"x"}]},
+            {"table_name":"loose","values":[{"Int64":4},{"String":"y"}]}]}"#;
+        std::fs::write(
+            dir.path().join("loose.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+
+        for auto_create in [false, true] {
+            let store = MetadataStore::new_in_memory("wadup");
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 10);
+            processor.set_auto_create_tables(auto_create);
+            let root = Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "input.bin".to_string());
+            let summary = processor.process(vec![root], 1)?;
+            assert_eq!(summary.processed, 1);
+
+            let docs = store.captured_documents();
+            let of_type = |doc_type: &str| docs.iter().filter(|d| d.doc["doc_type"] == doc_type).collect::<Vec<_>>();
+            let errors = of_type("error");
+            let rows = of_type("row");
+            if auto_create {
+                assert!(errors.is_empty());
+                assert_eq!(summary.total_rows, 2);
+                assert_eq!(rows.len(), 2);
+                assert_eq!((&rows[1].doc["column_0"], &rows[1].doc["column_1"]), (&"4".into(), &"y".into()));
+            } else {
+                assert_eq!(summary.total_rows, 0);
+                assert!(rows.is_empty());
+                assert_eq!(errors.len(), 1, "one error per table, not per row");
+                assert_eq!(errors[0].doc["kind"], "undefined_table");
+                assert!(errors[0].doc["message"].as_str().unwrap().contains("'loose'"));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_rows_per_table_drops_and_counts_excess_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Five rows of `many` and one of `few`
+        let rows: Vec<String> = (0..5)
+            .map(|i| format!(r#"{{"table_name":"many","values":[{{"Int64":{}}}]}}"#, i))
+            .chain([r#"{"table_name":"few","values":[{"Int64":0}]}"#.to_string()])
+            .collect();
+        let metadata = format!(
+            r#"{{"tables":[{{"name":"many","columns":[{{"name":"n","data_type":"Int64"}}]}},{{"name":"few","columns":[{{"name":"n","data_type":"Int64"}}]}}],"rows":[{}]}}"#,
+            rows.join(",")
+        );
+        std::fs::write(
+            dir.path().join("prolific.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", &metadata)], true, "(i32.const 0)"),
+        )?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let mut processor = ContentProcessor::new(runtime, store.clone(), 10);
+        processor.set_max_rows_per_table(Some(2));
+        let root = Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "input.bin".to_string());
+        let summary = processor.process(vec![root], 1)?;
+
+        assert_eq!(summary.total_rows, 3);
+        assert_eq!(summary.per_table_counts, BTreeMap::from([("few".to_string(), 1), ("many".to_string(), 2)]));
+        assert_eq!(summary.rows_truncated, 3);
+        let docs = store.captured_documents();
+        let content = docs.iter().find(|d| d.doc["doc_type"] == "content").unwrap();
+        assert_eq!(content.doc["rows_truncated"], serde_json::json!({"many": 3}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stall_timeout_fails_a_looping_content_and_the_rest_go_on() -> Result<()> {
+        // Loops forever on contents longer than one byte
+        let wat = r#"
+            (module
+                (import "wadup" "get_content_size" (func $size (result i64)))
+                (func (export "process") (result i32)
+                    (if (i64.gt_u (call $size) (i64.const 1))
+                        (then (loop $forever (br $forever))))
+                    (i32.const 0)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("looper.wasm"), wat)?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.set_stall_timeout(Some(Duration::from_millis(200)))?;
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, store.clone(), 10);
+        let contents = (0..20)
+            .map(|i| {
+                let data = if i == 3 { b"slow".to_vec() } else { b"x".to_vec() };
+                Content::new_root(SharedBuffer::from_vec(data), format!("{}.bin", i))
+            })
+            .collect();
+        let summary = processor.process(contents, 2)?;
+
+        assert_eq!((summary.processed, summary.failed), (19, 1));
+        assert!(summary.errors[0].starts_with("3.bin"), "{:?}", summary.errors);
+        let docs = store.captured_documents();
+        assert!(docs.iter().any(|d| d.doc["doc_type"] == "error" && d.doc["kind"] == "stalled"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeats_reach_the_progress_callback_and_hold_off_the_stall_timeout() -> Result<()> {
+        // Busy-loops for 600ms, sending heartbeats on contents longer than one byte
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "clock_time_get" (func $clock (param i32 i64 i32) (result i32)))
+                (import "wadup" "get_content_size" (func $size (result i64)))
+                (import "wadup" "heartbeat" (func $heartbeat (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "busy")
+                (func $now (result i64)
+                    (drop (call $clock (i32.const 0) (i64.const 0) (i32.const 0)))
+                    (i64.load (i32.const 0)))
+                (func (export "process") (result i32)
+                    (local $end i64)
+                    (local.set $end (i64.add (call $now) (i64.const 600000000)))
+                    (loop $busy
+                        (if (i64.gt_u (call $size) (i64.const 1))
+                            (then (drop (call $heartbeat (i32.const 500) (i32.const 16) (i32.const 4)))))
+                        (br_if $busy (i64.lt_u (call $now) (local.get $end))))
+                    (i32.const 0)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("busy.wasm"), wat)?;
+
+        let heartbeats = Arc::new(Mutex::new(Vec::new()));
+        let seen = heartbeats.clone();
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.set_stall_timeout(Some(Duration::from_millis(200)))?;
+        runtime.set_progress_callback(Some(Arc::new(move |heartbeat: &Heartbeat| {
+            seen.lock().unwrap().push(heartbeat.clone());
+        })));
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, store.clone(), 10);
+        let contents = vec![
+            Content::new_root(SharedBuffer::from_vec(b"beating".to_vec()), "beating.bin".to_string()),
+            Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "silent.bin".to_string()),
+        ];
+        let summary = processor.process(contents, 2)?;
+
+        assert_eq!((summary.processed, summary.failed), (1, 1));
+        assert!(summary.errors[0].starts_with("silent.bin"), "{:?}", summary.errors);
+        let heartbeats = heartbeats.lock().unwrap();
+        assert!(!heartbeats.is_empty());
+        assert!(heartbeats.iter().all(|h| h.module == "busy" && h.progress_permille == Some(500) && h.message == "busy"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_a_panic_while_processing_ends_the_run_with_an_error() -> Result<()> {
+        // Sends a heartbeat, whose callback panics, on contents longer than one byte
+        let wat = r#"
+            (module
+                (import "wadup" "get_content_size" (func $size (result i64)))
+                (import "wadup" "heartbeat" (func $heartbeat (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (if (i64.gt_u (call $size) (i64.const 1))
+                        (then (drop (call $heartbeat (i32.const -1) (i32.const 0) (i32.const 0)))))
+                    (i32.const 0)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("beating.wasm"), wat)?;
+
+        for intra_content_parallel in [false, true] {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_progress_callback(Some(Arc::new(|_: &Heartbeat| panic!("callback failed"))));
+            runtime.load_modules(dir.path())?;
+            let mut processor = ContentProcessor::new(runtime, MetadataStore::new_in_memory("wadup"), 10);
+            processor.set_intra_content_parallel(intra_content_parallel);
+            let contents = (0..8)
+                .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i; if i == 3 { 2 } else { 1 }]), format!("{}.bin", i)))
+                .collect();
+            let err = processor.process(contents, 4).unwrap_err();
+            assert!(err.to_string().contains("panicked: callback failed"), "{err:#}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn_empty_tables_lists_tables_without_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"used","columns":[{"name":"n","data_type":"Int64"}]},{"name":"unused","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"used","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("definer.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let mut processor = ContentProcessor::new(runtime, store, 10);
+        let root = || Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "input.bin".to_string());
+        assert!(processor.process(vec![root()], 1)?.empty_tables.is_empty());
+
+        processor.set_warn_empty_tables(true);
+        let summary = processor.process(vec![root()], 1)?;
+        assert_eq!(summary.empty_tables, BTreeMap::from([("definer".to_string(), vec!["unused".to_string()])]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_files_add_columns_to_defined_tables() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"fields","columns":[{"name":"key","data_type":"String"}]}],"columns":[{"table_name":"fields","column":{"name":"extra","data_type":"Int64"}}],"rows":[{"table_name":"fields","values":[{"String":"a"}]},{"table_name":"fields","values":[{"String":"b"},{"Int64":7}]}]}"#;
+        std::fs::write(
+            dir.path().join("widener.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, store.clone(), 10);
+        let root = Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "input.bin".to_string());
+        assert_eq!(processor.process(vec![root], 1)?.total_rows, 2);
+
+        let docs = store.captured_documents();
+        let extras: Vec<_> = docs.iter().filter(|d| d.doc["doc_type"] == "row").map(|d| d.doc.get("extra").cloned()).collect();
+        assert_eq!(extras, [None, Some(serde_json::json!("7"))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_one_returns_rows_and_unprocessed_subcontent() -> Result<()> {
+        // Every content gets one row and one child
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("emitter.wasm"),
+            file_writing_guest(
+                &[
+                    ("/metadata/output_0.json", metadata),
+                    ("/subcontent/data_0.bin", "child"),
+                    ("/subcontent/metadata_0.json", r#"{"filename":"child.bin"}"#),
+                ],
+                true,
+                "(i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 10);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let result = processor.process_one(root)?;
+
+        assert_eq!(result.outcome, ContentOutcome::Success);
+        assert!(result.errors.is_empty());
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0].table_name, "seen");
+        assert_eq!(result.subcontent.len(), 1);
+        let child = result.subcontent.into_iter().next().unwrap();
+        assert_eq!(child.filename, "child.bin");
+        assert!(matches!(&child.data, ContentData::Owned(data) if data.as_slice() == b"child"));
+        let content_docs = || store.captured_documents().into_iter().filter(|d| d.doc["doc_type"] == "content").count();
+        assert_eq!(content_docs(), 1, "the child isn't processed");
+
+        // The child can be run in turn, on the same instances
+        let result = processor.process_one(child)?;
+        assert_eq!((result.outcome, result.rows.len(), result.subcontent.len()), (ContentOutcome::Success, 1, 1));
+        assert_eq!(content_docs(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_results_runs_module_once_per_content_hash() -> Result<()> {
+        // Writes a row, and the start of its first path to stdout so runs can be counted
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":7}]}]}"#;
+        let print = "(i32.store (i32.const 8) (i32.const 1024))
+            (i32.store (i32.const 12) (i32.const 4))
+            (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+            (i32.const 0)";
+        std::fs::write(
+            dir.path().join("seen.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, print),
+        )?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        for (run, filename) in ["first.bin", "copy.bin"].into_iter().enumerate() {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 10);
+            processor.set_reuse_results(true);
+            let root = Content::new_root(SharedBuffer::from_vec(b"same bytes".to_vec()), filename.to_string());
+            let summary = processor.process(vec![root], 1)?;
+            assert_eq!((summary.processed, summary.total_rows), (1, 1), "run {}", run);
+        }
+
+        let docs = store.captured_documents();
+        let of_type = |doc_type: &str| docs.iter().filter(|d| d.doc["doc_type"] == doc_type).collect::<Vec<_>>();
+        assert_eq!(of_type("module_output").len(), 1, "the module ran once");
+        let statuses: Vec<_> = of_type("module_run").iter().map(|d| d.doc["status"].as_str().unwrap()).collect();
+        assert_eq!(statuses, ["success", "reused"]);
+        let rows = of_type("row");
+        assert_eq!(rows.len(), 2);
+        assert_ne!(rows[0].doc["content_uuid"], rows[1].doc["content_uuid"]);
+        assert!(rows.iter().all(|d| d.doc["n"] == "7"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_labeled_subcontent_records_edge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("splitter.wasm"),
+            file_writing_guest(
+                &[(
+                    "/subcontent/metadata_0.json",
+                    r#"{"filename":"head.bin","offset":0,"length":2,"relation":"prefix"}"#,
+                )],
+                true,
+                "(i32.const 0)",
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+
+        let root = Content::new_root(SharedBuffer::from_vec(b"abcd".to_vec()), "root.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        processor.process(vec![root], 1)?;
+
+        let docs = store.captured_documents();
+        let edges: Vec<_> = docs.iter().filter(|d| d.doc["doc_type"] == "edge").collect();
+        assert_eq!(edges.len(), 1, "only the root is shallow enough to emit");
+        let edge = &edges[0].doc;
+        assert_eq!(edge["parent_uuid"], root_uuid.as_str());
+        assert_eq!(edge["relation"], "prefix");
+
+        let child = docs.iter()
+            .find(|d| d.doc["doc_type"] == "content" && d.doc["content_uuid"] == edge["child_uuid"])
+            .expect("child content document should be posted");
+        assert_eq!(child.doc["parent_uuid"], root_uuid.as_str());
+        assert_eq!(child.doc["filename"], "head.bin");
+        Ok(())
+    }
+
+    #[test]
+    fn test_subcontent_records_the_module_that_produced_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for (module, offset) in [("alpha", 0), ("beta", 2)] {
+            let metadata = format!(r#"{{"filename":"{}.bin","offset":{},"length":2}}"#, module, offset);
+            std::fs::write(
+                dir.path().join(format!("{}.wasm", module)),
+                file_writing_guest(&[("/subcontent/metadata_0.json", &metadata)], true, "(i32.const 0)"),
+            )?;
+        }
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let root = Content::new_root(SharedBuffer::from_vec(b"abcd".to_vec()), "root.bin".to_string());
+        processor.process(vec![root], 1)?;
+
+        let mut produced_by: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .map(|d| (d.doc["filename"].as_str().unwrap().to_string(), d.doc["produced_by"].as_str().map(String::from)))
+            .collect();
+        produced_by.sort();
+        assert_eq!(produced_by, [
+            ("alpha.bin".to_string(), Some("alpha".to_string())),
+            ("beta.bin".to_string(), Some("beta".to_string())),
+            ("root.bin".to_string(), None),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_content_records_extra_edge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let imports = r#"
+            (import "wadup" "subcontent_uuid" (func $subcontent_uuid (param i32 i32) (result i32)))
+            (import "wadup" "link_content" (func $link_content (param i32 i32 i32) (result i32)))"#;
+        // Links the first emitted child, then the nil UUID at 700, as "copy"
+        let finish = "
+            (drop (call $subcontent_uuid (i32.const 0) (i32.const 512)))
+            (i32.store (i32.const 600) (i32.const 0x79706f63))
+            (drop (call $link_content (i32.const 512) (i32.const 600) (i32.const 4)))
+            (drop (call $link_content (i32.const 700) (i32.const 600) (i32.const 4)))
+            (i32.const 0)";
+        std::fs::write(
+            dir.path().join("linker.wasm"),
+            file_writing_guest_with_imports(
+                &[
+                    ("/subcontent/data_0.bin", "blob"),
+                    ("/subcontent/metadata_0.json", r#"{"filename":"blob.bin","relation":"member"}"#),
+                ],
+                true,
+                imports,
+                finish,
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        processor.process(vec![root], 1)?;
+
+        let docs = store.captured_documents();
+        let mut relations: Vec<_> = docs.iter()
+            .filter(|d| d.doc["doc_type"] == "edge" && d.doc["parent_uuid"] == root_uuid.as_str())
+            .map(|d| d.doc["relation"].as_str().unwrap().to_string())
+            .collect();
+        relations.sort();
+        assert_eq!(relations, ["copy", "member"]);
+        assert!(docs.iter().any(|d| d.doc["kind"] == "dangling_reference"), "the nil UUID is not a known content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_fan_out_is_shared_between_workers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let children: Vec<(String, String)> = (0..200)
+            .map(|i| (
+                format!("/subcontent/metadata_{}.json", i),
+                format!(r#"{{"filename":"part_{0}.bin","offset":{0},"length":1}}"#, i),
+            ))
+            .collect();
+        let files: Vec<(&str, &str)> = children.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+        std::fs::write(dir.path().join("splitter.wasm"), file_writing_guest(&files, true, "(i32.const 0)"))?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+
+        let root = Content::new_root(SharedBuffer::from_vec(vec![0; 200]), "root.bin".to_string());
+        let summary = processor.process(vec![root], 4)?;
+
+        assert_eq!(summary.processed_per_worker.iter().sum::<usize>(), 201);
+        let busy = summary.processed_per_worker.iter().filter(|&&n| n > 0).count();
+        assert!(busy > 1, "children of one root should spread across workers: {:?}", summary.processed_per_worker);
+        Ok(())
+    }
+
+    /// Prints the realtime clock in hex, spins, and prints it again
+    const TIMED_SPIN: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "clock_time_get" (func $clock (param i32 i64 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func $stamp (local $t i64) (local $i i32) (local $n i32)
+                (drop (call $clock (i32.const 0) (i64.const 0) (i32.const 0)))
+                (local.set $t (i64.load (i32.const 0)))
+                (loop $digit
+                    (local.set $n (i32.wrap_i64 (i64.and
+                        (i64.shr_u (local.get $t) (i64.extend_i32_u (i32.sub (i32.const 60) (i32.mul (local.get $i) (i32.const 4)))))
+                        (i64.const 15))))
+                    (i32.store8 (i32.add (i32.const 64) (local.get $i))
+                        (i32.add (local.get $n) (select (i32.const 48) (i32.const 87) (i32.lt_u (local.get $n) (i32.const 10)))))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $digit (i32.lt_u (local.get $i) (i32.const 16))))
+                (i32.store8 (i32.const 80) (i32.const 10))
+                (i32.store (i32.const 8) (i32.const 64))
+                (i32.store (i32.const 12) (i32.const 17))
+                (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16))))
+            (func (export "process") (result i32)
+                (local $n i64)
+                (call $stamp)
+                (local.set $n (i64.const 300000000))
+                (loop $spin
+                    (local.set $n (i64.sub (local.get $n) (i64.const 1)))
+                    (br_if $spin (i64.ne (local.get $n) (i64.const 0))))
+                (call $stamp)
+                (i32.const 0)))
+    "#;
+
+    #[test]
+    fn test_intra_content_parallel_runs_modules_at_the_same_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("slow_a.wasm"), TIMED_SPIN)?;
+        std::fs::write(dir.path().join("slow_b.wasm"), TIMED_SPIN)?;
+
+        let run = |parallel: bool| -> Result<(Duration, Vec<(u64, u64)>)> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 0);
+            processor.set_intra_content_parallel(parallel);
+
+            let started = Instant::now();
+            let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+            let summary = processor.process(vec![root], 1)?;
+            let elapsed = started.elapsed();
+            assert_eq!(summary.processed, 1);
+
+            // Start and end of each module's run, in module order
+            let docs = store.captured_documents();
+            let mut outputs: Vec<_> = docs.iter().filter(|d| d.doc["doc_type"] == "module_output").collect();
+            assert_eq!(outputs.len(), 2);
+            outputs.sort_by_key(|d| d.doc["module_name"].as_str().unwrap().to_string());
+            let spans = outputs.iter()
+                .map(|d| {
+                    let stamps: Vec<u64> = d.doc["stdout"].as_str().unwrap().lines()
+                        .map(|line| u64::from_str_radix(line, 16).unwrap())
+                        .collect();
+                    (stamps[0], stamps[1])
+                })
+                .collect();
+            Ok((elapsed, spans))
+        };
+
+        let (sequential, spans) = run(false)?;
+        assert!(spans[1].0 >= spans[0].1, "sequential modules should not overlap: {:?}", spans);
+
+        let (parallel, spans) = run(true)?;
+        assert!(spans[0].0 < spans[1].1 && spans[1].0 < spans[0].1, "parallel modules should overlap: {:?}", spans);
+        // On one core the runs overlap without finishing any sooner
+        if thread::available_parallelism().map_or(1, |n| n.get()) > 1 {
+            assert!(parallel < sequential * 3 / 4, "parallel {:?} should be closer to one run than to two ({:?})", parallel, sequential);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eager_subcontent_starts_before_its_parent_finishes() -> Result<()> {
+        // Emits one child, then spins for a while before returning
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("slow.wasm"),
+            file_writing_guest_with_imports(
+                &[("/subcontent/metadata_0.json", r#"{"filename":"child.bin","offset":0,"length":1}"#)],
+                true,
+                "(global $n (mut i64) (i64.const 0))",
+                "(global.set $n (i64.const 200000000))
+                 (loop $spin
+                     (global.set $n (i64.sub (global.get $n) (i64.const 1)))
+                     (br_if $spin (i64.ne (global.get $n) (i64.const 0))))
+                 (i32.const 0)",
+            ),
+        )?;
+
+        for eager in [false, true] {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 1);
+            processor.set_eager_subcontent(eager);
+
+            let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+            processor.process(vec![root], 2)?;
+
+            let docs = store.captured_documents();
+            let content = |filename: &str| docs.iter()
+                .find(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == filename)
+                .map(|d| d.doc.clone())
+                .unwrap_or_else(|| panic!("no content document for {}", filename));
+            let child_started = content("child.bin")["started_at_ms"].as_i64().unwrap();
+            let root_finished = content("root.bin")["finished_at_ms"].as_i64().unwrap();
+            assert_eq!(child_started < root_finished, eager, "child started at {}, root finished at {}", child_started, root_finished);
+        }
+        Ok(())
+    }
+
+    /// A worker with its own queues, for driving content through one at a time
+    fn single_worker(runtime: &WasmRuntime, store: &MetadataStore, max_recursion_depth: usize) -> Result<WorkerThread> {
+        Ok(WorkerThread {
+            id: 0,
+            worker: Worker::new_fifo(),
+            injector: Arc::new(Injector::new()),
+            stealers: Vec::new(),
+            pending: Arc::new(AtomicUsize::new(0)),
+            content_limit: Arc::new(ContentLimit::new(None)),
+            content_store: ContentStore::new(),
+            metadata_store: store.clone(),
+            max_recursion_depth,
+            strict_schema: false,
+            slice_dedup: true,
+            skip_empty: false,
+            fail_fast: false,
+            reuse_results: false,
+            auto_create_tables: false,
+            detect_encoding: false,
+            eager_subcontent: false,
+            intra_content_parallel: false,
+            dispatch_table: None,
+            max_rows_per_table: None,
+            passthrough_output: false,
+            abort: Arc::new(AtomicBool::new(false)),
+            instances: runtime.create_instances(store.clone())?,
+            stats: ProcessSummary::default(),
+            collected: None,
+        })
+    }
+
+    const REF_IMPORTS: &str = r#"
+        (import "wadup" "subcontent_uuid" (func $subcontent_uuid (param i32 i32) (result i32)))
+        (import "wadup" "emit_subcontent_ref" (func $emit_subcontent_ref (param i32 i32 i32) (result i32)))"#;
+
+    /// Writes "dup.bin" at 600 and emits a reference to the UUID at 512
+    const EMIT_REF_AT_512: &str = "
+        (i64.store (i32.const 600) (i64.const 0x006e69622e707564))
+        (drop (call $emit_subcontent_ref (i32.const 512) (i32.const 600) (i32.const 7)))";
+
+    #[test]
+    fn test_subcontent_ref_reuses_emitted_child() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let finish = format!("(drop (call $subcontent_uuid (i32.const 0) (i32.const 512))) {} (i32.const 0)", EMIT_REF_AT_512);
+        std::fs::write(
+            dir.path().join("dedup.wasm"),
+            file_writing_guest_with_imports(
+                &[
+                    ("/subcontent/data_0.bin", "blob"),
+                    ("/subcontent/metadata_0.json", r#"{"filename":"blob.bin"}"#),
+                ],
+                true,
+                REF_IMPORTS,
+                &finish,
+            ),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut worker = single_worker(&runtime, &store, 1)?;
+
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        worker.process_content(root)?;
+
+        // The reference is queued without bytes of its own and resolves to the
+        // buffer stored for the child it names
+        let blob = worker.worker.pop().expect("blob.bin should be queued");
+        let dup = worker.worker.pop().expect("dup.bin should be queued");
+        assert_eq!((blob.filename.as_str(), dup.filename.as_str()), ("blob.bin", "dup.bin"));
+        assert!(matches!(dup.data, ContentData::Reference(target) if target == blob.uuid), "{:?}", dup.data);
+        let blob_bytes = worker.content_store.resolve(&blob).unwrap();
+        let dup_bytes = worker.content_store.resolve(&dup).unwrap();
+        assert_eq!(dup_bytes.as_slice().as_ptr(), blob_bytes.as_slice().as_ptr(), "dup.bin must share blob.bin's bytes");
+        assert_eq!(dup_bytes.as_slice(), b"blob");
+
+        worker.process_content(blob)?;
+        worker.process_content(dup)?;
+
+        let docs = store.captured_documents();
+        let content = |name: &str| docs.iter()
+            .find(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == name)
+            .unwrap_or_else(|| panic!("{} should be processed", name));
+        assert_eq!(content("blob.bin").doc["status"], "success");
+        assert_eq!(content("dup.bin").doc["status"], "success");
+        assert!(docs.iter().all(|d| d.doc["kind"] != "dangling_reference"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dangling_subcontent_ref_is_skipped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Memory at 512 is zero, so this references the nil UUID
+        let finish = format!("{} (i32.const 0)", EMIT_REF_AT_512);
+        std::fs::write(
+            dir.path().join("dangling.wasm"),
+            file_writing_guest_with_imports(&[], true, REF_IMPORTS, &finish),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        processor.process(vec![root], 1)?;
+
+        let docs = store.captured_documents();
+        assert!(!docs.iter().any(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "dup.bin"));
+        let error = docs.iter().find(|d| d.doc["kind"] == "dangling_reference").expect("error document");
+        assert_eq!(error.doc["module_name"], "dangling");
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_counts_match_documents() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("rows.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+        // Traps on empty content: hashing its first byte fails
+        std::fs::write(
+            dir.path().join("picky.wasm"),
+            r#"(module
+                (import "wadup" "hash_content_range" (func $hash (param i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (if (call $hash (i32.const 0) (i64.const 0) (i64.const 1) (i32.const 0) (i32.const 32))
+                        (then (unreachable)))
+                    (i32.const 0)))"#,
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+
+        let contents = vec![
+            Content::new_root(SharedBuffer::from_vec(b"a".to_vec()), "full_1.bin".to_string()),
+            Content::new_root(SharedBuffer::from_vec(b"b".to_vec()), "full_2.bin".to_string()),
+            Content::new_root(SharedBuffer::from_vec(Vec::new()), "empty.bin".to_string()),
+        ];
+        let summary = processor.process(contents, 2)?;
+
+        let docs = store.captured_documents();
+        let count = |doc_type: &str, status: &str| docs.iter()
+            .filter(|d| d.doc["doc_type"] == doc_type && (status.is_empty() || d.doc["status"] == status))
+            .count();
+        assert_eq!(summary.input_count, 3);
+        assert_eq!(summary.processed, count("content", "success"));
+        assert_eq!(summary.failed + summary.partial, count("content", "failed"));
+        assert_eq!((summary.processed, summary.partial, summary.failed), (2, 1, 0));
+        assert_eq!(summary.total_rows, count("row", ""));
+        assert_eq!(summary.per_table_counts.get("seen"), Some(&3));
+        assert_eq!(summary.errors.len(), 1);
+        assert!(summary.errors[0].starts_with("empty.bin ("), "{}", summary.errors[0]);
+
+        let json = serde_json::to_value(&summary)?;
+        assert_eq!(json["per_table_counts"]["seen"], 3);
+        assert!(json.get("processed_per_worker").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_limit_stops_taking_content() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]},{"table_name":"seen","values":[{"Int64":2}]}]}"#;
+        std::fs::write(
+            dir.path().join("rows.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let mut store = MetadataStore::new_in_memory("wadup");
+        store.set_max_output_bytes(Some(1));
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+
+        let contents = (0..5)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(Vec::new()), format!("{}.bin", i)))
+            .collect();
+        let summary = processor.process(contents, 1)?;
+
+        assert!(summary.output_limit_reached);
+        assert_eq!(summary.processed, 1, "content in progress finishes, nothing new starts");
+        let content_docs = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .count();
+        assert_eq!(content_docs, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_noop_module_run_is_recorded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("noop.wasm"),
+            r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+
+        let root = Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "empty.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        processor.process(vec![root], 1)?;
+
+        let runs: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .collect();
+        assert_eq!(runs.len(), 1);
+        let run = &runs[0].doc;
+        assert_eq!(run["content_uuid"], root_uuid.as_str());
+        assert_eq!(run["module_name"], "noop");
+        assert_eq!(run["status"], "success");
+        assert_eq!(run["emitted_rows"], 0);
+        assert_eq!(run["emitted_subcontent"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_table_schemas_are_recorded() -> Result<()> {
+        let dir = conflicting_modules()?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+
+        let contents = (0..3)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(Vec::new()), format!("{}.bin", i)))
+            .collect();
+        processor.process(contents, 1)?;
+
+        let docs = store.captured_documents();
+        let errors: Vec<_> = docs.iter().filter(|d| d.doc["doc_type"] == "error").collect();
+        assert_eq!(errors.len(), 1, "a conflict is recorded once, not per content");
+        assert_eq!(errors[0].doc["kind"], "schema_conflict");
+        let message = errors[0].doc["message"].as_str().unwrap();
+        assert!(message.contains("Table 'shared'"), "{}", message);
+        assert!(message.contains("module 'alpha'") && message.contains("module 'beta'"), "{}", message);
+        assert!(message.contains("~n Int64 -> String") || message.contains("~n String -> Int64"), "{}", message);
+
+        // Without --strict-schema every content still completes
+        assert_eq!(docs.iter().filter(|d| d.doc["doc_type"] == "content" && d.doc["status"] == "success").count(), 3);
+        Ok(())
+    }
+
+    /// Log sink shared between a test and its subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_lines_carry_content_and_module() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("failing.wasm"), file_writing_guest(&[], true, "(i32.const 1)"))?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut worker = single_worker(&runtime, &store, 0)?;
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let uuid = root.uuid;
+
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || worker.process_content(root))?;
+
+        let output = String::from_utf8(log.0.lock().unwrap().clone())?;
+        let line = output.lines()
+            .find(|l| l.contains("Module 'failing' failed"))
+            .unwrap_or_else(|| panic!("no failure logged: {}", output));
+        let expected = format!("content{{content_uuid={} filename=root.bin depth=0}}:module{{module=failing}}", uuid);
+        assert!(line.contains(&expected), "{}", line);
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_runs_record_peak_memory() -> Result<()> {
+        // Grows its memory by 16 pages on every call, starting from 1, in
+        // one instance kept across contents
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("grower.manifest.json"), r#"{"stateful": true}"#)?;
+        std::fs::write(dir.path().join("grower.wasm"), r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (drop (memory.grow (i32.const 16)))
+                    (i32.const 0)))
+        "#)?;
+        let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory: Some(64 << 20), max_stack: None })?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let roots = ["a.bin", "b.bin"].map(|name| Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), name.to_string()));
+        processor.process(roots.into(), 1)?;
+
+        let mut peaks: Vec<u64> = store.captured_documents().iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .map(|d| d.doc["peak_memory_bytes"].as_u64().unwrap())
+            .collect();
+        peaks.sort();
+        assert_eq!(peaks, [17 * 65536, 33 * 65536]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace_wasi_counts_the_errnos_file_calls_fail_with() -> Result<()> {
+        // Opens a file that doesn't exist and fails unless it got ENOENT (44)
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("opener.wasm"), r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "missing.txt")
+                (func (export "process") (result i32)
+                    (i32.ne (i32.const 44) (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 11)
+                        (i32.const 0) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))))
+        "#)?;
+        let run = |trace: bool| -> Result<serde_json::Value> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_trace_wasi(trace);
+            runtime.load_modules(dir.path())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let processor = ContentProcessor::new(runtime, store.clone(), 1);
+            let summary = processor.process(vec![Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x.bin".to_string())], 1)?;
+            assert_eq!(summary.failed, 0, "{:?}", summary.errors);
+            let docs = store.captured_documents();
+            let run = docs.iter().find(|d| d.doc["doc_type"] == "module_run").unwrap();
+            Ok(run.doc.get("wasi_errnos").cloned().unwrap_or_default())
+        };
+
+        assert_eq!(run(true)?, serde_json::json!({"ENOENT": 1}));
+        assert_eq!(run(false)?, serde_json::Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_stateful_modules_keep_state_between_contents() -> Result<()> {
+        // Counts its calls in a global and fails when it's past the first
+        let dir = tempfile::tempdir()?;
+        let wat = r#"
+            (module
+                (global $calls (mut i32) (i32.const 0))
+                (func (export "process") (result i32)
+                    (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                    (i32.gt_u (global.get $calls) (i32.const 1))))
+        "#;
+        std::fs::write(dir.path().join("stateless.wasm"), wat)?;
+        std::fs::write(dir.path().join("stateful.wasm"), wat)?;
+        std::fs::write(dir.path().join("stateful.manifest.json"), r#"{"stateful": true}"#)?;
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let contents = (0..3)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i]), format!("{}.bin", i)))
+            .collect();
+        processor.process(contents, 1)?;
+
+        let statuses = |module: &str| -> Vec<String> {
+            store.captured_documents().iter()
+                .filter(|d| d.doc["doc_type"] == "module_run" && d.doc["module_name"] == module)
+                .map(|d| d.doc["status"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(statuses("stateless"), ["success"; 3]);
+        assert_eq!(statuses("stateful"), ["success", "failed", "failed"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reused_metadata_index_is_not_double_counted() -> Result<()> {
+        // Flushes output_0.json twice with the same row, as after a counter reset, then with a new one
+        let dir = tempfile::tempdir()?;
+        let row = |n: u8| format!(r#"{{"tables":[{{"name":"seen","columns":[{{"name":"n","data_type":"Int64"}}]}}],"rows":[{{"table_name":"seen","values":[{{"Int64":{}}}]}}]}}"#, n);
+        let (first, second) = (row(1), row(2));
+        std::fs::write(
+            dir.path().join("flusher.wasm"),
+            file_writing_guest(&[
+                ("/metadata/output_0.json", &first),
+                ("/metadata/output_0.json", &first),
+                ("/metadata/output_0.json", &second),
+            ], true, "(i32.const 0)"),
+        )?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut worker = single_worker(&runtime, &store, 0)?;
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || worker.process_content(root))?;
+
+        let values: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .map(|d| d.doc["n"].clone())
+            .collect();
+        assert_eq!(values, ["1", "2"]);
+        let output = String::from_utf8(log.0.lock().unwrap().clone())?;
+        assert!(output.contains("/metadata/output_0.json was written again with the same contents"), "{}", output);
+        assert!(output.contains("/metadata/output_0.json was already processed"), "{}", output);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_before_running_out_of_fuel_is_kept() -> Result<()> {
+        // Records a row and emits a child, then spins until fuel runs out
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("spin.wasm"),
+            file_writing_guest(&[
+                ("/metadata/output_0.json", metadata),
+                ("/subcontent/data_0.bin", "child"),
+                ("/subcontent/metadata_0.json", r#"{"filename":"child.bin"}"#),
+            ], true, "(loop (br 0)) (i32.const 0)"),
+        )?;
+
+        let mut runtime = WasmRuntime::new(ResourceLimits { fuel: Some(1_000_000), max_memory: None, max_stack: None })?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let summary = processor.process(vec![root], 1)?;
+
+        // The root and the child it emitted both ran, and both ran out
+        assert_eq!((summary.processed, summary.partial, summary.failed), (0, 2, 0));
+        assert_eq!(summary.per_table_counts.get("seen"), Some(&2));
+        let docs = store.captured_documents();
+        let runs: Vec<_> = docs.iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .map(|d| d.doc["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(runs, ["partial", "partial"]);
+        assert_eq!(docs.iter().filter(|d| d.doc["kind"] == "out_of_fuel").count(), 2);
+        assert!(docs.iter().any(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "child.bin"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subcontent_with_mismatched_size_is_rejected() -> Result<()> {
+        // data_1.bin is missing, so metadata_0.json's size catches data_0 being paired with the wrong data
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("pairs.wasm"),
+            file_writing_guest(&[
+                ("/subcontent/data_0.bin", "short"),
+                ("/subcontent/metadata_0.json", r#"{"filename":"long.bin","size":11}"#),
+                ("/subcontent/metadata_1.json", r#"{"filename":"gap.bin"}"#),
+                ("/subcontent/data_2.bin", "exact"),
+                ("/subcontent/metadata_2.json", r#"{"filename":"exact.bin","size":5}"#),
+            ], true, "(i32.const 0)"),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "root.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        let summary = processor.process(vec![root], 1)?;
+        assert_eq!(summary.processed, 2, "only exact.bin is emitted");
+
+        // exact.bin runs the same module and is rejected the same way
+        let docs = store.captured_documents();
+        let mut errors: Vec<_> = docs.iter()
+            .filter(|d| d.doc["kind"] == "subcontent_rejected" && d.doc["content_uuid"] == root_uuid.as_str())
+            .map(|d| d.doc["message"].as_str().unwrap())
+            .collect();
+        errors.sort();
+        assert_eq!(errors, [
+            "Sub-content 'gap.bin' has no data file /subcontent/data_1.bin",
+            "Sub-content 'long.bin' declares 11 bytes in /subcontent/metadata_0.json but its data is 5 bytes",
+        ]);
+        assert!(docs.iter().any(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "exact.bin"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_empty_content_and_subcontent() -> Result<()> {
+        // Records a row and emits an empty child for every content
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("rows.wasm"),
+            file_writing_guest(&[
+                ("/metadata/output_0.json", metadata),
+                ("/subcontent/data_0.bin", ""),
+                ("/subcontent/metadata_0.json", r#"{"filename":"empty.bin"}"#),
+            ], true, "(i32.const 0)"),
+        )?;
+
+        let run = |skip_empty: bool| -> Result<(ProcessSummary, Vec<CapturedDoc>)> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let mut processor = ContentProcessor::new(runtime, store.clone(), 1);
+            processor.set_skip_empty(skip_empty);
+            let contents = vec![
+                Content::new_root(SharedBuffer::from_vec(Vec::new()), "empty.bin".to_string()),
+                Content::new_root(SharedBuffer::from_vec(b"data".to_vec()), "data.bin".to_string()),
+            ];
+            Ok((processor.process(contents, 1)?, store.captured_documents()))
+        };
+        let count = |docs: &[CapturedDoc], doc_type: &str| docs.iter().filter(|d| d.doc["doc_type"] == doc_type).count();
+
+        let (summary, docs) = run(false)?;
+        assert_eq!((summary.processed, summary.skipped_empty), (4, 0));
+        assert_eq!(count(&docs, "row"), 4);
+
+        let (summary, docs) = run(true)?;
+        assert_eq!((summary.processed, summary.skipped_empty), (1, 1));
+        assert_eq!(count(&docs, "row"), 1, "only the non-empty root reaches the module");
+        assert_eq!(count(&docs, "module_run"), 1);
+        let statuses: Vec<_> = docs.iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .map(|d| (d.doc["filename"].as_str().unwrap(), d.doc["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(statuses.len(), 2, "the empty child is never queued: {:?}", statuses);
+        assert!(statuses.contains(&("empty.bin", "skipped_empty")), "{:?}", statuses);
+        assert!(statuses.contains(&("data.bin", "success")), "{:?}", statuses);
+        Ok(())
+    }
+
+    #[test]
+    fn test_child_module_reads_parent_filename() -> Result<()> {
+        // As root content: emits one child. As a child: records parent_filename
+        // as a row, writing the JSON around it in three iovecs.
+        let prefix = r#"{"tables":[{"name":"parents","columns":[{"name":"filename","data_type":"String"}]}],"rows":[{"table_name":"parents","values":[{"String":""#;
+        let suffix = r#""}]}]}"#;
+        let escape = |s: &str| s.bytes().map(|b| format!("\\{:02x}", b)).collect::<String>();
+        let wat = format!(r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+                (import "wadup" "parent_filename" (func $parent_filename (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "/subcontent/data_0.bin")
+                (data (i32.const 1056) "/subcontent/metadata_0.json")
+                (data (i32.const 1088) "{{\"filename\":\"child.bin\"}}")
+                (data (i32.const 1120) "/metadata/output_0.json")
+                (data (i32.const 1152) "{prefix}")
+                (data (i32.const 1536) "{suffix}")
+                (func $write (param $path i32) (param $path_len i32) (param $iovs i32)
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (local.get $path) (local.get $path_len)
+                        (i32.const 9) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+                    (drop (call $fd_write (i32.load (i32.const 0)) (i32.const 32) (local.get $iovs) (i32.const 16)))
+                    (drop (call $fd_close (i32.load (i32.const 0)))))
+                (func (export "process") (result i32)
+                    (local $len i32)
+                    (local.set $len (call $parent_filename (i32.const 2048) (i32.const 256)))
+                    (if (i32.lt_s (local.get $len) (i32.const 0))
+                        (then
+                            (i32.store (i32.const 32) (i32.const 1088))
+                            (i32.store (i32.const 36) (i32.const 24))
+                            (call $write (i32.const 1024) (i32.const 22) (i32.const 1))
+                            (call $write (i32.const 1056) (i32.const 27) (i32.const 1))
+                            (return (i32.const 0))))
+                    (i32.store (i32.const 32) (i32.const 1152))
+                    (i32.store (i32.const 36) (i32.const {prefix_len}))
+                    (i32.store (i32.const 40) (i32.const 2048))
+                    (i32.store (i32.const 44) (local.get $len))
+                    (i32.store (i32.const 48) (i32.const 1536))
+                    (i32.store (i32.const 52) (i32.const {suffix_len}))
+                    (call $write (i32.const 1120) (i32.const 23) (i32.const 3))
+                    (i32.const 0)))
+        "#, prefix = escape(prefix), suffix = escape(suffix), prefix_len = prefix.len(), suffix_len = suffix.len());
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("parents.wasm"), wat)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 1);
+        let root = Content::new_root(SharedBuffer::from_vec(b"root".to_vec()), "archive.zip".to_string());
+        let summary = processor.process(vec![root], 1)?;
+
+        assert_eq!(summary.processed, 2);
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .collect();
+        assert_eq!(rows.len(), 1, "only the child has a parent");
+        assert_eq!(rows[0].doc["filename"], "archive.zip");
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_rows_have_no_content_uuid() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"totals","columns":[{"name":"n","data_type":"Int64"}],"global":true},{"name":"local","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"totals","values":[{"Int64":1}],"global":true},{"table_name":"totals","values":[{"Int64":2}]},{"table_name":"local","values":[{"Int64":3}],"global":true}]}"#;
+        std::fs::write(
+            dir.path().join("summary.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+        let content = Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "a.bin".to_string());
+        let uuid = content.uuid.to_string();
+        let summary = processor.process(vec![content], 1)?;
+
+        assert_eq!(summary.per_table_counts.get("totals"), Some(&2));
+        assert_eq!(summary.per_table_counts.get("local"), None, "local tables refuse global rows");
+        let rows: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "row")
+            .map(|d| (d.doc["n"].clone(), d.doc["content_uuid"].clone()))
+            .collect();
+        assert!(rows.contains(&(serde_json::json!("1"), serde_json::Value::Null)), "{:?}", rows);
+        assert!(rows.contains(&(serde_json::json!("2"), serde_json::json!(uuid))), "{:?}", rows);
+        Ok(())
+    }
+
+    #[test]
+    fn test_later_modules_read_published_streams() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("a_split.wasm"),
+            file_writing_guest(&[("/streams/one", "head"), ("/streams/two", "body")], true, "(i32.const 0)"),
+        )?;
+        // Reads both streams to 100 and 200 and checks their first bytes
+        std::fs::write(
+            dir.path().join("b_read.wasm"),
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "streams/one")
+                (data (i32.const 32) "streams/two")
+                ;; fd at 0, nread at 4, iovec at 8
+                (func $slurp (param $path i32) (param $out i32) (result i32)
+                    (local $errno i32)
+                    (local.set $errno (call $path_open (i32.const 3) (i32.const 0) (local.get $path) (i32.const 11)
+                        (i32.const 0) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+                    (if (local.get $errno) (then (return (local.get $errno))))
+                    (i32.store (i32.const 8) (local.get $out))
+                    (i32.store (i32.const 12) (i32.const 16))
+                    (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 4)))
+                (func (export "process") (result i32)
+                    (local $errno i32)
+                    (local.set $errno (call $slurp (i32.const 16) (i32.const 100)))
+                    (if (local.get $errno) (then (return (local.get $errno))))
+                    (local.set $errno (call $slurp (i32.const 32) (i32.const 200)))
+                    (if (local.get $errno) (then (return (local.get $errno))))
+                    (if (i32.ne (i32.load8_u (i32.const 100)) (i32.const 104)) (then (return (i32.const 90))))
+                    (if (i32.ne (i32.load8_u (i32.const 200)) (i32.const 98)) (then (return (i32.const 91))))
+                    (i32.const 0)))"#,
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, MetadataStore::new_in_memory("wadup"), 0);
+        let contents = (0..2)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), format!("{}.eml", i)))
+            .collect();
+        let summary = processor.process(contents, 1)?;
+        assert_eq!(summary.processed, 2, "{:?}", summary.errors);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fail_fast_stops_at_first_failed_content() -> Result<()> {
+        // Fails on content starting with "b"
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("picky.wasm"),
+            r#"(module
+                (import "wadup" "read_content" (func $read (param i64 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (drop (call $read (i64.const 0) (i32.const 100) (i32.const 1) (i32.const 0)))
+                    (i32.eq (i32.load8_u (i32.const 100)) (i32.const 98))))"#,
+        )?;
+        let contents = || ["good", "bad", "good", "good", "good"].iter().enumerate()
+            .map(|(i, data)| Content::new_root(SharedBuffer::from_vec(data.as_bytes().to_vec()), format!("{}_{}.bin", i, data)))
+            .collect::<Vec<_>>();
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let mut processor = ContentProcessor::new(runtime, MetadataStore::new_in_memory("wadup"), 0);
+        processor.set_fail_fast(true);
+        let (summary, result) = processor.process_with_summary(contents(), 1);
+        let err = result.unwrap_err().to_string();
+        assert!(err.starts_with("Stopped at the first failed content: 1_bad.bin"), "{}", err);
+        assert_eq!((summary.processed, summary.failed), (1, 1), "the rest is never started");
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let processor = ContentProcessor::new(runtime, MetadataStore::new_in_memory("wadup"), 0);
+        let summary = processor.process(contents(), 1)?;
+        assert_eq!((summary.processed, summary.failed), (4, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reported_errors_are_recorded() -> Result<()> {
+        let wat = r#"
+            (module
+                (import "wadup" "report_error" (func $report_error (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 64) "bad magic")
+                (func (export "process") (result i32)
+                    (drop (call $report_error (i32.const 7) (i32.const 64) (i32.const 9)))
+                    (i32.const 1)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("validator.wasm"), wat)?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+        let root = Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x.bin".to_string());
+        let root_uuid = root.uuid.to_string();
+        let summary = processor.process(vec![root], 1)?;
+        assert_eq!(summary.failed, 1);
+
+        let errors: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "error")
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].doc["kind"], "guest_error");
+        assert_eq!(errors[0].doc["code"], 7);
+        assert_eq!(errors[0].doc["message"], "bad magic");
+        assert_eq!(errors[0].doc["module_name"], "validator");
+        assert_eq!(errors[0].doc["content_uuid"], root_uuid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_output_is_flagged() -> Result<()> {
+        // 16 writes of 64 KiB fill the 1 MiB stdout capture exactly, so the
+        // 17th is dropped whole; stderr stays short
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 2)
+                (data (i32.const 65536) "x")
+                (func (export "process") (result i32)
+                    (local $i i32)
+                    (i32.store (i32.const 0) (i32.const 65536))
+                    (i32.store (i32.const 4) (i32.const 65536))
+                    (loop $write
+                        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $write (i32.lt_u (local.get $i) (i32.const 17))))
+                    (i32.store (i32.const 4) (i32.const 1))
+                    (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (i32.const 0)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("chatty.wasm"), wat)?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+        let root = Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x.bin".to_string());
+        processor.process(vec![root], 1)?;
+
+        let output = store.captured_documents().into_iter()
+            .find(|d| d.doc["doc_type"] == "module_output")
+            .expect("module_output document");
+        assert_eq!(output.doc["stdout_truncated"], true);
+        assert_eq!(output.doc["stdout"].as_str().unwrap().len(), 1024 * 1024);
+        assert_eq!(output.doc["stderr_truncated"], false);
+        assert_eq!(output.doc["stderr"], "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_runtimes_are_recorded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let byte_counter = r#"(module (@producers (language "Rust" "") (processed-by "rustc" "1.80.0"))
+            (func (export "process") (result i32) (i32.const 0)))"#;
+        let reactor = r#"(module (func (export "_initialize")) (func (export "process") (result i32) (i32.const 0)))"#;
+        std::fs::write(dir.path().join("byte_counter.wasm"), wat::parse_str(byte_counter)?)?;
+        std::fs::write(dir.path().join("reactor.wasm"), wat::parse_str(reactor)?)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+        let summary = processor.process(vec![Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x".to_string())], 1)?;
+
+        let rust = ModuleRuntime { language: Some("rust".to_string()), runtime: "core".to_string() };
+        assert_eq!(summary.module_runtimes["byte_counter"], rust);
+        assert_eq!(summary.module_runtimes["reactor"], ModuleRuntime { language: None, runtime: "reactor".to_string() });
+
+        let runs: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "module_run")
+            .map(|d| (d.doc["module_name"].clone(), d.doc["language"].clone(), d.doc["runtime"].clone()))
+            .collect();
+        assert!(runs.contains(&("byte_counter".into(), "rust".into(), "core".into())));
+        assert!(runs.contains(&("reactor".into(), serde_json::Value::Null, "reactor".into())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_order() -> Result<()> {
+        // Each module adds its name to the shared "order" table
+        let dir = tempfile::tempdir()?;
+        for name in ["a", "b", "c"] {
+            let metadata = format!(
+                r#"{{"tables":[{{"name":"order","columns":[{{"name":"module","data_type":"String"}}]}}],"rows":[{{"table_name":"order","values":[{{"String":"{}"}}]}}]}}"#,
+                name
+            );
+            std::fs::write(
+                dir.path().join(format!("{}.wasm", name)),
+                file_writing_guest(&[("/metadata/output_0.json", &metadata)], true, "(i32.const 0)"),
+            )?;
+        }
+        let run = |order: &[&str]| -> Result<(Vec<String>, Vec<String>)> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.load_modules(dir.path())?;
+            runtime.set_module_order(&order.iter().map(|s| s.to_string()).collect::<Vec<_>>())?;
+            let store = MetadataStore::new_in_memory("wadup");
+            let processor = ContentProcessor::new(runtime, store.clone(), 0);
+            let root = Content::new_root(SharedBuffer::from_vec(b"x".to_vec()), "x.bin".to_string());
+            let summary = processor.process(vec![root], 1)?;
+            let ran = store.captured_documents().into_iter()
+                .filter(|d| d.doc["doc_type"] == "row")
+                .map(|d| d.doc["module"].as_str().unwrap().to_string())
+                .collect();
+            Ok((ran, summary.module_order))
+        };
+
+        assert_eq!(run(&[])?.0, ["a", "b", "c"], "name order by default");
+        let (ran, recorded) = run(&["c", "a"])?;
+        assert_eq!(ran, ["c", "a", "b"]);
+        assert_eq!(recorded, ran);
+        assert!(run(&["d"]).unwrap_err().to_string().contains("Unknown module 'd'"));
+        assert!(run(&["a", "a"]).unwrap_err().to_string().contains("listed twice"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_module_is_left_out_of_the_run() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("good.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+        std::fs::write(
+            dir.path().join("broken.wasm"),
+            r#"(module
+                (import "wadup" "no_such_function" (func))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+        let contents = || (0..2)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(Vec::new()), format!("{}.bin", i)))
+            .collect();
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let processor = ContentProcessor::new(runtime, store.clone(), 0);
+        let summary = processor.process(contents(), 2)?;
+
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.per_table_counts.get("seen"), Some(&2), "the good module still runs");
+        assert_eq!(summary.instantiation_failures.len(), 1, "recorded once, not per worker");
+        assert!(summary.instantiation_failures[0].contains("Module 'broken'"), "{:?}", summary.instantiation_failures);
+        let errors: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "error")
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].doc["kind"], "instantiation_failed");
+        assert_eq!(errors[0].doc["module_name"], "broken");
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let mut processor = ContentProcessor::new(runtime, MetadataStore::new_in_memory("wadup"), 0);
+        processor.set_strict_instantiation(true);
+        let err = processor.process(contents(), 2).unwrap_err();
+        assert!(err.to_string().contains("Module 'broken' failed to instantiate"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_schema_stops_the_run() -> Result<()> {
+        let dir = conflicting_modules()?;
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let store = MetadataStore::new_in_memory("wadup");
+        let mut processor = ContentProcessor::new(runtime, store.clone(), 0);
+        processor.set_strict_schema(true);
+
+        let contents = (0..3)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(Vec::new()), format!("{}.bin", i)))
+            .collect();
+        let (summary, result) = processor.process_with_summary(contents, 1);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("Table 'shared'"), "{}", err);
+        // The summary still covers the content that failed before the run stopped
+        assert_eq!((summary.input_count, summary.failed), (3, 1));
+        assert_eq!(summary.errors.len(), 1);
+        assert!(summary.fatal_error.as_deref().is_some_and(|e| e.contains("Table 'shared'")));
 
+        let content_docs: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .collect();
+        assert_eq!(content_docs.len(), 1, "no content is processed after the conflict");
+        assert_eq!(content_docs[0].doc["status"], "failed");
         Ok(())
     }
 }
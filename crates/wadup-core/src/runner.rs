@@ -0,0 +1,1022 @@
+//! Library entrypoint running modules over a directory of files, as
+//! `wadup run` does, for callers embedding WADUP in their own service.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::content::Content;
+use crate::dispatch::DispatchTable;
+use crate::error::WadupError;
+use crate::metadata::{Label, MetadataStore, OutputMode};
+use crate::processor::{ContentProcessor, ProcessSummary};
+use crate::secrets::Secret;
+use crate::shared_buffer::SharedBuffer;
+use crate::wasm::{Mount, ProgressCallback, ResourceLimits, WasiProfile, WasmRuntime, DEFAULT_ENTRYPOINT};
+
+/// Options for [`run`]. Each field matches the `wadup run` flag of the same
+/// name, and the defaults are the CLI's.
+#[derive(Clone)]
+pub struct RunConfig {
+    /// Directory containing WASM modules, or a single .wasm file
+    pub modules: PathBuf,
+    /// Directory containing input files
+    pub input: Option<PathBuf>,
+    /// File listing the input files to read instead of `input`, in order
+    /// (see [`load_input_list`])
+    pub input_list: Option<PathBuf>,
+    /// Named blobs processed as root content alongside `input`, for callers
+    /// that already hold the bytes in memory
+    pub inputs: Vec<(String, Bytes)>,
+    pub es_url: String,
+    pub es_index: String,
+    pub output_mode: OutputMode,
+    pub threads: usize,
+    pub limits: ResourceLimits,
+    pub max_recursion_depth: usize,
+    /// Most sub-content items queued across the whole run
+    pub max_total_content: Option<usize>,
+    /// Most rows kept per table for each content
+    pub max_rows_per_table_per_content: Option<usize>,
+    pub entrypoint: String,
+    /// Gzip the precompiled cache files written for modules
+    pub compress_cache: bool,
+    pub dump_fs_on_error: Option<PathBuf>,
+    /// Leave files in `/tmp` from one content to the next
+    pub keep_scratch: bool,
+    /// Copy the metadata files modules write to
+    /// `<dir>/<module>/<content-uuid>/`, as they wrote them
+    pub keep_metadata_json: Option<PathBuf>,
+    pub strict_schema: bool,
+    /// Stop when a module fails to instantiate or a file in `input_list`
+    /// is missing (`--strict`)
+    pub strict_instantiation: bool,
+    pub migrate: bool,
+    /// Write the summary as JSON here, even when the run fails
+    pub summary_json: Option<PathBuf>,
+    pub max_db_bytes: Option<u64>,
+    /// Drop slices repeating a range already emitted (off with `--no-slice-dedup`)
+    pub slice_dedup: bool,
+    pub skip_empty: bool,
+    pub fail_fast: bool,
+    pub table_prefix: Option<String>,
+    /// Tags recorded on every content document and in the summary
+    pub labels: Vec<Label>,
+    pub seed: Option<u64>,
+    /// Seed `random_get` from each content's bytes when `seed` is unset
+    pub content_seeded_random: bool,
+    pub wasi_profile: WasiProfile,
+    /// Host directories every module can read, loaded once for the run
+    pub mounts: Vec<Mount>,
+    /// Arguments every module sees after `argv[0]`, its name
+    pub module_args: Vec<String>,
+    /// Values modules read with `get_secret`; override `secrets_file`
+    pub secrets: Vec<Secret>,
+    /// `NAME=VALUE` lines loaded as secrets (`--secrets-file`)
+    pub secrets_file: Option<PathBuf>,
+    /// Most fuel per content a module can reach with `add_fuel`; `add_fuel`
+    /// is refused without it
+    pub fuel_ceiling: Option<u64>,
+    /// Fail a content when a module hasn't finished it in this long
+    pub stall_timeout: Option<std::time::Duration>,
+    /// Called with each heartbeat modules send; heartbeats also restart
+    /// `stall_timeout`
+    pub progress: Option<ProgressCallback>,
+    /// Most files a module instance can have open at once
+    pub max_open_files: Option<usize>,
+    pub module_order: Vec<String>,
+    /// TOML dispatch table routing contents to modules (`--dispatch`)
+    pub dispatch: Option<PathBuf>,
+    /// Skip modules on content whose hash they already produced results for
+    pub reuse_results: bool,
+    /// Time each phase of the run and each module, reported in
+    /// `ProcessSummary::profile`
+    pub profile: bool,
+    /// Define tables modules write rows to without defining them, instead
+    /// of skipping those rows
+    pub auto_create_tables: bool,
+    /// Warn about defined tables that got no rows (`--warn-empty-tables`)
+    pub warn_empty_tables: bool,
+    /// Also print module output to the host streams (`--passthrough-output`)
+    pub passthrough_output: bool,
+    /// Log WASI file calls at debug level and count failed calls' errnos
+    /// on `module_run` documents (`--trace-wasi`)
+    pub trace_wasi: bool,
+    /// Guess text encodings (`--detect-encoding`)
+    pub detect_encoding: bool,
+    /// Queue sub-content as it is emitted (`--eager-subcontent`)
+    pub eager_subcontent: bool,
+    /// Run a content's modules concurrently (`--intra-content-parallel`)
+    pub intra_content_parallel: bool,
+    /// Process only the root contents recorded as failed in the store,
+    /// reloaded from the inputs by filename
+    pub retry_failed: bool,
+    /// Process only this many root contents, picked at random (with
+    /// `seed`, the same ones each run)
+    pub sample: Option<usize>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            modules: PathBuf::new(),
+            input: None,
+            input_list: None,
+            inputs: Vec::new(),
+            es_url: "http://localhost:9200".to_string(),
+            es_index: "wadup".to_string(),
+            output_mode: OutputMode::default(),
+            threads: 4,
+            limits: ResourceLimits {
+                fuel: None,
+                max_memory: None,
+                max_stack: None,
+            },
+            max_recursion_depth: 100,
+            max_total_content: None,
+            max_rows_per_table_per_content: None,
+            entrypoint: DEFAULT_ENTRYPOINT.to_string(),
+            compress_cache: false,
+            dump_fs_on_error: None,
+            keep_scratch: false,
+            keep_metadata_json: None,
+            strict_schema: false,
+            strict_instantiation: false,
+            migrate: false,
+            summary_json: None,
+            max_db_bytes: None,
+            slice_dedup: true,
+            skip_empty: false,
+            fail_fast: false,
+            table_prefix: None,
+            labels: Vec::new(),
+            seed: None,
+            content_seeded_random: false,
+            wasi_profile: WasiProfile::default(),
+            mounts: Vec::new(),
+            module_args: Vec::new(),
+            secrets: Vec::new(),
+            secrets_file: None,
+            fuel_ceiling: None,
+            stall_timeout: None,
+            progress: None,
+            max_open_files: None,
+            module_order: Vec::new(),
+            dispatch: None,
+            reuse_results: false,
+            auto_create_tables: false,
+            warn_empty_tables: false,
+            passthrough_output: false,
+            trace_wasi: false,
+            detect_encoding: false,
+            eager_subcontent: false,
+            intra_content_parallel: false,
+            profile: false,
+            retry_failed: false,
+            sample: None,
+        }
+    }
+}
+
+/// Run the modules over every file in `config.input` (or `config.input_list`)
+/// and every blob in `config.inputs`, writing results to Elasticsearch at
+/// `config.es_url`.
+pub fn run(config: &RunConfig) -> Result<ProcessSummary, WadupError> {
+    run_inputs(config).map_err(WadupError::from)
+}
+
+fn run_inputs(config: &RunConfig) -> Result<ProcessSummary> {
+    validate(config)?;
+    if config.input.is_none() && config.input_list.is_none() && config.inputs.is_empty() {
+        anyhow::bail!("No input: set an input directory, an input list or in-memory inputs");
+    }
+    if let Some(input) = &config.input {
+        if !input.is_dir() {
+            anyhow::bail!("Input directory does not exist: {:?}", input);
+        }
+        tracing::info!("  Input directory: {:?}", input);
+    }
+    if let Some(list) = &config.input_list {
+        tracing::info!("  Input list: {:?}", list);
+    }
+    tracing::info!("  Elasticsearch URL: {}", config.es_url);
+    tracing::info!("  Elasticsearch index: {}", config.es_index);
+
+    tracing::info!("Connecting to Elasticsearch...");
+    let store = MetadataStore::new(&config.es_url, &config.es_index)?;
+
+    let started = Instant::now();
+    let contents = match (&config.input, &config.input_list) {
+        (Some(input), _) => {
+            tracing::info!("Loading input files...");
+            let contents = load_files(input)?;
+            tracing::info!("Found {} input files", contents.len());
+            contents
+        }
+        (None, Some(list)) => {
+            tracing::info!("Loading input files from list...");
+            let contents = load_input_list(list, config.strict_instantiation)?;
+            tracing::info!("Found {} input files", contents.len());
+            contents
+        }
+        (None, None) => Vec::new(),
+    };
+    let load_files_ms = started.elapsed().as_millis() as u64;
+
+    run_loaded(config, store, contents, load_files_ms)
+}
+
+/// Where a run spent its time, collected with [`RunConfig::profile`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunProfile {
+    /// Reading the input directory (0 for [`run_with`], which is given the contents)
+    pub load_files_ms: u64,
+    /// Compiling or loading the modules, and preparing mounts
+    pub load_modules_ms: u64,
+    pub processing_ms: u64,
+    /// Modules loaded from their `_precompiled` cache file
+    pub precompiled_hits: u64,
+    /// Modules compiled from source
+    pub compiled: u64,
+    /// Time spent in each module's entrypoint, summed over all workers
+    pub module_ms: BTreeMap<String, u64>,
+}
+
+impl RunProfile {
+    /// The profile as a table, one line per phase and per module
+    pub fn render(&self) -> String {
+        let mut rows = vec![
+            ("load files".to_string(), self.load_files_ms, String::new()),
+            (
+                "load modules".to_string(),
+                self.load_modules_ms,
+                format!("{} precompiled, {} compiled", self.precompiled_hits, self.compiled),
+            ),
+            ("processing".to_string(), self.processing_ms, String::new()),
+        ];
+        rows.extend(self.module_ms.iter().map(|(name, ms)| (format!("  module {}", name), *ms, String::new())));
+
+        let width = rows.iter().map(|(label, _, _)| label.len()).max().unwrap_or(0);
+        let mut out = format!("{:<width$}  {:>10}\n", "phase", "time (ms)");
+        for (label, ms, note) in rows {
+            out.push_str(format!("{:<width$}  {:>10}  {}", label, ms, note).trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Run the modules over `contents` and `config.inputs`, writing results to
+/// `store` (e.g. one from [`MetadataStore::new_in_memory`]).
+/// `config.input`, `es_url` and `es_index` are not used.
+///
+/// A failed run returns the error after writing `config.summary_json`.
+pub fn run_with(config: &RunConfig, store: MetadataStore, contents: Vec<Content>) -> Result<ProcessSummary, WadupError> {
+    run_loaded(config, store, contents, 0).map_err(WadupError::from)
+}
+
+fn run_loaded(config: &RunConfig, mut store: MetadataStore, contents: Vec<Content>, load_files_ms: u64) -> Result<ProcessSummary> {
+    validate(config)?;
+    log_config(config);
+    let mut contents = contents;
+    contents.extend(config.inputs.iter().map(|(name, bytes)| {
+        Content::new_root(SharedBuffer::from_bytes(bytes.clone()), name.clone())
+    }));
+
+    // Load WASM modules (uses precompiled cache if available)
+    tracing::info!("Loading WASM modules...");
+    let started = Instant::now();
+    let mut runtime = WasmRuntime::new(config.limits.clone())?;
+    runtime.set_stall_timeout(config.stall_timeout)?;
+    runtime.set_progress_callback(config.progress.clone());
+    runtime.set_entrypoint(config.entrypoint.clone());
+    runtime.set_compress_cache(config.compress_cache);
+    runtime.set_dump_fs_on_error(config.dump_fs_on_error.clone());
+    runtime.set_keep_scratch(config.keep_scratch);
+    runtime.set_trace_wasi(config.trace_wasi);
+    runtime.set_keep_metadata_json(config.keep_metadata_json.clone());
+    runtime.set_seed(config.seed);
+    runtime.set_content_seeded_random(config.content_seeded_random);
+    runtime.set_wasi_profile(config.wasi_profile);
+    runtime.set_module_args(config.module_args.clone());
+    let mut secrets = match &config.secrets_file {
+        Some(path) => Secret::load_file(path)?,
+        None => Vec::new(),
+    };
+    secrets.extend(config.secrets.iter().cloned());
+    runtime.set_secrets(secrets);
+    runtime.set_fuel_ceiling(config.fuel_ceiling);
+    runtime.set_max_open_files(config.max_open_files);
+    for mount in &config.mounts {
+        runtime.add_mount(mount)?;
+    }
+    runtime.load_modules(&config.modules)?;
+    runtime.set_module_order(&config.module_order)?;
+    tracing::info!("  Module order: {}", runtime.module_names().join(", "));
+    let load_modules_ms = started.elapsed().as_millis() as u64;
+    let (precompiled_hits, compiled) = (runtime.module_cache().precompiled_hits(), runtime.module_cache().compiled());
+
+    store.set_output_mode(config.output_mode);
+    store.set_table_prefix(config.table_prefix.as_deref())?;
+    store.set_labels(&config.labels);
+    store.init_schema_meta(config.migrate)?;
+    store.set_max_output_bytes(config.max_db_bytes);
+    if config.retry_failed {
+        contents = failed_inputs(&store, contents)?;
+        tracing::info!("Retrying {} failed contents", contents.len());
+    }
+    let mut sample = None;
+    if let Some(size) = config.sample {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        let population = contents.len();
+        contents = sample_contents(contents, size, seed);
+        tracing::info!("Sampled {} of {} root contents (seed {})", contents.len(), population, seed);
+        sample = Some(Sample { population, size: contents.len(), seed });
+    }
+
+    let mut processor = ContentProcessor::new(runtime, store, config.max_recursion_depth);
+    processor.set_strict_schema(config.strict_schema);
+    processor.set_strict_instantiation(config.strict_instantiation);
+    processor.set_slice_dedup(config.slice_dedup);
+    processor.set_skip_empty(config.skip_empty);
+    processor.set_fail_fast(config.fail_fast);
+    processor.set_max_total_content(config.max_total_content);
+    processor.set_max_rows_per_table(config.max_rows_per_table_per_content);
+    processor.set_reuse_results(config.reuse_results);
+    processor.set_auto_create_tables(config.auto_create_tables);
+    processor.set_warn_empty_tables(config.warn_empty_tables);
+    processor.set_passthrough_output(config.passthrough_output);
+    processor.set_detect_encoding(config.detect_encoding);
+    processor.set_eager_subcontent(config.eager_subcontent);
+    processor.set_intra_content_parallel(config.intra_content_parallel);
+    if let Some(path) = &config.dispatch {
+        processor.set_dispatch_table(DispatchTable::load(path)?)?;
+    }
+
+    tracing::info!("Starting processing...");
+    let (mut summary, result) = processor.process_with_summary(contents, config.threads);
+    summary.sample = sample;
+    if config.profile {
+        summary.profile = Some(RunProfile {
+            load_files_ms,
+            load_modules_ms,
+            processing_ms: summary.duration_ms,
+            precompiled_hits,
+            compiled,
+            module_ms: summary.module_time.iter()
+                .map(|(name, time)| (name.clone(), time.as_millis() as u64))
+                .collect(),
+        });
+    }
+    tracing::info!(
+        "Processed {} contents ({} failed, {} partial, {} skipped empty) in {} ms",
+        summary.processed,
+        summary.failed,
+        summary.partial,
+        summary.skipped_empty,
+        summary.duration_ms
+    );
+    if let Some(path) = &config.summary_json {
+        std::fs::write(path, serde_json::to_string_pretty(&summary)?)
+            .with_context(|| format!("Failed to write summary to {:?}", path))?;
+        tracing::info!("Summary written to {:?}", path);
+    }
+    result?;
+    Ok(summary)
+}
+
+/// Which root contents `--sample` kept, recorded in the run's summary
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    /// Root contents the sample was drawn from
+    pub population: usize,
+    /// Root contents kept: `--sample`, or `population` if that is smaller
+    pub size: usize,
+    /// `--seed`, or the random seed drawn without one; passing it as
+    /// `--seed` picks the same sample again
+    pub seed: u64,
+}
+
+/// Keep `size` of `contents`, picked with `seed`, in their given order. The
+/// picks are made over the contents ordered by filename (ties in their
+/// given order), so a seed picks the same files however the input directory
+/// happens to list them.
+fn sample_contents(contents: Vec<Content>, size: usize, seed: u64) -> Vec<Content> {
+    use rand::RngCore;
+
+    let mut by_name: Vec<usize> = (0..contents.len()).collect();
+    by_name.sort_by(|&a, &b| contents[a].filename.cmp(&contents[b].filename));
+    // A partial Fisher-Yates shuffle, drawing from the stream itself rather
+    // than `rand`'s samplers, which may change between releases
+    let mut rng = crate::wasm::seeded_rng(seed, &[b"sample"]);
+    let size = size.min(by_name.len());
+    for i in 0..size {
+        let j = i + (rng.next_u64() % (by_name.len() - i) as u64) as usize;
+        by_name.swap(i, j);
+    }
+    let picked: std::collections::HashSet<usize> = by_name[..size].iter().copied().collect();
+    contents.into_iter()
+        .enumerate()
+        .filter_map(|(i, content)| picked.contains(&i).then_some(content))
+        .collect()
+}
+
+/// The inputs whose earlier processing failed, under their earlier
+/// `content_uuid` so the new content document replaces the failed one.
+/// Their earlier error documents are deleted, so a retry that succeeds
+/// leaves no errors behind.
+fn failed_inputs(store: &MetadataStore, contents: Vec<Content>) -> Result<Vec<Content>> {
+    let failed = store.failed_contents()?;
+    if let Some(child) = failed.iter().find(|f| f.parent_uuid.is_some()) {
+        anyhow::bail!(
+            "Can't retry failed sub-content '{}' ({}): sub-content bytes aren't stored, so only root files can be retried",
+            child.filename,
+            child.content_uuid
+        );
+    }
+
+    let mut retried = Vec::with_capacity(failed.len());
+    for f in failed {
+        let mut content = contents.iter()
+            .find(|c| c.filename == f.filename)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Failed content '{}' ({}) is not among the inputs", f.filename, f.content_uuid))?;
+        content.uuid = uuid::Uuid::parse_str(&f.content_uuid)
+            .with_context(|| format!("Failed content '{}' has an invalid content_uuid", f.filename))?;
+        retried.push(content);
+    }
+    for content in &retried {
+        store.delete_content_errors(&content.uuid.to_string())?;
+    }
+    Ok(retried)
+}
+
+/// Checks that don't need the modules loaded or Elasticsearch
+fn validate(config: &RunConfig) -> Result<()> {
+    if !config.modules.exists() {
+        anyhow::bail!("Modules path does not exist: {:?}", config.modules);
+    }
+    if config.threads == 0 {
+        anyhow::bail!("Number of threads must be at least 1");
+    }
+    if config.sample == Some(0) {
+        anyhow::bail!("Sample size must be at least 1");
+    }
+    if config.input.is_some() && config.input_list.is_some() {
+        anyhow::bail!("Set an input directory or an input list, not both");
+    }
+    Ok(())
+}
+
+fn log_config(config: &RunConfig) {
+    let limits = &config.limits;
+    tracing::info!("  Modules: {:?}", config.modules);
+    tracing::info!("  Output mode: {:?}", config.output_mode);
+    tracing::info!("  Worker threads: {}", config.threads);
+    tracing::info!("  Max recursion depth: {}", config.max_recursion_depth);
+    if let Some(max) = config.max_total_content {
+        tracing::info!("  Max total content: {}", max);
+    }
+    if let Some(size) = config.sample {
+        tracing::info!("  Sample: {} root contents", size);
+    }
+    if let Some(max) = config.max_rows_per_table_per_content {
+        tracing::info!("  Max rows per table per content: {}", max);
+    }
+    tracing::info!("  Entrypoint: {}", config.entrypoint);
+    if config.compress_cache {
+        tracing::info!("  Compressed cache: on");
+    }
+    if let Some(ref dir) = config.dump_fs_on_error {
+        tracing::info!("  Dump filesystem on error: {:?}", dir);
+    }
+    if config.keep_scratch {
+        tracing::info!("  Keep scratch: on");
+    }
+    if let Some(ref dir) = config.keep_metadata_json {
+        tracing::info!("  Keep metadata files: {:?}", dir);
+    }
+    if config.strict_schema {
+        tracing::info!("  Strict schema: on");
+    }
+    if config.strict_instantiation {
+        tracing::info!("  Strict instantiation: on");
+    }
+    if config.fail_fast {
+        tracing::info!("  Fail fast: on");
+    }
+    if config.reuse_results {
+        tracing::info!("  Reuse results: on");
+    }
+    if config.auto_create_tables {
+        tracing::info!("  Auto-create tables: on");
+    }
+    if config.warn_empty_tables {
+        tracing::info!("  Warn about empty tables: on");
+    }
+    if config.passthrough_output {
+        tracing::info!("  Pass through module output: on");
+    }
+    if config.trace_wasi {
+        tracing::info!("  Trace WASI file calls: on");
+    }
+    if config.detect_encoding {
+        tracing::info!("  Detect text encoding: on");
+    }
+    if config.eager_subcontent {
+        tracing::info!("  Eager sub-content: on");
+    }
+    if config.intra_content_parallel {
+        tracing::info!("  Intra-content parallel: on");
+    }
+    if let Some(ref path) = config.dispatch {
+        tracing::info!("  Dispatch table: {:?}", path);
+    }
+    if !config.module_args.is_empty() {
+        tracing::info!("  Module arguments: {:?}", config.module_args);
+    }
+    if let Some(ref path) = config.secrets_file {
+        tracing::info!("  Secrets file: {:?}", path);
+    }
+    if !config.secrets.is_empty() {
+        let names: Vec<&str> = config.secrets.iter().map(|s| s.name.as_str()).collect();
+        tracing::info!("  Secrets: {}", names.join(", "));
+    }
+    if !config.labels.is_empty() {
+        let labels: Vec<String> = config.labels.iter().map(|l| format!("{}={}", l.key, l.value)).collect();
+        tracing::info!("  Labels: {}", labels.join(", "));
+    }
+    if let Some(seed) = config.seed {
+        tracing::info!("  Random seed: {}", seed);
+    } else if config.content_seeded_random {
+        tracing::info!("  Random seed: from content");
+    }
+    tracing::info!("  WASI profile: {:?}", config.wasi_profile);
+    for mount in &config.mounts {
+        tracing::info!("  Mount: {} at {} (read-only)", mount.host_dir.display(), mount.guest_path);
+    }
+
+    if let Some(fuel) = limits.fuel {
+        tracing::info!("  Fuel limit: {}", fuel);
+        if let Some(ceiling) = config.fuel_ceiling {
+            tracing::info!("  Fuel ceiling: {}", ceiling);
+        }
+    } else {
+        tracing::info!("  Fuel limit: None (no CPU limit)");
+    }
+    if let Some(timeout) = config.stall_timeout {
+        tracing::info!("  Stall timeout: {:?}", timeout);
+    }
+
+    if let Some(mem) = limits.max_memory {
+        tracing::info!("  Memory limit: {} bytes ({} MB)", mem, mem / 1024 / 1024);
+    } else {
+        tracing::info!("  Memory limit: None (wasmtime defaults)");
+    }
+
+    if let Some(stack) = limits.max_stack {
+        tracing::info!("  Stack limit: {} bytes ({} KB)", stack, stack / 1024);
+    } else {
+        tracing::info!("  Stack limit: None (wasmtime defaults)");
+    }
+
+    if let Some(max) = config.max_open_files {
+        tracing::info!("  Open file limit: {}", max);
+    }
+}
+
+/// Load every regular file directly in `input_dir` as root content
+pub fn load_files(input_dir: &Path) -> Result<Vec<Content>> {
+    let mut contents = Vec::new();
+
+    for entry in std::fs::read_dir(input_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if path.is_file() {
+            let filename = path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown")
+                .to_string();
+
+            tracing::debug!("Loading file: {}", filename);
+            // Use memory mapping for zero-copy file loading
+            let buffer = SharedBuffer::from_file(&path)?;
+            let content = Content::new_root(buffer, filename);
+
+            contents.push(content);
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Load the files named in `list` as root content, in the order listed.
+/// Each line is a path, relative to the list's directory unless absolute,
+/// optionally followed by a tab and the filename to give the content (its
+/// file name by default). Blank lines and lines starting with `#` are
+/// skipped. A missing file is skipped with a warning, or fails the load
+/// when `strict`.
+pub fn load_input_list(list: &Path, strict: bool) -> Result<Vec<Content>> {
+    let text = std::fs::read_to_string(list).with_context(|| format!("Failed to read input list {:?}", list))?;
+    let base = list.parent().unwrap_or(Path::new(""));
+    let mut contents = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (path, filename) = match line.split_once('\t') {
+            Some((path, filename)) => (path, Some(filename.to_string())),
+            None => (line, None),
+        };
+        let path = base.join(path);
+        if !path.is_file() {
+            if strict {
+                anyhow::bail!("Input {:?} on line {} of {:?} does not exist", path, index + 1, list);
+            }
+            tracing::warn!("Skipping missing input {:?} (line {} of {:?})", path, index + 1, list);
+            continue;
+        }
+        let filename = filename.unwrap_or_else(|| {
+            path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown").to_string()
+        });
+
+        tracing::debug!("Loading file: {}", filename);
+        let buffer = SharedBuffer::from_file(&path)?;
+        contents.push(Content::new_root(buffer, filename));
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_guests::file_writing_guest;
+
+    #[test]
+    fn test_run_with_in_memory_contents() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = r#"{"tables":[{"name":"seen","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[{"table_name":"seen","values":[{"Int64":1}]}]}"#;
+        std::fs::write(
+            dir.path().join("seen.wasm"),
+            file_writing_guest(&[("/metadata/output_0.json", metadata)], true, "(i32.const 0)"),
+        )?;
+        let summary_path = dir.path().join("summary.json");
+        let config = RunConfig {
+            modules: dir.path().join("seen.wasm"),
+            threads: 2,
+            summary_json: Some(summary_path.clone()),
+            ..RunConfig::default()
+        };
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let contents = (0..3)
+            .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i]), format!("{}.bin", i)))
+            .collect();
+        let summary = run_with(&config, store.clone(), contents)?;
+
+        assert_eq!(summary.input_count, 3);
+        assert_eq!(summary.processed, 3);
+        assert_eq!(summary.failed, 0);
+        assert_eq!(summary.module_order, ["seen"]);
+        let rows = store.captured_documents().into_iter().filter(|d| d.doc["doc_type"] == "row").count();
+        assert_eq!(rows, 3);
+        let written: serde_json::Value = serde_json::from_slice(&std::fs::read(&summary_path)?)?;
+        assert_eq!(written["processed"], 3);
+
+        let missing = RunConfig { modules: dir.path().join("missing"), ..config };
+        let err = run_with(&missing, MetadataStore::new_in_memory("wadup"), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("Modules path does not exist"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_are_recorded_on_content_and_summary() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("idle.wasm"), file_writing_guest(&[], true, "(i32.const 0)"))?;
+        let summary_path = dir.path().join("summary.json");
+        let config = RunConfig {
+            modules: dir.path().join("idle.wasm"),
+            labels: vec!["campaign=spring".parse()?, "source=mail gateway".parse()?],
+            summary_json: Some(summary_path.clone()),
+            ..RunConfig::default()
+        };
+        assert!("9lives=x".parse::<Label>().is_err());
+        assert!("no-separator".parse::<Label>().is_err());
+
+        let store = MetadataStore::new_in_memory("wadup");
+        let contents = vec![Content::new_root(SharedBuffer::from_vec(b"a".to_vec()), "a.bin".to_string())];
+        run_with(&config, store.clone(), contents)?;
+
+        let expected = serde_json::json!({"campaign": "spring", "source": "mail gateway"});
+        let content = store.captured_documents().into_iter().find(|d| d.doc["doc_type"] == "content").unwrap();
+        assert_eq!(content.doc["labels"], expected);
+        let written: serde_json::Value = serde_json::from_slice(&std::fs::read(&summary_path)?)?;
+        assert_eq!(written["labels"], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_processes_a_seeded_subset_of_roots() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("idle.wasm"), file_writing_guest(&[], true, "(i32.const 0)"))?;
+        let config = RunConfig {
+            modules: dir.path().join("idle.wasm"),
+            sample: Some(3),
+            seed: Some(7),
+            ..RunConfig::default()
+        };
+
+        let sampled = || -> Result<(ProcessSummary, Vec<String>)> {
+            let store = MetadataStore::new_in_memory("wadup");
+            // Listed in reverse, as a directory might; the seed still picks the same files
+            let contents = (0..20).rev()
+                .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i]), format!("{:02}.bin", i)))
+                .collect();
+            let summary = run_with(&config, store.clone(), contents)?;
+            let mut names: Vec<String> = store.captured_documents().into_iter()
+                .filter(|d| d.doc["doc_type"] == "content")
+                .map(|d| d.doc["filename"].as_str().unwrap().to_string())
+                .collect();
+            names.sort();
+            names.dedup();
+            Ok((summary, names))
+        };
+
+        let (summary, names) = sampled()?;
+        assert_eq!((summary.input_count, summary.processed), (3, 3));
+        assert_eq!(names.len(), 3);
+        let sample = summary.sample.unwrap();
+        assert_eq!((sample.population, sample.size, sample.seed), (20, 3, 7));
+        assert_eq!(sampled()?.1, names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_keeps_the_given_order() {
+        let roots = |order: Vec<u8>| -> Vec<Content> {
+            order.into_iter()
+                .map(|i| Content::new_root(SharedBuffer::from_vec(vec![i]), format!("{:02}.bin", i)))
+                .collect()
+        };
+        let names = |contents: Vec<Content>| -> Vec<String> {
+            contents.into_iter().map(|content| content.filename).collect()
+        };
+
+        // An input list's order survives, and the seed picks the same files
+        // whatever that order is
+        let listed = names(sample_contents(roots((0..20).rev().collect()), 5, 7));
+        let mut sorted = names(sample_contents(roots((0..20).collect()), 5, 7));
+        assert_eq!(listed.len(), 5);
+        assert!(listed.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", listed);
+        sorted.reverse();
+        assert_eq!(listed, sorted);
+    }
+
+    #[test]
+    fn test_profile_covers_every_phase_and_module() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input");
+        std::fs::create_dir(&input)?;
+        std::fs::write(input.join("a.bin"), b"a")?;
+        let modules = dir.path().join("modules");
+        std::fs::create_dir(&modules)?;
+        std::fs::write(modules.join("idle.wasm"), file_writing_guest(&[], true, "(i32.const 0)"))?;
+        let config = RunConfig { modules, profile: true, ..RunConfig::default() };
+
+        let contents = load_files(&input)?;
+        let profile = run_with(&config, MetadataStore::new_in_memory("wadup"), contents)?.profile.unwrap();
+        assert_eq!((profile.precompiled_hits, profile.compiled), (0, 1));
+        let table = profile.render();
+        for label in ["load files", "load modules", "processing", "module idle", "0 precompiled, 1 compiled"] {
+            assert!(table.contains(label), "{} missing from:\n{}", label, table);
+        }
+
+        // The cache file written by the first run is used by the next
+        let contents = load_files(&input)?;
+        let profile = run_with(&config, MetadataStore::new_in_memory("wadup"), contents)?.profile.unwrap();
+        assert_eq!((profile.precompiled_hits, profile.compiled), (1, 0));
+
+        let config = RunConfig { profile: false, ..config };
+        assert!(run_with(&config, MetadataStore::new_in_memory("wadup"), Vec::new())?.profile.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_list_loads_listed_files_in_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("z.bin"), b"z")?;
+        std::fs::write(dir.path().join("sub").join("a.bin"), b"aa")?;
+        let list = dir.path().join("inputs.txt");
+        std::fs::write(&list, "# inputs\nz.bin\n\nmissing.bin\nsub/a.bin\tlogical/a.bin\n")?;
+
+        let contents = load_input_list(&list, false)?;
+        let names: Vec<_> = contents.iter().map(|c| c.filename.as_str()).collect();
+        assert_eq!(names, ["z.bin", "logical/a.bin"]);
+        let err = load_input_list(&list, true).unwrap_err();
+        assert!(err.to_string().contains("line 4"), "{err}");
+
+        std::fs::write(dir.path().join("idle.wasm"), file_writing_guest(&[], true, "(i32.const 0)"))?;
+        let config = RunConfig { modules: dir.path().join("idle.wasm"), threads: 1, ..RunConfig::default() };
+        let store = MetadataStore::new_in_memory("wadup");
+        let summary = run_with(&config, store.clone(), contents)?;
+        assert_eq!(summary.processed, 2);
+        let processed: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "content")
+            .map(|d| d.doc["filename"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(processed, ["z.bin", "logical/a.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_failed_reprocesses_only_failed_roots() -> Result<()> {
+        // Only content of 2 bytes or more reaches the module
+        let picky = |body: &str| -> Result<tempfile::TempDir> {
+            let dir = tempfile::tempdir()?;
+            let wat = format!(r#"
+                (module
+                    (import "wadup" "report_error" (func $report_error (param i32 i32 i32) (result i32)))
+                    (memory (export "memory") 1)
+                    (data (i32.const 0) "broken")
+                    (func (export "process") (result i32) {}))
+            "#, body);
+            std::fs::write(dir.path().join("picky.wasm"), wat)?;
+            std::fs::write(dir.path().join("picky.manifest.json"), r#"{"min_size":2}"#)?;
+            Ok(dir)
+        };
+        let broken = picky("(drop (call $report_error (i32.const 1) (i32.const 0) (i32.const 6))) (unreachable)")?;
+        let inputs = vec![
+            ("big.bin".to_string(), Bytes::from_static(b"aa")),
+            ("small.bin".to_string(), Bytes::from_static(b"a")),
+        ];
+        let config = RunConfig { modules: broken.path().to_path_buf(), inputs, ..RunConfig::default() };
+        let store = MetadataStore::new_in_memory("wadup");
+        let first = run_with(&config, store.clone(), Vec::new())?;
+        assert_eq!(first.failed, 1);
+        let failed = store.failed_contents()?;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].filename, "big.bin");
+        let errors = |store: &MetadataStore| -> usize {
+            store.captured_documents().iter()
+                .filter(|d| d.doc["doc_type"] == "error" && d.doc["content_uuid"] == failed[0].content_uuid.as_str())
+                .count()
+        };
+        assert!(errors(&store) > 0);
+
+        // Retry with the module fixed
+        let fixed = picky("(i32.const 0)")?;
+        let retry = RunConfig { modules: fixed.path().to_path_buf(), retry_failed: true, ..config };
+        let summary = run_with(&retry, store.clone(), Vec::new())?;
+        assert_eq!((summary.input_count, summary.processed, summary.failed), (1, 1, 0));
+
+        let latest = store.captured_documents().into_iter()
+            .rev()
+            .find(|d| d.id.as_deref() == Some(failed[0].content_uuid.as_str()))
+            .expect("content document for the retried content");
+        assert_eq!(latest.doc["status"], "success");
+        assert!(latest.doc["error_message"].is_null());
+        assert!(store.failed_contents()?.is_empty());
+        assert_eq!(errors(&store), 0, "the failed attempt's errors are cleared");
+
+        // Failed sub-content can't be reloaded
+        let child = Content::new_root(SharedBuffer::from_vec(b"xx".to_vec()), "child.bin".to_string());
+        store.start_content(&child.uuid.to_string(), "child.bin", Some(&failed[0].content_uuid))?;
+        store.finalize_content_failure(&child.uuid.to_string(), "boom")?;
+        let err = run_with(&retry, store, Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("Can't retry failed sub-content 'child.bin'"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mounted_rules_are_readable_but_not_writable() -> Result<()> {
+        let rules = tempfile::tempdir()?;
+        std::fs::create_dir(rules.path().join("yara"))?;
+        std::fs::write(rules.path().join("yara/a.yar"), "rule a { condition: true }")?;
+        // A symlinked directory looping back up the tree is skipped
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", rules.path().join("yara/loop"))?;
+        let dir = tempfile::tempdir()?;
+        // Copies /rules/yara/a.yar to stdout, then fails if it could open it for truncation
+        std::fs::write(dir.path().join("rules.wasm"), r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "/rules/yara/a.yar")
+                (func (export "process") (result i32)
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 17) (i32.const 0)
+                        (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+                    (i32.store (i32.const 8) (i32.const 2048))
+                    (i32.store (i32.const 12) (i32.const 1024))
+                    (drop (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.store (i32.const 12) (i32.load (i32.const 16)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.eqz (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 17) (i32.const 8)
+                        (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 4)))))
+        "#)?;
+
+        let mount = format!("{}:/rules:ro", rules.path().display());
+        let config = RunConfig {
+            modules: dir.path().to_path_buf(),
+            inputs: vec![
+                ("one.bin".to_string(), Bytes::from_static(b"1")),
+                ("two.bin".to_string(), Bytes::from_static(b"2")),
+            ],
+            threads: 1,
+            mounts: vec![mount.parse()?],
+            ..RunConfig::default()
+        };
+        let store = MetadataStore::new_in_memory("wadup");
+        let summary = run_with(&config, store.clone(), Vec::new())?;
+        assert_eq!((summary.processed, summary.failed), (2, 0));
+        let outputs: Vec<_> = store.captured_documents().into_iter()
+            .filter(|d| d.doc["doc_type"] == "module_output")
+            .map(|d| d.doc["stdout"].as_str().unwrap_or_default().to_string())
+            .collect();
+        assert_eq!(outputs, ["rule a { condition: true }"; 2]);
+
+        assert!("rules:/rules".parse::<Mount>().is_ok());
+        assert!("rules:/rules:rw".parse::<Mount>().unwrap_err().to_string().contains("only 'ro'"));
+        assert!("rules:/tmp/rules:ro".parse::<Mount>().unwrap_err().to_string().contains("/tmp"));
+        assert!("rules:relative:ro".parse::<Mount>().is_err());
+        Ok(())
+    }
+
+    /// A zip holding one stored (uncompressed) member
+    fn stored_zip(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut zip = b"PK\x03\x04\x0a\x00\x00\x00\x00\x00".to_vec();
+        zip.extend_from_slice(&[0; 8]); // time, date, crc-32 (unchecked here)
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data);
+        zip
+    }
+
+    #[test]
+    fn test_in_memory_zip_input_is_extracted() -> Result<()> {
+        let zip = stored_zip("hello.txt", b"hello world");
+        let dir = tempfile::tempdir()?;
+        // Stands in for an extractor: slices the member out of the zip
+        let member = format!(r#"{{"filename":"hello.txt","offset":{},"length":11}}"#, 30 + "hello.txt".len());
+        std::fs::write(
+            dir.path().join("unzip.wasm"),
+            file_writing_guest(&[("/subcontent/metadata_0.json", &member)], true, "(i32.const 0)"),
+        )?;
+        std::fs::write(dir.path().join("unzip.manifest.json"), r#"{"content_types":["application/zip"]}"#)?;
+        // Copies /data.bin to stdout, so each content's bytes can be checked
+        std::fs::write(dir.path().join("cat.wasm"), r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "/data.bin")
+                (func (export "process") (result i32)
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 9) (i32.const 0)
+                        (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))
+                    (i32.store (i32.const 8) (i32.const 2048))
+                    (i32.store (i32.const 12) (i32.const 1024))
+                    (drop (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.store (i32.const 12) (i32.load (i32.const 16)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.const 0)))
+        "#)?;
+
+        let config = RunConfig {
+            modules: dir.path().to_path_buf(),
+            inputs: vec![("archive.zip".to_string(), Bytes::from(zip.clone()))],
+            ..RunConfig::default()
+        };
+        let store = MetadataStore::new_in_memory("wadup");
+        let summary = run_with(&config, store.clone(), Vec::new())?;
+        assert_eq!(summary.input_count, 1);
+        assert_eq!(summary.processed, 2, "the zip and its member");
+
+        let docs = store.captured_documents();
+        let root = docs.iter()
+            .find(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "archive.zip")
+            .expect("root content document");
+        let child = docs.iter()
+            .find(|d| d.doc["doc_type"] == "content" && d.doc["filename"] == "hello.txt")
+            .expect("extracted member");
+        assert_eq!(child.doc["parent_uuid"], root.doc["content_uuid"]);
+        let stdout_of = |uuid: &serde_json::Value| docs.iter()
+            .find(|d| d.doc["doc_type"] == "module_output" && d.doc["content_uuid"] == *uuid)
+            .map(|d| d.doc["stdout"].as_str().unwrap_or_default().to_string());
+        assert_eq!(stdout_of(&child.doc["content_uuid"]).as_deref(), Some("hello world"));
+        assert_eq!(stdout_of(&root.doc["content_uuid"]).unwrap().as_bytes(), &zip[..]);
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Values modules read only through the `get_secret` host function
+//! (`--secret`, `--secrets-file`), never through the environment or
+//! arguments, so they don't appear in environment dumps or logs.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A named secret. The value is zeroed when dropped and `Debug` leaves it out.
+#[derive(Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Secret {
+    pub name: String,
+    value: Zeroizing<String>,
+}
+
+impl Secret {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self { name: name.into(), value: Zeroizing::new(value.into()) }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Read `NAME=VALUE` lines from `path`, skipping blank lines and lines
+    /// starting with `#`. Whitespace around each line is trimmed.
+    pub fn load_file(path: &Path) -> Result<Vec<Self>> {
+        let text = Zeroizing::new(
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read secrets file {:?}", path))?,
+        );
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .enumerate()
+            .map(|(index, line)| {
+                Self::parse(line).with_context(|| format!("Invalid secret {} in {:?}", index + 1, path))
+            })
+            .collect()
+    }
+
+    fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok(Self::new(name, value)),
+            // The value isn't echoed back, even when the line is invalid
+            _ => anyhow::bail!("expected NAME=VALUE"),
+        }
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secret").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s).context("Invalid secret")
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = anyhow::Error;
+
+    fn try_from(mut s: String) -> Result<Self> {
+        let secret = s.parse();
+        s.zeroize();
+        secret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_parse_and_hide_their_value() {
+        let secret: Secret = "API_KEY=a=b".parse().unwrap();
+        assert_eq!((secret.name.as_str(), secret.value()), ("API_KEY", "a=b"));
+        assert!(!format!("{:?}", secret).contains("a=b"));
+        assert!("=value".parse::<Secret>().is_err());
+        assert!("no-separator".parse::<Secret>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets");
+        std::fs::write(&path, "# comment\nA=1\n\n B=two \n").unwrap();
+        let secrets = Secret::load_file(&path).unwrap();
+        let pairs: Vec<_> = secrets.iter().map(|s| (s.name.as_str(), s.value())).collect();
+        assert_eq!(pairs, [("A", "1"), ("B", "two")]);
+    }
+}
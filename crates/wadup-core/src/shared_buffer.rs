@@ -6,8 +6,8 @@ use anyhow::Result;
 /// Unified abstraction over memory-mapped and in-memory data
 ///
 /// This type provides zero-copy slicing and efficient sharing of content data.
-/// Files are memory-mapped then immediately converted to Bytes for consistent
-/// zero-copy operations throughout the processing pipeline.
+/// Files are memory-mapped and the mapping itself backs the Bytes, so file
+/// content is never copied on its way through the processing pipeline.
 #[derive(Clone, Debug)]
 pub struct SharedBuffer {
     data: Bytes,
@@ -16,14 +16,14 @@ pub struct SharedBuffer {
 impl SharedBuffer {
     /// Create from file via memory mapping
     ///
-    /// The file is memory-mapped and immediately converted to Bytes.
-    /// This involves one copy from the memory-mapped region to Bytes,
-    /// but enables all subsequent operations to be zero-copy.
+    /// The Mmap is moved into the Bytes as its owner, so the mapping stays
+    /// alive for as long as any clone or slice of the buffer does. No copy
+    /// is made; pages are faulted in lazily as they are read.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = std::fs::File::open(path)?;
+        // SAFETY: input files are treated as immutable for the duration of a run.
         let mmap = unsafe { Mmap::map(&file)? };
-        // Convert to Bytes (one copy, but enables zero-copy slicing downstream)
-        let data = Bytes::copy_from_slice(&mmap[..]);
+        let data = Bytes::from_owner(mmap);
         Ok(Self { data })
     }
 
@@ -114,6 +114,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_from_file_large() -> Result<()> {
+        const SIZE: usize = 100 * 1024 * 1024;
+
+        let mut temp_file = NamedTempFile::new()?;
+        let chunk: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        for _ in 0..SIZE / chunk.len() {
+            temp_file.write_all(&chunk)?;
+        }
+        temp_file.flush()?;
+
+        let buffer = SharedBuffer::from_file(temp_file.path())?;
+        assert_eq!(buffer.len(), SIZE);
+        assert_eq!(&buffer.as_slice()[..chunk.len()], &chunk[..]);
+        assert_eq!(&buffer.as_slice()[SIZE - chunk.len()..], &chunk[..]);
+
+        // Slices keep the mapping alive after the original buffer is dropped
+        let tail = buffer.slice(SIZE - 16..SIZE);
+        drop(buffer);
+        assert_eq!(tail.as_slice(), &chunk[chunk.len() - 16..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_file_empty() -> Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        let buffer = SharedBuffer::from_file(temp_file.path())?;
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_zero_copy_slice() {
         let buffer = SharedBuffer::from_vec(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
@@ -0,0 +1,75 @@
+//! Global allocator for unit tests that counts the bytes each thread has
+//! allocated, so a test can measure what a call allocates while other tests
+//! run on other threads.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+struct Counting;
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Add `delta` bytes to this thread's count. A thread's count can go
+/// negative when it frees memory another thread allocated.
+fn record(delta: isize) {
+    // Thread-locals are gone while a thread is torn down
+    let _ = LIVE.try_with(|live| {
+        live.set(live.get() + delta);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+    });
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+/// Bytes `f` allocated on the calling thread
+pub(crate) struct Allocated {
+    /// Most bytes held at once while it ran
+    pub peak: usize,
+    /// Bytes still held when it returned
+    pub retained: usize,
+}
+
+/// Run `f` and report how much it allocated on the calling thread
+pub(crate) fn measure<R>(f: impl FnOnce() -> R) -> (R, Allocated) {
+    let start = LIVE.with(Cell::get);
+    PEAK.with(|peak| peak.set(start));
+    let result = f();
+    let peak = PEAK.with(Cell::get) - start;
+    let retained = LIVE.with(Cell::get) - start;
+    (result, Allocated { peak: peak.max(0) as usize, retained: retained.max(0) as usize })
+}
@@ -0,0 +1,51 @@
+//! Inline WAT guests shared by unit tests.
+
+/// Build a guest whose `process` writes each `(path, contents)` pair to a file
+/// and then runs `finish` (e.g. `(i32.const 0)` or `(unreachable)`).
+/// Files are closed after writing unless `close` is false.
+pub(crate) fn file_writing_guest(files: &[(&str, &str)], close: bool, finish: &str) -> String {
+    file_writing_guest_with_imports(files, close, "", finish)
+}
+
+/// Like `file_writing_guest`, with extra `imports` (e.g. WADUP host functions)
+/// available to `finish`.
+pub(crate) fn file_writing_guest_with_imports(files: &[(&str, &str)], close: bool, imports: &str, finish: &str) -> String {
+    let mut data = String::new();
+    let mut body = String::new();
+    let mut offset = 1024;
+    for (path, contents) in files {
+        let (path_ptr, contents_ptr) = (offset, offset + path.len());
+        offset = contents_ptr + contents.len();
+        let escape = |s: &str| s.bytes().map(|b| format!("\\{:02x}", b)).collect::<String>();
+        data.push_str(&format!(
+            "(data (i32.const {}) \"{}{}\")\n",
+            path_ptr, escape(path), escape(contents)
+        ));
+        // path_open(O_CREAT | O_TRUNC) -> fd at 0, iovec at 8, nwritten at 16
+        body.push_str(&format!(
+            "(if (call $path_open (i32.const 3) (i32.const 0) (i32.const {}) (i32.const {}) (i32.const 9) \
+                (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)) (then (return (i32.const 1))))
+             (i32.store (i32.const 8) (i32.const {}))
+             (i32.store (i32.const 12) (i32.const {}))
+             (drop (call $fd_write (i32.load (i32.const 0)) (i32.const 8) (i32.const 1) (i32.const 16)))\n",
+            path_ptr, path.len(), contents_ptr, contents.len()
+        ));
+        if close {
+            body.push_str("(drop (call $fd_close (i32.load (i32.const 0))))\n");
+        }
+    }
+    format!(r#"
+        (module
+            (import "wasi_snapshot_preview1" "path_open"
+                (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+            {}
+            (memory (export "memory") 1)
+            {}
+            (func (export "process") (result i32)
+                {}
+                {}))
+    "#, imports, data, body, finish)
+}
@@ -1,9 +1,15 @@
 //! Test output types for the `wadup test` subcommand.
 //!
 //! These types define the JSON output format that matches the Python test_runner.py
-//! for compatibility with WADUP Web.
+//! for compatibility with WADUP Web. `run_test` produces them from a built module,
+//! so tests can check a module's output without going through the CLI.
 
+use anyhow::Result;
 use serde::Serialize;
+use std::path::Path;
+
+/// Default number of subcontent bytes rendered into `data_hex` (4KB).
+pub const DEFAULT_MAX_HEX_BYTES: usize = 4096;
 
 /// Output from running a single module test.
 #[derive(Debug, Serialize)]
@@ -29,6 +35,10 @@ pub struct TestOutput {
 
     /// Extracted subcontent files from /subcontent/.
     pub subcontent: Option<Vec<SubcontentOutput>>,
+
+    /// MIME type the host detected from the sample's magic bytes, also
+    /// passed to the module as `WADUP_CONTENT_TYPE`.
+    pub detected_content_type: Option<String>,
 }
 
 /// A single extracted subcontent file.
@@ -40,13 +50,13 @@ pub struct SubcontentOutput {
     /// Original filename from metadata_N.json.
     pub filename: Option<String>,
 
-    /// Binary data as hex string (truncated to 4KB).
+    /// Binary data as hex string (truncated to the configured maximum, 4KB by default).
     pub data_hex: String,
 
     /// Actual file size in bytes.
     pub size: usize,
 
-    /// Whether the data was truncated (file > 4KB).
+    /// Whether the data was truncated (file larger than the configured maximum).
     pub truncated: bool,
 
     /// Full metadata from metadata_N.json.
@@ -69,6 +79,7 @@ impl TestOutput {
             exit_code: 0,
             metadata,
             subcontent,
+            detected_content_type: None,
         }
     }
 
@@ -88,6 +99,93 @@ impl TestOutput {
             exit_code,
             metadata: None,
             subcontent,
+            detected_content_type: None,
         }
     }
 }
+
+/// Run the module at `module` once on the file `sample`, as `wadup test` does.
+///
+/// The module sees `filename` as `WADUP_FILENAME`. Errors are only returned
+/// when the module or sample can't be loaded; a module that fails on the
+/// sample gives a `TestOutput` with `success` false.
+pub fn run_test(
+    module: &Path,
+    sample: &Path,
+    filename: &str,
+    limits: &crate::wasm::ResourceLimits,
+    entrypoint: &str,
+    max_hex_bytes: usize,
+) -> Result<TestOutput> {
+    if !module.exists() {
+        anyhow::bail!("Module not found: {:?}", module);
+    }
+    if !sample.exists() {
+        anyhow::bail!("Sample not found: {:?}", sample);
+    }
+
+    let engine = crate::wasm::WasmRuntime::build_engine(limits, false)?;
+    let wasm_module = crate::precompile::load_module_with_cache(&engine, module)?;
+    let module_name = module.file_stem().and_then(|s| s.to_str()).unwrap_or("module");
+    let sample_data = crate::shared_buffer::SharedBuffer::from_file(sample)?;
+
+    // WADUP_CONTENT_TYPE is set by process_content_for_test
+    let env_vars = vec![("WADUP_FILENAME".to_string(), filename.to_string())];
+    let mut instance =
+        crate::wasm::ModuleInstance::with_env_vars(&engine, &wasm_module, module_name, limits, env_vars)?;
+    instance.set_entrypoint(entrypoint);
+    Ok(instance.process_content_for_test(sample_data, max_hex_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::ResourceLimits;
+    use std::path::PathBuf;
+
+    fn repo_root() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../..")
+    }
+
+    /// The `examples/<name>` module, rebuilt with cargo first (a no-op when
+    /// it is up to date) so the test never runs a stale build. Fails the
+    /// test when it doesn't build, e.g. without the wasm32-wasip1 target.
+    fn example_module(name: &str) -> PathBuf {
+        let dir = repo_root().join("examples").join(name);
+        let built = std::process::Command::new(env!("CARGO"))
+            .args(["build", "--release", "--quiet", "--target", "wasm32-wasip1", "--manifest-path"])
+            .arg(dir.join("Cargo.toml"))
+            .status()
+            .is_ok_and(|status| status.success());
+        assert!(built, "examples/{} doesn't build (is the wasm32-wasip1 target installed?)", name);
+        dir.join("target/wasm32-wasip1/release").join(format!("{}.wasm", name.replace('-', "_")))
+    }
+
+    fn run_example(name: &str, fixture: &str) -> TestOutput {
+        let module = example_module(name);
+        let sample = repo_root().join("tests/fixtures").join(fixture);
+        let limits = ResourceLimits { fuel: Some(100_000_000), max_memory: None, max_stack: None };
+        run_test(&module, &sample, fixture, &limits, "process", DEFAULT_MAX_HEX_BYTES).unwrap()
+    }
+
+    #[test]
+    fn test_byte_counter_records_the_sample_size() {
+        let output = run_example("byte-counter", "test1.txt");
+        assert!(output.success, "{:?}", output.error);
+        let metadata = output.metadata.unwrap();
+        assert_eq!(metadata["tables"][0]["name"], "file_sizes");
+        assert_eq!(metadata["rows"][0]["table_name"], "file_sizes");
+        assert_eq!(metadata["rows"][0]["values"][0]["Int64"], 97);
+    }
+
+    #[test]
+    fn test_zip_extractor_emits_each_entry() {
+        let output = run_example("zip-extractor", "test.zip");
+        assert!(output.success, "{:?}", output.error);
+        assert_eq!(output.detected_content_type.as_deref(), Some("application/zip"));
+        let entries: Vec<_> = output.subcontent.unwrap().into_iter()
+            .map(|sub| (sub.filename.unwrap(), sub.size, sub.truncated))
+            .collect();
+        assert_eq!(entries, [("file1.txt".to_string(), 40, false), ("file2.txt".to_string(), 53, false)]);
+    }
+}
@@ -1,13 +1,19 @@
-use crate::memory_fs::{MemoryFilesystem, MemoryFile, MemoryDirectory};
-use std::collections::HashMap;
+use crate::memory_fs::{self, MemoryFilesystem, MemoryFile, MemoryDirectory};
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use parking_lot::{RwLock, Mutex};
 use std::io::{Read, Write, Seek, SeekFrom};
 
 /// Maximum bytes to capture from stdout/stderr per content (1 MB)
 const MAX_CAPTURE_BYTES: usize = 1024 * 1024;
 
+/// fdflags bit: writes always go to the end of the file
+const FDFLAGS_APPEND: u16 = 1;
+
+/// lookupflags bit: follow a trailing symlink. The only lookupflag WASI defines
+const LOOKUPFLAGS_SYMLINK_FOLLOW: u32 = 1;
+
 /// File descriptor
 type Fd = u32;
 
@@ -35,28 +41,93 @@ pub enum Errno {
     Again = 6,
     Badf = 8,
     Exist = 20,
+    Fault = 21,
+    Ilseq = 25,
     Inval = 28,
     Io = 29,
     Isdir = 31,
+    Mfile = 33,
+    Msgsize = 35,
+    Nametoolong = 37,
     Noent = 44,
+    Nospc = 51,
     Notdir = 54,
     Nosys = 52,
+    Notsup = 58,
+    Rofs = 69,
+    Spipe = 70,
+}
+
+impl Errno {
+    /// The POSIX name, e.g. `ENOENT`, as logged and counted by `--trace-wasi`
+    pub fn name(self) -> &'static str {
+        match self {
+            Errno::Success => "ESUCCESS",
+            Errno::TooBig => "E2BIG",
+            Errno::Acces => "EACCES",
+            Errno::Again => "EAGAIN",
+            Errno::Badf => "EBADF",
+            Errno::Exist => "EEXIST",
+            Errno::Fault => "EFAULT",
+            Errno::Ilseq => "EILSEQ",
+            Errno::Inval => "EINVAL",
+            Errno::Io => "EIO",
+            Errno::Isdir => "EISDIR",
+            Errno::Mfile => "EMFILE",
+            Errno::Msgsize => "EMSGSIZE",
+            Errno::Nametoolong => "ENAMETOOLONG",
+            Errno::Noent => "ENOENT",
+            Errno::Nospc => "ENOSPC",
+            Errno::Notdir => "ENOTDIR",
+            Errno::Nosys => "ENOSYS",
+            Errno::Notsup => "ENOTSUP",
+            Errno::Rofs => "EROFS",
+            Errno::Spipe => "ESPIPE",
+        }
+    }
+
+    /// Map an I/O error from the in-memory filesystem to the closest WASI errno
+    pub fn from_io_error(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match e.kind() {
+            ErrorKind::PermissionDenied => Errno::Acces,
+            ErrorKind::StorageFull | ErrorKind::FileTooLarge => Errno::Nospc,
+            ErrorKind::NotFound => Errno::Noent,
+            ErrorKind::AlreadyExists => Errno::Exist,
+            ErrorKind::InvalidInput => Errno::Inval,
+            _ => Errno::Io,
+        }
+    }
 }
 
 /// Open file handle
 enum FileHandle {
-    File(MemoryFile, Option<String>), // file + optional path for tracking
-    Directory(MemoryDirectory, usize), // directory + readdir position
+    File(MemoryFile, String, u16), // file + normalized path + fdflags
+    Directory(MemoryDirectory, String, DirListing), // directory + normalized path + fd_readdir listing
     Stdin,
     Stdout,
     Stderr,
 }
 
+/// Sorted entries of an open directory, cached by `fd_readdir` so resuming
+/// from a cookie indexes the same listing instead of re-listing. Reading
+/// from cookie 0 takes a fresh listing.
+type DirListing = Mutex<Option<Vec<(String, bool)>>>;
+
+#[cfg(test)]
+thread_local! {
+    /// Directory listings taken by `fd_readdir` on this thread
+    static DIR_LISTINGS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /// Sub-content emission data (paired data + metadata files, or slice reference)
 pub struct SubcontentEmission {
     pub filename: String,
     /// The sub-content data - either owned bytes or a slice reference
     pub data: SubcontentEmissionData,
+    /// Optional label for the parent -> child relationship (e.g. "zip_member")
+    pub relation: Option<String>,
 }
 
 /// Data for a sub-content emission
@@ -67,10 +138,62 @@ pub enum SubcontentEmissionData {
     Slice { offset: usize, length: usize },
 }
 
+/// Format of a file written to `/metadata/`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MetadataFormat {
+    /// `*.json` - a single `{ "tables": [...], "rows": [...] }` object
+    Json,
+    /// `*.jsonl` - one such object per line, for modules that stream metadata
+    JsonLines,
+    /// `*.bin` - packed records, see `metadata_binary`
+    Binary,
+}
+
+impl MetadataFormat {
+    /// Determine the metadata format from a file name, if it is a metadata file
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path.ends_with(".json") {
+            Some(Self::Json)
+        } else if path.ends_with(".jsonl") {
+            Some(Self::JsonLines)
+        } else if path.ends_with(".bin") {
+            Some(Self::Binary)
+        } else {
+            None
+        }
+    }
+}
+
+/// Raw contents of a closed metadata file
+pub struct MetadataContent {
+    /// Where the module wrote the file, e.g. `/metadata/output_0.json`
+    pub path: String,
+    pub format: MetadataFormat,
+    pub data: bytes::Bytes,
+}
+
 /// Result of closing a file - may contain metadata or subcontent if it was a special file
+#[derive(Default)]
 pub struct CloseResult {
-    pub metadata_content: Option<Vec<u8>>,
+    pub metadata_content: Option<MetadataContent>,
     pub subcontent_emission: Option<SubcontentEmission>,
+    /// Why a closed sub-content metadata file emitted nothing
+    pub rejected_subcontent: Option<String>,
+    /// The closed `/subcontent/metadata_N.json` file as the module wrote it,
+    /// whether or not it emitted anything
+    pub subcontent_metadata: Option<MetadataContent>,
+}
+
+/// Size of the fixed part of a WASI dirent (d_next, d_ino, d_namlen, d_type, padding)
+const DIRENT_SIZE: usize = 24;
+
+/// Synthetic inode number: FNV-1a hash of the normalized path, never zero.
+/// Stable across runs so a path always reports the same `ino`.
+fn inode_for_path(path: &str) -> u64 {
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash.max(1)
 }
 
 /// WASI context with in-memory filesystem
@@ -78,8 +201,15 @@ pub struct WasiCtx {
     pub filesystem: Arc<MemoryFilesystem>,
     file_table: Arc<RwLock<HashMap<Fd, FileHandle>>>,
     next_fd: Arc<RwLock<Fd>>,
+    /// Closed fd numbers, handed out again lowest first
+    free_fds: Mutex<BTreeSet<Fd>>,
+    /// Most files and directories open at once (`--max-open-files`), not
+    /// counting stdio and the preopened root
+    max_open_files: Option<usize>,
     /// Environment variables
     env_vars: Vec<(String, String)>,
+    /// Command line arguments, `argv[0]` first
+    args: Vec<String>,
     /// Captured stdout output
     stdout_capture: Mutex<Vec<u8>>,
     /// Captured stderr output
@@ -88,6 +218,10 @@ pub struct WasiCtx {
     stdout_truncated: AtomicBool,
     /// Whether stderr was truncated due to size limit
     stderr_truncated: AtomicBool,
+    /// Last value returned by `now_ns`, so the clock never goes backwards
+    last_clock_ns: AtomicU64,
+    /// Refuse creating, truncating and writing files (stdout/stderr still work)
+    read_only: bool,
 }
 
 impl WasiCtx {
@@ -104,17 +238,22 @@ impl WasiCtx {
         file_table.insert(1, FileHandle::Stdout);
         file_table.insert(2, FileHandle::Stderr);
         // FD 3 is reserved for the preopened root directory
-        file_table.insert(3, FileHandle::Directory(filesystem.root().clone(), 0));
+        file_table.insert(3, FileHandle::Directory(filesystem.root().clone(), "/".to_string(), DirListing::default()));
 
         Self {
             filesystem,
             file_table: Arc::new(RwLock::new(file_table)),
             next_fd: Arc::new(RwLock::new(4)),
+            free_fds: Mutex::new(BTreeSet::new()),
+            max_open_files: None,
             env_vars,
+            args: Vec::new(),
             stdout_capture: Mutex::new(Vec::new()),
             stderr_capture: Mutex::new(Vec::new()),
             stdout_truncated: AtomicBool::new(false),
             stderr_truncated: AtomicBool::new(false),
+            last_clock_ns: AtomicU64::new(0),
+            read_only: false,
         }
     }
 
@@ -134,26 +273,100 @@ impl WasiCtx {
             .collect()
     }
 
+    /// Set the command line arguments, `argv[0]` first.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// Get the number of arguments and total buffer size needed.
+    pub fn args_sizes(&self) -> (usize, usize) {
+        (self.args.len(), self.args.iter().map(|a| a.len() + 1).sum())
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Refuse `path_open` with `O_CREAT` or `O_TRUNC` and writes to files
+    /// with `ERofs`; stdout and stderr stay writable.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// The environment the module sees, in order
+    pub fn env_vars(&self) -> &[(String, String)] {
+        &self.env_vars
+    }
+
+    /// Set an environment variable, replacing an existing value, or remove
+    /// it when `value` is None.
+    pub fn set_env_var(&mut self, key: &str, value: Option<&str>) {
+        self.env_vars.retain(|(k, _)| k != key);
+        if let Some(value) = value {
+            self.env_vars.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    /// Make `path_open` fail with `EMfile` while `max` files and
+    /// directories are open; unlimited when `None`.
+    pub fn set_max_open_files(&mut self, max: Option<usize>) {
+        self.max_open_files = max;
+    }
+
     fn allocate_fd(&self) -> Fd {
+        if let Some(fd) = self.free_fds.lock().pop_first() {
+            return fd;
+        }
         let mut next = self.next_fd.write();
         let fd = *next;
         *next += 1;
         fd
     }
 
-    /// Check if a path should be tracked for special handling on close
-    fn should_track_path(path: &str) -> Option<String> {
-        if path.starts_with("/metadata/") && path.ends_with(".json") {
-            Some(path.to_string())
-        } else if path.starts_with("/subcontent/metadata_") && path.ends_with(".json") {
-            Some(path.to_string())
-        } else if path.starts_with("/subcontent/data_") {
-            Some(path.to_string())
-        } else {
-            None
+    /// Make a closed fd number available to `allocate_fd` again
+    fn release_fd(&self, fd: Fd) {
+        if fd >= 4 {
+            self.free_fds.lock().insert(fd);
         }
     }
 
+    fn open_file_limit_reached(&self) -> bool {
+        self.max_open_files.is_some_and(|max| {
+            self.file_table.read().keys().filter(|&&fd| fd >= 4).count() >= max
+        })
+    }
+
+    /// Current wall-clock time in nanoseconds since the Unix epoch.
+    ///
+    /// Never returns less than a previous call, so guests see monotonic
+    /// clock readings and file timestamps even if the host clock steps back.
+    pub fn now_ns(&self) -> u64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let prev = self.last_clock_ns.fetch_max(now, Ordering::Relaxed);
+        prev.max(now)
+    }
+
+    /// Fill a WASI filestat. Layout (little endian, 64 bytes):
+    /// dev 0..8, ino 8..16, filetype 16, nlink 24..32, size 32..40,
+    /// atim 40..48, mtim 48..56, ctim 56..64.
+    ///
+    /// The inode is a stable hash of the normalized path and nlink is always 1;
+    /// all three timestamps are the current store clock reading.
+    fn write_filestat(&self, filestat: &mut [u8; 64], path: &str, filetype: Filetype, size: u64) {
+        let now = self.now_ns();
+        filestat.fill(0);
+        filestat[8..16].copy_from_slice(&inode_for_path(path).to_le_bytes());
+        filestat[16] = filetype as u8;
+        filestat[24..32].copy_from_slice(&1u64.to_le_bytes());
+        filestat[32..40].copy_from_slice(&size.to_le_bytes());
+        filestat[40..48].copy_from_slice(&now.to_le_bytes());
+        filestat[48..56].copy_from_slice(&now.to_le_bytes());
+        filestat[56..64].copy_from_slice(&now.to_le_bytes());
+    }
+
     /// path_open - Open a file or directory
     ///
     /// oflags bits:
@@ -161,6 +374,9 @@ impl WasiCtx {
     /// - bit 1: O_DIRECTORY - expect a directory
     /// - bit 2: O_EXCL - error if file exists when O_CREAT is set
     /// - bit 3: O_TRUNC - truncate file to 0 on open
+    ///
+    /// fdflags are kept on file handles; see `fd_fdstat_set_flags`.
+    #[allow(clippy::too_many_arguments)]
     pub fn path_open(
         &self,
         dirfd: Fd,
@@ -169,21 +385,26 @@ impl WasiCtx {
         oflags: u16,
         _fs_rights_base: u64,
         _fs_rights_inheriting: u64,
-        _fdflags: u16,
+        fdflags: u16,
         fd_out: &mut Fd,
     ) -> Errno {
-        // For now, only support opening from root directory (FD 3)
-        if dirfd != 3 {
-            return Errno::Badf;
-        }
-
         let o_creat = (oflags & 1) != 0;
         let o_directory = (oflags & 2) != 0;
         let o_excl = (oflags & 4) != 0;
         let o_trunc = (oflags & 8) != 0;
+        if self.read_only && (o_creat || o_trunc) {
+            return Errno::Rofs;
+        }
+        if self.open_file_limit_reached() {
+            return Errno::Mfile;
+        }
 
-        // Normalize the path for tracking
-        let normalized_path = format!("/{}", path.trim_start_matches('/'));
+        // Resolve the path against the directory fd, rejecting `..` components
+        let normalized_path = match self.resolve_at(dirfd, path) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        let path = normalized_path.as_str();
 
         // If O_DIRECTORY is set, only open as directory
         if o_directory {
@@ -195,7 +416,7 @@ impl WasiCtx {
             match parent_dir.get_dir(&filename) {
                 Ok(dir) => {
                     let new_fd = self.allocate_fd();
-                    self.file_table.write().insert(new_fd, FileHandle::Directory(dir, 0));
+                    self.file_table.write().insert(new_fd, FileHandle::Directory(dir, normalized_path, DirListing::default()));
                     *fd_out = new_fd;
                     Errno::Success
                 }
@@ -210,15 +431,11 @@ impl WasiCtx {
                         return Errno::Exist;
                     }
                     // Truncate file if O_TRUNC is set
-                    if o_trunc {
-                        if file.truncate().is_err() {
-                            return Errno::Io;
-                        }
+                    if o_trunc && file.truncate().is_err() {
+                        return Errno::Io;
                     }
                     let new_fd = self.allocate_fd();
-                    // Track path for metadata and subcontent files
-                    let track_path = Self::should_track_path(&normalized_path);
-                    self.file_table.write().insert(new_fd, FileHandle::File(file, track_path));
+                    self.file_table.write().insert(new_fd, FileHandle::File(file, normalized_path, fdflags));
                     *fd_out = new_fd;
                     Errno::Success
                 }
@@ -231,9 +448,7 @@ impl WasiCtx {
                                 match self.filesystem.open_file(path) {
                                     Ok(file) => {
                                         let new_fd = self.allocate_fd();
-                                        // Track path for metadata and subcontent files
-                                        let track_path = Self::should_track_path(&normalized_path);
-                                        self.file_table.write().insert(new_fd, FileHandle::File(file, track_path));
+                                        self.file_table.write().insert(new_fd, FileHandle::File(file, normalized_path, fdflags));
                                         *fd_out = new_fd;
                                         Errno::Success
                                     }
@@ -252,7 +467,7 @@ impl WasiCtx {
                         match parent_dir.get_dir(&filename) {
                             Ok(dir) => {
                                 let new_fd = self.allocate_fd();
-                                self.file_table.write().insert(new_fd, FileHandle::Directory(dir, 0));
+                                self.file_table.write().insert(new_fd, FileHandle::Directory(dir, normalized_path, DirListing::default()));
                                 *fd_out = new_fd;
                                 Errno::Success
                             }
@@ -264,7 +479,62 @@ impl WasiCtx {
         }
     }
 
+    /// fd_fdstat_get - Write the 24-byte fdstat (filetype, fdflags, rights)
+    pub fn fd_fdstat_get(&self, fd: Fd, fdstat: &mut [u8; 24]) -> Errno {
+        let file_table = self.file_table.read();
+        let (filetype, fdflags) = match file_table.get(&fd) {
+            Some(FileHandle::File(_, _, fdflags)) => (Filetype::RegularFile, *fdflags),
+            Some(FileHandle::Directory(..)) => (Filetype::Directory, 0),
+            Some(FileHandle::Stdin | FileHandle::Stdout | FileHandle::Stderr) => (Filetype::CharacterDevice, 0),
+            None => return Errno::Badf,
+        };
+
+        fdstat.fill(0);
+        fdstat[0] = filetype as u8;
+        fdstat[2..4].copy_from_slice(&fdflags.to_le_bytes());
+        // All rights, base and inheriting
+        fdstat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        fdstat[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        Errno::Success
+    }
+
+    /// fd_fdstat_set_flags - Replace a file's fdflags.
+    ///
+    /// APPEND makes every later write go to the end of the file. The other
+    /// flags (DSYNC, NONBLOCK, RSYNC, SYNC) are stored and reported back but
+    /// change nothing, since in-memory files never block or need syncing.
+    /// Directories and stdio have no flags to store, so setting any returns
+    /// Notsup.
+    pub fn fd_fdstat_set_flags(&self, fd: Fd, flags: u16) -> Errno {
+        match self.file_table.write().get_mut(&fd) {
+            Some(FileHandle::File(_, _, fdflags)) => {
+                *fdflags = flags;
+                Errno::Success
+            }
+            Some(_) if flags == 0 => Errno::Success,
+            Some(_) => Errno::Notsup,
+            None => Errno::Badf,
+        }
+    }
+
+    /// fd_filestat_set_size - Truncate or zero-extend an open file
+    pub fn fd_filestat_set_size(&self, fd: Fd, size: u64) -> Errno {
+        match self.file_table.read().get(&fd) {
+            Some(FileHandle::File(..)) if self.read_only => Errno::Rofs,
+            Some(FileHandle::File(file, _, _)) => match file.set_len(size) {
+                Ok(()) => Errno::Success,
+                Err(e) => Errno::from_io_error(&e),
+            },
+            Some(FileHandle::Directory(..)) => Errno::Isdir,
+            Some(_) => Errno::Inval,
+            None => Errno::Badf,
+        }
+    }
+
     /// fd_read - Read from file descriptor
+    ///
+    /// Buffers are filled in order, like POSIX `readv`: reading stops at the
+    /// first buffer that is not filled completely.
     pub fn fd_read(&self, fd: Fd, bufs: &mut [&mut [u8]], nread_out: &mut usize) -> Errno {
         let mut file_table = self.file_table.write();
 
@@ -274,12 +544,17 @@ impl WasiCtx {
         };
 
         match handle {
-            FileHandle::File(ref mut file, _) => {
+            FileHandle::File(ref mut file, _, _) => {
                 let mut total = 0;
                 for buf in bufs {
                     match file.read(buf) {
-                        Ok(n) => total += n,
-                        Err(_) => return Errno::Io,
+                        Ok(n) => {
+                            total += n;
+                            if n < buf.len() {
+                                break;
+                            }
+                        }
+                        Err(e) => return Errno::from_io_error(&e),
                     }
                 }
                 *nread_out = total;
@@ -290,10 +565,40 @@ impl WasiCtx {
                 *nread_out = 0;
                 Errno::Success
             }
+            FileHandle::Directory(..) => Errno::Isdir,
             _ => Errno::Badf,
         }
     }
 
+    /// fd_pread - Read from file descriptor at `offset` without moving its position
+    pub fn fd_pread(&self, fd: Fd, bufs: &mut [&mut [u8]], offset: u64, nread_out: &mut usize) -> Errno {
+        let mut current_pos = 0u64;
+        {
+            let file_table = self.file_table.read();
+            match file_table.get(&fd) {
+                Some(FileHandle::File(..)) => {}
+                Some(FileHandle::Directory(..)) => return Errno::Isdir,
+                Some(_) => return Errno::Spipe,
+                None => return Errno::Badf,
+            }
+        }
+
+        let Ok(offset) = i64::try_from(offset) else {
+            return Errno::Inval;
+        };
+        let errno = self.fd_seek(fd, 0, 1, &mut current_pos);
+        if errno != Errno::Success {
+            return errno;
+        }
+        let errno = self.fd_seek(fd, offset, 0, &mut 0);
+        if errno != Errno::Success {
+            return errno;
+        }
+        let read_errno = self.fd_read(fd, bufs, nread_out);
+        let _ = self.fd_seek(fd, current_pos as i64, 0, &mut 0);
+        read_errno
+    }
+
     /// fd_write - Write to file descriptor
     pub fn fd_write(&self, fd: Fd, bufs: &[&[u8]], nwritten_out: &mut usize) -> Errno {
         let mut file_table = self.file_table.write();
@@ -304,12 +609,18 @@ impl WasiCtx {
         };
 
         match handle {
-            FileHandle::File(ref mut file, _) => {
+            FileHandle::File(..) if self.read_only => Errno::Rofs,
+            FileHandle::File(ref mut file, _, fdflags) => {
+                if *fdflags & FDFLAGS_APPEND != 0 {
+                    if let Err(e) = file.seek(SeekFrom::End(0)) {
+                        return Errno::from_io_error(&e);
+                    }
+                }
                 let mut total = 0;
                 for buf in bufs {
                     match file.write(buf) {
                         Ok(n) => total += n,
-                        Err(_) => return Errno::Io,
+                        Err(e) => return Errno::from_io_error(&e),
                     }
                 }
                 *nwritten_out = total;
@@ -320,13 +631,12 @@ impl WasiCtx {
                 let mut capture = self.stdout_capture.lock();
                 let mut total = 0;
                 for buf in bufs {
+                    // Output dropped once the capture is full still counts as truncation
                     let remaining = MAX_CAPTURE_BYTES.saturating_sub(capture.len());
-                    if remaining > 0 {
-                        let to_write = buf.len().min(remaining);
-                        capture.extend_from_slice(&buf[..to_write]);
-                        if to_write < buf.len() {
-                            self.stdout_truncated.store(true, Ordering::SeqCst);
-                        }
+                    let to_write = buf.len().min(remaining);
+                    capture.extend_from_slice(&buf[..to_write]);
+                    if to_write < buf.len() {
+                        self.stdout_truncated.store(true, Ordering::SeqCst);
                     }
                     total += buf.len(); // Report full length as written
                 }
@@ -338,13 +648,12 @@ impl WasiCtx {
                 let mut capture = self.stderr_capture.lock();
                 let mut total = 0;
                 for buf in bufs {
+                    // Output dropped once the capture is full still counts as truncation
                     let remaining = MAX_CAPTURE_BYTES.saturating_sub(capture.len());
-                    if remaining > 0 {
-                        let to_write = buf.len().min(remaining);
-                        capture.extend_from_slice(&buf[..to_write]);
-                        if to_write < buf.len() {
-                            self.stderr_truncated.store(true, Ordering::SeqCst);
-                        }
+                    let to_write = buf.len().min(remaining);
+                    capture.extend_from_slice(&buf[..to_write]);
+                    if to_write < buf.len() {
+                        self.stderr_truncated.store(true, Ordering::SeqCst);
                     }
                     total += buf.len(); // Report full length as written
                 }
@@ -364,9 +673,12 @@ impl WasiCtx {
             None => return Errno::Badf,
         };
 
-        if let FileHandle::File(ref mut file, _) = handle {
+        if let FileHandle::File(ref mut file, _, _) = handle {
             let seek_from = match whence {
-                0 => SeekFrom::Start(offset as u64),
+                0 => match u64::try_from(offset) {
+                    Ok(offset) => SeekFrom::Start(offset),
+                    Err(_) => return Errno::Inval,
+                },
                 1 => SeekFrom::Current(offset),
                 2 => SeekFrom::End(offset),
                 _ => return Errno::Inval,
@@ -377,51 +689,82 @@ impl WasiCtx {
                     *newoffset_out = pos;
                     Errno::Success
                 }
-                Err(_) => Errno::Io,
+                Err(e) => Errno::from_io_error(&e),
             }
         } else {
             Errno::Badf
         }
     }
 
+    /// fd_renumber - Move the handle at `from` to `to`, closing whatever `to` held.
+    ///
+    /// The displaced handle is closed like `fd_close` would, so a metadata or
+    /// sub-content file open at `to` is still processed.
+    pub fn fd_renumber(&self, from: Fd, to: Fd) -> (Errno, CloseResult) {
+        let displaced = {
+            let mut file_table = self.file_table.write();
+            if !file_table.contains_key(&to) || !file_table.contains_key(&from) {
+                return (Errno::Badf, CloseResult::default());
+            }
+            if from == to {
+                return (Errno::Success, CloseResult::default());
+            }
+            let handle = file_table.remove(&from).expect("checked above");
+            file_table.insert(to, handle)
+        };
+        self.release_fd(from);
+        // The table lock is released, so closing can touch the filesystem
+        let result = displaced.map(|handle| self.close_handle(handle)).unwrap_or_default();
+        (Errno::Success, result)
+    }
+
     /// fd_close - Close file descriptor
     /// Returns CloseResult with metadata/subcontent content if this was a special file
     pub fn fd_close(&self, fd: Fd) -> (Errno, CloseResult) {
         if fd <= 2 {
             // Don't close stdio
-            return (Errno::Success, CloseResult { metadata_content: None, subcontent_emission: None });
+            return (Errno::Success, CloseResult::default());
         }
 
-        let mut file_table = self.file_table.write();
-        match file_table.remove(&fd) {
-            Some(FileHandle::File(_, Some(path))) if path.starts_with("/metadata/") && path.ends_with(".json") => {
-                // This is a metadata file - read its contents and delete it
-                let content = match self.filesystem.read_file(&path) {
-                    Ok(data) => Some(data),
-                    Err(_) => None,
-                };
-
-                // Delete the file from the filesystem
-                if let Ok((parent_dir, filename)) = self.resolve_path(&path) {
-                    let _ = parent_dir.remove(&filename);
-                }
+        let handle = self.file_table.write().remove(&fd);
+        match handle {
+            Some(handle) => {
+                self.release_fd(fd);
+                (Errno::Success, self.close_handle(handle))
+            }
+            None => (Errno::Badf, CloseResult::default()),
+        }
+    }
 
-                (Errno::Success, CloseResult { metadata_content: content, subcontent_emission: None })
+    /// Finish with a handle already removed from the file table, collecting
+    /// the metadata or sub-content a special file carried
+    fn close_handle(&self, handle: FileHandle) -> CloseResult {
+        match handle {
+            FileHandle::File(_, path, _) if path.starts_with("/metadata/") && MetadataFormat::from_path(&path).is_some() => {
+                // This is a metadata file - take its contents (without
+                // copying them) and delete it
+                let content = self.filesystem.take_file_bytes(&path).ok().map(|data| MetadataContent {
+                    path: path.clone(),
+                    format: MetadataFormat::from_path(&path).unwrap_or(MetadataFormat::Json),
+                    data,
+                });
+
+                CloseResult { metadata_content: content, ..Default::default() }
             }
-            Some(FileHandle::File(_, Some(path))) if path.starts_with("/subcontent/metadata_") && path.ends_with(".json") => {
+            FileHandle::File(_, path, _) if path.starts_with("/subcontent/metadata_") && path.ends_with(".json") => {
                 // This is a subcontent metadata file - find matching data file
                 // Path format: /subcontent/metadata_N.json -> /subcontent/data_N.bin
-                let emission = self.process_subcontent_metadata(&path);
-
-                (Errno::Success, CloseResult { metadata_content: None, subcontent_emission: emission })
-            }
-            Some(FileHandle::File(_, Some(path))) if path.starts_with("/subcontent/data_") => {
-                // This is a subcontent data file - just close it, don't process
-                // It will be processed when the matching metadata file is closed
-                (Errno::Success, CloseResult { metadata_content: None, subcontent_emission: None })
+                let raw = self.filesystem.read_file(&path).ok();
+                let mut result = match self.process_subcontent_metadata(&path, raw.as_deref()) {
+                    Ok(emission) => CloseResult { subcontent_emission: emission, ..Default::default() },
+                    Err(reason) => CloseResult { rejected_subcontent: Some(reason), ..Default::default() },
+                };
+                result.subcontent_metadata = raw.map(|data| MetadataContent { path, format: MetadataFormat::Json, data: data.into() });
+                result
             }
-            Some(_) => (Errno::Success, CloseResult { metadata_content: None, subcontent_emission: None }),
-            None => (Errno::Badf, CloseResult { metadata_content: None, subcontent_emission: None }),
+            // Sub-content data files are processed when the matching metadata
+            // file is closed; everything else needs nothing on close
+            _ => CloseResult::default(),
         }
     }
 
@@ -432,27 +775,51 @@ impl WasiCtx {
     ///
     /// For slice data: If the metadata contains `offset` and `length` fields, it's treated as a
     /// slice of the parent content and no data file is expected.
-    fn process_subcontent_metadata(&self, metadata_path: &str) -> Option<SubcontentEmission> {
+    ///
+    /// For file data: If the metadata contains a `path`, that file is taken (zero-copy, and
+    /// removed from the filesystem) instead of `/subcontent/data_N.bin`.
+    ///
+    /// Data whose length differs from the metadata's optional `size`, or a missing data
+    /// file, rejects the emission with the reason as the error, so a data file paired with
+    /// the wrong metadata isn't emitted under its name.
+    fn process_subcontent_metadata(
+        &self,
+        metadata_path: &str,
+        metadata_content: Option<&[u8]>,
+    ) -> Result<Option<SubcontentEmission>, String> {
         // Extract N from /subcontent/metadata_N.json
         let filename = metadata_path.trim_start_matches("/subcontent/");
-        let n = filename
+        let Some(n) = filename
             .strip_prefix("metadata_")
-            .and_then(|s| s.strip_suffix(".json"))?;
+            .and_then(|s| s.strip_suffix(".json")) else {
+            return Ok(None);
+        };
 
-        // Read metadata file to get the target filename and optional slice info
-        let metadata_content = self.filesystem.read_file(metadata_path).ok()?;
-        let metadata_str = String::from_utf8(metadata_content).ok()?;
+        // The metadata file gives the target filename and optional slice info
+        let Some(metadata_content) = metadata_content else {
+            return Ok(None);
+        };
+        // Lossy, so a filename copied raw from an archive doesn't drop the emission
+        let metadata_str = String::from_utf8_lossy(metadata_content);
 
         // Parse JSON to get filename and optional slice info
         // Format: {"filename": "extracted.txt"} for bytes
         // Format: {"filename": "extracted.txt", "offset": 0, "length": 100} for slice
+        // Format: {"filename": "extracted.txt", "path": "/tmp/decoded.bin"} for an existing file
+        // Any format may carry "relation": "<label>" to record a content edge
+        // Bytes and file formats may carry "size": <bytes> to check the data against
         #[derive(serde::Deserialize)]
         struct SubcontentMetadata {
             filename: String,
             offset: Option<usize>,
             length: Option<usize>,
+            path: Option<String>,
+            relation: Option<String>,
+            size: Option<u64>,
         }
-        let metadata: SubcontentMetadata = serde_json::from_str(&metadata_str).ok()?;
+        let Ok(metadata) = serde_json::from_str::<SubcontentMetadata>(&metadata_str) else {
+            return Ok(None);
+        };
 
         // Delete the metadata file first
         if let Ok((parent_dir, fname)) = self.resolve_path(metadata_path) {
@@ -460,24 +827,59 @@ impl WasiCtx {
         }
 
         // Check if this is a slice reference (both offset and length present)
-        let data = match (metadata.offset, metadata.length) {
-            (Some(offset), Some(length)) => {
+        let data = match (metadata.offset, metadata.length, metadata.path) {
+            (Some(offset), Some(length), _) => {
                 // Slice reference - no data file expected
                 SubcontentEmissionData::Slice { offset, length }
             }
+            (_, _, Some(path)) => {
+                // Paths are taken from the root. The content itself is read-only and
+                // shared, so it is emitted as a slice instead
+                let path = memory_fs::path_components(&path).ok()
+                    .map(|parts| format!("/{}", parts.join("/")))
+                    .filter(|p| p != "/data.bin");
+                let Some(path) = path else {
+                    tracing::warn!("Sub-content '{}' names a path that can't be emitted", metadata.filename);
+                    return Ok(None);
+                };
+                match self.filesystem.take_file_bytes(&path) {
+                    Ok(bytes) => SubcontentEmissionData::Bytes(bytes),
+                    Err(e) => {
+                        tracing::warn!("Sub-content '{}' names unreadable file {}: {}", metadata.filename, path, e);
+                        return Ok(None);
+                    }
+                }
+            }
             _ => {
                 // Owned data - take ownership of the data file as Bytes (zero-copy)
                 // This also removes the file from the filesystem
                 let data_path = format!("/subcontent/data_{}.bin", n);
-                let bytes = self.filesystem.take_file_bytes(&data_path).ok()?;
-                SubcontentEmissionData::Bytes(bytes)
+                match self.filesystem.take_file_bytes(&data_path) {
+                    Ok(bytes) => SubcontentEmissionData::Bytes(bytes),
+                    Err(_) => {
+                        return Err(format!("Sub-content '{}' has no data file {}", metadata.filename, data_path));
+                    }
+                }
             }
         };
 
-        Some(SubcontentEmission {
+        if let (Some(size), SubcontentEmissionData::Bytes(bytes)) = (metadata.size, &data) {
+            if bytes.len() as u64 != size {
+                return Err(format!(
+                    "Sub-content '{}' declares {} bytes in {} but its data is {} bytes",
+                    metadata.filename,
+                    size,
+                    metadata_path,
+                    bytes.len()
+                ));
+            }
+        }
+
+        Ok(Some(SubcontentEmission {
             filename: metadata.filename,
             data,
-        })
+            relation: metadata.relation,
+        }))
     }
 
     /// fd_filestat_get - Get file metadata
@@ -489,24 +891,18 @@ impl WasiCtx {
             None => return Errno::Badf,
         };
 
-        // Clear the filestat buffer
-        filestat.fill(0);
-
         match handle {
-            FileHandle::File(file, _) => {
-                // Set filetype to regular file (byte 16)
-                filestat[16] = Filetype::RegularFile as u8;
-                // Set file size (bytes 32-39, little endian)
-                let size = file.len() as u64;
-                filestat[32..40].copy_from_slice(&size.to_le_bytes());
-                Errno::Success
+            FileHandle::File(file, path, _) => {
+                self.write_filestat(filestat, path, Filetype::RegularFile, file.len() as u64);
             }
-            FileHandle::Directory(_, _) => {
-                filestat[16] = Filetype::Directory as u8;
-                Errno::Success
+            FileHandle::Directory(dir, path, _) => {
+                self.write_filestat(filestat, path, Filetype::Directory, dir.len() as u64);
             }
-            _ => Errno::Success,
+            FileHandle::Stdin => self.write_filestat(filestat, "/dev/stdin", Filetype::CharacterDevice, 0),
+            FileHandle::Stdout => self.write_filestat(filestat, "/dev/stdout", Filetype::CharacterDevice, 0),
+            FileHandle::Stderr => self.write_filestat(filestat, "/dev/stderr", Filetype::CharacterDevice, 0),
         }
+        Errno::Success
     }
 
     /// fd_prestat_get - Get preopen info
@@ -537,102 +933,134 @@ impl WasiCtx {
     }
 
     /// path_filestat_get - Get file metadata by path
+    ///
+    /// The filesystem has no symlinks, so with or without
+    /// `LOOKUPFLAGS_SYMLINK_FOLLOW` this stats the path itself, as a
+    /// following stat would. Other flag bits are reserved and rejected.
     pub fn path_filestat_get(
         &self,
         dirfd: Fd,
-        _flags: u32,
+        flags: u32,
         path: &str,
         filestat: &mut [u8; 64],
     ) -> Errno {
-        if dirfd != 3 {
-            return Errno::Badf;
+        if flags & !LOOKUPFLAGS_SYMLINK_FOLLOW != 0 {
+            return Errno::Inval;
         }
-
-        filestat.fill(0);
+        let normalized_path = match self.resolve_at(dirfd, path) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        let path = normalized_path.as_str();
 
         // Try to open as file
         match self.filesystem.open_file(path) {
             Ok(file) => {
-                filestat[16] = Filetype::RegularFile as u8;
-                let size = file.len() as u64;
-                filestat[32..40].copy_from_slice(&size.to_le_bytes());
+                self.write_filestat(filestat, &normalized_path, Filetype::RegularFile, file.len() as u64);
                 Errno::Success
             }
             Err(_) => {
                 // Try as directory
+                if normalized_path == "/" {
+                    let size = self.filesystem.root().len() as u64;
+                    self.write_filestat(filestat, "/", Filetype::Directory, size);
+                    return Errno::Success;
+                }
                 match self.resolve_path(path) {
-                    Ok((parent_dir, filename)) => {
-                        if parent_dir.get_dir(&filename).is_ok() {
-                            filestat[16] = Filetype::Directory as u8;
+                    Ok((parent_dir, filename)) => match parent_dir.get_dir(&filename) {
+                        Ok(dir) => {
+                            self.write_filestat(filestat, &normalized_path, Filetype::Directory, dir.len() as u64);
                             Errno::Success
-                        } else {
-                            Errno::Noent
                         }
-                    }
+                        Err(_) => Errno::Noent,
+                    },
                     Err(e) => e,
                 }
             }
         }
     }
 
+    /// path_create_directory - Create a directory and any missing parents
+    pub fn path_create_directory(&self, dirfd: Fd, path: &str) -> Errno {
+        let path = match self.resolve_at(dirfd, path) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        match self.filesystem.create_dir_all(&path) {
+            Ok(_) => Errno::Success,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Errno::Acces,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Errno::Exist,
+            Err(_) => Errno::Io,
+        }
+    }
+
     /// fd_readdir - Read directory entries
+    ///
+    /// Entries are listed in name order starting at `cookie`; each entry's
+    /// `d_next` is the cookie for the entry after it. When the buffer fills,
+    /// the entry that does not fit is written partially and `bufused` equals
+    /// the buffer length, telling the caller to resume from the last complete
+    /// entry's cookie. The handle keeps the listing taken at cookie 0, so a
+    /// read resumed from a later cookie doesn't list the directory again.
     pub fn fd_readdir(
         &self,
         fd: Fd,
         buf: &mut [u8],
-        _cookie: u64,
+        cookie: u64,
         bufused_out: &mut usize,
     ) -> Errno {
-        let mut file_table = self.file_table.write();
+        let file_table = self.file_table.read();
 
-        let handle = match file_table.get_mut(&fd) {
+        let handle = match file_table.get(&fd) {
             Some(h) => h,
             None => return Errno::Badf,
         };
 
-        if let FileHandle::Directory(dir, ref mut pos) = handle {
-            let entries = dir.list();
+        if let FileHandle::Directory(dir, dir_path, listing) = handle {
+            let mut listing = listing.lock();
+            if cookie == 0 || listing.is_none() {
+                let mut entries = dir.list();
+                entries.sort();
+                *listing = Some(entries);
+                #[cfg(test)]
+                DIR_LISTINGS.with(|n| n.set(n.get() + 1));
+            }
+            let entries = listing.as_deref().unwrap_or_default();
 
             let mut offset = 0;
-            let start_pos = *pos;
-
-            for (idx, (name, is_dir)) in entries.iter().enumerate().skip(start_pos) {
-                // dirent structure: next(8) + ino(8) + namelen(4) + type(1)
-                let entry_size = 8 + 8 + 4 + 1 + name.len();
+            let start = usize::try_from(cookie).unwrap_or(usize::MAX);
 
-                if offset + entry_size > buf.len() {
-                    break;
-                }
+            for (idx, (name, is_dir)) in entries.iter().enumerate().skip(start) {
+                // dirent structure: next(8) + ino(8) + namelen(4) + type(1) + padding(3)
+                let mut entry = Vec::with_capacity(DIRENT_SIZE + name.len());
 
                 // next cookie
-                let next = (idx + 1) as u64;
-                buf[offset..offset+8].copy_from_slice(&next.to_le_bytes());
-                offset += 8;
-
-                // inode (fake)
-                let ino = (idx + 1) as u64;
-                buf[offset..offset+8].copy_from_slice(&ino.to_le_bytes());
-                offset += 8;
-
+                entry.extend_from_slice(&((idx + 1) as u64).to_le_bytes());
+                // inode (same as fd_filestat_get reports for the entry)
+                let entry_path = format!("{}/{}", dir_path.trim_end_matches('/'), name);
+                entry.extend_from_slice(&inode_for_path(&entry_path).to_le_bytes());
                 // name length
-                let namelen = name.len() as u32;
-                buf[offset..offset+4].copy_from_slice(&namelen.to_le_bytes());
-                offset += 4;
-
+                entry.extend_from_slice(&(name.len() as u32).to_le_bytes());
                 // file type
-                let filetype = if *is_dir {
+                entry.push(if *is_dir {
                     Filetype::Directory as u8
                 } else {
                     Filetype::RegularFile as u8
-                };
-                buf[offset] = filetype;
-                offset += 1;
-
+                });
+                entry.resize(DIRENT_SIZE, 0);
                 // name
-                buf[offset..offset+name.len()].copy_from_slice(name.as_bytes());
-                offset += name.len();
+                entry.extend_from_slice(name.as_bytes());
 
-                *pos = idx + 1;
+                let remaining = buf.len() - offset;
+                if entry.len() > remaining {
+                    // Truncated entry signals that the buffer is full
+                    buf[offset..].copy_from_slice(&entry[..remaining]);
+                    offset = buf.len();
+                    break;
+                }
+
+                buf[offset..offset + entry.len()].copy_from_slice(&entry);
+                offset += entry.len();
             }
 
             *bufused_out = offset;
@@ -642,26 +1070,34 @@ impl WasiCtx {
         }
     }
 
-    fn resolve_path(&self, path: &str) -> Result<(MemoryDirectory, String), Errno> {
-        let path = path.trim_start_matches('/');
-
-        if path.is_empty() {
-            return Err(Errno::Inval);
-        }
+    /// Resolve `path` relative to the directory open at `dirfd`, returning an
+    /// absolute normalized path. Absolute paths still resolve from the root.
+    fn resolve_at(&self, dirfd: Fd, path: &str) -> Result<String, Errno> {
+        let base = match self.file_table.read().get(&dirfd) {
+            Some(FileHandle::Directory(_, dir_path, _)) => dir_path.clone(),
+            Some(_) => return Err(Errno::Notdir),
+            None => return Err(Errno::Badf),
+        };
+        let joined = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/{}", base, path)
+        };
+        let parts = memory_fs::path_components(&joined).map_err(|_| Errno::Acces)?;
+        Ok(format!("/{}", parts.join("/")))
+    }
 
-        let parts: Vec<&str> = path.split('/').collect();
-        let filename = parts.last().unwrap().to_string();
+    fn resolve_path(&self, path: &str) -> Result<(MemoryDirectory, String), Errno> {
+        let parts = memory_fs::path_components(path).map_err(|_| Errno::Acces)?;
 
-        if parts.len() == 1 {
-            return Ok((self.filesystem.root().clone(), filename));
-        }
+        let (filename, parents) = parts.split_last().ok_or(Errno::Inval)?;
 
         let mut current_dir = self.filesystem.root().clone();
-        for &part in &parts[..parts.len() - 1] {
+        for &part in parents {
             current_dir = current_dir.get_dir(part).map_err(|_| Errno::Noent)?;
         }
 
-        Ok((current_dir, filename))
+        Ok((current_dir, filename.to_string()))
     }
 
     /// Take captured stdout output and reset the buffer.
@@ -691,3 +1127,446 @@ impl WasiCtx {
         self.stderr_truncated.store(false, Ordering::SeqCst);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse complete dirents from a readdir buffer, returning (d_next, name) pairs
+    fn parse_dirents(buf: &[u8]) -> Vec<(u64, String)> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + DIRENT_SIZE <= buf.len() {
+            let next = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+            let namelen = u32::from_le_bytes(buf[offset + 16..offset + 20].try_into().unwrap()) as usize;
+            let name_start = offset + DIRENT_SIZE;
+            if name_start + namelen > buf.len() {
+                break;
+            }
+            entries.push((next, String::from_utf8(buf[name_start..name_start + namelen].to_vec()).unwrap()));
+            offset = name_start + namelen;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_path_traversal_returns_acces() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/metadata").unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut fd = 0;
+        for path in ["/metadata/../../etc/passwd", "/metadata/../data.bin", "../x"] {
+            assert_eq!(ctx.path_open(3, 0, path, 1, 0, 0, 0, &mut fd), Errno::Acces, "{}", path);
+            assert_eq!(ctx.path_open(3, 0, path, 2, 0, 0, 0, &mut fd), Errno::Acces, "{}", path);
+        }
+        assert_eq!(ctx.path_create_directory(3, "/metadata/../../escape"), Errno::Acces);
+        assert_eq!(ctx.path_create_directory(3, "/tmp/ok/nested"), Errno::Success);
+        assert!(fs.get_dir("/tmp/ok/nested").is_ok());
+
+        // "." and repeated separators still resolve to the tracked path
+        assert_eq!(ctx.path_open(3, 0, "/metadata/./out.json", 1, 0, 0, 0, &mut fd), Errno::Success);
+        let (_, result) = ctx.fd_close(fd);
+        assert!(result.metadata_content.is_some());
+    }
+
+    #[test]
+    fn test_path_open_relative_to_directory_fd() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/tmp").unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut dir_fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/tmp", 2, 0, 0, 0, &mut dir_fd), Errno::Success);
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(dir_fd, 0, "out.txt", 1, 0, 0, 0, &mut fd), Errno::Success);
+        let mut written = 0;
+        assert_eq!(ctx.fd_write(fd, &[b"hello"], &mut written), Errno::Success);
+        ctx.fd_close(fd);
+        assert_eq!(fs.read_file("/tmp/out.txt").unwrap(), b"hello");
+
+        assert_eq!(ctx.path_create_directory(dir_fd, "sub"), Errno::Success);
+        assert!(fs.get_dir("/tmp/sub").is_ok());
+        let mut filestat = [0u8; 64];
+        assert_eq!(ctx.path_filestat_get(dir_fd, 0, "out.txt", &mut filestat), Errno::Success);
+        assert_eq!(u64::from_le_bytes(filestat[32..40].try_into().unwrap()), 5);
+
+        // `..` still cannot climb out of the directory, and files are not directory fds
+        assert_eq!(ctx.path_open(dir_fd, 0, "../data.bin", 0, 0, 0, 0, &mut fd), Errno::Acces);
+        assert_eq!(ctx.path_open(dir_fd, 0, "out.txt", 0, 0, 0, 0, &mut fd), Errno::Success);
+        assert_eq!(ctx.path_open(fd, 0, "x", 1, 0, 0, 0, &mut fd), Errno::Notdir);
+        assert_eq!(ctx.path_open(99, 0, "x", 1, 0, 0, 0, &mut fd), Errno::Badf);
+    }
+
+    #[test]
+    fn test_fdstat_set_flags_enables_append() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/tmp").unwrap();
+        fs.create_file("/tmp/log.txt", b"first".to_vec()).unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut fd = 0;
+        let mut written = 0;
+        assert_eq!(ctx.path_open(3, 0, "/tmp/log.txt", 0, 0, 0, 0, &mut fd), Errno::Success);
+        assert_eq!(ctx.fd_write(fd, &[b"F"], &mut written), Errno::Success);
+        assert_eq!(fs.read_file("/tmp/log.txt").unwrap(), b"First");
+
+        assert_eq!(ctx.fd_fdstat_set_flags(fd, FDFLAGS_APPEND), Errno::Success);
+        let mut newoffset = 0;
+        assert_eq!(ctx.fd_seek(fd, 0, 0, &mut newoffset), Errno::Success);
+        assert_eq!(ctx.fd_write(fd, &[b" second"], &mut written), Errno::Success);
+        assert_eq!(ctx.fd_write(fd, &[b" third"], &mut written), Errno::Success);
+        assert_eq!(fs.read_file("/tmp/log.txt").unwrap(), b"First second third");
+
+        let mut fdstat = [0u8; 24];
+        assert_eq!(ctx.fd_fdstat_get(fd, &mut fdstat), Errno::Success);
+        assert_eq!(fdstat[0], Filetype::RegularFile as u8);
+        assert_eq!(u16::from_le_bytes([fdstat[2], fdstat[3]]), FDFLAGS_APPEND);
+
+        // Clearing the flag goes back to positional writes
+        assert_eq!(ctx.fd_fdstat_set_flags(fd, 0), Errno::Success);
+        assert_eq!(ctx.fd_seek(fd, 0, 0, &mut newoffset), Errno::Success);
+        assert_eq!(ctx.fd_write(fd, &[b"f"], &mut written), Errno::Success);
+        assert_eq!(fs.read_file("/tmp/log.txt").unwrap(), b"first second third");
+        assert_eq!(ctx.fd_fdstat_set_flags(99, 0), Errno::Badf);
+
+        // Handles without stored flags refuse any, so fdstat never disagrees
+        assert_eq!(ctx.fd_fdstat_set_flags(1, FDFLAGS_APPEND), Errno::Notsup);
+        assert_eq!(ctx.fd_fdstat_set_flags(3, FDFLAGS_APPEND), Errno::Notsup);
+        assert_eq!(ctx.fd_fdstat_set_flags(3, 0), Errno::Success);
+        assert_eq!(ctx.fd_fdstat_get(3, &mut fdstat), Errno::Success);
+        assert_eq!(u16::from_le_bytes([fdstat[2], fdstat[3]]), 0);
+    }
+
+    #[test]
+    fn test_fd_renumber_moves_handle() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.set_data_bin(bytes::Bytes::from_static(b"xyz")).unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let (mut a, mut b) = (0, 0);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut a), Errno::Success);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut b), Errno::Success);
+        assert_eq!(ctx.fd_renumber(a, b).0, Errno::Success);
+
+        let mut buf = [0u8; 3];
+        let mut nread = 0;
+        assert_eq!(ctx.fd_read(a, &mut [&mut buf], &mut nread), Errno::Badf);
+        assert_eq!(ctx.fd_read(b, &mut [&mut buf], &mut nread), Errno::Success);
+        assert_eq!(&buf, b"xyz");
+        assert_eq!(ctx.fd_renumber(a, b).0, Errno::Badf);
+        assert_eq!(ctx.fd_renumber(b, b).0, Errno::Success);
+    }
+
+    #[test]
+    fn test_max_open_files_refuses_opens_and_recycles_fds() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.set_data_bin(bytes::Bytes::from_static(b"xyz")).unwrap();
+        let mut ctx = WasiCtx::new(fs);
+        ctx.set_max_open_files(Some(2));
+
+        let (mut a, mut b, mut c) = (0, 0, 0);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut a), Errno::Success);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut b), Errno::Success);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut c), Errno::Mfile);
+
+        // Closing one makes room, and its number is handed out again
+        assert_eq!(ctx.fd_close(a).0, Errno::Success);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut c), Errno::Success);
+        assert_eq!(c, a);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut c), Errno::Mfile);
+    }
+
+    #[test]
+    fn test_fd_renumber_closes_displaced_metadata_file() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/metadata").unwrap();
+        fs.set_data_bin(bytes::Bytes::from_static(b"xyz")).unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let (mut data, mut meta) = (0, 0);
+        let mut written = 0;
+        assert_eq!(ctx.path_open(3, 0, "/metadata/output_0.json", 1, 0, 0, 0, &mut meta), Errno::Success);
+        assert_eq!(ctx.fd_write(meta, &[b"{}"], &mut written), Errno::Success);
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut data), Errno::Success);
+
+        let (errno, result) = ctx.fd_renumber(data, meta);
+        assert_eq!(errno, Errno::Success);
+        let content = result.metadata_content.expect("displaced metadata file is processed");
+        assert_eq!(content.data, &b"{}"[..]);
+        assert!(fs.open_file("/metadata/output_0.json").is_err(), "file should be removed");
+    }
+
+    #[test]
+    fn test_fd_read_stops_at_first_short_buffer() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.set_data_bin(bytes::Bytes::from_static(b"abcdefg")).unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut fd), Errno::Success);
+        let (mut a, mut b, mut c, mut d) = ([0u8; 4], [0u8; 2], [0xffu8; 8], [0xffu8; 4]);
+        let mut nread = 0;
+        assert_eq!(ctx.fd_read(fd, &mut [&mut a, &mut b, &mut c, &mut d], &mut nread), Errno::Success);
+        assert_eq!(nread, 7);
+        assert_eq!(&a, b"abcd");
+        assert_eq!(&b, b"ef");
+        assert_eq!(&c[..1], b"g");
+        assert_eq!(d, [0xff; 4], "buffers after a short read are not touched");
+    }
+
+    #[test]
+    fn test_subcontent_from_existing_file() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/tmp/out").unwrap();
+        fs.create_dir_all("/subcontent").unwrap();
+        fs.set_data_bin(bytes::Bytes::from_static(b"content")).unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let write = |path: &str, data: &[u8]| {
+            let mut fd = 0;
+            assert_eq!(ctx.path_open(3, 0, path, 1 | 8, 0, 0, 0, &mut fd), Errno::Success);
+            let mut written = 0;
+            assert_eq!(ctx.fd_write(fd, &[data], &mut written), Errno::Success);
+            ctx.fd_close(fd).1
+        };
+        let emit = |n: usize, metadata: &str| {
+            write(&format!("/subcontent/metadata_{}.json", n), metadata.as_bytes()).subcontent_emission
+        };
+
+        assert!(write("/tmp/out/decoded.bin", b"decoded").subcontent_emission.is_none());
+
+        let emission = emit(0, r#"{"filename":"decoded.bin","path":"tmp/out/./decoded.bin"}"#).unwrap();
+        assert!(matches!(emission.data, SubcontentEmissionData::Bytes(ref b) if &b[..] == b"decoded"));
+        assert!(fs.read_file("/tmp/out/decoded.bin").is_err(), "the file moves into the emission");
+
+        assert!(emit(1, r#"{"filename":"again.bin","path":"/tmp/out/decoded.bin"}"#).is_none());
+        assert!(emit(2, r#"{"filename":"self.bin","path":"/data.bin"}"#).is_none());
+        assert!(emit(3, r#"{"filename":"up.bin","path":"/tmp/../data.bin"}"#).is_none());
+        assert_eq!(fs.read_file("/data.bin").unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_subcontent_filename_need_not_be_utf8() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/subcontent").unwrap();
+        fs.set_data_bin(bytes::Bytes::from_static(b"content")).unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/subcontent/metadata_0.json", 1 | 8, 0, 0, 0, &mut fd), Errno::Success);
+        let metadata = b"{\"filename\":\"caf\xe9.txt\",\"offset\":0,\"length\":4}";
+        let mut written = 0;
+        assert_eq!(ctx.fd_write(fd, &[metadata], &mut written), Errno::Success);
+        let emission = ctx.fd_close(fd).1.subcontent_emission.expect("the emission is kept");
+        assert_eq!(emission.filename, "caf\u{fffd}.txt");
+    }
+
+    #[test]
+    fn test_read_from_directory_returns_isdir() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/tmp").unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/tmp", 2, 0, 0, 0, &mut fd), Errno::Success);
+        let mut buf = [0u8; 4];
+        let mut nread = 0;
+        assert_eq!(ctx.fd_read(fd, &mut [&mut buf], &mut nread), Errno::Isdir);
+        assert_eq!(ctx.fd_pread(fd, &mut [&mut buf], 0, &mut nread), Errno::Isdir);
+        assert_eq!(ctx.fd_read(3, &mut [&mut buf], &mut nread), Errno::Isdir, "preopened root is a directory too");
+    }
+
+    #[test]
+    fn test_pread_keeps_position() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.set_data_bin(bytes::Bytes::from_static(b"abcdef")).unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut fd), Errno::Success);
+        let mut buf = [0u8; 2];
+        let mut nread = 0;
+        assert_eq!(ctx.fd_read(fd, &mut [&mut buf], &mut nread), Errno::Success);
+        assert_eq!(ctx.fd_pread(fd, &mut [&mut buf], 4, &mut nread), Errno::Success);
+        assert_eq!(&buf, b"ef");
+        assert_eq!(ctx.fd_read(fd, &mut [&mut buf], &mut nread), Errno::Success);
+        assert_eq!(&buf, b"cd");
+    }
+
+    #[test]
+    fn test_filestat_set_size_resizes_open_file() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/tmp").unwrap();
+        fs.create_file("/tmp/f.bin", b"abcdef".to_vec()).unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/tmp/f.bin", 0, 0, 0, 0, &mut fd), Errno::Success);
+        let mut buf = [0xffu8; 8];
+        let mut nread = 0;
+
+        // Extending then reading past the old end gives zeros
+        assert_eq!(ctx.fd_filestat_set_size(fd, 8), Errno::Success);
+        assert_eq!(ctx.fd_pread(fd, &mut [&mut buf], 0, &mut nread), Errno::Success);
+        assert_eq!((nread, &buf), (8, b"abcdef\0\0"));
+
+        // Shrinking drops the tail
+        assert_eq!(ctx.fd_filestat_set_size(fd, 3), Errno::Success);
+        assert_eq!(ctx.fd_pread(fd, &mut [&mut buf], 0, &mut nread), Errno::Success);
+        assert_eq!(&buf[..nread], b"abc");
+        assert_eq!(fs.read_file("/tmp/f.bin").unwrap(), b"abc");
+
+        assert_eq!(ctx.fd_filestat_set_size(3, 0), Errno::Isdir);
+        assert_eq!(ctx.fd_filestat_set_size(99, 0), Errno::Badf);
+
+        fs.set_data_bin(bytes::Bytes::from_static(b"content")).unwrap();
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut fd), Errno::Success);
+        assert_eq!(ctx.fd_filestat_set_size(fd, 0), Errno::Acces);
+    }
+
+    #[test]
+    fn test_write_to_readonly_file_returns_acces() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.set_data_bin(bytes::Bytes::from_static(b"content")).unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/data.bin", 0, 0, 0, 0, &mut fd), Errno::Success);
+        let mut written = 0;
+        assert_eq!(ctx.fd_write(fd, &[b"overwrite"], &mut written), Errno::Acces);
+        assert_eq!(fs.read_file("/data.bin").unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_errno_from_io_error() {
+        use std::io::{Error, ErrorKind};
+
+        assert_eq!(Errno::from_io_error(&Error::from(ErrorKind::PermissionDenied)), Errno::Acces);
+        assert_eq!(Errno::from_io_error(&Error::from(ErrorKind::StorageFull)), Errno::Nospc);
+        assert_eq!(Errno::from_io_error(&Error::from(ErrorKind::Other)), Errno::Io);
+    }
+
+    #[test]
+    fn test_jsonl_metadata_processed_on_close() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/metadata").unwrap();
+        let ctx = WasiCtx::new(fs.clone());
+
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/metadata/stream.jsonl", 1, 0, 0, 0, &mut fd), Errno::Success);
+        let mut written = 0;
+        assert_eq!(ctx.fd_write(fd, &[b"{}\n{}\n"], &mut written), Errno::Success);
+
+        let (errno, result) = ctx.fd_close(fd);
+        assert_eq!(errno, Errno::Success);
+        let content = result.metadata_content.expect("jsonl file should be returned on close");
+        assert_eq!(content.format, MetadataFormat::JsonLines);
+        assert_eq!(content.data, &b"{}\n{}\n"[..]);
+        assert!(fs.open_file("/metadata/stream.jsonl").is_err(), "file should be removed");
+    }
+
+    #[test]
+    fn test_readdir_resumes_from_cookie() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/many").unwrap();
+        let mut expected: Vec<String> = (0..40).map(|i| format!("file_{:02}.txt", i)).collect();
+        for name in &expected {
+            fs.create_file(&format!("/many/{}", name), Vec::new()).unwrap();
+        }
+        expected.sort();
+
+        let ctx = WasiCtx::new(fs);
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/many", 2, 0, 0, 0, &mut fd), Errno::Success);
+
+        // Room for roughly three entries per call
+        let mut buf = [0u8; 120];
+        let mut cookie = 0;
+        let mut seen = Vec::new();
+        let mut calls = 0;
+        loop {
+            let mut bufused = 0;
+            assert_eq!(ctx.fd_readdir(fd, &mut buf, cookie, &mut bufused), Errno::Success);
+            calls += 1;
+
+            let entries = parse_dirents(&buf[..bufused]);
+            for (next, name) in &entries {
+                seen.push(name.clone());
+                cookie = *next;
+            }
+
+            if bufused < buf.len() {
+                break;
+            }
+            assert!(!entries.is_empty(), "full buffer without a complete entry");
+        }
+
+        assert!(calls > 10);
+        assert_eq!(seen, expected);
+
+        // Re-reading from an earlier cookie repeats the same entries
+        let mut bufused = 0;
+        ctx.fd_readdir(fd, &mut buf, 5, &mut bufused);
+        let entries = parse_dirents(&buf[..bufused]);
+        assert_eq!(entries[0], (6, expected[5].clone()));
+    }
+
+    #[test]
+    fn test_readdir_lists_a_large_directory_once() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_dir_all("/many").unwrap();
+        let expected: Vec<String> = (0..10_000).map(|i| format!("file_{:05}.txt", i)).collect();
+        for name in &expected {
+            fs.create_file(&format!("/many/{}", name), Vec::new()).unwrap();
+        }
+
+        let ctx = WasiCtx::new(fs.clone());
+        let mut fd = 0;
+        assert_eq!(ctx.path_open(3, 0, "/many", 2, 0, 0, 0, &mut fd), Errno::Success);
+
+        let listings_before = DIR_LISTINGS.with(|n| n.get());
+        let mut buf = [0u8; 256];
+        let mut cookie = 0;
+        let mut seen = Vec::new();
+        let mut calls = 0;
+        loop {
+            let mut bufused = 0;
+            assert_eq!(ctx.fd_readdir(fd, &mut buf, cookie, &mut bufused), Errno::Success);
+            calls += 1;
+            for (next, name) in parse_dirents(&buf[..bufused]) {
+                seen.push(name);
+                cookie = next;
+            }
+            if calls == 1 {
+                // Entries created mid-read don't shift later cookies
+                fs.create_file("/many/aaa.txt", Vec::new()).unwrap();
+            }
+            if bufused < buf.len() {
+                break;
+            }
+        }
+
+        assert!(calls > 1000);
+        assert_eq!(seen, expected);
+        assert_eq!(DIR_LISTINGS.with(|n| n.get()) - listings_before, 1, "directory listed once for the whole read");
+
+        // Rewinding to cookie 0 lists the directory again
+        let mut bufused = 0;
+        ctx.fd_readdir(fd, &mut buf, 0, &mut bufused);
+        assert_eq!(parse_dirents(&buf[..bufused])[0].1, "aaa.txt");
+    }
+
+    #[test]
+    fn test_filestat_follow_flag() {
+        let fs = Arc::new(MemoryFilesystem::new());
+        fs.create_file("/data.bin", b"abc".to_vec()).unwrap();
+        let ctx = WasiCtx::new(fs);
+
+        let mut filestat = [0u8; 64];
+        assert_eq!(ctx.path_filestat_get(3, LOOKUPFLAGS_SYMLINK_FOLLOW, "data.bin", &mut filestat), Errno::Success);
+        assert_eq!(u64::from_le_bytes(filestat[32..40].try_into().unwrap()), 3);
+        assert_eq!(ctx.path_filestat_get(3, 2, "data.bin", &mut filestat), Errno::Inval);
+    }
+}
@@ -1,11 +1,264 @@
 use wasmtime::*;
-use anyhow::Result;
-use std::path::Path;
+use anyhow::{Context, Result};
+use rand::rngs::OsRng;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use crate::bindings_context::ProcessingContext;
+use crate::bindings_context::{GuestError, ParentInfo, ProcessingContext, SubContentEmission};
+use crate::error::WadupError;
+use crate::host_functions::{read_guest_bytes, GuestReadError, MAX_GUEST_NAME_LEN};
 use crate::metadata::MetadataStore;
 use crate::memory_fs::MemoryFilesystem;
 use crate::wasi_impl::WasiCtx;
+use wadup_abi::mode::{MODE_RUN, MODE_TEST, MODE_VAR};
+
+/// Default name of the export called for each content
+pub const DEFAULT_ENTRYPOINT: &str = "process";
+
+/// `sched_yield` calls allowed per content. Yielding costs the guest next to
+/// no fuel, so a module spinning on it would otherwise hang its worker.
+pub const MAX_SCHED_YIELDS: u64 = 1_000_000;
+
+/// Epoch ticks in one `--stall-timeout`. A module is interrupted between one
+/// and `1 + 1 / STALL_TICKS` timeouts after its content began.
+const STALL_TICKS: u64 = 10;
+
+/// Every `wasi_snapshot_preview1` function the host provides. This is the
+/// whole Preview1 API, so toolchains that import more than they call (Go's
+/// wasip1 port imports most of it from `syscall`) always instantiate; calls the
+/// in-memory sandbox can't honour return `ENOSYS` rather than failing to link.
+pub const WASI_PREVIEW1_IMPORTS: &[&str] = &[
+    "args_get", "args_sizes_get", "environ_get", "environ_sizes_get",
+    "clock_res_get", "clock_time_get",
+    "fd_advise", "fd_allocate", "fd_close", "fd_datasync", "fd_fdstat_get",
+    "fd_fdstat_set_flags", "fd_filestat_get", "fd_filestat_set_size",
+    "fd_filestat_set_times", "fd_pread", "fd_prestat_dir_name", "fd_prestat_get",
+    "fd_pwrite", "fd_read", "fd_readdir", "fd_renumber", "fd_seek", "fd_sync",
+    "fd_tell", "fd_write",
+    "path_create_directory", "path_filestat_get", "path_filestat_set_times",
+    "path_link", "path_open", "path_readlink", "path_remove_directory",
+    "path_rename", "path_symlink", "path_unlink_file",
+    "poll_oneoff", "proc_exit", "proc_raise", "random_get", "sched_yield",
+    "sock_accept", "sock_recv", "sock_send", "sock_shutdown",
+];
+
+/// Which WASI Preview1 functions modules may import (`--wasi-profile`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WasiProfile {
+    /// Everything in `WASI_PREVIEW1_IMPORTS`
+    #[default]
+    Full,
+    /// No function that only modifies the filesystem; `path_open` can't
+    /// create or truncate and files can't be written. The `wadup` host
+    /// imports are still linked.
+    Readonly,
+    /// Only `fd_write` (useful for stdout/stderr) and `proc_exit`
+    Minimal,
+}
+
+impl WasiProfile {
+    /// Functions `Readonly` refuses to link
+    const WRITE_FUNCTIONS: &'static [&'static str] = &[
+        "fd_allocate", "fd_filestat_set_size", "fd_filestat_set_times", "fd_pwrite",
+        "path_create_directory", "path_filestat_set_times", "path_link",
+        "path_remove_directory", "path_rename", "path_symlink", "path_unlink_file",
+    ];
+
+    /// Whether a module may import `wasi_snapshot_preview1::name`
+    pub fn allows(self, name: &str) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Readonly => !Self::WRITE_FUNCTIONS.contains(&name),
+            Self::Minimal => matches!(name, "fd_write" | "proc_exit"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Readonly => "readonly",
+            Self::Minimal => "minimal",
+        }
+    }
+}
+
+impl std::str::FromStr for WasiProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(Self::Full),
+            "readonly" => Ok(Self::Readonly),
+            "minimal" => Ok(Self::Minimal),
+            other => anyhow::bail!("Unknown WASI profile '{}' (expected 'full', 'readonly' or 'minimal')", other),
+        }
+    }
+}
+
+/// Paths that are part of every instance's filesystem and can't be mounted over
+const RESERVED_PATHS: &[&str] = &["/tmp", "/metadata", "/subcontent", "/streams", "/data.bin"];
+
+/// A host directory exposed read-only to every module (`--mount host:/guest:ro`)
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Mount {
+    pub host_dir: PathBuf,
+    /// Absolute path the directory appears at inside the guest
+    pub guest_path: String,
+}
+
+impl std::str::FromStr for Mount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let spec = match s.rsplit_once(':') {
+            Some((spec, "ro")) => spec,
+            Some((_, mode)) if !mode.starts_with('/') => {
+                anyhow::bail!("Unsupported mount mode '{}' in '{}' (only 'ro' mounts are supported)", mode, s)
+            }
+            _ => s,
+        };
+        let Some((host_dir, guest_path)) = spec.rsplit_once(':') else {
+            anyhow::bail!("Invalid mount '{}' (expected HOST_DIR:/GUEST_PATH:ro)", s);
+        };
+        let guest_path = guest_path.trim_end_matches('/');
+        if host_dir.is_empty() || !guest_path.starts_with('/') {
+            anyhow::bail!("Invalid mount '{}' (expected HOST_DIR:/GUEST_PATH:ro)", s);
+        }
+        if let Some(reserved) = RESERVED_PATHS.iter()
+            .find(|p| guest_path == **p || guest_path.starts_with(&format!("{}/", p)))
+        {
+            anyhow::bail!("Can't mount over {} in '{}'", reserved, s);
+        }
+        Ok(Self { host_dir: PathBuf::from(host_dir), guest_path: guest_path.to_string() })
+    }
+}
+
+impl TryFrom<String> for Mount {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Per-instance settings that aren't resource limits
+#[derive(Debug, Clone, Default)]
+pub struct InstanceOptions {
+    /// Seed for `random_get`; OS entropy when `None`
+    pub seed: Option<u64>,
+    /// Without `seed`, seed `random_get` from the module name and content bytes alone
+    pub content_seeded_random: bool,
+    pub wasi_profile: WasiProfile,
+    /// Files from `--mount` directories as (guest path, data), loaded once
+    /// and shared by every instance without copying
+    pub mounted_files: Arc<Vec<(String, bytes::Bytes)>>,
+    /// Arguments after `argv[0]` (the module name), from `--module-arg`
+    pub module_args: Vec<String>,
+    /// Most fuel per content a module can reach with `add_fuel`
+    pub fuel_ceiling: Option<u64>,
+    /// Most files and directories a module can have open at once
+    pub max_open_files: Option<usize>,
+    /// Values modules can read with `get_secret`, shared by every instance
+    pub secrets: Arc<Vec<crate::secrets::Secret>>,
+    /// Leave files in `/tmp` from one content to the next
+    pub keep_scratch: bool,
+    /// Epoch ticks a module may run per content, under `--stall-timeout`
+    pub stall_deadline: Option<u64>,
+    /// Copy every metadata file a module writes under this directory
+    pub keep_metadata_json: Option<PathBuf>,
+    /// Log WASI file calls and count the errnos they fail with
+    pub trace_wasi: bool,
+}
+
+/// Which resource limit stopped a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Fuel,
+    Stack,
+    Memory,
+    /// `--stall-timeout` passed without the content finishing
+    Stall,
+}
+
+impl LimitExceeded {
+    /// Identify a limit trap from wasmtime's typed trap codes, or from the
+    /// limiter having refused a `memory.grow` during the same call
+    pub fn classify(error: &anyhow::Error, memory_refused: bool) -> Option<Self> {
+        match error.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => Some(Self::Fuel),
+            Some(Trap::StackOverflow) => Some(Self::Stack),
+            Some(Trap::Interrupt) => Some(Self::Stall),
+            // A refused memory.grow returns -1 to the guest, which then fails
+            // in its own way, so there is no trap code to go by
+            _ if memory_refused => Some(Self::Memory),
+            _ => None,
+        }
+    }
+
+    /// Error document `kind` for this limit
+    pub fn kind(self) -> &'static str {
+        match self {
+            Self::Fuel => "out_of_fuel",
+            Self::Stack => "stack_overflow",
+            Self::Memory => "memory_limit",
+            Self::Stall => "stalled",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Fuel => "exceeded fuel limit (CPU limit)",
+            Self::Stack => "stack overflow",
+            Self::Memory => "memory limit exceeded",
+            Self::Stall => "made no progress within the stall timeout",
+        }
+    }
+}
+
+/// A module stopped by one of its resource limits
+#[derive(Debug)]
+pub struct ResourceLimitError {
+    pub module: String,
+    pub limit: LimitExceeded,
+}
+
+impl std::fmt::Display for ResourceLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Module '{}' {}", self.module, self.limit.description())
+    }
+}
+
+impl std::error::Error for ResourceLimitError {}
+
+/// A module that could not be instantiated
+#[derive(Debug)]
+pub struct InstantiationError {
+    pub module: String,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for InstantiationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Module '{}' failed to instantiate: {:#}", self.module, self.error)
+    }
+}
+
+impl std::error::Error for InstantiationError {}
+
+/// A module file as `wadup list-modules` reports it
+#[derive(Debug, Clone)]
+pub struct ModuleDescription {
+    /// Which of the entrypoint, `_initialize` and `_start` the module exports
+    pub entry_exports: Vec<String>,
+    /// Imports, as `module::name`
+    pub imports: Vec<String>,
+    pub runtime: crate::module_runtime::ModuleRuntime,
+    pub manifest: crate::manifest::ModuleManifest,
+}
 
 #[derive(Clone)]
 pub struct ResourceLimits {
@@ -14,26 +267,137 @@ pub struct ResourceLimits {
     pub max_stack: Option<usize>,
 }
 
+/// A module's report that it is still working on a content, from the
+/// `heartbeat` host function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub module: String,
+    pub content_uuid: uuid::Uuid,
+    /// How far through the content the module is, in thousandths; None when
+    /// the module doesn't know
+    pub progress_permille: Option<u16>,
+    pub message: String,
+}
+
+/// Called with each heartbeat, on the worker thread running the module
+pub type ProgressCallback = Arc<dyn Fn(&Heartbeat) + Send + Sync>;
+
+/// Queues a sub-content emission as soon as its file is closed, while the
+/// module is still running. Returns whether it took the emission, which is
+/// then marked `dispatched` instead of being queued after the module returns.
+pub type SubcontentSink = Box<dyn FnMut(&SubContentEmission) -> bool + Send>;
+
 // Wrapper to combine ProcessingContext with WASI support
 pub struct StoreData {
     pub processing_ctx: ProcessingContext,
     pub wasi_ctx: WasiCtx,
     resource_limiter: Option<ResourceLimiterImpl>,
+    /// Source for `random_get` under `--seed`; OS entropy when `None`
+    rng: Option<ChaCha8Rng>,
+    /// `sched_yield` calls during the current entrypoint call
+    sched_yields: u64,
+    /// Fuel given to the current content so far: the `--fuel` budget plus
+    /// what `add_fuel` added
+    pub(crate) fuel_granted: u64,
+    /// `--fuel-ceiling`: most fuel `add_fuel` can bring a content to;
+    /// `add_fuel` is disabled when `None`
+    pub(crate) fuel_ceiling: Option<u64>,
+    /// Where closed sub-content files go under `--eager-subcontent`
+    subcontent_sink: Option<SubcontentSink>,
+    /// `--secret` values for `get_secret`; later entries win
+    pub(crate) secrets: Arc<Vec<crate::secrets::Secret>>,
+    /// `{keep_metadata_json}/{module}`, where metadata files are copied
+    keep_metadata_dir: Option<PathBuf>,
+    /// Epoch ticks allowed per content, when the engine interrupts stalls;
+    /// `heartbeat` grants them afresh
+    pub(crate) stall_deadline: Option<u64>,
+    /// Module name and the callback `heartbeat` reports to
+    pub(crate) progress: Option<(String, ProgressCallback)>,
+    /// Module name WASI file calls are logged under, with `--trace-wasi`
+    wasi_trace: Option<String>,
+    /// Failed WASI file calls of the current content by errno name, with
+    /// `--trace-wasi`
+    wasi_errnos: BTreeMap<&'static str, u64>,
+    /// Stream the last `inflate` stopped at a full buffer, for `inflate_more`
+    pub(crate) pending_inflate: Option<crate::host_functions::PendingInflate>,
 }
 
 pub struct WasmRuntime {
     engine: Engine,
     modules: Vec<ModuleInfo>,
     limits: ResourceLimits,
+    entrypoint: String,
+    module_cache: crate::precompile::ModuleCache,
+    dump_fs_on_error: Option<PathBuf>,
+    options: InstanceOptions,
+    /// Advances the engine's epoch while `--stall-timeout` is set
+    stall_ticker: Option<EpochTicker>,
+    progress: Option<ProgressCallback>,
+}
+
+/// Thread incrementing an engine's epoch at a fixed interval until dropped
+struct EpochTicker {
+    stop: Arc<std::sync::atomic::AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl EpochTicker {
+    fn start(engine: Engine, interval: std::time::Duration) -> Self {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::park_timeout(interval);
+                engine.increment_epoch();
+            }
+        });
+        Self { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
 }
 
 pub struct ModuleInfo {
+    /// Unique among the loaded modules; rows, tables and module documents
+    /// are keyed by it
     pub name: String,
+    /// File the module was loaded from
+    pub path: PathBuf,
     pub module: Module,
+    /// Fallback version (hash of the source file mtime) for modules that
+    /// don't export `wadup_module_version`
+    pub version: String,
+    /// Which contents the module is run on, from its sidecar manifest
+    pub manifest: crate::manifest::ModuleManifest,
+    /// Language and runtime recorded with the module's runs
+    pub runtime: crate::module_runtime::ModuleRuntime,
 }
 
 impl WasmRuntime {
     pub fn new(limits: ResourceLimits) -> Result<Self> {
+        Ok(Self {
+            engine: Self::build_engine(&limits, false)?,
+            modules: Vec::new(),
+            limits,
+            entrypoint: DEFAULT_ENTRYPOINT.to_string(),
+            module_cache: crate::precompile::ModuleCache::default(),
+            dump_fs_on_error: None,
+            options: InstanceOptions::default(),
+            stall_ticker: None,
+            progress: None,
+        })
+    }
+
+    pub(crate) fn build_engine(limits: &ResourceLimits, epoch_interruption: bool) -> Result<Engine> {
         let mut config = Config::new();
         config.wasm_multi_memory(true);
         config.async_support(false);
@@ -48,72 +412,384 @@ impl WasmRuntime {
             config.max_wasm_stack(max_stack);
         }
 
-        let engine = Engine::new(&config)?;
+        // Lets a stalled module be interrupted wherever it is looping
+        config.epoch_interruption(epoch_interruption);
 
-        Ok(Self {
-            engine,
-            modules: Vec::new(),
-            limits,
-        })
+        Engine::new(&config)
+    }
+
+    /// Override the export called for each content (default: `process`).
+    ///
+    /// Must be set before `load_modules`, which validates the export exists.
+    pub fn set_entrypoint(&mut self, entrypoint: impl Into<String>) {
+        self.entrypoint = entrypoint.into();
     }
 
-    pub fn load_modules(&mut self, dir: &Path) -> Result<()> {
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
+    /// Load modules from `path`, which is either a directory of `.wasm`
+    /// files or a single `.wasm` file.
+    pub fn load_modules(&mut self, path: &Path) -> Result<()> {
+        if path.is_file() {
+            if !Self::is_wasm_file(path) {
+                anyhow::bail!("Module file must have a .wasm extension: {:?}", path);
+            }
+            return self.load_module_file(path);
+        }
 
-            if path.extension().and_then(|s| s.to_str()) == Some("wasm") {
-                let name = path.file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("unknown")
-                    .to_string();
+        for path in Self::module_files(path)? {
+            self.load_module_file(&path)?;
+        }
 
-                let module = crate::precompile::load_module_with_cache(&self.engine, &path)?;
+        if self.modules.is_empty() {
+            anyhow::bail!("No WASM modules found in directory");
+        }
 
-                // Validate module exports - must have 'process' function
-                self.validate_module(&module)?;
+        Ok(())
+    }
 
-                tracing::info!("Loaded WASM module: {}", name);
-                self.modules.push(ModuleInfo { name, module });
+    /// Check each module at `path` (a directory or a single `.wasm` file)
+    /// as `load_modules` would, and describe it, without instantiating or
+    /// running anything. `load_modules` would fail on the modules whose
+    /// description is an error.
+    pub fn describe_modules(&mut self, path: &Path) -> Result<Vec<(String, Result<ModuleDescription>)>> {
+        let paths = if path.is_file() {
+            if !Self::is_wasm_file(path) {
+                anyhow::bail!("Module file must have a .wasm extension: {:?}", path);
             }
+            vec![path.to_path_buf()]
+        } else {
+            Self::module_files(path)?
+        };
+        Ok(paths.iter().map(|path| (Self::module_name(path), self.describe_module_file(path))).collect())
+    }
+
+    fn describe_module_file(&mut self, path: &Path) -> Result<ModuleDescription> {
+        let module = self.module_cache.load(&self.engine, path)?;
+        self.validate_module(&module)?;
+        let unsupported = module.imports().find(|import| match import.module() {
+            "wasi_snapshot_preview1" => {
+                !WASI_PREVIEW1_IMPORTS.contains(&import.name()) || !self.options.wasi_profile.allows(import.name())
+            }
+            "wadup" => !crate::host_functions::HOST_FUNCTIONS.contains(&import.name()),
+            _ => true,
+        });
+        if let Some(import) = unsupported {
+            anyhow::bail!("unsupported import {}::{}", import.module(), import.name());
         }
 
-        if self.modules.is_empty() {
-            anyhow::bail!("No WASM modules found in directory");
+        let manifest = crate::manifest::ModuleManifest::load_for(path)?;
+        let wasm = std::fs::read(path).with_context(|| format!("Failed to read module {:?}", path))?;
+        let runtime = crate::module_runtime::ModuleRuntime::detect(&module, &wasm).with_manifest(&manifest);
+        let entry_exports = [self.entrypoint.as_str(), "_initialize", "_start"].into_iter()
+            .filter(|name| module.exports().any(|export| export.name() == *name))
+            .map(String::from)
+            .collect();
+        let imports = module.imports().map(|import| format!("{}::{}", import.module(), import.name())).collect();
+        Ok(ModuleDescription { entry_exports, imports, runtime, manifest })
+    }
+
+    /// The `.wasm` files in `dir`. Modules run in file name order, so a
+    /// module can rely on the streams an earlier one published
+    fn module_files(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.retain(|path| Self::is_wasm_file(path));
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn module_name(path: &Path) -> String {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown")
+            .to_string()
+    }
+
+    /// Dump an instance's in-memory filesystem under `dir` when its entrypoint traps.
+    pub fn set_dump_fs_on_error(&mut self, dir: Option<PathBuf>) {
+        self.dump_fs_on_error = dir;
+    }
+
+    /// Seed `random_get` so runs are reproducible. Each module gets its own
+    /// stream per content, derived from the seed, its name and the content bytes.
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.options.seed = seed;
+    }
+
+    /// Without a seed, seed `random_get` from the module name and content
+    /// bytes alone, so the same content always gets the same stream without
+    /// choosing a seed for the run. These are the streams of seed 0.
+    pub fn set_content_seeded_random(&mut self, enabled: bool) {
+        self.options.content_seeded_random = enabled;
+    }
+
+    /// Restrict which WASI functions modules may import and how they may
+    /// use the filesystem. Modules importing anything else fail to instantiate.
+    pub fn set_wasi_profile(&mut self, profile: WasiProfile) {
+        self.options.wasi_profile = profile;
+    }
+
+    /// Let modules raise their fuel with `add_fuel`, up to `ceiling` per
+    /// content in total. Only meaningful with a fuel limit.
+    pub fn set_fuel_ceiling(&mut self, ceiling: Option<u64>) {
+        self.options.fuel_ceiling = ceiling;
+    }
+
+    /// Let modules read `secrets` with `get_secret`. They are kept out of
+    /// the environment and arguments; a later secret replaces an earlier
+    /// one with the same name.
+    pub fn set_secrets(&mut self, secrets: Vec<crate::secrets::Secret>) {
+        self.options.secrets = Arc::new(secrets);
+    }
+
+    /// Fail `path_open` with `EMfile` while a module has `max` files and
+    /// directories open (not counting stdio and the preopened root).
+    pub fn set_max_open_files(&mut self, max: Option<usize>) {
+        self.options.max_open_files = max;
+    }
+
+    /// Interrupt a module that hasn't finished a content `timeout` after
+    /// the content began, failing the content with [`LimitExceeded::Stall`]
+    /// so its worker moves on. Call before `load_modules`: modules are
+    /// compiled for epoch interruption, and precompiled cache files
+    /// written without it are recompiled.
+    pub fn set_stall_timeout(&mut self, timeout: Option<std::time::Duration>) -> Result<()> {
+        if !self.modules.is_empty() {
+            anyhow::bail!("The stall timeout must be set before modules are loaded");
+        }
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            anyhow::bail!("The stall timeout must be positive");
+        }
+        // Stop the old ticker before replacing the engine it ticks
+        self.stall_ticker = None;
+        self.engine = Self::build_engine(&self.limits, timeout.is_some())?;
+        self.stall_ticker = timeout.map(|timeout| EpochTicker::start(self.engine.clone(), timeout / STALL_TICKS as u32));
+        self.options.stall_deadline = timeout.map(|_| STALL_TICKS + 1);
+        Ok(())
+    }
+
+    /// Call `callback` with every heartbeat a module sends. Heartbeats are
+    /// dropped when no callback is set.
+    pub fn set_progress_callback(&mut self, callback: Option<ProgressCallback>) {
+        self.progress = callback;
+    }
+
+    /// Log each WASI file call's fd, path and errno at debug level, and
+    /// count the errnos failed calls return per content (`--trace-wasi`).
+    pub fn set_trace_wasi(&mut self, trace: bool) {
+        self.options.trace_wasi = trace;
+    }
+
+    /// Keep what modules write to `/tmp` from one content to the next,
+    /// rather than clearing it before each content.
+    pub fn set_keep_scratch(&mut self, keep: bool) {
+        self.options.keep_scratch = keep;
+    }
+
+    /// Copy each metadata file a module writes, as it wrote it, to
+    /// `{dir}/{module}/{content_uuid}/` under its guest path (e.g.
+    /// `metadata/output_0.json`) before the host consumes and deletes it.
+    pub fn set_keep_metadata_json(&mut self, dir: Option<PathBuf>) {
+        self.options.keep_metadata_json = dir;
+    }
+
+    /// Pass `args` to every module after `argv[0]`, which is the module name.
+    pub fn set_module_args(&mut self, args: Vec<String>) {
+        self.options.module_args = args;
+    }
+
+    /// Expose the files under `mount.host_dir` read-only at `mount.guest_path`
+    /// in every instance created from now on. The files are memory-mapped
+    /// once here; instances share the mapping rather than copying it.
+    pub fn add_mount(&mut self, mount: &Mount) -> Result<()> {
+        if !mount.host_dir.is_dir() {
+            anyhow::bail!("Mount directory {} doesn't exist", mount.host_dir.display());
+        }
+        let mut files = (*self.options.mounted_files).clone();
+        let mut pending = vec![(mount.host_dir.clone(), mount.guest_path.clone())];
+        while let Some((dir, guest_dir)) = pending.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().into_string()
+                    .map_err(|name| anyhow::anyhow!("Mounted file name {:?} isn't UTF-8", name))?;
+                let guest_path = format!("{}/{}", guest_dir, name);
+                let path = entry.path();
+                let mut file_type = entry.file_type()?;
+                if file_type.is_symlink() {
+                    // Files behind symlinks are mounted, but directories aren't:
+                    // a link back up the tree would be walked forever
+                    let Ok(metadata) = std::fs::metadata(&path) else { continue };
+                    if metadata.is_dir() {
+                        tracing::warn!("Not mounting symlinked directory {}", path.display());
+                        continue;
+                    }
+                    file_type = metadata.file_type();
+                }
+                if file_type.is_dir() {
+                    pending.push((path, guest_path));
+                } else if file_type.is_file() {
+                    let data = crate::shared_buffer::SharedBuffer::from_file(&path)?.to_bytes();
+                    files.push((guest_path, data));
+                }
+            }
         }
+        tracing::info!("Mounted {} read-only at {}", mount.host_dir.display(), mount.guest_path);
+        self.options.mounted_files = Arc::new(files);
+        Ok(())
+    }
+
+    /// Replace the loaded modules with those at `path`.
+    ///
+    /// Modules whose source is unchanged since they were last loaded are
+    /// served from the in-memory cache rather than deserialized again.
+    pub fn reload_modules(&mut self, path: &Path) -> Result<()> {
+        self.modules.clear();
+        self.load_modules(path)
+    }
+
+    /// Set how many compiled modules to keep in memory (0 disables the cache).
+    pub fn set_module_cache_capacity(&mut self, capacity: usize) {
+        let compress = self.module_cache.compress();
+        self.module_cache = crate::precompile::ModuleCache::new(capacity);
+        self.module_cache.set_compress(compress);
+    }
+
+    /// Gzip the `_precompiled` cache files written when loading modules.
+    /// Call before `load_modules`; cache files load either way.
+    pub fn set_compress_cache(&mut self, compress: bool) {
+        self.module_cache.set_compress(compress);
+    }
 
+    pub fn module_cache(&self) -> &crate::precompile::ModuleCache {
+        &self.module_cache
+    }
+
+    /// Run the named modules first, in the given order; the others follow
+    /// in name order. Call after `load_modules`.
+    pub fn set_module_order(&mut self, order: &[String]) -> Result<()> {
+        for (i, name) in order.iter().enumerate() {
+            let Some(pos) = self.modules[i..].iter().position(|m| &m.name == name) else {
+                if self.modules[..i].iter().any(|m| &m.name == name) {
+                    anyhow::bail!("Module '{}' is listed twice in the module order", name);
+                }
+                anyhow::bail!("Unknown module '{}' in the module order (loaded: {})", name, self.module_names().join(", "));
+            };
+            let module = self.modules.remove(i + pos);
+            self.modules.insert(i, module);
+        }
         Ok(())
     }
 
+    /// Names of the loaded modules, in the order they run
+    pub fn module_names(&self) -> Vec<&str> {
+        self.modules.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    /// Language and runtime of each loaded module, by name
+    pub fn module_runtimes(&self) -> BTreeMap<String, crate::module_runtime::ModuleRuntime> {
+        self.modules.iter().map(|m| (m.name.clone(), m.runtime.clone())).collect()
+    }
+
+    fn is_wasm_file(path: &Path) -> bool {
+        path.extension().and_then(|s| s.to_str()) == Some("wasm")
+    }
+
+    fn load_module_file(&mut self, path: &Path) -> Result<()> {
+        let name = Self::module_name(path);
+        if let Some(loaded) = self.modules.iter().find(|m| m.name == name) {
+            anyhow::bail!(
+                "Module name '{}' of {:?} is already taken by {:?}; rename one of the files",
+                name, path, loaded.path
+            );
+        }
+
+        let module = self.module_cache.load(&self.engine, path)?;
+
+        // Validate module exports - must have the entrypoint function
+        self.validate_module(&module)?;
+
+        let version = Self::mtime_version(path)?;
+        let manifest = crate::manifest::ModuleManifest::load_for(path)?;
+        let wasm = std::fs::read(path).with_context(|| format!("Failed to read module {:?}", path))?;
+        let runtime = crate::module_runtime::ModuleRuntime::detect(&module, &wasm).with_manifest(&manifest);
+
+        tracing::info!("Loaded WASM module: {} ({}, {})", name, runtime.language.as_deref().unwrap_or("unknown language"), runtime.runtime);
+        self.modules.push(ModuleInfo { name, path: path.to_path_buf(), module, version, manifest, runtime });
+        Ok(())
+    }
+
+    /// Derive a fallback module version from the source file's mtime.
+    fn mtime_version(path: &Path) -> Result<String> {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        crate::precompile::get_file_mtime(path)?.hash(&mut hasher);
+        Ok(format!("mtime:{:016x}", hasher.finish()))
+    }
+
     fn validate_module(&self, module: &Module) -> Result<()> {
-        let has_process = module.exports()
-            .any(|export| export.name() == "process");
+        let has_entrypoint = module.exports()
+            .any(|export| export.name() == self.entrypoint);
 
-        if has_process {
+        if has_entrypoint {
             Ok(())
         } else {
-            anyhow::bail!("Module missing required 'process' export. All WADUP modules must export a 'process' function.");
+            anyhow::bail!(
+                "Module missing required '{0}' export. All WADUP modules must export a '{0}' function.",
+                self.entrypoint
+            );
         }
     }
 
+    /// Instantiate every loaded module, stopping at the first that fails.
     pub fn create_instances(
         &self,
         metadata_store: MetadataStore,
     ) -> Result<Vec<ModuleInstance>> {
+        let (instances, mut failures) = self.try_create_instances(metadata_store);
+        if failures.is_empty() {
+            Ok(instances)
+        } else {
+            Err(failures.remove(0).into())
+        }
+    }
+
+    /// Instantiate every loaded module, returning those that succeeded and
+    /// an error naming each module that didn't (e.g. over a missing import).
+    pub fn try_create_instances(
+        &self,
+        metadata_store: MetadataStore,
+    ) -> (Vec<ModuleInstance>, Vec<InstantiationError>) {
         let mut instances = Vec::new();
+        let mut failures = Vec::new();
 
         for module_info in &self.modules {
-            let instance = ModuleInstance::new(
+            let mut instance = match ModuleInstance::new(
                 &self.engine,
                 &module_info.module,
                 &module_info.name,
                 &self.limits,
                 metadata_store.clone(),
-            )?;
+                &self.options,
+            ) {
+                Ok(instance) => instance,
+                Err(error) => {
+                    failures.push(InstantiationError { module: module_info.name.clone(), error });
+                    continue;
+                }
+            };
+            if instance.version.is_none() {
+                instance.version = Some(module_info.version.clone());
+            }
+            instance.set_entrypoint(&self.entrypoint);
+            instance.set_dump_fs_on_error(self.dump_fs_on_error.clone());
+            instance.set_progress_callback(self.progress.clone());
+            instance.manifest = module_info.manifest.clone();
+            instance.runtime = Some(module_info.runtime.clone());
             instances.push(instance);
         }
 
-        Ok(instances)
+        (instances, failures)
     }
 
     pub fn engine(&self) -> &Engine {
@@ -125,13 +801,63 @@ impl WasmRuntime {
     }
 }
 
+/// Deterministic stream for `seed` and `parts`, e.g. a module name and a
+/// content digest for `random_get`. It is ChaCha8 keyed with the SHA-256 of
+/// the parts, both fixed algorithms, so a seed reproduces its streams across
+/// toolchain and `rand` upgrades.
+pub(crate) fn seeded_rng(seed: u64, parts: &[&[u8]]) -> ChaCha8Rng {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    ChaCha8Rng::from_seed(hasher.finalize().into())
+}
+
+/// Sort key for `/metadata` files: `output_2.json` comes before `output_10.json`
+fn metadata_file_order(name: &str) -> (String, Option<u64>, String) {
+    let stem = name.split('.').next().unwrap_or(name);
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    (prefix.to_string(), stem[prefix.len()..].parse().ok(), name.to_string())
+}
+
+/// Write `strings` as NUL-terminated strings from `buf_ptr`, and a pointer to
+/// each into the array at `ptrs_ptr`, as `args_get` and `environ_get` return them
+fn write_string_list(caller: &mut Caller<StoreData>, memory: Memory, strings: &[String], ptrs_ptr: i32, buf_ptr: i32) -> Result<()> {
+    let mut buf_offset = buf_ptr as usize;
+    let mut ptr_offset = ptrs_ptr as usize;
+    for s in strings {
+        memory.write(&mut *caller, ptr_offset, &(buf_offset as u32).to_le_bytes())?;
+        ptr_offset += 4;
+
+        let bytes = format!("{}\0", s);
+        memory.write(&mut *caller, buf_offset, bytes.as_bytes())?;
+        buf_offset += bytes.len();
+    }
+    Ok(())
+}
+
 struct ResourceLimiterImpl {
-    max_memory: usize,
+    /// `None` without `--max-memory`; the limiter still tracks `peak_memory`
+    max_memory: Option<usize>,
+    /// Set when a growth past `max_memory` is refused; cleared before each call
+    memory_refused: bool,
+    /// Largest linear memory size, in bytes, allowed since the last reset
+    peak_memory: usize,
 }
 
 impl ResourceLimiter for ResourceLimiterImpl {
     fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool> {
-        Ok(desired <= self.max_memory)
+        let allowed = self.max_memory.is_none_or(|max| desired <= max);
+        if allowed {
+            self.peak_memory = self.peak_memory.max(desired);
+        } else {
+            self.memory_refused = true;
+        }
+        Ok(allowed)
     }
 
     fn table_growing(&mut self, _current: usize, _desired: usize, _maximum: Option<usize>) -> Result<bool> {
@@ -143,8 +869,37 @@ pub struct ModuleInstance {
     store: Store<StoreData>,
     instance: Instance,
     name: String,
+    /// Version declared by the guest via `wadup_module_version`, or the
+    /// loader's fallback
+    version: Option<String>,
+    /// Export called for each content
+    entrypoint: String,
+    /// Host directory to dump the in-memory filesystem to when the entrypoint traps
+    dump_fs_on_error: Option<PathBuf>,
     fuel_limit: Option<u64>,
+    /// `--seed`, reapplied per content so each content sees the same stream
+    seed: Option<u64>,
+    /// Leave `/tmp` as the previous content left it
+    keep_scratch: bool,
+    /// Parent of the next content, taken by `process_content`
+    parent: Option<ParentInfo>,
+    /// SHA-256 of the next content, taken by `process_content`
+    content_digest: Option<[u8; 32]>,
+    /// Streams mounted for the next content, taken by `process_content`
+    streams: Vec<(String, bytes::Bytes)>,
+    /// Output salvaged from a content that ran out of fuel
+    partial_ctx: Option<ProcessingContext>,
     metadata_store: MetadataStore,
+    manifest: crate::manifest::ModuleManifest,
+    /// Language and runtime of the module, when it was loaded from a file
+    runtime: Option<crate::module_runtime::ModuleRuntime>,
+    /// What the instance was built from, to build a fresh one for modules
+    /// that aren't `stateful`
+    module: Module,
+    limits: ResourceLimits,
+    options: InstanceOptions,
+    /// Whether the entrypoint has run on a content in this instance
+    used: bool,
 }
 
 impl ModuleInstance {
@@ -154,7 +909,50 @@ impl ModuleInstance {
         name: &str,
         limits: &ResourceLimits,
         metadata_store: MetadataStore,
+        options: &InstanceOptions,
+    ) -> Result<Self> {
+        let mode = (MODE_VAR.to_string(), MODE_RUN.to_string());
+        Self::instantiate(engine, module, name, limits, vec![mode], metadata_store, options)
+    }
+
+    /// Create a new instance with environment variables (for test mode).
+    pub fn with_env_vars(
+        engine: &Engine,
+        module: &Module,
+        name: &str,
+        limits: &ResourceLimits,
+        env_vars: Vec<(String, String)>,
+    ) -> Result<Self> {
+        let env_vars = std::iter::once((MODE_VAR.to_string(), MODE_TEST.to_string()))
+            .chain(env_vars.into_iter().filter(|(key, _)| key != MODE_VAR))
+            .collect();
+        // Use a dummy metadata store for test mode (not used)
+        Self::instantiate(engine, module, name, limits, env_vars, MetadataStore::new_dummy(), &InstanceOptions::default())
+    }
+
+    /// Shared by `new` and `with_env_vars`, so a module sees the same imports,
+    /// filesystem and base environment under `wadup run` and `wadup test`.
+    fn instantiate(
+        engine: &Engine,
+        module: &Module,
+        name: &str,
+        limits: &ResourceLimits,
+        env_vars: Vec<(String, String)>,
+        metadata_store: MetadataStore,
+        options: &InstanceOptions,
     ) -> Result<Self> {
+        let profile = options.wasi_profile;
+        if let Some(import) = module.imports()
+            .find(|i| i.module() == "wasi_snapshot_preview1" && !profile.allows(i.name()))
+        {
+            anyhow::bail!(
+                "import wasi_snapshot_preview1::{} isn't allowed by the '{}' WASI profile",
+                import.name(),
+                profile.name()
+            );
+        }
+        let seed = options.seed.or(options.content_seeded_random.then_some(0));
+
         // Create a dummy context for initialization
         let dummy_ctx = ProcessingContext::new(
             uuid::Uuid::nil(),
@@ -168,22 +966,46 @@ impl ModuleInstance {
         filesystem.create_dir_all("/tmp")?;
         filesystem.create_dir_all("/metadata")?;
         filesystem.create_dir_all("/subcontent")?;
+        filesystem.create_dir_all("/streams")?;
 
         // Create empty /data.bin file
         filesystem.create_file("/data.bin", Vec::new())?;
 
-        // Create WASI context with our in-memory filesystem
-        let wasi_ctx = WasiCtx::new(filesystem);
+        for (path, data) in options.mounted_files.iter() {
+            filesystem.create_readonly_file(path, data.clone())?;
+        }
 
-        // Create resource limiter if memory limit is specified
-        let resource_limiter = limits.max_memory.map(|max_memory| {
-            ResourceLimiterImpl { max_memory }
-        });
+        // Go's wasip1 runtime takes its working directory from PWD
+        let mut environment = vec![("PWD".to_string(), "/".to_string())];
+        environment.extend(env_vars.into_iter().filter(|(key, _)| key != "PWD"));
+
+        // Create WASI context with our in-memory filesystem and env vars
+        let mut wasi_ctx = WasiCtx::with_env_vars(filesystem, environment);
+        wasi_ctx.set_read_only(profile == WasiProfile::Readonly);
+        wasi_ctx.set_max_open_files(options.max_open_files);
+        // Programs may read argv[0] during startup, so there is always one
+        wasi_ctx.set_args(std::iter::once(name.to_string()).chain(options.module_args.iter().cloned()).collect());
+
+        // The limiter enforces --max-memory if given and always records peak memory
+        let resource_limiter = Some(ResourceLimiterImpl { max_memory: limits.max_memory, memory_refused: false, peak_memory: 0 });
 
         let store_data = StoreData {
             processing_ctx: dummy_ctx,
             wasi_ctx,
             resource_limiter,
+            // Setup and `_start` draw from a stream of their own
+            rng: seed.map(|seed| seeded_rng(seed, &[name.as_bytes()])),
+            sched_yields: 0,
+            fuel_granted: limits.fuel.unwrap_or(0),
+            fuel_ceiling: options.fuel_ceiling,
+            subcontent_sink: None,
+            secrets: Arc::clone(&options.secrets),
+            keep_metadata_dir: options.keep_metadata_json.as_ref().map(|dir| dir.join(name)),
+            stall_deadline: options.stall_deadline,
+            progress: None,
+            wasi_trace: options.trace_wasi.then(|| name.to_string()),
+            wasi_errnos: BTreeMap::new(),
+            pending_inflate: None,
         };
 
         let mut store = Store::new(engine, store_data);
@@ -193,16 +1015,22 @@ impl ModuleInstance {
             store.set_fuel(fuel)?;
         }
 
-        // Set memory limits if specified
+        // `_start` and `setup` get one stall timeout between them
+        if let Some(ticks) = options.stall_deadline {
+            store.set_epoch_deadline(ticks);
+        }
+
+        // Set memory limits and peak tracking
         if store.data().resource_limiter.is_some() {
             store.limiter(|data| data.resource_limiter.as_mut().unwrap());
         }
 
-        let mut linker = Linker::new(engine);
-
-        // Add WASI Preview1 functions
-        Self::add_wasi_functions(&mut linker)?;
-
+        let linker = Self::linker(engine)?;
+        // Name the first import the linker can't satisfy rather than
+        // surfacing wasmtime's "unknown import" error
+        if let Some(import) = module.imports().find(|i| linker.get_by_import(&mut store, i).is_none()) {
+            anyhow::bail!("unsupported import {}::{}", import.module(), import.name());
+        }
         let instance = linker.instantiate(&mut store, module)?;
 
         // Call _start once during initialization if it exists (for Go runtime initialization)
@@ -211,89 +1039,222 @@ impl ModuleInstance {
             let _ = start_func.call(&mut store, ());
         }
 
+        // Call the optional one-time setup export
+        Self::run_setup(&instance, &mut store, name)?;
+
+        let version = Self::read_declared_version(&instance, &mut store);
+
         Ok(Self {
             store,
             instance,
             name: name.to_string(),
+            version,
+            entrypoint: DEFAULT_ENTRYPOINT.to_string(),
+            dump_fs_on_error: None,
             fuel_limit: limits.fuel,
+            seed,
+            keep_scratch: options.keep_scratch,
+            parent: None,
+            content_digest: None,
+            streams: Vec::new(),
+            partial_ctx: None,
             metadata_store,
+            manifest: Default::default(),
+            runtime: None,
+            module: module.clone(),
+            limits: limits.clone(),
+            options: options.clone(),
+            used: false,
         })
     }
 
-    /// Create a new instance with environment variables (for test mode).
-    pub fn with_env_vars(
-        engine: &Engine,
-        module: &Module,
-        name: &str,
-        limits: &ResourceLimits,
-        env_vars: Vec<(String, String)>,
-    ) -> Result<Self> {
-        // Create a dummy context for initialization
-        let dummy_ctx = ProcessingContext::new(
-            uuid::Uuid::nil(),
-            crate::shared_buffer::SharedBuffer::from_vec(Vec::new()),
-        );
+    /// Swap in a fresh store and instance, calling `teardown` on the old
+    /// one, so a module that isn't `stateful` starts each content from
+    /// scratch. What was set for the next content (environment, sub-content
+    /// sink, progress callback) carries over.
+    fn reinstantiate(&mut self) -> Result<()> {
+        let env_vars = self.store.data().wasi_ctx.env_vars().to_vec();
+        let mut fresh = Self::instantiate(
+            &self.store.engine().clone(),
+            &self.module,
+            &self.name,
+            &self.limits,
+            env_vars,
+            self.metadata_store.clone(),
+            &self.options,
+        )
+        .with_context(|| format!("Failed to re-instantiate module '{}'", self.name))?;
+        std::mem::swap(&mut self.store, &mut fresh.store);
+        std::mem::swap(&mut self.instance, &mut fresh.instance);
+        let old = fresh.store.data_mut();
+        let data = self.store.data_mut();
+        data.progress = old.progress.take();
+        data.subcontent_sink = old.subcontent_sink.take();
+        Ok(())
+    }
 
-        // Create in-memory filesystem
-        let filesystem = Arc::new(MemoryFilesystem::new());
+    /// Linker with every import a module may use: WASI Preview1, the `wadup`
+    /// host functions and the `env` soft-float intrinsics.
+    fn linker(engine: &Engine) -> Result<Linker<StoreData>> {
+        let mut linker = Linker::new(engine);
+        Self::add_wasi_functions(&mut linker)?;
+        crate::host_functions::add_to_linker(&mut linker)?;
+        Ok(linker)
+    }
 
-        // Create directories for WASI filesystem
-        filesystem.create_dir_all("/tmp")?;
-        filesystem.create_dir_all("/metadata")?;
-        filesystem.create_dir_all("/subcontent")?;
+    /// Dump the in-memory filesystem under `dir` when the entrypoint traps.
+    pub fn set_dump_fs_on_error(&mut self, dir: Option<PathBuf>) {
+        self.dump_fs_on_error = dir;
+    }
 
-        // Create empty /data.bin file
-        filesystem.create_file("/data.bin", Vec::new())?;
+    /// Report the module's heartbeats to `callback`.
+    pub fn set_progress_callback(&mut self, callback: Option<ProgressCallback>) {
+        self.store.data_mut().progress = callback.map(|callback| (self.name.clone(), callback));
+    }
 
-        // Create WASI context with our in-memory filesystem and env vars
-        let wasi_ctx = WasiCtx::with_env_vars(filesystem, env_vars);
+    /// Parent reported by `parent_uuid` / `parent_filename` during the next
+    /// `process_content` call only; after it the module sees root content.
+    pub fn set_parent(&mut self, parent: Option<ParentInfo>) {
+        self.parent = parent;
+    }
 
-        // Create resource limiter if memory limit is specified
-        let resource_limiter = limits.max_memory.map(|max_memory| {
-            ResourceLimiterImpl { max_memory }
-        });
+    /// SHA-256 of the next content's bytes, which seeds its `random_get`
+    /// stream, so a content run through several modules is hashed once.
+    /// Used by the next `process_content` call only; without it the
+    /// instance hashes the content itself when it needs to.
+    pub fn set_content_digest(&mut self, digest: Option<[u8; 32]>) {
+        self.content_digest = digest;
+    }
 
-        let store_data = StoreData {
-            processing_ctx: dummy_ctx,
-            wasi_ctx,
-            resource_limiter,
-        };
+    /// Whether `random_get` is seeded per content, which needs the
+    /// content's digest
+    pub fn is_seeded(&self) -> bool {
+        self.seed.is_some()
+    }
 
-        let mut store = Store::new(engine, store_data);
+    /// Expose `encoding` to the module as `WADUP_TEXT_ENCODING` (unset for
+    /// `None`) until it is changed.
+    pub fn set_text_encoding(&mut self, encoding: Option<&str>) {
+        self.store.data_mut().wasi_ctx.set_env_var("WADUP_TEXT_ENCODING", encoding);
+    }
 
-        // Set fuel limit if specified
-        if let Some(fuel) = limits.fuel {
-            store.set_fuel(fuel)?;
-        }
+    /// Hand sub-content emissions to `sink` as their files are closed, for
+    /// the contents processed until it is changed. Emissions it takes are
+    /// returned `dispatched` in the processing context.
+    pub fn set_subcontent_sink(&mut self, sink: Option<SubcontentSink>) {
+        self.store.data_mut().subcontent_sink = sink;
+    }
 
-        // Set memory limits if specified
-        if store.data().resource_limiter.is_some() {
-            store.limiter(|data| data.resource_limiter.as_mut().unwrap());
-        }
+    /// Read-only files mounted under `/streams` during the next
+    /// `process_content` call only, e.g. the streams an earlier module
+    /// split the content into.
+    pub fn set_streams(&mut self, streams: Vec<(String, bytes::Bytes)>) {
+        self.streams = streams;
+    }
 
-        let mut linker = Linker::new(engine);
+    /// Override the export called for each content (default: `process`).
+    pub fn set_entrypoint(&mut self, entrypoint: &str) {
+        self.entrypoint = entrypoint.to_string();
+    }
 
-        // Add WASI Preview1 functions
-        Self::add_wasi_functions(&mut linker)?;
+    /// Call a no-argument export, accepting both `() -> i32` and `() -> ()`
+    /// signatures (the latter reports 0).
+    ///
+    /// Returns None if the export is missing or has another signature.
+    fn call_export(instance: &Instance, store: &mut Store<StoreData>, name: &str) -> Option<Result<i32>> {
+        if let Ok(func) = instance.get_typed_func::<(), i32>(&mut *store, name) {
+            Some(func.call(&mut *store, ()))
+        } else if let Ok(func) = instance.get_typed_func::<(), ()>(&mut *store, name) {
+            Some(func.call(&mut *store, ()).map(|_| 0))
+        } else {
+            None
+        }
+    }
 
-        let instance = linker.instantiate(&mut store, module)?;
+    /// Call the module's `setup` export, if present, once after instantiation.
+    fn run_setup(instance: &Instance, store: &mut Store<StoreData>, name: &str) -> Result<()> {
+        match Self::call_export(instance, store, "setup").map(Self::resolve_exit) {
+            None | Some(Ok(0)) => Ok(()),
+            Some(Ok(code)) => anyhow::bail!("Module '{}' setup returned error code: {}", name, code),
+            Some(Err(e)) => Err(e.context(format!("Module '{}' setup failed", name))),
+        }
+    }
 
-        // Call _start once during initialization if it exists (for Go runtime initialization)
-        if let Ok(start_func) = instance.get_typed_func::<(), ()>(&mut store, "_start") {
-            // Call and ignore any errors (Go modules may return normally or trap)
-            let _ = start_func.call(&mut store, ());
+    /// Clear files a previous content left in `/metadata` and `/subcontent`
+    /// (e.g. when its module failed) and, unless scratch is kept, in `/tmp`,
+    /// then call the module's `wadup_begin_content` export, if present, so
+    /// guest libraries can restart their file counters for each content.
+    fn begin_content(&mut self) -> Result<()> {
+        self.arm_stall_deadline();
+        let filesystem = &self.store.data().wasi_ctx.filesystem;
+        let scratch = (!self.keep_scratch).then_some("/tmp");
+        for path in ["/metadata", "/subcontent"].into_iter().chain(scratch) {
+            if let Ok(dir) = filesystem.get_dir(path) {
+                if !dir.is_empty() {
+                    tracing::debug!("Removing {} leftover entries from {} of module '{}'", dir.len(), path, self.name);
+                    dir.clear();
+                }
+            }
         }
+        match Self::call_export(&self.instance, &mut self.store, "wadup_begin_content").map(Self::resolve_exit) {
+            None | Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => Err(e.context(format!("Module '{}' wadup_begin_content failed", self.name))),
+        }
+    }
 
-        // Use a dummy metadata store for test mode (not used)
-        let metadata_store = MetadataStore::new_dummy();
+    /// Give the module a full stall timeout from now, when one is set
+    fn arm_stall_deadline(&mut self) {
+        if let Some(ticks) = self.store.data().stall_deadline {
+            self.store.set_epoch_deadline(ticks);
+        }
+    }
 
-        Ok(Self {
-            store,
-            instance,
-            name: name.to_string(),
-            fuel_limit: limits.fuel,
-            metadata_store,
-        })
+    /// For modules whose manifest sets `wants_direct_content`, write the
+    /// content into the buffer returned by the module's
+    /// `content_buffer(len: i32) -> i32` export, so `process` can use it
+    /// without opening `/data.bin`. Returns whether the content was written;
+    /// the module reads `/data.bin` as usual when it wasn't (no such export,
+    /// or a pointer of 0 or below because the buffer can't hold `len` bytes).
+    fn write_direct_content(&mut self, data: &[u8]) -> Result<bool> {
+        if !self.manifest.wants_direct_content {
+            return Ok(false);
+        }
+        let Ok(len) = i32::try_from(data.len()) else {
+            return Ok(false);
+        };
+        let Ok(func) = self.instance.get_typed_func::<i32, i32>(&mut self.store, "content_buffer") else {
+            tracing::debug!("Module '{}' wants direct content but has no content_buffer export", self.name);
+            return Ok(false);
+        };
+        let ptr = func.call(&mut self.store, len)
+            .with_context(|| format!("Module '{}' content_buffer failed", self.name))?;
+        if ptr <= 0 {
+            return Ok(false);
+        }
+        let memory = self.instance.get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("Module '{}' has no exported memory", self.name))?;
+        memory.write(&mut self.store, ptr as usize, data).map_err(|_| {
+            anyhow::anyhow!("Module '{}' content_buffer returned {} bytes at {} outside its memory", self.name, len, ptr)
+        })?;
+        Ok(true)
+    }
+
+    /// Read the version string a guest declares by exporting
+    /// `wadup_module_version() -> i32`, returning a pointer to a
+    /// NUL-terminated UTF-8 string in its memory.
+    fn read_declared_version(instance: &Instance, store: &mut Store<StoreData>) -> Option<String> {
+        const MAX_VERSION_LEN: usize = 256;
+
+        let func = instance.get_typed_func::<(), i32>(&mut *store, "wadup_module_version").ok()?;
+        let ptr = func.call(&mut *store, ()).ok()? as u32 as usize;
+        let memory = instance.get_memory(&mut *store, "memory")?;
+
+        let data = memory.data(&*store);
+        let end = data.len().min(ptr.saturating_add(MAX_VERSION_LEN));
+        let bytes = data.get(ptr..end)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        let version = std::str::from_utf8(&bytes[..len]).ok()?;
+        Some(version.to_string())
     }
 
     fn add_wasi_functions(linker: &mut Linker<StoreData>) -> Result<()> {
@@ -306,14 +1267,102 @@ impl ModuleInstance {
                 .ok_or_else(|| anyhow::anyhow!("No memory export found"))
         }
 
-        // Helper to read string from guest memory
-        fn read_string<T>(caller: &Caller<T>, memory: Memory, ptr: i32, len: i32) -> Result<String> {
-            if ptr < 0 || len < 0 {
-                anyhow::bail!("Invalid pointer or length");
+        // Helper to read a path from guest memory. Returns Fault if it falls
+        // outside memory, Nametoolong past MAX_GUEST_NAME_LEN, and Ilseq if it
+        // isn't UTF-8: the in-memory filesystem names files by string, and a
+        // lossy conversion could make two different paths open the same file
+        fn read_string<T>(caller: &Caller<T>, memory: Memory, ptr: i32, len: i32) -> Result<String, Errno> {
+            let buffer = read_guest_bytes(caller, memory, ptr, len, MAX_GUEST_NAME_LEN).map_err(|error| match error {
+                GuestReadError::Fault => Errno::Fault,
+                GuestReadError::TooLong => Errno::Nametoolong,
+            })?;
+            String::from_utf8(buffer).map_err(|_| Errno::Ilseq)
+        }
+
+        // Helper that hands a file call's errno back to the guest. Under
+        // --trace-wasi it also logs the call with its fd and path at debug
+        // level and counts the errno for the content when it isn't Success
+        fn traced(caller: &mut Caller<StoreData>, call: &str, fd: i32, path: Option<&str>, errno: Errno) -> Result<i32> {
+            let data = caller.data_mut();
+            if let Some(module) = &data.wasi_trace {
+                let uuid = data.processing_ctx.content_uuid;
+                match path {
+                    Some(path) => tracing::debug!("[{} {}] {}(fd {}, {:?}) -> {}", module, uuid, call, fd, path, errno.name()),
+                    None => tracing::debug!("[{} {}] {}(fd {}) -> {}", module, uuid, call, fd, errno.name()),
+                }
+                if errno != Errno::Success {
+                    *data.wasi_errnos.entry(errno.name()).or_default() += 1;
+                }
+            }
+            Ok(errno as i32)
+        }
+
+        // Helper to allocate a buffer the host fills and then writes to guest
+        // memory, once the guest's range is known to fit in it
+        fn output_buffer<T>(caller: &Caller<T>, memory: Memory, ptr: i32, len: i32) -> Result<Vec<u8>, Errno> {
+            let len = usize::try_from(len).map_err(|_| Errno::Fault)?;
+            let end = (ptr as u32 as usize).checked_add(len);
+            if end.is_none_or(|end| end > memory.data_size(caller)) {
+                return Err(Errno::Fault);
+            }
+            Ok(vec![0u8; len])
+        }
+
+        // Helper to read and bounds-check an iovec array from guest memory.
+        // Returns (buf_ptr, buf_len) pairs, or Inval if the array or any buffer
+        // falls outside memory, or the total length exceeds what a 32-bit guest
+        // can be told was transferred.
+        fn read_iovecs<T>(caller: &Caller<T>, memory: Memory, iovs_ptr: i32, iovs_len: i32) -> Result<Vec<(usize, usize)>, Errno> {
+            const MAX_IOVS: usize = 1024;
+
+            let data = memory.data(caller);
+            let iovs_ptr = iovs_ptr as u32 as usize;
+            let iovs_len = match usize::try_from(iovs_len) {
+                Ok(len) if len <= MAX_IOVS => len,
+                _ => return Err(Errno::Inval),
+            };
+            let iovs = iovs_ptr
+                .checked_add(iovs_len * 8)
+                .and_then(|end| data.get(iovs_ptr..end))
+                .ok_or(Errno::Inval)?;
+
+            let mut total = 0usize;
+            let mut iov_info = Vec::with_capacity(iovs_len);
+            for iov in iovs.chunks_exact(8) {
+                let buf_ptr = u32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]) as usize;
+                let buf_len = u32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]) as usize;
+                if buf_ptr.checked_add(buf_len).is_none_or(|end| end > data.len()) {
+                    return Err(Errno::Inval);
+                }
+                total += buf_len;
+                if total > i32::MAX as usize {
+                    return Err(Errno::Inval);
+                }
+                iov_info.push((buf_ptr, buf_len));
+            }
+            Ok(iov_info)
+        }
+
+        // Copy the first `total` bytes read into the guest's iovecs, in order.
+        // `WasiCtx::fd_read` fills each buffer completely before starting the
+        // next, so buffer i holds bytes only if every earlier buffer is full.
+        fn scatter_to_iovecs<T>(
+            caller: &mut Caller<T>,
+            memory: Memory,
+            iov_info: &[(usize, usize)],
+            bufs: &[Vec<u8>],
+            total: usize,
+        ) -> Result<()> {
+            let mut remaining = total;
+            for ((buf_ptr, buf_len), buf) in iov_info.iter().zip(bufs) {
+                if remaining == 0 {
+                    break;
+                }
+                let n = remaining.min(*buf_len);
+                memory.write(&mut *caller, *buf_ptr, &buf[..n])?;
+                remaining -= n;
             }
-            let mut buffer = vec![0u8; len as usize];
-            memory.read(caller, ptr as usize, &mut buffer)?;
-            Ok(String::from_utf8(buffer)?)
+            Ok(())
         }
 
         // fd_write - Write to file descriptor
@@ -324,19 +1373,12 @@ impl ModuleInstance {
                 let memory = get_memory(&mut caller)?;
 
                 // Read iovec array
-                let mut bufs = Vec::new();
-                for i in 0..iovs_len {
-                    let iov_ptr = iovs_ptr + (i * 8);
-                    let mut iov_buf = [0u8; 8];
-                    memory.read(&caller, iov_ptr as usize, &mut iov_buf)?;
-
-                    let buf_ptr = u32::from_le_bytes([iov_buf[0], iov_buf[1], iov_buf[2], iov_buf[3]]);
-                    let buf_len = u32::from_le_bytes([iov_buf[4], iov_buf[5], iov_buf[6], iov_buf[7]]);
-
-                    let mut buf = vec![0u8; buf_len as usize];
-                    memory.read(&caller, buf_ptr as usize, &mut buf)?;
-                    bufs.push(buf);
-                }
+                let iov_info = match read_iovecs(&caller, memory, iovs_ptr, iovs_len) {
+                    Ok(iov_info) => iov_info,
+                    Err(errno) => return traced(&mut caller, "fd_write", fd, None, errno),
+                };
+                let data = memory.data(&caller);
+                let bufs: Vec<Vec<u8>> = iov_info.iter().map(|&(ptr, len)| data[ptr..ptr + len].to_vec()).collect();
 
                 let buf_refs: Vec<&[u8]> = bufs.iter().map(|b| b.as_slice()).collect();
                 let mut nwritten = 0;
@@ -345,7 +1387,7 @@ impl ModuleInstance {
                 // Write result
                 memory.write(&mut caller, nwritten_ptr as usize, &(nwritten as i32).to_le_bytes())?;
 
-                Ok(errno as i32)
+                traced(&mut caller, "fd_write", fd, None, errno)
             },
         )?;
 
@@ -356,6 +1398,14 @@ impl ModuleInstance {
             |mut caller: Caller<StoreData>, fd: i32, iovs_ptr: i32, iovs_len: i32, offset: i64, nwritten_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
 
+                // Read iovec array
+                let iov_info = match read_iovecs(&caller, memory, iovs_ptr, iovs_len) {
+                    Ok(iov_info) => iov_info,
+                    Err(errno) => return traced(&mut caller, "fd_pwrite", fd, None, errno),
+                };
+                let data = memory.data(&caller);
+                let bufs: Vec<Vec<u8>> = iov_info.iter().map(|&(ptr, len)| data[ptr..ptr + len].to_vec()).collect();
+
                 // Save current position
                 let mut current_pos = 0u64;
                 let _ = caller.data().wasi_ctx.fd_seek(fd as u32, 0, 1, &mut current_pos); // SEEK_CUR = 1
@@ -364,22 +1414,7 @@ impl ModuleInstance {
                 let mut _new_offset = 0u64;
                 let errno = caller.data().wasi_ctx.fd_seek(fd as u32, offset, 0, &mut _new_offset); // SEEK_SET = 0
                 if errno != Errno::Success {
-                    return Ok(errno as i32);
-                }
-
-                // Read iovec array
-                let mut bufs = Vec::new();
-                for i in 0..iovs_len {
-                    let iov_ptr = iovs_ptr + (i * 8);
-                    let mut iov_buf = [0u8; 8];
-                    memory.read(&caller, iov_ptr as usize, &mut iov_buf)?;
-
-                    let buf_ptr = u32::from_le_bytes([iov_buf[0], iov_buf[1], iov_buf[2], iov_buf[3]]);
-                    let buf_len = u32::from_le_bytes([iov_buf[4], iov_buf[5], iov_buf[6], iov_buf[7]]);
-
-                    let mut buf = vec![0u8; buf_len as usize];
-                    memory.read(&caller, buf_ptr as usize, &mut buf)?;
-                    bufs.push(buf);
+                    return traced(&mut caller, "fd_pwrite", fd, None, errno);
                 }
 
                 let buf_refs: Vec<&[u8]> = bufs.iter().map(|b| b.as_slice()).collect();
@@ -392,7 +1427,7 @@ impl ModuleInstance {
                 // Restore original position
                 let _ = caller.data().wasi_ctx.fd_seek(fd as u32, current_pos as i64, 0, &mut _new_offset); // SEEK_SET = 0
 
-                Ok(write_errno as i32)
+                traced(&mut caller, "fd_pwrite", fd, None, write_errno)
             },
         )?;
 
@@ -404,36 +1439,21 @@ impl ModuleInstance {
                 let memory = get_memory(&mut caller)?;
 
                 // Read iovec array and prepare buffers
-                let mut iov_info = Vec::new();
-                for i in 0..iovs_len {
-                    let iov_ptr = iovs_ptr + (i * 8);
-                    let mut iov_buf = [0u8; 8];
-                    memory.read(&caller, iov_ptr as usize, &mut iov_buf)?;
-
-                    let buf_ptr = u32::from_le_bytes([iov_buf[0], iov_buf[1], iov_buf[2], iov_buf[3]]);
-                    let buf_len = u32::from_le_bytes([iov_buf[4], iov_buf[5], iov_buf[6], iov_buf[7]]);
-                    iov_info.push((buf_ptr, buf_len));
-                }
+                let iov_info = match read_iovecs(&caller, memory, iovs_ptr, iovs_len) {
+                    Ok(iov_info) => iov_info,
+                    Err(errno) => return traced(&mut caller, "fd_read", fd, None, errno),
+                };
 
                 let mut total_read = 0;
-                let mut temp_bufs: Vec<Vec<u8>> = iov_info.iter().map(|(_, len)| vec![0u8; *len as usize]).collect();
+                let mut temp_bufs: Vec<Vec<u8>> = iov_info.iter().map(|(_, len)| vec![0u8; *len]).collect();
                 let mut buf_refs: Vec<&mut [u8]> = temp_bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
 
                 let errno = caller.data().wasi_ctx.fd_read(fd as u32, &mut buf_refs, &mut total_read);
-
-                // Write buffers back to guest memory
-                let mut offset = 0;
-                for (i, (buf_ptr, buf_len)) in iov_info.iter().enumerate() {
-                    let to_write = (total_read - offset).min(*buf_len as usize);
-                    if to_write > 0 {
-                        memory.write(&mut caller, *buf_ptr as usize, &temp_bufs[i][..to_write])?;
-                        offset += to_write;
-                    }
-                }
+                scatter_to_iovecs(&mut caller, memory, &iov_info, &temp_bufs, total_read)?;
 
                 memory.write(&mut caller, nread_ptr as usize, &(total_read as i32).to_le_bytes())?;
 
-                Ok(errno as i32)
+                traced(&mut caller, "fd_read", fd, None, errno)
             },
         )?;
 
@@ -444,51 +1464,26 @@ impl ModuleInstance {
             |mut caller: Caller<StoreData>, fd: i32, iovs_ptr: i32, iovs_len: i32, offset: i64, nread_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
 
-                // Save current position
-                let mut current_pos = 0u64;
-                let _ = caller.data().wasi_ctx.fd_seek(fd as u32, 0, 1, &mut current_pos); // SEEK_CUR = 1
-
-                // Seek to offset
-                let mut _new_offset = 0u64;
-                let errno = caller.data().wasi_ctx.fd_seek(fd as u32, offset, 0, &mut _new_offset); // SEEK_SET = 0
-                if errno != Errno::Success {
-                    return Ok(errno as i32);
-                }
-
                 // Read iovec array and prepare buffers
-                let mut iov_info = Vec::new();
-                for i in 0..iovs_len {
-                    let iov_ptr = iovs_ptr + (i * 8);
-                    let mut iov_buf = [0u8; 8];
-                    memory.read(&caller, iov_ptr as usize, &mut iov_buf)?;
-
-                    let buf_ptr = u32::from_le_bytes([iov_buf[0], iov_buf[1], iov_buf[2], iov_buf[3]]);
-                    let buf_len = u32::from_le_bytes([iov_buf[4], iov_buf[5], iov_buf[6], iov_buf[7]]);
-                    iov_info.push((buf_ptr, buf_len));
-                }
+                let iov_info = match read_iovecs(&caller, memory, iovs_ptr, iovs_len) {
+                    Ok(iov_info) => iov_info,
+                    Err(errno) => return traced(&mut caller, "fd_pread", fd, None, errno),
+                };
 
                 let mut total_read = 0;
-                let mut temp_bufs: Vec<Vec<u8>> = iov_info.iter().map(|(_, len)| vec![0u8; *len as usize]).collect();
+                let mut temp_bufs: Vec<Vec<u8>> = iov_info.iter().map(|(_, len)| vec![0u8; *len]).collect();
                 let mut buf_refs: Vec<&mut [u8]> = temp_bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
 
-                let read_errno = caller.data().wasi_ctx.fd_read(fd as u32, &mut buf_refs, &mut total_read);
-
-                // Write buffers back to guest memory
-                let mut write_offset = 0;
-                for (i, (buf_ptr, buf_len)) in iov_info.iter().enumerate() {
-                    let to_write = (total_read - write_offset).min(*buf_len as usize);
-                    if to_write > 0 {
-                        memory.write(&mut caller, *buf_ptr as usize, &temp_bufs[i][..to_write])?;
-                        write_offset += to_write;
-                    }
+                // The offset is a u64 filesize in WASI
+                let errno = caller.data().wasi_ctx.fd_pread(fd as u32, &mut buf_refs, offset as u64, &mut total_read);
+                if errno != Errno::Success {
+                    return traced(&mut caller, "fd_pread", fd, None, errno);
                 }
+                scatter_to_iovecs(&mut caller, memory, &iov_info, &temp_bufs, total_read)?;
 
                 memory.write(&mut caller, nread_ptr as usize, &(total_read as i32).to_le_bytes())?;
 
-                // Restore original position
-                let _ = caller.data().wasi_ctx.fd_seek(fd as u32, current_pos as i64, 0, &mut _new_offset); // SEEK_SET = 0
-
-                Ok(read_errno as i32)
+                traced(&mut caller, "fd_pread", fd, None, Errno::Success)
             },
         )?;
 
@@ -501,7 +1496,7 @@ impl ModuleInstance {
                 let mut newoffset = 0u64;
                 let errno = caller.data().wasi_ctx.fd_seek(fd as u32, offset, whence as u8, &mut newoffset);
                 memory.write(&mut caller, newoffset_ptr as usize, &newoffset.to_le_bytes())?;
-                Ok(errno as i32)
+                traced(&mut caller, "fd_seek", fd, None, errno)
             },
         )?;
 
@@ -512,30 +1507,8 @@ impl ModuleInstance {
             "fd_close",
             |mut caller: Caller<StoreData>, fd: i32| -> Result<i32> {
                 let (errno, close_result) = caller.data().wasi_ctx.fd_close(fd as u32);
-
-                // If this was a metadata file, process it immediately
-                if let Some(content) = close_result.metadata_content {
-                    tracing::debug!("Processing metadata on fd_close ({} bytes)", content.len());
-                    if let Err(e) = Self::process_metadata_content(&content, caller.data_mut()) {
-                        tracing::warn!("Failed to process metadata on close: {}", e);
-                    }
-                }
-
-                // If this was a subcontent emission (paired data+metadata files or slice), process it immediately
-                if let Some(emission) = close_result.subcontent_emission {
-                    use crate::wasi_impl::SubcontentEmissionData;
-                    match &emission.data {
-                        SubcontentEmissionData::Bytes(bytes) => {
-                            tracing::debug!("Processing subcontent on fd_close: {} ({} bytes)", emission.filename, bytes.len());
-                        }
-                        SubcontentEmissionData::Slice { offset, length } => {
-                            tracing::debug!("Processing subcontent slice on fd_close: {} (offset={}, length={})", emission.filename, offset, length);
-                        }
-                    }
-                    Self::process_subcontent_emission(emission, caller.data_mut());
-                }
-
-                Ok(errno as i32)
+                Self::apply_close_result(close_result, caller.data_mut());
+                traced(&mut caller, "fd_close", fd, None, errno)
             },
         )?;
 
@@ -548,7 +1521,7 @@ impl ModuleInstance {
                 let mut filestat = [0u8; 64];
                 let errno = caller.data().wasi_ctx.fd_filestat_get(fd as u32, &mut filestat);
                 memory.write(&mut caller, filestat_ptr as usize, &filestat)?;
-                Ok(errno as i32)
+                traced(&mut caller, "fd_filestat_get", fd, None, errno)
             },
         )?;
 
@@ -571,7 +1544,10 @@ impl ModuleInstance {
             "fd_prestat_dir_name",
             |mut caller: Caller<StoreData>, fd: i32, path_ptr: i32, path_len: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let mut path_buf = vec![0u8; path_len as usize];
+                let mut path_buf = match output_buffer(&caller, memory, path_ptr, path_len) {
+                    Ok(buf) => buf,
+                    Err(errno) => return Ok(errno as i32),
+                };
                 let errno = caller.data().wasi_ctx.fd_prestat_dir_name(fd as u32, &mut path_buf);
                 memory.write(&mut caller, path_ptr as usize, &path_buf)?;
                 Ok(errno as i32)
@@ -585,7 +1561,10 @@ impl ModuleInstance {
             |mut caller: Caller<StoreData>, dirfd: i32, dirflags: i32, path_ptr: i32, path_len: i32,
              oflags: i32, _fs_rights_base: i64, _fs_rights_inheriting: i64, fdflags: i32, fd_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let path = read_string(&caller, memory, path_ptr, path_len)?;
+                let path = match read_string(&caller, memory, path_ptr, path_len) {
+                    Ok(path) => path,
+                    Err(errno) => return traced(&mut caller, "path_open", dirfd, None, errno),
+                };
                 let mut fd_out = 0u32;
                 let errno = caller.data().wasi_ctx.path_open(
                     dirfd as u32,
@@ -598,7 +1577,7 @@ impl ModuleInstance {
                     &mut fd_out,
                 );
                 memory.write(&mut caller, fd_ptr as usize, &(fd_out as i32).to_le_bytes())?;
-                Ok(errno as i32)
+                traced(&mut caller, "path_open", dirfd, Some(&path), errno)
             },
         )?;
 
@@ -608,11 +1587,14 @@ impl ModuleInstance {
             "path_filestat_get",
             |mut caller: Caller<StoreData>, dirfd: i32, flags: i32, path_ptr: i32, path_len: i32, filestat_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let path = read_string(&caller, memory, path_ptr, path_len)?;
+                let path = match read_string(&caller, memory, path_ptr, path_len) {
+                    Ok(path) => path,
+                    Err(errno) => return traced(&mut caller, "path_filestat_get", dirfd, None, errno),
+                };
                 let mut filestat = [0u8; 64];
                 let errno = caller.data().wasi_ctx.path_filestat_get(dirfd as u32, flags as u32, &path, &mut filestat);
                 memory.write(&mut caller, filestat_ptr as usize, &filestat)?;
-                Ok(errno as i32)
+                traced(&mut caller, "path_filestat_get", dirfd, Some(&path), errno)
             },
         )?;
 
@@ -622,12 +1604,15 @@ impl ModuleInstance {
             "fd_readdir",
             |mut caller: Caller<StoreData>, fd: i32, buf_ptr: i32, buf_len: i32, cookie: i64, bufused_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let mut buf = vec![0u8; buf_len as usize];
+                let mut buf = match output_buffer(&caller, memory, buf_ptr, buf_len) {
+                    Ok(buf) => buf,
+                    Err(errno) => return traced(&mut caller, "fd_readdir", fd, None, errno),
+                };
                 let mut bufused = 0usize;
                 let errno = caller.data().wasi_ctx.fd_readdir(fd as u32, &mut buf, cookie as u64, &mut bufused);
                 memory.write(&mut caller, buf_ptr as usize, &buf[..bufused])?;
                 memory.write(&mut caller, bufused_ptr as usize, &(bufused as i32).to_le_bytes())?;
-                Ok(errno as i32)
+                traced(&mut caller, "fd_readdir", fd, None, errno)
             },
         )?;
 
@@ -636,7 +1621,17 @@ impl ModuleInstance {
             "wasi_snapshot_preview1",
             "proc_exit",
             |_caller: Caller<StoreData>, code: i32| -> Result<()> {
-                anyhow::bail!("proc_exit called with code {}", code)
+                // Trap with a typed exit so callers can recover the exit code
+                Err(wasmtime_wasi::I32Exit(code).into())
+            },
+        )?;
+
+        // proc_raise - Signals aren't supported
+        linker.func_wrap(
+            "wasi_snapshot_preview1",
+            "proc_raise",
+            |_caller: Caller<StoreData>, _sig: i32| -> Result<i32> {
+                Ok(Errno::Nosys as i32)
             },
         )?;
 
@@ -660,21 +1655,7 @@ impl ModuleInstance {
             |mut caller: Caller<StoreData>, environ_ptr: i32, environ_buf_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
                 let env_strings = caller.data().wasi_ctx.environ_strings();
-
-                let mut buf_offset = environ_buf_ptr as usize;
-                let mut ptr_offset = environ_ptr as usize;
-
-                for env_str in env_strings {
-                    // Write pointer to this env var
-                    memory.write(&mut caller, ptr_offset, &(buf_offset as u32).to_le_bytes())?;
-                    ptr_offset += 4;
-
-                    // Write "KEY=VALUE\0"
-                    let env_bytes = format!("{}\0", env_str);
-                    memory.write(&mut caller, buf_offset, env_bytes.as_bytes())?;
-                    buf_offset += env_bytes.len();
-                }
-
+                write_string_list(&mut caller, memory, &env_strings, environ_ptr, environ_buf_ptr)?;
                 Ok(Errno::Success as i32)
             },
         )?;
@@ -685,10 +1666,7 @@ impl ModuleInstance {
             "clock_time_get",
             |mut caller: Caller<StoreData>, _clock_id: i32, _precision: i64, time_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let time = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos() as i64;
+                let time = caller.data().wasi_ctx.now_ns();
                 memory.write(&mut caller, time_ptr as usize, &time.to_le_bytes())?;
                 Ok(Errno::Success as i32)
             },
@@ -713,7 +1691,18 @@ impl ModuleInstance {
             "random_get",
             |mut caller: Caller<StoreData>, buf_ptr: i32, buf_len: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let buf = vec![0u8; buf_len as usize]; // For now, zeros (should use rand crate)
+                let mut buf = match output_buffer(&caller, memory, buf_ptr, buf_len) {
+                    Ok(buf) => buf,
+                    Err(errno) => return Ok(errno as i32),
+                };
+                match caller.data_mut().rng.as_mut() {
+                    Some(rng) => rng.fill_bytes(&mut buf),
+                    None => {
+                        if OsRng.try_fill_bytes(&mut buf).is_err() {
+                            return Ok(Errno::Io as i32);
+                        }
+                    }
+                }
                 memory.write(&mut caller, buf_ptr as usize, &buf)?;
                 Ok(Errno::Success as i32)
             },
@@ -728,7 +1717,19 @@ impl ModuleInstance {
                 let mut newoffset = 0u64;
                 let errno = caller.data().wasi_ctx.fd_seek(fd as u32, 0, 1, &mut newoffset);
                 memory.write(&mut caller, offset_ptr as usize, &newoffset.to_le_bytes())?;
-                Ok(errno as i32)
+                traced(&mut caller, "fd_tell", fd, None, errno)
+            },
+        )?;
+
+        // fd_renumber - Move a file descriptor onto another number
+        linker.func_wrap(
+            "wasi_snapshot_preview1",
+            "fd_renumber",
+            |mut caller: Caller<StoreData>, from: i32, to: i32| -> Result<i32> {
+                // The handle displaced from `to` is closed like fd_close
+                let (errno, close_result) = caller.data().wasi_ctx.fd_renumber(from as u32, to as u32);
+                Self::apply_close_result(close_result, caller.data_mut());
+                traced(&mut caller, "fd_renumber", from, None, errno)
             },
         )?;
 
@@ -738,17 +1739,13 @@ impl ModuleInstance {
             "fd_fdstat_get",
             |mut caller: Caller<StoreData>, fd: i32, fdstat_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                // fdstat structure: filetype(1) + flags(2) + rights_base(8) + rights_inheriting(8) = 24 bytes
+                // fdstat structure: filetype(1) + pad(1) + flags(2) + pad(4) + rights_base(8) + rights_inheriting(8) = 24 bytes
                 let mut fdstat = [0u8; 24];
-                // Set filetype based on FD (3=directory for fd 3, 4=regular for others)
-                fdstat[0] = if fd == 3 { 3 } else { 4 }; // directory or regular file
-                // flags (fdflags) - 0 for now
-                // rights_base - all rights (0xFFFFFFFFFFFFFFFF)
-                fdstat[4..12].copy_from_slice(&0xFFFFFFFFFFFFFFFFu64.to_le_bytes());
-                // rights_inheriting - all rights
-                fdstat[12..20].copy_from_slice(&0xFFFFFFFFFFFFFFFFu64.to_le_bytes());
-                memory.write(&mut caller, fdstat_ptr as usize, &fdstat)?;
-                Ok(Errno::Success as i32)
+                let errno = caller.data().wasi_ctx.fd_fdstat_get(fd as u32, &mut fdstat);
+                if errno == Errno::Success {
+                    memory.write(&mut caller, fdstat_ptr as usize, &fdstat)?;
+                }
+                traced(&mut caller, "fd_fdstat_get", fd, None, errno)
             },
         )?;
 
@@ -756,9 +1753,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "fd_fdstat_set_flags",
-            |_caller: Caller<StoreData>, _fd: i32, _flags: i32| -> Result<i32> {
-                // For now, just return success (we don't actually track these flags)
-                Ok(Errno::Success as i32)
+            |mut caller: Caller<StoreData>, fd: i32, flags: i32| -> Result<i32> {
+                let errno = caller.data().wasi_ctx.fd_fdstat_set_flags(fd as u32, flags as u16);
+                traced(&mut caller, "fd_fdstat_set_flags", fd, None, errno)
             },
         )?;
 
@@ -766,9 +1763,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "fd_filestat_set_size",
-            |_caller: Caller<StoreData>, _fd: i32, _size: i64| -> Result<i32> {
-                // For now, just return success (our in-memory files auto-resize on write)
-                Ok(Errno::Success as i32)
+            |mut caller: Caller<StoreData>, fd: i32, size: i64| -> Result<i32> {
+                let errno = caller.data().wasi_ctx.fd_filestat_set_size(fd as u32, size as u64);
+                traced(&mut caller, "fd_filestat_set_size", fd, None, errno)
             },
         )?;
 
@@ -796,13 +1793,14 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_create_directory",
-            |mut caller: Caller<StoreData>, _dirfd: i32, path_ptr: i32, path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, dirfd: i32, path_ptr: i32, path_len: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                let path = read_string(&caller, memory, path_ptr, path_len)?;
-                match caller.data().wasi_ctx.filesystem.create_dir_all(&path) {
-                    Ok(_) => Ok(Errno::Success as i32),
-                    Err(_) => Ok(Errno::Io as i32),
-                }
+                let path = match read_string(&caller, memory, path_ptr, path_len) {
+                    Ok(path) => path,
+                    Err(errno) => return traced(&mut caller, "path_create_directory", dirfd, None, errno),
+                };
+                let errno = caller.data().wasi_ctx.path_create_directory(dirfd as u32, &path);
+                traced(&mut caller, "path_create_directory", dirfd, Some(&path), errno)
             },
         )?;
 
@@ -810,9 +1808,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_unlink_file",
-            |_caller: Caller<StoreData>, _dirfd: i32, _path_ptr: i32, _path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, dirfd: i32, _path_ptr: i32, _path_len: i32| -> Result<i32> {
                 // For now, not supported
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_unlink_file", dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -820,9 +1818,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_remove_directory",
-            |_caller: Caller<StoreData>, _dirfd: i32, _path_ptr: i32, _path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, dirfd: i32, _path_ptr: i32, _path_len: i32| -> Result<i32> {
                 // For now, not supported
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_remove_directory", dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -850,9 +1848,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_readlink",
-            |_caller: Caller<StoreData>, _dirfd: i32, _path_ptr: i32, _path_len: i32, _buf_ptr: i32, _buf_len: i32, _bufused_ptr: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, dirfd: i32, _path_ptr: i32, _path_len: i32, _buf_ptr: i32, _buf_len: i32, _bufused_ptr: i32| -> Result<i32> {
                 // Symlinks not supported
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_readlink", dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -860,9 +1858,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_rename",
-            |_caller: Caller<StoreData>, _old_dirfd: i32, _old_path_ptr: i32, _old_path_len: i32, _new_dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, old_dirfd: i32, _old_path_ptr: i32, _old_path_len: i32, _new_dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
                 // Not supported for now
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_rename", old_dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -870,9 +1868,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_link",
-            |_caller: Caller<StoreData>, _old_dirfd: i32, _old_flags: i32, _old_path_ptr: i32, _old_path_len: i32, _new_dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, old_dirfd: i32, _old_flags: i32, _old_path_ptr: i32, _old_path_len: i32, _new_dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
                 // Not supported in WASI - return ENOSYS
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_link", old_dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -880,9 +1878,9 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "path_symlink",
-            |_caller: Caller<StoreData>, _old_path_ptr: i32, _old_path_len: i32, _dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, _old_path_ptr: i32, _old_path_len: i32, dirfd: i32, _new_path_ptr: i32, _new_path_len: i32| -> Result<i32> {
                 // Symlinks not supported
-                Ok(Errno::Nosys as i32)
+                traced(&mut caller, "path_symlink", dirfd, None, Errno::Nosys)
             },
         )?;
 
@@ -910,7 +1908,12 @@ impl ModuleInstance {
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "sched_yield",
-            |_caller: Caller<StoreData>| -> Result<i32> {
+            |mut caller: Caller<StoreData>| -> Result<i32> {
+                let yields = &mut caller.data_mut().sched_yields;
+                *yields += 1;
+                if *yields > MAX_SCHED_YIELDS {
+                    anyhow::bail!("Module appears stuck (excessive sched_yield: over {} calls for one content)", MAX_SCHED_YIELDS);
+                }
                 Ok(Errno::Success as i32)
             },
         )?;
@@ -921,17 +1924,21 @@ impl ModuleInstance {
             "args_sizes_get",
             |mut caller: Caller<StoreData>, count_ptr: i32, size_ptr: i32| -> Result<i32> {
                 let memory = get_memory(&mut caller)?;
-                memory.write(&mut caller, count_ptr as usize, &0i32.to_le_bytes())?;
-                memory.write(&mut caller, size_ptr as usize, &0i32.to_le_bytes())?;
+                let (count, buf_size) = caller.data().wasi_ctx.args_sizes();
+                memory.write(&mut caller, count_ptr as usize, &(count as i32).to_le_bytes())?;
+                memory.write(&mut caller, size_ptr as usize, &(buf_size as i32).to_le_bytes())?;
                 Ok(Errno::Success as i32)
             },
         )?;
 
-        // args_get - Get command line arguments
+        // args_get - Get command line arguments (the module name, then `--module-arg`s)
         linker.func_wrap(
             "wasi_snapshot_preview1",
             "args_get",
-            |_caller: Caller<StoreData>, _argv_ptr: i32, _argv_buf_ptr: i32| -> Result<i32> {
+            |mut caller: Caller<StoreData>, argv_ptr: i32, argv_buf_ptr: i32| -> Result<i32> {
+                let memory = get_memory(&mut caller)?;
+                let args = caller.data().wasi_ctx.args().to_vec();
+                write_string_list(&mut caller, memory, &args, argv_ptr, argv_buf_ptr)?;
                 Ok(Errno::Success as i32)
             },
         )?;
@@ -1134,35 +2141,93 @@ impl ModuleInstance {
         Ok(())
     }
 
+    /// Whether the limiter refused a memory growth since the last call, clearing the flag
+    fn take_memory_refused(&mut self) -> bool {
+        self.store.data_mut().resource_limiter.as_mut()
+            .is_some_and(|limiter| std::mem::take(&mut limiter.memory_refused))
+    }
+
+    /// Errnos the current or last content's failed WASI file calls
+    /// returned, by name; empty without `--trace-wasi`
+    pub fn wasi_errnos(&self) -> &BTreeMap<&'static str, u64> {
+        &self.store.data().wasi_errnos
+    }
+
+    /// Peak linear memory, in bytes, of the current or last content's run
+    pub fn peak_memory(&self) -> usize {
+        self.store.data().resource_limiter.as_ref().map_or(0, |limiter| limiter.peak_memory)
+    }
+
+    /// Restart peak memory tracking from the memory the instance already has,
+    /// which never shrinks
+    fn reset_peak_memory(&mut self) {
+        let current = self.instance.get_memory(&mut self.store, "memory")
+            .map_or(0, |memory| memory.data_size(&self.store));
+        if let Some(limiter) = self.store.data_mut().resource_limiter.as_mut() {
+            limiter.peak_memory = current;
+        }
+    }
+
+    /// Run the entrypoint over one content and return what the module emitted.
     pub fn process_content(
         &mut self,
         content_uuid: uuid::Uuid,
         content_data: crate::shared_buffer::SharedBuffer,
+    ) -> Result<ProcessingContext, WadupError> {
+        self.run_content(content_uuid, content_data).map_err(WadupError::from)
+    }
+
+    fn run_content(
+        &mut self,
+        content_uuid: uuid::Uuid,
+        content_data: crate::shared_buffer::SharedBuffer,
     ) -> Result<ProcessingContext> {
+        if self.used && !self.manifest.stateful {
+            self.reinstantiate()?;
+        }
+        self.used = true;
+
         // Update /data.bin in the in-memory filesystem (zero-copy)
         let filesystem = &self.store.data().wasi_ctx.filesystem;
         filesystem.set_data_bin(content_data.to_bytes())?;
+        filesystem.set_streams(&std::mem::take(&mut self.streams))?;
+
+        let digest = self.content_digest.take();
+        if let Some(seed) = self.seed {
+            use sha2::{Digest, Sha256};
+            let digest = digest.unwrap_or_else(|| Sha256::digest(content_data.as_slice()).into());
+            self.store.data_mut().rng = Some(seeded_rng(seed, &[self.name.as_bytes(), &digest]));
+        }
 
         // Set up new context
-        let ctx = ProcessingContext::new(content_uuid, content_data);
+        let mut ctx = ProcessingContext::new(content_uuid, content_data);
+        ctx.parent = self.parent.take();
+        self.partial_ctx = None;
         self.store.data_mut().processing_ctx = ctx;
 
         // Replenish fuel
         if let Some(fuel) = self.fuel_limit {
             self.store.set_fuel(fuel)?;
+            self.store.data_mut().fuel_granted = fuel;
         }
-
-        // Call the process function - try () -> i32 first, then () -> () for compatibility
-        let result = if let Ok(process_func) = self.instance
-            .get_typed_func::<(), i32>(&mut self.store, "process")
-        {
-            process_func.call(&mut self.store, ()).map(|code| code)
-        } else if let Ok(process_func) = self.instance
-            .get_typed_func::<(), ()>(&mut self.store, "process")
-        {
-            process_func.call(&mut self.store, ()).map(|_| 0)
-        } else {
-            anyhow::bail!("Module '{}' process function has unsupported signature", self.name);
+        self.take_memory_refused();
+        self.reset_peak_memory();
+        self.store.data_mut().sched_yields = 0;
+        self.store.data_mut().wasi_errnos.clear();
+        self.store.data_mut().pending_inflate = None;
+        self.begin_content()?;
+        let content_data = self.store.data().processing_ctx.content_data.clone();
+        self.write_direct_content(content_data.as_slice())?;
+
+        // Call the entrypoint - () -> i32 or () -> () for compatibility
+        let result = match Self::call_export(&self.instance, &mut self.store, &self.entrypoint) {
+            Some(result) => Self::resolve_exit(result),
+            None => {
+                return Err(WadupError::ModuleMissingProcess {
+                    module: self.name.clone(),
+                    entrypoint: self.entrypoint.clone(),
+                }.into());
+            }
         };
 
         // Get filesystem reference before borrowing store mutably
@@ -1179,59 +2244,193 @@ impl ModuleInstance {
                 Self::process_remaining_metadata_files(&filesystem, &mut self.store)?;
 
                 // Success - extract context
-                let ctx = &mut self.store.data_mut().processing_ctx;
-                let extracted = ProcessingContext {
-                    content_uuid: ctx.content_uuid,
-                    content_data: ctx.content_data.clone(),
-                    subcontent: std::mem::take(&mut ctx.subcontent),
-                    metadata: std::mem::take(&mut ctx.metadata),
-                    table_schemas: std::mem::take(&mut ctx.table_schemas),
-                    stdout: if stdout.is_empty() { None } else { Some(stdout) },
-                    stderr: if stderr.is_empty() { None } else { Some(stderr) },
-                    stdout_truncated,
-                    stderr_truncated,
-                };
+                let extracted = self.take_context((stdout, stdout_truncated), (stderr, stderr_truncated));
                 Ok(extracted)
             }
             Ok(code) => {
                 // Return context with captured output even on failure (for debugging)
-                let ctx = &mut self.store.data_mut().processing_ctx;
-                let extracted = ProcessingContext {
-                    content_uuid: ctx.content_uuid,
-                    content_data: ctx.content_data.clone(),
-                    subcontent: std::mem::take(&mut ctx.subcontent),
-                    metadata: std::mem::take(&mut ctx.metadata),
-                    table_schemas: std::mem::take(&mut ctx.table_schemas),
-                    stdout: if stdout.is_empty() { None } else { Some(stdout) },
-                    stderr: if stderr.is_empty() { None } else { Some(stderr) },
-                    stdout_truncated,
-                    stderr_truncated,
-                };
+                let extracted = self.take_context((stdout, stdout_truncated), (stderr, stderr_truncated));
                 // Log stderr if present for debugging
                 if let Some(ref stderr_content) = extracted.stderr {
                     tracing::warn!("Module '{}' stderr: {}", self.name, stderr_content);
                 }
                 anyhow::bail!("Module '{}' returned error code: {}", self.name, code)
             }
-            Err(e) => {
-                // Log stdout/stderr if present for debugging (before error classification)
-                if !stdout.is_empty() {
-                    tracing::info!("Module '{}' stdout: {}", self.name, stdout);
-                }
-                if !stderr.is_empty() {
-                    tracing::warn!("Module '{}' stderr: {}", self.name, stderr);
-                }
+            Err(e) => {
+                self.dump_filesystem(&filesystem, content_uuid);
+
+                // Log stdout/stderr if present for debugging (before error classification)
+                if !stdout.is_empty() {
+                    tracing::info!("Module '{}' stdout: {}", self.name, stdout);
+                }
+                if !stderr.is_empty() {
+                    tracing::warn!("Module '{}' stderr: {}", self.name, stderr);
+                }
+
+                match LimitExceeded::classify(&e, self.take_memory_refused()) {
+                    Some(limit) => {
+                        // Everything emitted before fuel ran out was committed host-side
+                        if limit == LimitExceeded::Fuel {
+                            self.partial_ctx = Some(self.take_context((stdout, stdout_truncated), (stderr, stderr_truncated)));
+                        }
+                        Err(ResourceLimitError { module: self.name.clone(), limit }.into())
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Errors the module reported with `report_error` during its last
+    /// `process_content` call, whatever its outcome
+    pub fn take_reported_errors(&mut self) -> Vec<GuestError> {
+        std::mem::take(&mut self.store.data_mut().processing_ctx.errors)
+    }
+
+    /// Output the module committed before its last `process_content` call
+    /// ran out of fuel, if it did
+    pub fn take_partial_context(&mut self) -> Option<ProcessingContext> {
+        self.partial_ctx.take()
+    }
+
+    /// Move the current content's emissions out of the store, along with the captured output
+    fn take_context(&mut self, (stdout, stdout_truncated): (String, bool), (stderr, stderr_truncated): (String, bool)) -> ProcessingContext {
+        let streams = self.store.data().wasi_ctx.filesystem.take_streams();
+        let ctx = &mut self.store.data_mut().processing_ctx;
+        ProcessingContext {
+            content_uuid: ctx.content_uuid,
+            content_data: ctx.content_data.clone(),
+            parent: ctx.parent.take(),
+            subcontent: std::mem::take(&mut ctx.subcontent),
+            links: std::mem::take(&mut ctx.links),
+            metadata: std::mem::take(&mut ctx.metadata),
+            table_schemas: std::mem::take(&mut ctx.table_schemas),
+            added_columns: std::mem::take(&mut ctx.added_columns),
+            streams,
+            stdout: if stdout.is_empty() { None } else { Some(stdout) },
+            stderr: if stderr.is_empty() { None } else { Some(stderr) },
+            stdout_truncated,
+            stderr_truncated,
+            errors: Vec::new(),
+            rejected_subcontent: std::mem::take(&mut ctx.rejected_subcontent),
+            processed_metadata: std::mem::take(&mut ctx.processed_metadata),
+        }
+    }
+
+    /// Process the metadata or sub-content a closed special file carried
+    fn apply_close_result(close_result: crate::wasi_impl::CloseResult, store_data: &mut StoreData) {
+        // If this was a metadata file, process it immediately
+        if let Some(content) = close_result.metadata_content {
+            tracing::debug!("Processing metadata on fd_close ({} bytes)", content.data.len());
+            if let Err(e) = Self::process_metadata_file(&content, store_data) {
+                tracing::warn!("Failed to process metadata on close: {}", e);
+            }
+        }
+
+        // If this was a subcontent emission (paired data+metadata files or slice), process it immediately
+        if let Some(emission) = close_result.subcontent_emission {
+            use crate::wasi_impl::SubcontentEmissionData;
+            match &emission.data {
+                SubcontentEmissionData::Bytes(bytes) => {
+                    tracing::debug!("Processing subcontent on fd_close: {} ({} bytes)", emission.filename, bytes.len());
+                }
+                SubcontentEmissionData::Slice { offset, length } => {
+                    tracing::debug!("Processing subcontent slice on fd_close: {} (offset={}, length={})", emission.filename, offset, length);
+                }
+            }
+            Self::process_subcontent_emission(emission, store_data);
+        }
+
+        if let Some(reason) = close_result.rejected_subcontent {
+            tracing::warn!("Rejected sub-content: {}", reason);
+            store_data.processing_ctx.rejected_subcontent.push(reason);
+        }
+
+        if let Some(content) = close_result.subcontent_metadata {
+            Self::keep_metadata_file(&content, store_data);
+        }
+    }
+
+    /// Copy a metadata file to `{keep_metadata_dir}/{content_uuid}/`, if enabled
+    fn keep_metadata_file(content: &crate::wasi_impl::MetadataContent, store_data: &StoreData) {
+        let Some(dir) = &store_data.keep_metadata_dir else {
+            return;
+        };
+
+        let target = dir
+            .join(store_data.processing_ctx.content_uuid.to_string())
+            .join(content.path.trim_start_matches('/'));
+        let written = target.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(&target, &content.data));
+        if let Err(e) = written {
+            tracing::warn!("Failed to keep metadata file {} at {:?}: {}", content.path, target, e);
+        }
+    }
+
+    /// Convert a `proc_exit` trap into the exit code it carries.
+    ///
+    /// Some language shims call `exit(0)` at the end of `process()`; this makes
+    /// that equivalent to returning 0, and a non-zero exit equivalent to
+    /// returning that code.
+    fn resolve_exit(result: Result<i32>) -> Result<i32> {
+        match result {
+            Err(e) => match e.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                Some(exit) => Ok(exit.0),
+                None => Err(e),
+            },
+            ok => ok,
+        }
+    }
+
+    /// Process a metadata file in the single-object, JSON Lines or binary format.
+    ///
+    /// JSON Lines files hold one metadata object per line; blank lines are skipped
+    /// and a malformed line is reported with its line number.
+    ///
+    /// A path already processed for this content means the guest reused a
+    /// file index (e.g. after resetting its counter): identical contents are
+    /// ignored so their rows aren't recorded twice, and either way a warning
+    /// is logged.
+    ///
+    /// Under `--keep-metadata-json` the file is copied out first, as written.
+    fn process_metadata_file(
+        content: &crate::wasi_impl::MetadataContent,
+        store_data: &mut StoreData,
+    ) -> Result<()> {
+        use crate::wasi_impl::MetadataFormat;
+        use sha2::{Digest, Sha256};
+
+        Self::keep_metadata_file(content, store_data);
+
+        let digest: [u8; 32] = Sha256::digest(&content.data).into();
+        match store_data.processing_ctx.processed_metadata.insert(content.path.clone(), digest) {
+            Some(previous) if previous == digest => {
+                tracing::warn!("Metadata file {} was written again with the same contents; ignoring it", content.path);
+                return Ok(());
+            }
+            Some(_) => tracing::warn!("Metadata file {} was already processed for this content; its index was reused", content.path),
+            None => {}
+        }
 
-                let error_msg = e.to_string();
-                if error_msg.contains("fuel") || error_msg.contains("out of fuel") {
-                    anyhow::bail!("Module '{}' exceeded fuel limit (CPU limit)", self.name)
-                } else if error_msg.contains("stack overflow") {
-                    anyhow::bail!("Module '{}' stack overflow", self.name)
-                } else if error_msg.contains("memory") {
-                    anyhow::bail!("Module '{}' memory limit exceeded", self.name)
-                } else {
-                    Err(e.into())
+        match content.format {
+            MetadataFormat::Json => Self::process_metadata_content(&content.data, store_data),
+            MetadataFormat::JsonLines => {
+                for (i, line) in content.data.split(|&b| b == b'\n').enumerate() {
+                    if line.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    Self::process_metadata_content(line, store_data)
+                        .map_err(|e| anyhow::anyhow!("Line {}: {}", i + 1, e))?;
                 }
+                Ok(())
+            }
+            MetadataFormat::Binary => {
+                let (tables, rows) = crate::metadata_binary::decode(&content.data)?;
+                let ctx = &mut store_data.processing_ctx;
+                ctx.table_schemas.extend(tables);
+                ctx.metadata.extend(rows);
+                tracing::debug!("Processed binary metadata content ({} bytes)", content.data.len());
+                Ok(())
             }
         }
     }
@@ -1247,58 +2446,93 @@ impl ModuleInstance {
     ///   "tables": [
     ///     { "name": "table_name", "columns": [{ "name": "col", "data_type": "Int64" }] }
     ///   ],
+    ///   "columns": [
+    ///     { "table_name": "table_name", "column": { "name": "extra", "data_type": "String" } }
+    ///   ],
     ///   "rows": [
     ///     { "table_name": "table_name", "values": [{ "Int64": 42 }] }
     ///   ]
     /// }
     /// ```
     fn process_metadata_content(content: &[u8], store_data: &mut StoreData) -> Result<()> {
-        use crate::bindings_context::MetadataRow;
-        use crate::bindings_types::{Column, Value, TableSchema};
+        use serde::de::{DeserializeSeed, Deserializer, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
 
-        #[derive(serde::Deserialize)]
-        struct MetadataFile {
-            #[serde(default)]
-            tables: Vec<TableDef>,
-            #[serde(default)]
-            rows: Vec<RowDef>,
-        }
+        /// Appends the `tables`, `columns` and `rows` of a metadata object to the
+        /// processing context as each element is parsed, so a large `rows`
+        /// array is never held twice
+        struct MetadataFile<'a>(&'a mut ProcessingContext);
 
-        #[derive(serde::Deserialize)]
-        struct TableDef {
-            name: String,
-            columns: Vec<Column>,
+        impl<'de> DeserializeSeed<'de> for MetadataFile<'_> {
+            type Value = ();
+
+            fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+                deserializer.deserialize_map(self)
+            }
         }
 
-        #[derive(serde::Deserialize)]
-        struct RowDef {
-            table_name: String,
-            values: Vec<Value>,
+        impl<'de> Visitor<'de> for MetadataFile<'_> {
+            type Value = ();
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an object with tables and rows")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+                let (mut tables, mut columns, mut rows) = (false, false, false);
+                while let Some(key) = map.next_key::<std::borrow::Cow<str>>()? {
+                    match &*key {
+                        "tables" if std::mem::replace(&mut tables, true) => return Err(A::Error::duplicate_field("tables")),
+                        "columns" if std::mem::replace(&mut columns, true) => return Err(A::Error::duplicate_field("columns")),
+                        "rows" if std::mem::replace(&mut rows, true) => return Err(A::Error::duplicate_field("rows")),
+                        "tables" => map.next_value_seed(Append(&mut self.0.table_schemas))?,
+                        "columns" => map.next_value_seed(Append(&mut self.0.added_columns))?,
+                        "rows" => map.next_value_seed(Append(&mut self.0.metadata))?,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(())
+            }
         }
 
-        // Parse as JSON
-        let json_str = String::from_utf8(content.to_vec())
-            .map_err(|e| anyhow::anyhow!("Metadata is not valid UTF-8: {}", e))?;
+        /// Pushes each element of a JSON array onto the vector as it is parsed
+        struct Append<'a, T>(&'a mut Vec<T>);
 
-        let metadata: MetadataFile = serde_json::from_str(&json_str)
-            .map_err(|e| anyhow::anyhow!("Failed to parse metadata JSON: {}", e))?;
+        impl<'de, T: serde::Deserialize<'de>> DeserializeSeed<'de> for Append<'_, T> {
+            type Value = ();
 
-        let ctx = &mut store_data.processing_ctx;
+            fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+                deserializer.deserialize_seq(self)
+            }
+        }
+
+        impl<'de, T: serde::Deserialize<'de>> Visitor<'de> for Append<'_, T> {
+            type Value = ();
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an array")
+            }
 
-        // Process table definitions
-        for table in metadata.tables {
-            ctx.table_schemas.push(TableSchema {
-                name: table.name,
-                columns: table.columns,
-            });
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+                while let Some(element) = seq.next_element()? {
+                    self.0.push(element);
+                }
+                Ok(())
+            }
         }
 
-        // Process row insertions
-        for row in metadata.rows {
-            ctx.metadata.push(MetadataRow {
-                table_name: row.table_name,
-                values: row.values,
-            });
+        // Parsed straight from the file's bytes; elements parsed before an
+        // error are discarded with the rest of the file
+        let ctx = &mut store_data.processing_ctx;
+        let (tables, columns, rows) = (ctx.table_schemas.len(), ctx.added_columns.len(), ctx.metadata.len());
+        let mut deserializer = serde_json::Deserializer::from_slice(content);
+        let parsed = MetadataFile(ctx).deserialize(&mut deserializer).and_then(|()| deserializer.end());
+        if let Err(e) = parsed {
+            ctx.table_schemas.truncate(tables);
+            ctx.added_columns.truncate(columns);
+            ctx.metadata.truncate(rows);
+            anyhow::bail!("Failed to parse metadata JSON: {}", e);
         }
 
         tracing::debug!("Processed metadata content ({} bytes)", content.len());
@@ -1314,7 +2548,7 @@ impl ModuleInstance {
     ///
     /// For slice data: If the metadata contains offset and length, it's a slice of parent content.
     fn process_subcontent_emission(emission: crate::wasi_impl::SubcontentEmission, store_data: &mut StoreData) {
-        use crate::bindings_context::{SubContentEmission, SubContentData};
+        use crate::bindings_context::SubContentData;
         use crate::wasi_impl::SubcontentEmissionData;
 
         tracing::debug!("Processed subcontent emission: {}", emission.filename);
@@ -1324,10 +2558,17 @@ impl ModuleInstance {
             SubcontentEmissionData::Slice { offset, length } => SubContentData::Slice { offset, length },
         };
 
-        store_data.processing_ctx.subcontent.push(SubContentEmission {
+        let mut emission = SubContentEmission {
+            uuid: uuid::Uuid::new_v4(),
             data,
             filename: emission.filename,
-        });
+            relation: emission.relation,
+            dispatched: false,
+        };
+        if let Some(sink) = store_data.subcontent_sink.as_mut() {
+            emission.dispatched = sink(&emission);
+        }
+        store_data.processing_ctx.subcontent.push(emission);
     }
 
     /// Process any remaining metadata files after _start or process() completes.
@@ -1344,17 +2585,19 @@ impl ModuleInstance {
             Err(_) => return Ok(()), // No metadata dir, nothing to process
         };
 
-        // List all files in /metadata
-        let entries = metadata_dir.list();
+        // List all files in /metadata, in the order they were numbered
+        let mut entries = metadata_dir.list();
+        entries.sort_by_cached_key(|(name, _)| metadata_file_order(name));
 
         for (name, is_dir) in entries {
-            if is_dir || !name.ends_with(".json") {
-                continue;
-            }
+            let format = match crate::wasi_impl::MetadataFormat::from_path(&name) {
+                Some(format) if !is_dir => format,
+                _ => continue,
+            };
 
-            // Read the file
+            // Take the file's bytes, removing it
             let path = format!("/metadata/{}", name);
-            let contents = match filesystem.read_file(&path) {
+            let contents = match filesystem.take_file_bytes(&path) {
                 Ok(c) => c,
                 Err(e) => {
                     tracing::warn!("Failed to read metadata file {}: {}", path, e);
@@ -1363,43 +2606,92 @@ impl ModuleInstance {
             };
 
             // Process the content
-            if let Err(e) = Self::process_metadata_content(&contents, store.data_mut()) {
+            let content = crate::wasi_impl::MetadataContent { path: path.clone(), format, data: contents };
+            if let Err(e) = Self::process_metadata_file(&content, store.data_mut()) {
                 tracing::warn!("Failed to process metadata file {}: {}", path, e);
             } else {
                 tracing::debug!("Processed remaining metadata file: {}", path);
             }
-
-            // Delete the file after processing
-            let _ = metadata_dir.remove(&name);
         }
 
         Ok(())
     }
 
+    /// Write the filesystem to `{dump_fs_on_error}/{module}/{content_uuid}`, if enabled
+    fn dump_filesystem(&self, filesystem: &MemoryFilesystem, content_uuid: uuid::Uuid) {
+        let Some(dir) = &self.dump_fs_on_error else {
+            return;
+        };
+
+        let target = dir.join(&self.name).join(content_uuid.to_string());
+        match filesystem.export_to(&target) {
+            Ok(()) => tracing::info!("Module '{}' filesystem dumped to {:?}", self.name, target),
+            Err(e) => tracing::warn!("Failed to dump filesystem for module '{}' to {:?}: {}", self.name, target, e),
+        }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Version of the module that produced this instance's results.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Language and runtime of the module, when it was loaded from a file
+    pub fn runtime(&self) -> Option<&crate::module_runtime::ModuleRuntime> {
+        self.runtime.as_ref()
+    }
+
     pub fn metadata_store(&self) -> &MetadataStore {
         &self.metadata_store
     }
 
+    /// Which contents this module is run on
+    pub fn manifest(&self) -> &crate::manifest::ModuleManifest {
+        &self.manifest
+    }
+
+    /// Run the module once against an empty `/data.bin` and return the tables
+    /// it declares. Rows and sub-content are discarded.
+    pub fn declared_schemas(&mut self) -> Result<Vec<crate::bindings_types::TableSchema>> {
+        let ctx = self.process_content(uuid::Uuid::nil(), crate::shared_buffer::SharedBuffer::from_vec(Vec::new()))?;
+        Ok(ctx.table_schemas)
+    }
+
     /// Process content and return TestOutput for the test subcommand.
     ///
     /// Unlike process_content(), this:
     /// - Returns TestOutput instead of ProcessingContext
     /// - Does NOT recursively process subcontent (returns hex-encoded bytes)
     /// - Always returns a result (even on failure)
+    ///
+    /// Each subcontent's `data_hex` holds at most `max_hex_bytes` bytes;
+    /// longer payloads are cut there and marked `truncated`.
+    ///
+    /// The module sees the detected content type as `WADUP_CONTENT_TYPE`
+    /// (unset when it can't be detected), and the output reports the same value.
     pub fn process_content_for_test(
         &mut self,
         content_data: crate::shared_buffer::SharedBuffer,
+        max_hex_bytes: usize,
+    ) -> crate::test_output::TestOutput {
+        let detected_content_type = crate::content_type::detect_content_type(content_data.as_slice());
+        self.store.data_mut().wasi_ctx.set_env_var("WADUP_CONTENT_TYPE", detected_content_type);
+        let mut output = self.run_test_inner(content_data, max_hex_bytes);
+        output.detected_content_type = detected_content_type.map(|s| s.to_string());
+        output
+    }
+
+    fn run_test_inner(
+        &mut self,
+        content_data: crate::shared_buffer::SharedBuffer,
+        max_hex_bytes: usize,
     ) -> crate::test_output::TestOutput {
         use crate::test_output::{TestOutput, SubcontentOutput};
         use crate::bindings_context::SubContentData;
 
-        // Maximum bytes to include in hex output (4KB)
-        const MAX_HEX_BYTES: usize = 4096;
-
         // Update /data.bin in the in-memory filesystem (zero-copy)
         let filesystem = &self.store.data().wasi_ctx.filesystem;
         if let Err(e) = filesystem.set_data_bin(content_data.to_bytes()) {
@@ -1416,22 +2708,27 @@ impl ModuleInstance {
             if let Err(e) = self.store.set_fuel(fuel) {
                 return TestOutput::failure(format!("Failed to set fuel: {}", e), 1, String::new(), String::new(), None);
             }
+            self.store.data_mut().fuel_granted = fuel;
+        }
+        self.take_memory_refused();
+        self.reset_peak_memory();
+        self.store.data_mut().sched_yields = 0;
+        if let Err(e) = self.begin_content().and_then(|_| self.write_direct_content(content_data.as_slice())) {
+            return TestOutput::failure(format!("{:#}", e), 1, String::new(), String::new(), None);
         }
 
-        // Call the process function
-        let result = if let Ok(process_func) = self.instance
-            .get_typed_func::<(), i32>(&mut self.store, "process")
-        {
-            process_func.call(&mut self.store, ()).map(|code| code)
-        } else if let Ok(process_func) = self.instance
-            .get_typed_func::<(), ()>(&mut self.store, "process")
-        {
-            process_func.call(&mut self.store, ()).map(|_| 0)
-        } else {
-            return TestOutput::failure(
-                format!("Module '{}' process function has unsupported signature", self.name),
-                1, String::new(), String::new(), None
-            );
+        // Call the entrypoint
+        let result = match Self::call_export(&self.instance, &mut self.store, &self.entrypoint) {
+            Some(result) => Self::resolve_exit(result),
+            None => {
+                return TestOutput::failure(
+                    format!(
+                        "Module '{}' {} function is missing or has unsupported signature",
+                        self.name, self.entrypoint
+                    ),
+                    1, String::new(), String::new(), None
+                );
+            }
         };
 
         // Get filesystem reference before borrowing store mutably
@@ -1445,18 +2742,10 @@ impl ModuleInstance {
         let (exit_code, error) = match &result {
             Ok(0) => (0, None),
             Ok(code) => (*code, Some(format!("Module returned error code: {}", code))),
-            Err(e) => {
-                let error_msg = e.to_string();
-                if error_msg.contains("fuel") || error_msg.contains("out of fuel") {
-                    (1, Some("Module exceeded fuel limit (CPU limit)".to_string()))
-                } else if error_msg.contains("stack overflow") {
-                    (1, Some("Module stack overflow".to_string()))
-                } else if error_msg.contains("memory") {
-                    (1, Some("Module memory limit exceeded".to_string()))
-                } else {
-                    (1, Some(error_msg))
-                }
-            }
+            Err(e) => match LimitExceeded::classify(e, self.take_memory_refused()) {
+                Some(limit) => (1, Some(format!("Module {}", limit.description()))),
+                None => (1, Some(e.to_string())),
+            },
         };
 
         // Process any remaining metadata files
@@ -1498,25 +2787,27 @@ impl ModuleInstance {
         };
 
         // Convert subcontent to hex-encoded format (no recursion)
+        let parent_data = content_data.to_bytes();
         let subcontent_list: Vec<SubcontentOutput> = ctx.subcontent.iter().enumerate().map(|(index, emission)| {
-            let (data_bytes, size): (Vec<u8>, usize) = match &emission.data {
+            let (data_bytes, size): (&[u8], usize) = match &emission.data {
                 SubContentData::Bytes(bytes) => {
-                    (bytes.to_vec(), bytes.len())
+                    (&bytes[..], bytes.len())
                 }
                 SubContentData::Slice { offset, length } => {
                     // Extract slice from parent content
-                    let parent_data = content_data.to_bytes();
-                    let end = (*offset + *length).min(parent_data.len());
+                    let end = offset.saturating_add(*length).min(parent_data.len());
                     let start = (*offset).min(end);
-                    (parent_data[start..end].to_vec(), *length)
+                    (&parent_data[start..end], *length)
                 }
+                // Only the processor knows other contents' bytes
+                SubContentData::Reference(_) => (&[], 0),
             };
 
-            let truncated = data_bytes.len() > MAX_HEX_BYTES;
+            let truncated = data_bytes.len() > max_hex_bytes;
             let hex_bytes = if truncated {
-                &data_bytes[..MAX_HEX_BYTES]
+                &data_bytes[..max_hex_bytes]
             } else {
-                &data_bytes[..]
+                data_bytes
             };
             let data_hex = hex::encode(hex_bytes);
 
@@ -1526,7 +2817,17 @@ impl ModuleInstance {
                 data_hex,
                 size,
                 truncated,
-                metadata: None,
+                metadata: match (&emission.data, &emission.relation) {
+                    (SubContentData::Reference(target), _) => Some(serde_json::json!({
+                        "filename": emission.filename,
+                        "reference": target.to_string(),
+                    })),
+                    (_, Some(relation)) => Some(serde_json::json!({
+                        "filename": emission.filename,
+                        "relation": relation,
+                    })),
+                    _ => None,
+                },
             }
         }).collect();
 
@@ -1540,6 +2841,7 @@ impl ModuleInstance {
         ctx.subcontent.clear();
         ctx.metadata.clear();
         ctx.table_schemas.clear();
+        ctx.added_columns.clear();
 
         if exit_code == 0 {
             TestOutput::success(stdout, stderr, metadata_json, subcontent)
@@ -1554,3 +2856,1328 @@ impl ModuleInstance {
         }
     }
 }
+
+impl Drop for ModuleInstance {
+    /// Call the module's `teardown` export, if present, before the instance is dropped.
+    fn drop(&mut self) {
+        if let Some(fuel) = self.fuel_limit {
+            let _ = self.store.set_fuel(fuel);
+        }
+        self.arm_stall_deadline();
+        if let Some(Err(e)) = Self::call_export(&self.instance, &mut self.store, "teardown") {
+            tracing::warn!("Module '{}' teardown failed: {}", self.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings_context::SubContentData;
+    use crate::secrets::Secret;
+    use crate::shared_buffer::SharedBuffer;
+    use crate::test_guests::file_writing_guest;
+    use crate::test_output::DEFAULT_MAX_HEX_BYTES;
+    use crate::wasi_impl::Errno;
+
+    fn no_limits() -> ResourceLimits {
+        ResourceLimits { fuel: None, max_memory: None, max_stack: None }
+    }
+
+    fn instance_from_wat(wat: &str) -> ModuleInstance {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        ModuleInstance::new(&engine, &module, "test", &no_limits(), MetadataStore::new_dummy(), &InstanceOptions::default()).unwrap()
+    }
+
+    fn exiting_guest(code: i32) -> String {
+        format!(r#"
+            (module
+                (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (call $proc_exit (i32.const {}))
+                    (i32.const 99)))
+        "#, code)
+    }
+
+    #[test]
+    fn test_declared_module_version() {
+        let instance = instance_from_wat(r#"
+            (module
+                (memory (export "memory") 1)
+                (data (i32.const 16) "1.4.2\00")
+                (func (export "wadup_module_version") (result i32) (i32.const 16))
+                (func (export "process") (result i32) (i32.const 0)))
+        "#);
+        assert_eq!(instance.version(), Some("1.4.2"));
+    }
+
+    #[test]
+    fn test_module_version_falls_back_to_mtime() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("plain.wasm"),
+            r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let instances = runtime.create_instances(MetadataStore::new_dummy())?;
+
+        let version = instances[0].version().unwrap();
+        assert!(version.starts_with("mtime:"), "{}", version);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_single_module_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let module = r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#;
+        std::fs::write(dir.path().join("first.wasm"), module)?;
+        std::fs::write(dir.path().join("second.wasm"), module)?;
+        std::fs::write(dir.path().join("notes.txt"), "not a module")?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(&dir.path().join("second.wasm"))?;
+        let instances = runtime.create_instances(MetadataStore::new_dummy())?;
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].name(), "second");
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        assert!(runtime.load_modules(&dir.path().join("notes.txt")).is_err());
+
+        let empty = tempfile::tempdir()?;
+        let err = WasmRuntime::new(no_limits())?.load_modules(empty.path()).unwrap_err();
+        assert!(err.to_string().contains("No WASM modules found"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_module_names_are_rejected() -> Result<()> {
+        let module = r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#;
+        let (first, second) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::write(first.path().join("parser.wasm"), module)?;
+        std::fs::write(second.path().join("parser.wasm"), module)?;
+        std::fs::write(second.path().join("other.wasm"), module)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(first.path())?;
+        let err = runtime.load_modules(second.path()).unwrap_err();
+        assert!(err.to_string().contains("Module name 'parser'"), "{}", err);
+        assert!(err.to_string().contains(&format!("{:?}", first.path().join("parser.wasm"))), "{}", err);
+
+        // Reloading the same directory replaces the modules rather than adding to them
+        runtime.reload_modules(first.path())?;
+        assert_eq!(runtime.module_names(), ["parser"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_uses_memory_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("m.wasm"),
+            r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        runtime.reload_modules(dir.path())?;
+
+        assert_eq!(runtime.create_instances(MetadataStore::new_dummy())?.len(), 1);
+        assert_eq!(runtime.module_cache().hits(), 1);
+        assert_eq!(runtime.module_cache().misses(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_entrypoint_and_setup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // "run" succeeds only if setup ran exactly once before it
+        std::fs::write(dir.path().join("custom.wasm"), r#"
+            (module
+                (memory (export "memory") 1)
+                (global $setups (mut i32) (i32.const 0))
+                (func (export "setup")
+                    (global.set $setups (i32.add (global.get $setups) (i32.const 1))))
+                (func (export "run") (result i32)
+                    (i32.ne (global.get $setups) (i32.const 1))))
+        "#)?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        assert!(runtime.load_modules(dir.path()).is_err(), "default entrypoint should be required");
+
+        runtime.set_entrypoint("run");
+        runtime.load_modules(dir.path())?;
+        let mut instances = runtime.create_instances(MetadataStore::new_dummy())?;
+
+        for _ in 0..2 {
+            instances[0].process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new()))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_proc_exit_zero_is_success() {
+        let mut instance = instance_from_wat(&exiting_guest(0));
+        let result = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"data".to_vec()));
+        assert!(result.is_ok(), "proc_exit(0) should succeed: {:?}", result.err());
+    }
+
+    #[test]
+    fn test_proc_exit_nonzero_reports_code() {
+        let mut instance = instance_from_wat(&exiting_guest(3));
+        let err = instance
+            .process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new()))
+            .err()
+            .expect("proc_exit(3) should fail");
+        assert!(err.to_string().contains("returned error code: 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_dump_filesystem_on_trap() {
+        let dump = tempfile::tempdir().unwrap();
+        let mut instance = instance_from_wat(&file_writing_guest(
+            &[("/tmp/scratch.txt", "partial state"), ("/metadata/output_0.json", "{\"rows\":")],
+            false,
+            "(unreachable)",
+        ));
+        instance.set_dump_fs_on_error(Some(dump.path().to_path_buf()));
+
+        let uuid = uuid::Uuid::new_v4();
+        assert!(instance.process_content(uuid, SharedBuffer::from_vec(b"input".to_vec())).is_err());
+
+        let root = dump.path().join("test").join(uuid.to_string());
+        assert_eq!(std::fs::read_to_string(root.join("tmp/scratch.txt")).unwrap(), "partial state");
+        assert_eq!(std::fs::read_to_string(root.join("metadata/output_0.json")).unwrap(), "{\"rows\":");
+        assert!(!root.join("data.bin").exists());
+    }
+
+    #[test]
+    fn test_bogus_iovecs_return_inval() {
+        // (function, iovs_ptr, iovs_len, iovec buf_ptr, iovec buf_len)
+        let cases = [
+            ("fd_write", 8, -1, 0, 0),
+            ("fd_write", 8, i32::MAX, 0, 0),
+            ("fd_write", 65530, 1, 0, 0),
+            ("fd_write", 8, 1, 65000, 4096),
+            ("fd_read", 8, 1, 0, -1),
+            ("fd_read", 8, 100_000, 0, 0),
+        ];
+        for (func, iovs_ptr, iovs_len, buf_ptr, buf_len) in cases {
+            let wat = format!(r#"
+                (module
+                    (import "wasi_snapshot_preview1" "{func}"
+                        (func ${func} (param i32 i32 i32 i32) (result i32)))
+                    (memory (export "memory") 1)
+                    (func (export "process") (result i32)
+                        (i32.store (i32.const 8) (i32.const {buf_ptr}))
+                        (i32.store (i32.const 12) (i32.const {buf_len}))
+                        (call ${func} (i32.const 1) (i32.const {iovs_ptr}) (i32.const {iovs_len}) (i32.const 16))))
+            "#);
+            let mut instance = instance_from_wat(&wat);
+            let err = instance
+                .process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new()))
+                .err()
+                .expect("bogus iovec should be rejected");
+            assert!(
+                err.to_string().contains("returned error code: 28"),
+                "{} iovs_len={} buf=({}, {}): {}", func, iovs_len, buf_ptr, buf_len, err
+            );
+        }
+    }
+
+    #[test]
+    fn test_guest_stat_sees_inode_and_size() {
+        // path_filestat_get("data.bin") into 64, then open it and fd_filestat_get into 128.
+        // Returns 0 when both report a nonzero inode, nlink 1, size 4 and a nonzero mtime.
+        let check = |base: u32| format!(
+            "(if (i64.eqz (i64.load (i32.const {ino}))) (then (return (i32.const 10))))
+             (if (i64.ne (i64.load (i32.const {nlink})) (i64.const 1)) (then (return (i32.const 11))))
+             (if (i64.ne (i64.load (i32.const {size})) (i64.const 4)) (then (return (i32.const 12))))
+             (if (i64.eqz (i64.load (i32.const {mtim}))) (then (return (i32.const 13))))",
+            ino = base + 8, nlink = base + 24, size = base + 32, mtim = base + 48,
+        );
+        let wat = format!(r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_filestat_get"
+                    (func $path_filestat_get (param i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_filestat_get"
+                    (func $fd_filestat_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "data.bin")
+                (func (export "process") (result i32)
+                    (if (call $path_filestat_get (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 8) (i32.const 64))
+                        (then (return (i32.const 1))))
+                    {}
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 8) (i32.const 0)
+                            (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0))
+                        (then (return (i32.const 2))))
+                    (if (call $fd_filestat_get (i32.load (i32.const 0)) (i32.const 128))
+                        (then (return (i32.const 3))))
+                    {}
+                    (if (i64.ne (i64.load (i32.const 72)) (i64.load (i32.const 136)))
+                        (then (return (i32.const 4))))
+                    (i32.const 0)))
+        "#, check(64), check(128));
+
+        let mut instance = instance_from_wat(&wat);
+        let result = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"abcd".to_vec()));
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    fn test_subcontent_hex_respects_max_hex_bytes() {
+        let guest = file_writing_guest(
+            &[("/subcontent/metadata_0.json", r#"{"filename":"all.bin","offset":0,"length":8}"#)],
+            true,
+            "(i32.const 0)",
+        );
+        let content = b"\x00\x01\x02\x03\x04\x05\x06\x07".to_vec();
+
+        for (max_hex_bytes, expected_hex, truncated) in [
+            (8, "0001020304050607", false),
+            (9, "0001020304050607", false),
+            (3, "000102", true),
+            (0, "", true),
+        ] {
+            let mut instance = instance_from_wat(&guest);
+            let output = instance.process_content_for_test(SharedBuffer::from_vec(content.clone()), max_hex_bytes);
+            assert!(output.success, "{:?}", output.error);
+            let subcontent = &output.subcontent.expect("subcontent should be reported")[0];
+            assert_eq!(subcontent.data_hex, expected_hex, "max_hex_bytes={}", max_hex_bytes);
+            assert_eq!(subcontent.truncated, truncated, "max_hex_bytes={}", max_hex_bytes);
+            assert_eq!(subcontent.size, 8);
+        }
+    }
+
+    #[test]
+    fn test_subcontent_ref_does_not_copy_bytes() {
+        let guest = crate::test_guests::file_writing_guest_with_imports(
+            &[
+                ("/subcontent/data_0.bin", "blob"),
+                ("/subcontent/metadata_0.json", r#"{"filename":"blob.bin"}"#),
+            ],
+            true,
+            r#"
+            (import "wadup" "subcontent_uuid" (func $subcontent_uuid (param i32 i32) (result i32)))
+            (import "wadup" "emit_subcontent_ref" (func $emit_subcontent_ref (param i32 i32 i32) (result i32)))"#,
+            "(if (call $subcontent_uuid (i32.const 1) (i32.const 512)) (then) (else (return (i32.const 2))))
+             (if (call $subcontent_uuid (i32.const 0) (i32.const 512)) (then (return (i32.const 3))))
+             (i32.store (i32.const 600) (i32.const 0x707564))
+             (if (call $emit_subcontent_ref (i32.const 512) (i32.const 600) (i32.const 3)) (then (return (i32.const 4))))
+             (i32.const 0)",
+        );
+        let mut instance = instance_from_wat(&guest);
+        let ctx = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).unwrap();
+
+        assert_eq!(ctx.subcontent.len(), 2);
+        assert!(matches!(ctx.subcontent[0].data, SubContentData::Bytes(_)));
+        match &ctx.subcontent[1].data {
+            SubContentData::Reference(target) => assert_eq!(*target, ctx.subcontent[0].uuid),
+            _ => panic!("second emission should reference the first"),
+        }
+        assert_eq!(ctx.subcontent[1].filename, "dup");
+    }
+
+    #[test]
+    fn test_subcontent_exists_skips_repeated_filenames() {
+        // Emits "a", "b", "a" as references to itself, skipping names already emitted
+        let mut instance = instance_from_wat(r#"
+            (module
+                (import "wadup" "get_content_uuid" (func $uuid (param i32) (result i32)))
+                (import "wadup" "subcontent_exists" (func $exists (param i32 i32) (result i32)))
+                (import "wadup" "emit_subcontent_ref" (func $ref (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 100) "ab")
+                (func $emit_once (param $name i32)
+                    (if (i32.eqz (call $exists (local.get $name) (i32.const 1)))
+                        (then (drop (call $ref (i32.const 0) (local.get $name) (i32.const 1))))))
+                (func (export "process") (result i32)
+                    (drop (call $uuid (i32.const 0)))
+                    (call $emit_once (i32.const 100))
+                    (call $emit_once (i32.const 101))
+                    (call $emit_once (i32.const 100))
+                    ;; A name running past the end of memory is -1
+                    (i32.ne (call $exists (i32.const 0) (i32.const 0x10001)) (i32.const -1))))
+        "#);
+        let ctx = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).unwrap();
+        let names: Vec<&str> = ctx.subcontent.iter().map(|s| s.filename.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_linker_provides_all_preview1_imports() {
+        let engine = Engine::default();
+        let linker = ModuleInstance::linker(&engine).unwrap();
+        let mut store = Store::new(&engine, empty_store_data());
+        let mut defined: Vec<String> = linker.iter(&mut store)
+            .filter(|(module, _, _)| *module == "wasi_snapshot_preview1")
+            .map(|(_, name, _)| name.to_string())
+            .collect();
+        defined.sort();
+        let mut expected: Vec<String> = WASI_PREVIEW1_IMPORTS.iter().map(|s| s.to_string()).collect();
+        expected.sort();
+        assert_eq!(defined, expected);
+
+        let mut host: Vec<String> = linker.iter(&mut store)
+            .filter(|(module, _, _)| *module == crate::host_functions::HOST_MODULE)
+            .map(|(_, name, _)| name.to_string())
+            .collect();
+        host.sort();
+        let mut expected: Vec<String> = crate::host_functions::HOST_FUNCTIONS.iter().map(|s| s.to_string()).collect();
+        expected.sort();
+        assert_eq!(host, expected);
+    }
+
+    #[test]
+    fn test_c_header_matches_host_functions() {
+        // Each `WADUP_IMPORT(name) ret fn(params);` line, as wasm32 value types
+        // (pointers and 32-bit integers are i32)
+        let header = include_str!("../../../guest/c/wadup.h");
+        let val_type = |c_type: &str| if c_type.trim().starts_with("int64_t") { "i64" } else { "i32" };
+        let mut declared: Vec<(String, Vec<&str>, Vec<&str>)> = header.lines()
+            .filter_map(|line| line.strip_prefix("WADUP_IMPORT("))
+            .map(|line| {
+                let (name, decl) = line.split_once(')').unwrap();
+                let (ret, rest) = decl.split_once('(').unwrap();
+                let params = rest.trim_end_matches(");");
+                let params = if params == "void" { Vec::new() } else { params.split(',').map(val_type).collect() };
+                let ret = ret.split_whitespace().next().unwrap();
+                let results = if ret == "void" { Vec::new() } else { vec![val_type(ret)] };
+                (name.to_string(), params, results)
+            })
+            .collect();
+        declared.sort();
+
+        let engine = Engine::default();
+        let linker = ModuleInstance::linker(&engine).unwrap();
+        let mut store = Store::new(&engine, empty_store_data());
+        let externs: Vec<(String, Extern)> = linker.iter(&mut store)
+            .filter(|(module, _, _)| *module == crate::host_functions::HOST_MODULE)
+            .map(|(_, name, ext)| (name.to_string(), ext))
+            .collect();
+        let name_of = |t: ValType| if matches!(t, ValType::I64) { "i64" } else { "i32" };
+        let mut host: Vec<(String, Vec<&str>, Vec<&str>)> = externs.into_iter()
+            .map(|(name, ext)| {
+                let ty = ext.into_func().unwrap().ty(&store);
+                (name, ty.params().map(name_of).collect(), ty.results().map(name_of).collect())
+            })
+            .collect();
+        host.sort();
+        assert_eq!(declared, host);
+    }
+
+    #[test]
+    fn test_content_host_functions_read_current_content() {
+        // Copies bytes 1..4 of the content to 100 and its UUID to 200, and
+        // checks the size and a read past the end
+        let wat = r#"
+            (module
+                (import "wadup" "get_content_size" (func $size (result i64)))
+                (import "wadup" "read_content" (func $read (param i64 i32 i32 i32) (result i32)))
+                (import "wadup" "get_content_uuid" (func $uuid (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (if (i64.ne (call $size) (i64.const 5)) (then (return (i32.const 1))))
+                    (if (call $read (i64.const 1) (i32.const 100) (i32.const 3) (i32.const 0))
+                        (then (return (i32.const 2))))
+                    (if (i32.ne (i32.load (i32.const 0)) (i32.const 3)) (then (return (i32.const 3))))
+                    (if (i32.ne (i32.load16_u (i32.const 100)) (i32.const 0x6362)) (then (return (i32.const 4))))
+                    (if (call $read (i64.const 9) (i32.const 100) (i32.const 3) (i32.const 0))
+                        (then (return (i32.const 5))))
+                    (if (i32.load (i32.const 0)) (then (return (i32.const 6))))
+                    (if (i32.ne (call $read (i64.const -1) (i32.const 100) (i32.const 3) (i32.const 0))
+                            (i32.const 28))
+                        (then (return (i32.const 7))))
+                    (if (call $uuid (i32.const 200)) (then (return (i32.const 8))))
+                    (i32.const 0)))
+        "#;
+        let mut instance = instance_from_wat(wat);
+        let uuid = uuid::Uuid::new_v4();
+        let result = instance.process_content(uuid, SharedBuffer::from_vec(b"abcde".to_vec()));
+        assert!(result.is_ok(), "{:?}", result.err());
+
+        let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+        let data = memory.data(&instance.store);
+        assert_eq!(&data[100..103], b"bcd");
+        assert_eq!(&data[200..216], uuid.as_bytes());
+    }
+
+    #[test]
+    fn test_add_fuel_extends_a_content_up_to_the_ceiling() {
+        // Tops up once, then spins 10,000 times: far more than the 20,000 fuel it starts with
+        let wat = r#"
+            (module
+                (import "wadup" "add_fuel" (func $add_fuel (param i64) (result i64)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (local $i i32)
+                    (i64.store (i32.const 0) (call $add_fuel (i64.const 1000000)))
+                    (loop $spin
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $spin (i32.lt_u (local.get $i) (i32.const 10000))))
+                    (i32.const 0)))
+        "#;
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let limits = ResourceLimits { fuel: Some(20_000), max_memory: None, max_stack: None };
+        let run = |fuel_ceiling: Option<u64>| {
+            let options = InstanceOptions { fuel_ceiling, ..InstanceOptions::default() };
+            let mut instance = ModuleInstance::new(&engine, &module, "test", &limits, MetadataStore::new_dummy(), &options).unwrap();
+            let completed = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).is_ok();
+            let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+            let total = i64::from_le_bytes(memory.data(&instance.store)[..8].try_into().unwrap());
+            (completed, total)
+        };
+
+        let (completed, total) = run(Some(2_000_000));
+        assert!(completed, "the top-up should cover the loop");
+        assert!(total > 1_000_000 && total < 1_020_000, "{}", total);
+        // Without a ceiling, or with one the top-up would pass, the loop runs out of fuel
+        assert_eq!(run(None), (false, -1));
+        assert_eq!(run(Some(500_000)), (false, -1));
+    }
+
+    #[test]
+    fn test_running_out_of_fuel_is_a_typed_error() {
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (loop $spin (br $spin))
+                    (i32.const 0)))
+        "#;
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let limits = ResourceLimits { fuel: Some(10_000), max_memory: None, max_stack: None };
+        let mut instance = ModuleInstance::new(&engine, &module, "spinner", &limits, MetadataStore::new_dummy(), &InstanceOptions::default()).unwrap();
+
+        match instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())) {
+            Err(WadupError::OutOfFuel { module }) => assert_eq!(module, "spinner"),
+            other => panic!("expected OutOfFuel, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_args_start_with_the_module_name() {
+        // Writes the whole argv buffer to stdout
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "args_sizes_get" (func $sizes (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "args_get" (func $get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (drop (call $sizes (i32.const 0) (i32.const 4)))
+                    (drop (call $get (i32.const 64) (i32.const 1024)))
+                    (i32.store (i32.const 8) (i32.const 1024))
+                    (i32.store (i32.const 12) (i32.load (i32.const 4)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    ;; argv[0] points at the start of the buffer
+                    (i32.ne (i32.load (i32.const 64)) (i32.const 1024))))
+        "#;
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        for (module_args, expected) in [(vec![], "counter\0"), (vec!["--fast".to_string()], "counter\0--fast\0")] {
+            let options = InstanceOptions { module_args, ..InstanceOptions::default() };
+            let mut instance = ModuleInstance::new(&engine, &module, "counter", &no_limits(), MetadataStore::new_dummy(), &options).unwrap();
+            let ctx = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).unwrap();
+            assert_eq!(ctx.stdout.as_deref(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_run_and_test_instances_share_base_environment() {
+        let wat = r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#;
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let limits = ResourceLimits { fuel: None, max_memory: None, max_stack: None };
+
+        let run = ModuleInstance::new(&engine, &module, "m", &limits, MetadataStore::new_dummy(), &InstanceOptions::default()).unwrap();
+        let test = ModuleInstance::with_env_vars(
+            &engine, &module, "m", &limits,
+            vec![("PWD".to_string(), "/elsewhere".to_string()), ("WADUP_FILENAME".to_string(), "x".to_string())],
+        ).unwrap();
+        assert_eq!(run.store.data().wasi_ctx.environ_strings(), ["PWD=/", "WADUP_MODE=run"]);
+        assert_eq!(test.store.data().wasi_ctx.environ_strings(), ["PWD=/", "WADUP_MODE=test", "WADUP_FILENAME=x"]);
+    }
+
+    #[test]
+    fn test_secrets_are_read_with_get_secret_and_kept_out_of_the_environment() {
+        // Writes the secret named "API_KEY" and then its whole environment
+        // block to stdout
+        let wat = r#"
+            (module
+                (import "wadup" "get_secret" (func $get_secret (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_sizes_get" (func $sizes (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_get" (func $get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 32) "API_KEY")
+                (data (i32.const 48) "MISSING")
+                (func (export "process") (result i32)
+                    (if (i32.ne (call $get_secret (i32.const 48) (i32.const 7) (i32.const 4096) (i32.const 64)) (i32.const -1))
+                        (then (return (i32.const 1))))
+                    (i32.store (i32.const 8) (i32.const 4096))
+                    (i32.store (i32.const 12) (call $get_secret (i32.const 32) (i32.const 7) (i32.const 4096) (i32.const 64)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (drop (call $sizes (i32.const 0) (i32.const 4)))
+                    (drop (call $get (i32.const 64) (i32.const 1024)))
+                    (i32.store (i32.const 8) (i32.const 1024))
+                    (i32.store (i32.const 12) (i32.load (i32.const 4)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.const 0)))
+        "#;
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let secrets = vec![Secret::new("API_KEY", "old"), Secret::new("API_KEY", "s3cr3t")];
+        let options = InstanceOptions { secrets: Arc::new(secrets), ..InstanceOptions::default() };
+        let mut instance = ModuleInstance::new(&engine, &module, "m", &no_limits(), MetadataStore::new_dummy(), &options).unwrap();
+
+        let ctx = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).unwrap();
+        let stdout = ctx.stdout.unwrap();
+        let environment = stdout.strip_prefix("s3cr3t").expect("the last API_KEY wins");
+        assert!(environment.starts_with("PWD=/\0"), "{:?}", environment);
+        assert!(!environment.contains("s3cr3t") && !environment.contains("API_KEY"), "{:?}", environment);
+    }
+
+    #[test]
+    fn test_guest_observes_its_mode() {
+        // Writes its whole environment block to stdout
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "environ_sizes_get" (func $sizes (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_get" (func $get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (drop (call $sizes (i32.const 0) (i32.const 4)))
+                    (drop (call $get (i32.const 64) (i32.const 1024)))
+                    (i32.store (i32.const 8) (i32.const 1024))
+                    (i32.store (i32.const 12) (i32.load (i32.const 4)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))
+                    (i32.const 0)))
+        "#;
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+
+        // A caller can't pass its own mode to `wadup test`
+        let env_vars = vec![("WADUP_MODE".to_string(), "run".to_string())];
+        let mut test = ModuleInstance::with_env_vars(&engine, &module, "m", &no_limits(), env_vars).unwrap();
+        let output = test.process_content_for_test(SharedBuffer::from_vec(Vec::new()), DEFAULT_MAX_HEX_BYTES);
+        assert!(output.stdout.contains("WADUP_MODE=test\0"), "{:?}", output.stdout);
+        assert!(!output.stdout.contains("WADUP_MODE=run"), "{:?}", output.stdout);
+
+        let mut run = instance_from_wat(wat);
+        let ctx = run.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).unwrap();
+        assert!(ctx.stdout.unwrap().contains("WADUP_MODE=run\0"));
+    }
+
+    #[test]
+    fn test_max_stack_is_enforced_and_classified() -> Result<()> {
+        // Recurses 1000 frames deep: fine on the default stack, not in 16 KiB
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (func $recurse (param i32)
+                    (if (local.get 0)
+                        (then (call $recurse (i32.sub (local.get 0) (i32.const 1))))))
+                (func (export "process") (result i32)
+                    (call $recurse (i32.const 1000))
+                    (i32.const 0)))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("deep.wasm"), wat)?;
+
+        let run = |max_stack: Option<usize>| -> Result<Result<(), WadupError>> {
+            let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory: None, max_stack })?;
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            Ok(instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).map(|_| ()))
+        };
+
+        assert!(run(None)?.is_ok());
+        let err = run(Some(16 * 1024))?.unwrap_err();
+        assert!(matches!(&err, WadupError::StackOverflow { module } if module == "deep"), "{err:?}");
+        assert_eq!(err.limit_exceeded(), Some(LimitExceeded::Stack));
+        assert_eq!(err.to_string(), "Module 'deep' stack overflow");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_makes_random_get_reproducible() -> Result<()> {
+        // Leaves 16 random bytes at address 0
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "random_get"
+                    (func $random_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (call $random_get (i32.const 0) (i32.const 16))))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("rand.wasm"), wat)?;
+
+        let random = |seed: Option<u64>, content: &[u8]| -> Result<Vec<u8>> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_seed(seed);
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(content.to_vec()))?;
+            let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+            Ok(memory.data(&instance.store)[..16].to_vec())
+        };
+
+        let seeded = random(Some(42), b"a")?;
+        assert_eq!(random(Some(42), b"a")?, seeded);
+        assert_ne!(random(Some(43), b"a")?, seeded);
+        assert_ne!(random(Some(42), b"b")?, seeded, "each content gets its own stream");
+        assert_ne!(random(None, b"a")?, random(None, b"a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_rng_streams_are_pinned() {
+        // A seed must reproduce a run with any toolchain or `rand` release,
+        // so the stream itself is fixed
+        let mut rng = seeded_rng(7, &[b"module", &[0u8; 32]]);
+        let mut bytes = [0u8; 8];
+        rng.fill_bytes(&mut bytes);
+        assert_eq!(hex::encode(bytes), "77b3488ec4429a8c");
+    }
+
+    #[test]
+    fn test_content_seeded_random_repeats_ids_for_the_same_content() -> Result<()> {
+        // Leaves a 16-byte random ID at address 0
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "random_get"
+                    (func $random_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (call $random_get (i32.const 0) (i32.const 16))))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("ids.wasm"), wat)?;
+
+        // A fresh runtime per run, as separate `wadup run`s would use
+        let generated_id = |content: &[u8]| -> Result<Vec<u8>> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_content_seeded_random(true);
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(content.to_vec()))?;
+            let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+            Ok(memory.data(&instance.store)[..16].to_vec())
+        };
+
+        let id = generated_id(b"sample")?;
+        assert_eq!(generated_id(b"sample")?, id);
+        assert_ne!(generated_id(b"other")?, id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scratch_files_are_cleared_between_contents() -> Result<()> {
+        // Creates /tmp/state with O_CREAT | O_EXCL and returns path_open's errno
+        let creating = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "tmp/state")
+                (func (export "process") (result i32)
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 9)
+                        (i32.const 5) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0))))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("creating.wasm"), creating)?;
+        // A fresh instance would start with an empty /tmp either way
+        std::fs::write(dir.path().join("creating.manifest.json"), r#"{"stateful": true}"#)?;
+
+        let second_content = |keep: bool| -> Result<()> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_keep_scratch(keep);
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"1".to_vec()))?;
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"2".to_vec()))?;
+            Ok(())
+        };
+
+        second_content(false)?;
+        let err = second_content(true).unwrap_err();
+        assert!(err.to_string().contains(&format!("error code: {}", Errno::Exist as i32)), "{err:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_metadata_json_copies_raw_metadata_files() -> Result<()> {
+        let table = r#"{"tables":[{"name":"t","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[]}"#;
+        let broken = r#"{"rows":"#;
+        let subcontent = r#"{"filename":"child.txt"}"#;
+        let wat = file_writing_guest(
+            &[
+                ("/metadata/output_0.json", table),
+                ("/metadata/output_1.json", broken),
+                ("/subcontent/data_0.bin", "child"),
+                ("/subcontent/metadata_0.json", subcontent),
+            ],
+            true,
+            "(i32.const 0)",
+        );
+        let dir = tempfile::tempdir()?;
+        let options = InstanceOptions { keep_metadata_json: Some(dir.path().to_path_buf()), ..Default::default() };
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wat)?;
+        let mut instance = ModuleInstance::new(&engine, &module, "test", &no_limits(), MetadataStore::new_dummy(), &options)?;
+
+        let uuid = uuid::Uuid::new_v4();
+        let ctx = instance.process_content(uuid, SharedBuffer::from_vec(b"parent".to_vec()))?;
+        assert_eq!(ctx.subcontent.len(), 1);
+
+        let kept = dir.path().join("test").join(uuid.to_string());
+        assert_eq!(std::fs::read_to_string(kept.join("metadata/output_0.json"))?, table);
+        assert_eq!(std::fs::read_to_string(kept.join("metadata/output_1.json"))?, broken);
+        assert_eq!(std::fs::read_to_string(kept.join("subcontent/metadata_0.json"))?, subcontent);
+        assert!(!kept.join("subcontent/data_0.bin").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasi_profile_restricts_imports_and_writes() -> Result<()> {
+        // Creates /metadata/out.json and returns path_open's errno
+        let creating = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "metadata/out.json")
+                (func (export "process") (result i32)
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 17)
+                        (i32.const 1) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0))))
+        "#;
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("creating.wasm"), creating)?;
+
+        let run = |profile: WasiProfile| -> Result<()> {
+            let mut runtime = WasmRuntime::new(no_limits())?;
+            runtime.set_wasi_profile(profile);
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"x".to_vec()))?;
+            Ok(())
+        };
+
+        run(WasiProfile::Full)?;
+        assert!(run(WasiProfile::Readonly).is_err(), "O_CREAT is refused under readonly");
+        let err = run(WasiProfile::Minimal).unwrap_err();
+        assert!(
+            err.to_string().ends_with("import wasi_snapshot_preview1::path_open isn't allowed by the 'minimal' WASI profile"),
+            "{err:#}"
+        );
+
+        assert!(!WasiProfile::Readonly.allows("path_unlink_file"));
+        assert!(WasiProfile::Readonly.allows("fd_read"));
+        assert_eq!("readonly".parse::<WasiProfile>()?, WasiProfile::Readonly);
+        assert!("none".parse::<WasiProfile>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_import_names_the_import() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("unknown.wasm"),
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (import "env" "foo" (func))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+        std::fs::write(
+            dir.path().join("valid.wasm"),
+            r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#,
+        )?;
+
+        let mut runtime = WasmRuntime::new(no_limits())?;
+        runtime.load_modules(dir.path())?;
+        let (instances, failures) = runtime.try_create_instances(MetadataStore::new_dummy());
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].name(), "valid");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].to_string(), "Module 'unknown' failed to instantiate: unsupported import env::foo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_memory_is_enforced_and_classified() -> Result<()> {
+        // Asks for 1 MiB more and traps if the grow is refused
+        let greedy = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1))
+                        (then unreachable))
+                    (i32.const 0)))
+        "#;
+        // Traps without touching memory at all
+        let broken = r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    unreachable))
+        "#;
+
+        let run = |wat: &str, max_memory: Option<usize>| -> Result<Result<(), WadupError>> {
+            let dir = tempfile::tempdir()?;
+            std::fs::write(dir.path().join("greedy.wasm"), wat)?;
+            let mut runtime = WasmRuntime::new(ResourceLimits { fuel: None, max_memory, max_stack: None })?;
+            runtime.load_modules(dir.path())?;
+            let mut instance = runtime.create_instances(MetadataStore::new_dummy())?.remove(0);
+            Ok(instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).map(|_| ()))
+        };
+
+        assert!(run(greedy, None)?.is_ok());
+        assert!(run(greedy, Some(4 * 1024 * 1024))?.is_ok());
+        let err = run(greedy, Some(512 * 1024))?.unwrap_err();
+        assert!(matches!(&err, WadupError::MemoryLimit { module } if module == "greedy"), "{err:?}");
+        assert_eq!(err.limit_exceeded(), Some(LimitExceeded::Memory));
+        assert_eq!(err.to_string(), "Module 'greedy' memory limit exceeded");
+
+        // A trap that never hit the limiter stays an ordinary error
+        let err = run(broken, Some(512 * 1024))?.unwrap_err();
+        assert!(err.limit_exceeded().is_none(), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_fd_read_fills_iovecs_in_order() {
+        // Reads "abcdefg" into iovecs of 4, 2 and 8 bytes at 100, 200 and 300
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "/data.bin")
+                (data (i32.const 300) "\ff\ff")
+                (func (export "process") (result i32)
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const 9) (i32.const 0)
+                            (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0))
+                        (then (return (i32.const 1))))
+                    (i32.store (i32.const 32) (i32.const 100))
+                    (i32.store (i32.const 36) (i32.const 4))
+                    (i32.store (i32.const 40) (i32.const 200))
+                    (i32.store (i32.const 44) (i32.const 2))
+                    (i32.store (i32.const 48) (i32.const 300))
+                    (i32.store (i32.const 52) (i32.const 8))
+                    (if (call $fd_read (i32.load (i32.const 0)) (i32.const 32) (i32.const 3) (i32.const 16))
+                        (then (return (i32.const 2))))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.const 7)) (then (return (i32.const 3))))
+                    (if (i32.ne (i32.load (i32.const 100)) (i32.const 0x64636261)) (then (return (i32.const 4))))
+                    (if (i32.ne (i32.load16_u (i32.const 200)) (i32.const 0x6665)) (then (return (i32.const 5))))
+                    (if (i32.ne (i32.load8_u (i32.const 300)) (i32.const 0x67)) (then (return (i32.const 6))))
+                    ;; Bytes past the end of the file are left untouched
+                    (if (i32.ne (i32.load8_u (i32.const 301)) (i32.const 0xff)) (then (return (i32.const 7))))
+                    (i32.const 0)))
+        "#;
+        let mut instance = instance_from_wat(wat);
+        let result = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"abcdefg".to_vec()));
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    fn test_test_output_reports_detected_content_type() {
+        let guest = r#"(module (memory (export "memory") 1) (func (export "process") (result i32) (i32.const 0)))"#;
+        let mut instance = instance_from_wat(guest);
+
+        let zip = b"PK\x03\x04\x14\x00\x00\x00\x08\x00".to_vec();
+        let output = instance.process_content_for_test(SharedBuffer::from_vec(zip), DEFAULT_MAX_HEX_BYTES);
+        assert!(output.success, "{:?}", output.error);
+        assert_eq!(output.detected_content_type.as_deref(), Some("application/zip"));
+        let env = instance.store.data().wasi_ctx.environ_strings();
+        assert!(env.contains(&"WADUP_CONTENT_TYPE=application/zip".to_string()), "{:?}", env);
+
+        let output = instance.process_content_for_test(SharedBuffer::from_vec(b"text".to_vec()), DEFAULT_MAX_HEX_BYTES);
+        assert_eq!(output.detected_content_type, None);
+        let env = instance.store.data().wasi_ctx.environ_strings();
+        assert!(!env.iter().any(|v| v.starts_with("WADUP_CONTENT_TYPE=")), "{:?}", env);
+    }
+
+    #[test]
+    fn test_hash_content_range_matches_reference_digests() -> Result<()> {
+        let wat = r#"
+            (module
+                (import "wadup" "hash_content_range"
+                    (func $hash_content_range (param i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32) (i32.const 0))
+                (func (export "hash") (param $algo i32) (param $offset i64) (param $length i64) (param $out_len i32) (result i32)
+                    (call $hash_content_range (local.get $algo) (local.get $offset) (local.get $length)
+                        (i32.const 64) (local.get $out_len))))
+        "#;
+        let mut instance = instance_from_wat(wat);
+        instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"xxabcxx".to_vec()))?;
+
+        let hash = instance.instance.get_typed_func::<(i32, i64, i64, i32), i32>(&mut instance.store, "hash")?;
+        let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+
+        // FIPS 180-2 test vectors for "abc"
+        let cases = [
+            (0, 32, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (1, 64, "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                     2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"),
+        ];
+        for (algo, len, expected) in cases {
+            assert_eq!(hash.call(&mut instance.store, (algo, 2, 3, len as i32))?, Errno::Success as i32);
+            let digest = hex::encode(&memory.data(&instance.store)[64..64 + len]);
+            assert_eq!(digest, expected.replace(' ', ""), "algo {}", algo);
+        }
+
+        let inval = Errno::Inval as i32;
+        assert_eq!(hash.call(&mut instance.store, (7, 0, 3, 64))?, inval, "unknown algorithm");
+        assert_eq!(hash.call(&mut instance.store, (0, 5, 3, 32))?, inval, "range past the end");
+        assert_eq!(hash.call(&mut instance.store, (0, -1, 3, 32))?, inval, "negative offset");
+        assert_eq!(hash.call(&mut instance.store, (0, 1, i64::MAX, 32))?, inval, "overflowing range");
+        assert_eq!(hash.call(&mut instance.store, (0, 0, 3, 31))?, inval, "short output buffer");
+        Ok(())
+    }
+
+    #[test]
+    fn test_inflate_round_trips_zlib_and_gzip() -> Result<()> {
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        use flate2::Compression;
+        use std::io::Write;
+
+        let wat = r#"
+            (module
+                (import "wadup" "inflate" (func $inflate (param i32 i32 i32 i32) (result i32)))
+                (import "wadup" "inflate_more" (func $inflate_more (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32) (i32.const 0))
+                (func (export "inflate") (param i32 i32 i32 i32) (result i32)
+                    (call $inflate (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+                (func (export "inflate_more") (param i32 i32) (result i32)
+                    (call $inflate_more (local.get 0) (local.get 1))))
+        "#;
+        let mut instance = instance_from_wat(wat);
+        let inflate = instance.instance.get_typed_func::<(i32, i32, i32, i32), i32>(&mut instance.store, "inflate")?;
+        let inflate_more = instance.instance.get_typed_func::<(i32, i32), i32>(&mut instance.store, "inflate_more")?;
+        let memory = instance.instance.get_memory(&mut instance.store, "memory").unwrap();
+
+        let plain = b"wadup ".repeat(100);
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&plain)?;
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&plain)?;
+
+        for compressed in [zlib.finish()?, gzip.finish()?] {
+            memory.write(&mut instance.store, 0, &compressed)?;
+            let len = compressed.len() as i32;
+            assert_eq!(inflate.call(&mut instance.store, (0, len, 1024, 1000))?, 600);
+            assert_eq!(&memory.data(&instance.store)[1024..1624], &plain[..]);
+
+            // A short buffer is filled, and inflate_more continues from there
+            memory.write(&mut instance.store, 4096, &[0u8; 16])?;
+            assert_eq!(inflate.call(&mut instance.store, (0, len, 4096, 10))?, -4);
+            assert_eq!(&memory.data(&instance.store)[4096..4106], &plain[..10]);
+            assert_eq!(memory.data(&instance.store)[4106], 0);
+            assert_eq!(inflate_more.call(&mut instance.store, (4106, 90))?, -4);
+            assert_eq!(inflate_more.call(&mut instance.store, (4196, 1000))?, 500);
+            assert_eq!(&memory.data(&instance.store)[4096..4696], &plain[..]);
+            assert_eq!(inflate_more.call(&mut instance.store, (4096, 10))?, -1, "nothing pending");
+        }
+
+        memory.write(&mut instance.store, 0, b"not compressed")?;
+        assert_eq!(inflate.call(&mut instance.store, (0, 14, 1024, 1000))?, -1);
+        assert_eq!(inflate.call(&mut instance.store, (65530, 100, 1024, 1000))?, -2, "source past memory");
+        assert_eq!(inflate.call(&mut instance.store, (0, 14, 65530, 1000))?, -2, "output past memory");
+        Ok(())
+    }
+
+    fn empty_store_data() -> StoreData {
+        StoreData {
+            processing_ctx: ProcessingContext::new(uuid::Uuid::nil(), SharedBuffer::from_vec(Vec::new())),
+            wasi_ctx: WasiCtx::new(Arc::new(MemoryFilesystem::new())),
+            resource_limiter: None,
+            rng: None,
+            sched_yields: 0,
+            fuel_granted: 0,
+            fuel_ceiling: None,
+            subcontent_sink: None,
+            secrets: Arc::default(),
+            keep_metadata_dir: None,
+            stall_deadline: None,
+            progress: None,
+            wasi_trace: None,
+            wasi_errnos: BTreeMap::new(),
+            pending_inflate: None,
+        }
+    }
+
+    #[test]
+    fn test_yield_spinning_module_is_stopped() {
+        // Yields forever on non-empty content, once on empty content
+        let mut instance = instance_from_wat(r#"
+            (module
+                (import "wasi_snapshot_preview1" "sched_yield" (func $yield (result i32)))
+                (import "wadup" "get_content_size" (func $size (result i64)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    (loop
+                        (drop (call $yield))
+                        (br_if 0 (i64.ne (call $size) (i64.const 0))))
+                    (i32.const 0)))
+        "#);
+        let Err(err) = instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"spin".to_vec())) else {
+            panic!("a spinning module should be stopped");
+        };
+        assert!(format!("{:#}", err).contains("appears stuck (excessive sched_yield"), "{:#}", err);
+
+        // The count starts over for the next content
+        assert!(instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(Vec::new())).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_guest_lengths_are_rejected_before_allocating() {
+        // Returns the index of the first call that didn't fail as expected
+        let mut instance = instance_from_wat(r#"
+            (module
+                (import "wadup" "report_error" (func $report (param i32 i32 i32) (result i32)))
+                (import "wadup" "link_content" (func $link (param i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "process") (result i32)
+                    ;; Fault: 2 GiB is past the end of the 64 KiB memory
+                    (if (i32.ne (call $report (i32.const 1) (i32.const 0) (i32.const 0x7fffffff)) (i32.const 21))
+                        (then (return (i32.const 1))))
+                    (if (i32.ne (call $random_get (i32.const 0) (i32.const -1)) (i32.const 21))
+                        (then (return (i32.const 2))))
+                    ;; Nametoolong: in memory, but past MAX_GUEST_NAME_LEN
+                    (if (i32.ne (call $link (i32.const 0) (i32.const 0) (i32.const 5000)) (i32.const 37))
+                        (then (return (i32.const 3))))
+                    (if (i32.ne (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 5000)
+                            (i32.const 0) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 64)) (i32.const 37))
+                        (then (return (i32.const 4))))
+                    (i32.const 0)))
+        "#);
+        let (result, allocated) = crate::test_alloc::measure(|| {
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"x".to_vec()))
+        });
+        result.unwrap();
+        assert!(instance.take_reported_errors().is_empty());
+        assert!(allocated.peak < 1024 * 1024, "allocated {} bytes", allocated.peak);
+    }
+
+    #[test]
+    fn test_begin_content_runs_before_each_content() {
+        // Reports how many contents it has seen since wadup_begin_content last ran
+        let mut instance = instance_from_wat(r#"
+            (module
+                (import "wadup" "report_error" (func $report (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (global $seen (mut i32) (i32.const 0))
+                (func (export "wadup_begin_content") (global.set $seen (i32.const 0)))
+                (func (export "process") (result i32)
+                    (drop (call $report (global.get $seen) (i32.const 0) (i32.const 0)))
+                    (global.set $seen (i32.add (global.get $seen) (i32.const 1)))
+                    (i32.const 0)))
+        "#);
+        for data in [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()] {
+            instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(data)).unwrap();
+            let codes: Vec<u32> = instance.take_reported_errors().iter().map(|e| e.code).collect();
+            assert_eq!(codes, [0]);
+        }
+    }
+
+    #[test]
+    fn test_direct_content_is_written_into_content_buffer() {
+        // No path_open import: succeeds only if the host wrote "direct" at 1024
+        let wat = r#"
+            (module
+                (memory (export "memory") 1)
+                (global $len (mut i32) (i32.const 0))
+                (func (export "content_buffer") (param $len i32) (result i32)
+                    (global.set $len (local.get $len))
+                    (select (i32.const 1024) (i32.const 0) (i32.le_u (local.get $len) (i32.const 4096))))
+                (func (export "process") (result i32)
+                    (if (i32.ne (global.get $len) (i32.const 6)) (then (return (i32.const 1))))
+                    (if (i32.ne (i32.load8_u (i32.const 1024)) (i32.const 100)) (then (return (i32.const 2))))
+                    (if (i32.ne (i32.load8_u (i32.const 1029)) (i32.const 116)) (then (return (i32.const 3))))
+                    (i32.const 0)))
+        "#;
+        let mut instance = instance_from_wat(wat);
+        instance.manifest.wants_direct_content = true;
+        instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"direct".to_vec())).unwrap();
+        let output = instance.process_content_for_test(SharedBuffer::from_vec(b"direct".to_vec()), DEFAULT_MAX_HEX_BYTES);
+        assert!(output.success, "{:?}", output.error);
+
+        // Without the manifest flag the buffer is never filled
+        let mut instance = instance_from_wat(wat);
+        assert!(instance.process_content(uuid::Uuid::new_v4(), SharedBuffer::from_vec(b"direct".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_binary_metadata_file() {
+        use wadup_guest::{BinaryMetadataWriter, DataType, Value};
+
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.define_table("lines", vec![("n", DataType::Int64), ("text", DataType::String)]).unwrap();
+        writer.insert("lines", &[Value::Int64(1), Value::String("one".to_string())]).unwrap();
+        writer.insert("lines", &[Value::Int64(2), Value::String("two".to_string())]).unwrap();
+        // Small values keep the encoding valid UTF-8, as file_writing_guest requires
+        let binary = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        for close in [true, false] {
+            let mut instance = instance_from_wat(&file_writing_guest(
+                &[("/metadata/output_0.bin", &binary)],
+                close,
+                "(i32.const 0)",
+            ));
+            let output = instance.process_content_for_test(SharedBuffer::from_vec(Vec::new()), DEFAULT_MAX_HEX_BYTES);
+            assert!(output.success, "{:?}", output.error);
+
+            let metadata = output.metadata.expect("metadata should be collected");
+            assert_eq!(metadata["tables"][0]["name"], "lines");
+            let rows = metadata["rows"].as_array().unwrap();
+            assert_eq!(rows.len(), 2, "close={}", close);
+            assert_eq!(rows[1]["values"][1]["String"], "two");
+        }
+    }
+
+    #[test]
+    fn test_binary_metadata_matches_json_for_many_rows() -> Result<()> {
+        use crate::wasi_impl::{MetadataContent, MetadataFormat};
+        use wadup_guest::{BinaryMetadataWriter, DataType, Value};
+
+        const ROWS: i64 = 100_000;
+        let columns = vec![("n", DataType::Int64), ("ratio", DataType::Float64), ("name", DataType::String)];
+        let row = |i: i64| vec![
+            Value::Int64(i),
+            // Quarters survive serde_json's float parsing exactly; the binary format is always exact
+            Value::Float64(i as f64 / 4.0),
+            Value::String(format!("row-{}", i)),
+        ];
+
+        // The binary file comes from the guest library's writer, the JSON from
+        // the same values in the guest's JSON encoding
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).map_err(anyhow::Error::msg)?;
+        writer.define_table("rows", columns.clone()).map_err(anyhow::Error::msg)?;
+        let mut json_rows = Vec::new();
+        for i in 0..ROWS {
+            writer.insert("rows", &row(i)).map_err(anyhow::Error::msg)?;
+            json_rows.push(serde_json::json!({"table_name": "rows", "values": row(i)}));
+        }
+        let json_columns: Vec<_> = columns.into_iter().map(|(n, t)| wadup_guest::Column::new(n, t)).collect();
+        let json = serde_json::json!({
+            "tables": [{"name": "rows", "columns": json_columns}],
+            "rows": json_rows,
+        });
+
+        let decode = |format: MetadataFormat, data: Vec<u8>| -> Result<StoreData> {
+            let mut store_data = empty_store_data();
+            ModuleInstance::process_metadata_file(&MetadataContent { path: "/metadata/rows".to_string(), format, data: data.into() }, &mut store_data)?;
+            Ok(store_data)
+        };
+        let from_binary = decode(MetadataFormat::Binary, writer.into_inner().map_err(anyhow::Error::msg)?)?;
+        let from_json = decode(MetadataFormat::Json, serde_json::to_vec(&json)?)?;
+
+        let (binary, json) = (&from_binary.processing_ctx, &from_json.processing_ctx);
+        assert_eq!(binary.metadata.len(), ROWS as usize);
+        assert_eq!(binary.table_schemas[0].columns, json.table_schemas[0].columns);
+        for (b, j) in binary.metadata.iter().zip(&json.metadata) {
+            assert_eq!(b.table_name, j.table_name);
+            assert_eq!(serde_json::to_value(&b.values)?, serde_json::to_value(&j.values)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_json_metadata_is_parsed_in_place() -> Result<()> {
+        use crate::wasi_impl::{MetadataContent, MetadataFormat};
+
+        const ROWS: usize = 2_000;
+        let value = "x".repeat(1_000);
+        let rows: Vec<String> = (0..ROWS)
+            .map(|_| format!(r#"{{"table_name":"big","values":[{{"String":"{}"}}]}}"#, value))
+            .collect();
+        let json = format!(
+            r#"{{"tables":[{{"name":"big","columns":[{{"name":"s","data_type":"String"}}]}}],"rows":[{}]}}"#,
+            rows.join(",")
+        );
+        let content = MetadataContent { path: "/metadata/output_0.json".to_string(), format: MetadataFormat::Json, data: json.into() };
+
+        let mut store_data = empty_store_data();
+        let (result, allocated) = crate::test_alloc::measure(|| ModuleInstance::process_metadata_file(&content, &mut store_data));
+        result?;
+        assert_eq!(store_data.processing_ctx.metadata.len(), ROWS);
+
+        // Beyond the rows it keeps, parsing holds no copy of the file or of
+        // the rows, each of which would take about as much as the file
+        let transient = allocated.peak - allocated.retained;
+        assert!(transient < content.data.len() / 4, "{} bytes transient for a {} byte file", transient, content.data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_jsonl_metadata_stream() {
+        let stream = concat!(
+            r#"{"tables":[{"name":"lines","columns":[{"name":"n","data_type":"Int64"}]}],"rows":[]}"#, "\n",
+            r#"{"rows":[{"table_name":"lines","values":[{"Int64":1}]}]}"#, "\n",
+            "\n",
+            r#"{"rows":[{"table_name":"lines","values":[{"Int64":2}]}]}"#, "\n",
+        );
+        let single = r#"{"rows":[{"table_name":"lines","values":[{"Int64":3}]}]}"#;
+
+        for close in [true, false] {
+            let mut instance = instance_from_wat(&file_writing_guest(
+                &[("/metadata/stream.jsonl", stream), ("/metadata/output_0.json", single)],
+                close,
+                "(i32.const 0)",
+            ));
+            let output = instance.process_content_for_test(SharedBuffer::from_vec(Vec::new()), DEFAULT_MAX_HEX_BYTES);
+            assert!(output.success, "{:?}", output.error);
+
+            let metadata = output.metadata.expect("metadata should be collected");
+            assert_eq!(metadata["tables"][0]["name"], "lines");
+            let mut values: Vec<i64> = metadata["rows"].as_array().unwrap().iter()
+                .map(|row| row["values"][0]["Int64"].as_i64().unwrap())
+                .collect();
+            values.sort();
+            assert_eq!(values, vec![1, 2, 3], "close={}", close);
+        }
+    }
+
+    #[test]
+    fn test_unclosed_metadata_files_merge_in_numeric_order() {
+        let files: Vec<(String, String)> = (0..12)
+            .map(|i| (
+                format!("/metadata/output_{}.json", i),
+                format!(r#"{{"tables":[{{"name":"seq","columns":[{{"name":"i","data_type":"Int64"}}]}}],"rows":[{{"table_name":"seq","values":[{{"Int64":{}}}]}}]}}"#, i),
+            ))
+            .collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+        let mut instance = instance_from_wat(&file_writing_guest(&files, false, "(i32.const 0)"));
+        let output = instance.process_content_for_test(SharedBuffer::from_vec(Vec::new()), DEFAULT_MAX_HEX_BYTES);
+        assert!(output.success, "{:?}", output.error);
+
+        let metadata = output.metadata.expect("metadata should be collected");
+        let values: Vec<i64> = metadata["rows"].as_array().unwrap().iter()
+            .map(|row| row["values"][0]["Int64"].as_i64().unwrap())
+            .collect();
+        assert_eq!(values, (0..12).collect::<Vec<_>>());
+        assert!(metadata_file_order("output_2.json") < metadata_file_order("output_10.json"));
+    }
+}
@@ -0,0 +1,171 @@
+//! One schema for the entries of any archive format.
+//!
+//! Archive parsers (zip, tar, 7z, ...) implement [`ArchiveEntry`] for their
+//! entry type and hand each entry to [`ArchiveEntries::emit`], which streams
+//! it out as sub-content and records it in the shared
+//! `__wadup_archive_entries` table, so entries from every format can be
+//! queried together.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::io::Read;
+//! use wadup_guest::archive::{ArchiveEntries, ArchiveEntry};
+//!
+//! struct Entry<'a> { name: String, data: &'a [u8] }
+//!
+//! impl Read for Entry<'_> {
+//!     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.data.read(buf) }
+//! }
+//!
+//! impl ArchiveEntry for Entry<'_> {
+//!     fn name(&self) -> String { self.name.clone() }
+//!     fn size(&self) -> u64 { self.data.len() as u64 }
+//! }
+//!
+//! let entries = ArchiveEntries::define().unwrap();
+//! entries.emit(&mut Entry { name: "a.txt".to_string(), data: b"hello" }).unwrap();
+//! ```
+
+use crate::subcontent::SubContent;
+use crate::table::{Table, TableBuilder};
+use crate::types::{DataType, Value};
+use std::io::Read;
+
+/// Name of the table [`ArchiveEntries`] records entries in.
+pub const ARCHIVE_ENTRIES_TABLE: &str = "__wadup_archive_entries";
+
+/// An archive entry, read for its uncompressed bytes.
+pub trait ArchiveEntry: Read {
+    /// Path of the entry inside the archive, used as the sub-content filename
+    fn name(&self) -> String;
+
+    /// Uncompressed size in bytes
+    fn size(&self) -> u64;
+
+    /// Modification time in Unix seconds, if the archive records one
+    fn mtime(&self) -> Option<i64> {
+        None
+    }
+
+    /// Whether the entry is stored compressed in the archive
+    fn compressed(&self) -> bool {
+        false
+    }
+}
+
+/// Schema of [`ARCHIVE_ENTRIES_TABLE`]. Entries without a modification time
+/// record `-1` in `mtime`.
+pub fn entries_table() -> TableBuilder {
+    TableBuilder::new(ARCHIVE_ENTRIES_TABLE)
+        .column("name", DataType::String)
+        .description("path inside the archive")
+        .column("size", DataType::Int64)
+        .description("uncompressed size in bytes")
+        .column("mtime", DataType::Int64)
+        .description("modification time in Unix seconds, or -1 if unknown")
+        .column("compressed", DataType::Boolean)
+}
+
+/// Records archive entries in [`ARCHIVE_ENTRIES_TABLE`].
+pub struct ArchiveEntries {
+    table: Table,
+}
+
+impl ArchiveEntries {
+    /// Define [`ARCHIVE_ENTRIES_TABLE`] for the current content.
+    pub fn define() -> Result<Self, String> {
+        Ok(Self { table: entries_table().build()? })
+    }
+
+    /// Record `entry` without emitting it, e.g. for entries the module skips.
+    pub fn record(&self, entry: &impl ArchiveEntry) -> Result<(), String> {
+        let size = i64::try_from(entry.size()).unwrap_or(i64::MAX);
+        self.table.insert(&[
+            Value::String(entry.name()),
+            Value::Int64(size),
+            Value::Int64(entry.mtime().unwrap_or(-1)),
+            Value::Boolean(entry.compressed()),
+        ])
+    }
+
+    /// Stream `entry` into sub-content named after it and record it.
+    /// Returns the number of bytes emitted.
+    pub fn emit(&self, entry: &mut impl ArchiveEntry) -> Result<u64, String> {
+        let name = entry.name();
+        let written = SubContent::emit_from_reader(entry, &name)?;
+        self.record(entry)?;
+        Ok(written)
+    }
+}
+
+/// Unix seconds for a UTC calendar date and time, for formats that store
+/// broken-down timestamps (e.g. zip's DOS times).
+pub fn unix_time(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> i64 {
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    days * 86_400 + i64::from(hour) * 3_600 + i64::from(minute) * 60 + i64::from(second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Entry {
+        data: &'static [u8],
+        mtime: Option<i64>,
+    }
+
+    impl Read for Entry {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl ArchiveEntry for Entry {
+        fn name(&self) -> String {
+            "dir/a.txt".to_string()
+        }
+
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn mtime(&self) -> Option<i64> {
+            self.mtime
+        }
+
+        fn compressed(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_entries_share_one_table() {
+        let entries = ArchiveEntries::define().unwrap();
+        entries.record(&Entry { data: b"hello", mtime: Some(1_700_000_000) }).unwrap();
+        entries.record(&Entry { data: b"", mtime: None }).unwrap();
+
+        let (tables, rows) = crate::metadata::take_pending();
+        assert_eq!(tables, [ARCHIVE_ENTRIES_TABLE]);
+        assert_eq!(rows, [
+            serde_json::json!({"table_name": ARCHIVE_ENTRIES_TABLE, "values": [
+                {"String": "dir/a.txt"}, {"Int64": 5}, {"Int64": 1_700_000_000}, {"Boolean": true}]}),
+            serde_json::json!({"table_name": ARCHIVE_ENTRIES_TABLE, "values": [
+                {"String": "dir/a.txt"}, {"Int64": 0}, {"Int64": -1}, {"Boolean": true}]}),
+        ]);
+    }
+
+    #[test]
+    fn test_unix_time() {
+        assert_eq!(unix_time(1970, 1, 1, 0, 0, 0), 0);
+        assert_eq!(unix_time(2000, 2, 29, 12, 30, 15), 951_827_415);
+        assert_eq!(unix_time(1980, 1, 1, 0, 0, 0), 315_532_800);
+    }
+}
@@ -0,0 +1,69 @@
+//! Decompression done by the host, for guests that can't afford to bundle
+//! a zlib implementation.
+
+/// Decompresses a zlib or gzip stream with the host's `inflate` import.
+///
+/// # Example
+///
+/// ```no_run
+/// use wadup_guest::inflate;
+///
+/// let compressed = std::fs::read(wadup_guest::Content::path()).unwrap();
+/// let plain = inflate(&compressed).unwrap();
+/// ```
+pub fn inflate(compressed: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = vec![0u8; compressed.len().saturating_mul(4).clamp(1024, i32::MAX as usize)];
+    let mut filled = 0;
+    let mut status = host::inflate(compressed, &mut out)?;
+    loop {
+        match status {
+            -1 => return Err("Failed to inflate: not a valid zlib or gzip stream".to_string()),
+            -3 => return Err("Failed to inflate: output is larger than 2 GiB".to_string()),
+            // The buffer is full: grow it and let the host carry on where it stopped
+            -4 => {
+                filled = out.len();
+                out.resize(filled.saturating_mul(2).min(i32::MAX as usize), 0);
+                status = host::inflate_more(&mut out[filled..])?;
+            }
+            len => {
+                let len = usize::try_from(len).map_err(|_| format!("Failed to inflate: error {}", len))?;
+                out.truncate(filled + len);
+                return Ok(out);
+            }
+        }
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "inflate"]
+        fn wadup_inflate(src_ptr: *const u8, src_len: i32, out_ptr: *mut u8, out_cap: i32) -> i32;
+        #[link_name = "inflate_more"]
+        fn wadup_inflate_more(out_ptr: *mut u8, out_cap: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn inflate(src: &[u8], out: &mut [u8]) -> Result<i32, String> {
+        // SAFETY: the host reads `src` and writes at most `out.len()` bytes into `out`
+        Ok(unsafe { wadup_inflate(src.as_ptr(), src.len() as i32, out.as_mut_ptr(), out.len() as i32) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn inflate_more(out: &mut [u8]) -> Result<i32, String> {
+        // SAFETY: the host writes at most `out.len()` bytes into `out`
+        Ok(unsafe { wadup_inflate_more(out.as_mut_ptr(), out.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn inflate(_src: &[u8], _out: &mut [u8]) -> Result<i32, String> {
+        Err("Host decompression is only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn inflate_more(_out: &mut [u8]) -> Result<i32, String> {
+        Err("Host decompression is only available inside WADUP".to_string())
+    }
+}
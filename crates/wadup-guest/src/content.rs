@@ -1,3 +1,7 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
 /// Helper for accessing content data in WADUP modules.
 ///
 /// Content is exposed as a file at `/data.bin` in the WASM module's virtual filesystem.
@@ -24,4 +28,499 @@ impl Content {
     pub fn path() -> &'static str {
         "/data.bin"
     }
+
+    /// Opens the content as a buffered `Read + Seek` stream.
+    ///
+    /// Data is read from `/data.bin` in chunks as it is consumed, so parsers
+    /// such as `zip` can seek around the content without loading all of it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::io::{Read, Seek, SeekFrom};
+    /// use wadup_guest::Content;
+    ///
+    /// let mut reader = Content::reader().unwrap();
+    /// reader.seek(SeekFrom::End(-22)).unwrap();
+    /// let mut trailer = [0u8; 22];
+    /// reader.read_exact(&mut trailer).unwrap();
+    /// ```
+    pub fn reader() -> io::Result<ContentReader> {
+        ContentReader::open(Self::path())
+    }
+
+    /// Names of the streams mounted under `/streams`, sorted.
+    ///
+    /// Streams are extra read-only files an earlier module published for the
+    /// same content with [`Content::publish_stream`], e.g. the attachments of
+    /// an email or the sections of a PE file.
+    pub fn streams() -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(STREAMS_DIR)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Opens a stream mounted under `/streams` as a buffered `Read + Seek` stream.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::io::Read;
+    /// use wadup_guest::Content;
+    ///
+    /// for name in Content::streams().unwrap() {
+    ///     let mut data = Vec::new();
+    ///     Content::open_stream(&name).unwrap().read_to_end(&mut data).unwrap();
+    /// }
+    /// ```
+    pub fn open_stream(name: &str) -> io::Result<ContentReader> {
+        ContentReader::open(Path::new(STREAMS_DIR).join(name))
+    }
+
+    /// Publishes `data` as stream `name` for the modules that run after this
+    /// one on the same content (modules run in file name order).
+    pub fn publish_stream(name: &str, data: &[u8]) -> io::Result<()> {
+        std::fs::write(Path::new(STREAMS_DIR).join(name), data)
+    }
+
+    /// Reads up to the first `len` bytes of the content through a fresh file
+    /// descriptor, so readers already open on the content keep their position.
+    ///
+    /// Returns fewer bytes when the content is shorter than `len`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use wadup_guest::Content;
+    ///
+    /// if Content::peek(4).unwrap() == b"PK\x03\x04" {
+    ///     let reader = Content::reader().unwrap();
+    ///     // ... parse the ZIP from offset 0
+    /// }
+    /// ```
+    pub fn peek(len: usize) -> io::Result<Vec<u8>> {
+        peek_file(Self::path(), len)
+    }
+
+    /// Copies the content starting at `offset` into `buf` and returns the
+    /// number of bytes copied, which is less than `buf.len()` only at the
+    /// end of the content (0 at or past it).
+    ///
+    /// This is the zero-extra-copy way to parse content: the host copies
+    /// each window straight out of its shared buffer with `read_content`,
+    /// so a parser that reuses one `buf` never holds more than one window,
+    /// where reading `/data.bin` into a `Vec` copies all of it. Outside
+    /// WADUP it reads `/data.bin`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use wadup_guest::Content;
+    ///
+    /// let mut window = [0u8; 4096];
+    /// let mut offset = 0;
+    /// loop {
+    ///     let n = Content::read_into(&mut window, offset).unwrap();
+    ///     if n == 0 {
+    ///         break;
+    ///     }
+    ///     // ... parse window[..n]
+    ///     offset += n as u64;
+    /// }
+    /// ```
+    pub fn read_into(buf: &mut [u8], offset: u64) -> Result<usize, String> {
+        host::read_content(offset, buf)
+    }
+
+    /// Hashes `length` bytes of the content starting at `offset` and returns
+    /// the lowercase hex digest.
+    ///
+    /// The digest is computed by the host, which is much faster than hashing
+    /// in the guest (especially for Python modules) on large content.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use wadup_guest::{Content, HashAlgorithm};
+    ///
+    /// let len = std::fs::metadata(Content::path()).unwrap().len();
+    /// let sha256 = Content::hash_range(HashAlgorithm::Sha256, 0, len).unwrap();
+    /// ```
+    pub fn hash_range(algorithm: HashAlgorithm, offset: u64, length: u64) -> Result<String, String> {
+        let mut digest = [0u8; 64];
+        let digest_len = algorithm.digest_len();
+        let errno = host::hash_content_range(algorithm as i32, offset as i64, length as i64, &mut digest)?;
+        if errno != 0 {
+            return Err(format!(
+                "Failed to hash content range {}..{} with {:?}: errno {}",
+                offset,
+                offset.saturating_add(length),
+                algorithm,
+                errno
+            ));
+        }
+        Ok(digest[..digest_len].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// UUID of the content this one was emitted from, or `None` for root content.
+    pub fn parent_uuid() -> Result<Option<[u8; 16]>, String> {
+        let mut uuid = [0u8; 16];
+        match host::parent_uuid(&mut uuid)? {
+            0 => Ok(Some(uuid)),
+            ENOENT => Ok(None),
+            errno => Err(format!("Failed to read parent UUID: errno {}", errno)),
+        }
+    }
+
+    /// Filename of the content this one was emitted from (e.g. the archive a
+    /// member came out of), or `None` for root content.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use wadup_guest::Content;
+    ///
+    /// if let Some(parent) = Content::parent_filename().unwrap() {
+    ///     println!("extracted from {}", parent);
+    /// }
+    /// ```
+    pub fn parent_filename() -> Result<Option<String>, String> {
+        let mut buf = vec![0u8; 256];
+        loop {
+            let len = host::parent_filename(&mut buf)?;
+            if len == -1 {
+                return Ok(None);
+            }
+            let len = usize::try_from(len).map_err(|_| "Failed to read parent filename".to_string())?;
+            if len <= buf.len() {
+                buf.truncate(len);
+                return String::from_utf8(buf).map(Some).map_err(|e| format!("Parent filename is not UTF-8: {}", e));
+            }
+            buf.resize(len, 0);
+        }
+    }
+}
+
+fn peek_file<P: AsRef<Path>>(path: P, len: usize) -> io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    // `len` can be anything the caller likes; only reserve what the file has
+    let size = usize::try_from(file.metadata()?.len()).unwrap_or(usize::MAX);
+    let mut head = Vec::with_capacity(len.min(size));
+    file.take(len as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Fill as much of `buf` as the file has from `offset` on
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+fn read_file_at<P: AsRef<Path>>(path: P, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Directory holding the streams of the current content
+const STREAMS_DIR: &str = "/streams";
+
+/// `parent_uuid` result for root content
+const ENOENT: i32 = 44;
+
+/// Digest algorithms available through [`Content::hash_range`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256 = 0,
+    Sha512 = 1,
+}
+
+impl HashAlgorithm {
+    fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "read_content"]
+        fn wadup_read_content(offset: i64, buf_ptr: *mut u8, buf_len: i32, nread_ptr: *mut u32) -> i32;
+        #[link_name = "hash_content_range"]
+        fn wadup_hash_content_range(algo: i32, offset: i64, length: i64, out_ptr: *mut u8, out_len: i32) -> i32;
+        #[link_name = "parent_uuid"]
+        fn wadup_parent_uuid(out_ptr: *mut u8) -> i32;
+        #[link_name = "parent_filename"]
+        fn wadup_parent_filename(out_ptr: *mut u8, cap: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn read_content(offset: u64, buf: &mut [u8]) -> Result<usize, String> {
+        // Offsets past i64::MAX are past the end of any content
+        let Ok(offset) = i64::try_from(offset) else {
+            return Ok(0);
+        };
+        let len = buf.len().min(i32::MAX as usize) as i32;
+        let mut nread = 0u32;
+        // SAFETY: the host writes at most `len` bytes into `buf` and the count into `nread`
+        let errno = unsafe { wadup_read_content(offset, buf.as_mut_ptr(), len, &mut nread) };
+        if errno != 0 {
+            return Err(format!("Failed to read content at offset {}: errno {}", offset, errno));
+        }
+        Ok(nread as usize)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn hash_content_range(algo: i32, offset: i64, length: i64, out: &mut [u8]) -> Result<i32, String> {
+        // SAFETY: the host writes at most `out.len()` bytes into `out`
+        Ok(unsafe { wadup_hash_content_range(algo, offset, length, out.as_mut_ptr(), out.len() as i32) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn parent_uuid(out: &mut [u8; 16]) -> Result<i32, String> {
+        // SAFETY: the host writes exactly 16 bytes into `out`
+        Ok(unsafe { wadup_parent_uuid(out.as_mut_ptr()) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn parent_filename(out: &mut [u8]) -> Result<i32, String> {
+        // SAFETY: the host writes at most `out.len()` bytes into `out`
+        Ok(unsafe { wadup_parent_filename(out.as_mut_ptr(), out.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn parent_uuid(_out: &mut [u8; 16]) -> Result<i32, String> {
+        Err("Parent content is only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn parent_filename(_out: &mut [u8]) -> Result<i32, String> {
+        Err("Parent content is only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read_content(offset: u64, buf: &mut [u8]) -> Result<usize, String> {
+        super::read_file_at(super::Content::path(), buf, offset).map_err(|e| format!("Failed to read content: {}", e))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn hash_content_range(_algo: i32, _offset: i64, _length: i64, _out: &mut [u8]) -> Result<i32, String> {
+        Err("Host hashing is only available inside WADUP".to_string())
+    }
+}
+
+/// Buffered, seekable reader over a content file.
+///
+/// Only one chunk of the file is held in memory at a time. Seeks that land
+/// inside the current chunk reuse it; other seeks discard it.
+pub struct ContentReader {
+    file: File,
+    len: u64,
+    buf: Vec<u8>,
+    /// File offset of `buf[0]`
+    buf_start: u64,
+    /// Current logical position in the file
+    pos: u64,
+}
+
+impl ContentReader {
+    /// Default chunk size used by [`ContentReader::open`].
+    pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Opens `path` with the default chunk size.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_chunk_size(path, Self::DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Opens `path`, reading from it `chunk_size` bytes at a time.
+    pub fn with_chunk_size<P: AsRef<Path>>(path: P, chunk_size: usize) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            len,
+            buf: Vec::with_capacity(chunk_size.max(1)),
+            buf_start: 0,
+            pos: 0,
+        })
+    }
+
+    /// Total length of the content in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn buffered(&self) -> Option<&[u8]> {
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if self.pos >= self.buf_start && self.pos < buf_end {
+            Some(&self.buf[(self.pos - self.buf_start) as usize..])
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let capacity = self.buf.capacity();
+        self.buf.clear();
+        self.buf_start = self.pos;
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        (&mut self.file).take(capacity as u64).read_to_end(&mut self.buf)?;
+        Ok(())
+    }
+}
+
+impl Read for ContentReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        // Large reads bypass the buffer entirely
+        if self.buffered().is_none() && out.len() >= self.buf.capacity() {
+            self.file.seek(SeekFrom::Start(self.pos))?;
+            let n = self.file.read(out)?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        if self.buffered().is_none() {
+            self.fill()?;
+        }
+
+        let available = self.buffered().unwrap_or(&[]);
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ContentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_content(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("wadup-guest-{}-{}", name, std::process::id()));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_seek_from_end_and_read_back() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let path = temp_content("seek-end", &data);
+        let mut reader = ContentReader::with_chunk_size(&path, 16).unwrap();
+
+        assert_eq!(reader.len(), 1000);
+
+        // Read the trailing 22 bytes, like a ZIP end-of-central-directory lookup
+        assert_eq!(reader.seek(SeekFrom::End(-22)).unwrap(), 978);
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &data[978..]);
+
+        // Jump back into the middle and read across several chunks
+        reader.seek(SeekFrom::End(-900)).unwrap();
+        let mut middle = [0u8; 50];
+        reader.read_exact(&mut middle).unwrap();
+        assert_eq!(&middle[..], &data[100..150]);
+
+        reader.seek(SeekFrom::Current(-10)).unwrap();
+        let mut again = [0u8; 10];
+        reader.read_exact(&mut again).unwrap();
+        assert_eq!(&again[..], &data[140..150]);
+
+        // Seeking past EOF is allowed; reads there return nothing
+        reader.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(reader.read(&mut again).unwrap(), 0);
+
+        assert!(reader.seek(SeekFrom::End(-1001)).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_peek_leaves_reader_at_start() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let path = temp_content("peek", &data);
+
+        let mut reader = ContentReader::with_chunk_size(&path, 8).unwrap();
+        assert_eq!(peek_file(&path, 16).unwrap(), &data[..16]);
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+
+        assert_eq!(peek_file(&path, 1000).unwrap(), data, "short content");
+        assert_eq!(peek_file(&path, usize::MAX).unwrap(), data, "length past any allocation");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_records_parse_through_a_reused_window() {
+        // Records of a 1-byte length and that many payload bytes
+        let mut data = Vec::new();
+        for len in [3u8, 0, 7, 5] {
+            data.push(len);
+            data.extend((0..len).map(|i| len * 10 + i));
+        }
+        let path = temp_content("windows", &data);
+
+        // Every read goes through one 8-byte window, never a copy of the file
+        let mut window = [0u8; 8];
+        let (mut offset, mut records) = (0u64, Vec::new());
+        while read_file_at(&path, &mut window[..1], offset).unwrap() == 1 {
+            let len = usize::from(window[0]);
+            assert_eq!(read_file_at(&path, &mut window[..len], offset + 1).unwrap(), len);
+            records.push(window[..len].to_vec());
+            offset += 1 + len as u64;
+        }
+        assert_eq!(records, [vec![30, 31, 32], vec![], vec![70, 71, 72, 73, 74, 75, 76], vec![50, 51, 52, 53, 54]]);
+        assert_eq!(read_file_at(&path, &mut window, offset + 100).unwrap(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
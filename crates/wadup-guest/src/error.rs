@@ -0,0 +1,89 @@
+//! Structured failures: an error document with a module-defined code instead
+//! of only a nonzero return from `process`.
+
+/// Report a failure with `code` and `message` to WADUP, which stores it as an
+/// error document of kind `"guest_error"`. Returns `message`, to return as
+/// the error that makes `process` fail.
+///
+/// # Example
+///
+/// ```no_run
+/// fn check(data: &[u8]) -> Result<(), String> {
+///     if !data.starts_with(b"PK") {
+///         return Err(wadup_guest::fail(1, "not a zip file"));
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn fail(code: u32, message: &str) -> String {
+    // Outside WADUP there is nowhere to report to; the message still propagates
+    let _ = host::report_error(code, message);
+    message.to_string()
+}
+
+/// Report a failure with [`fail`] and return it as an `Err` from the
+/// enclosing function.
+///
+/// ```no_run
+/// fn parse(version: u8) -> Result<(), String> {
+///     wadup_guest::fail!(2, "unsupported version {}", version);
+/// }
+/// ```
+#[macro_export]
+macro_rules! fail {
+    ($code:expr, $($arg:tt)+) => {
+        return ::core::result::Result::Err($crate::fail($code, &::std::format!($($arg)+)).into())
+    };
+}
+
+/// Fail with `code` unless `cond` holds, like [`fail!`].
+///
+/// ```no_run
+/// fn parse(header: &[u8]) -> Result<(), String> {
+///     wadup_guest::ensure!(header.len() >= 4, 3, "header is {} bytes, expected 4", header.len());
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $code:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::fail!($code, $($arg)+);
+        }
+    };
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "report_error"]
+        fn wadup_report_error(code: i32, msg_ptr: *const u8, msg_len: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn report_error(code: u32, message: &str) -> Result<i32, String> {
+        // SAFETY: the host only reads `message.len()` bytes from `message`
+        Ok(unsafe { wadup_report_error(code as i32, message.as_ptr(), message.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn report_error(_code: u32, _message: &str) -> Result<i32, String> {
+        Err("Error reporting is only available inside WADUP".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn check(len: usize) -> Result<usize, String> {
+        crate::ensure!(len >= 4, 3, "header is {} bytes", len);
+        Ok(len)
+    }
+
+    #[test]
+    fn test_ensure_returns_the_message() {
+        assert_eq!(check(8), Ok(8));
+        assert_eq!(check(2), Err("header is 2 bytes".to_string()));
+    }
+}
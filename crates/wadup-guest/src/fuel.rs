@@ -0,0 +1,42 @@
+//! Fuel top-ups for modules that make several passes over a content.
+
+/// Asks WADUP for `amount` more fuel for the current content and returns the
+/// fuel now remaining. Fails when fuel isn't limited, the run has no
+/// `--fuel-ceiling`, or the content would pass it.
+///
+/// # Example
+///
+/// ```no_run
+/// // Before a second pass over the content
+/// if wadup_guest::add_fuel(10_000_000).is_err() {
+///     // Stop after the first pass instead of running out of fuel
+/// }
+/// ```
+pub fn add_fuel(amount: u64) -> Result<u64, String> {
+    let amount = i64::try_from(amount).map_err(|_| format!("Fuel amount {} is too large", amount))?;
+    match host::add_fuel(amount)? {
+        -1 => Err("Fuel top-up refused: fuel is unlimited, --fuel-ceiling isn't set, or it would be passed".to_string()),
+        total => Ok(total as u64),
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "add_fuel"]
+        fn wadup_add_fuel(amount: i64) -> i64;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn add_fuel(amount: i64) -> Result<i64, String> {
+        // SAFETY: takes and returns plain integers
+        Ok(unsafe { wadup_add_fuel(amount) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn add_fuel(_amount: i64) -> Result<i64, String> {
+        Err("Fuel top-ups are only available inside WADUP".to_string())
+    }
+}
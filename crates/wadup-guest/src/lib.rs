@@ -1,10 +1,36 @@
 pub mod metadata;
+pub mod archive;
+pub use wadup_abi::binary_format;
 pub mod types;
 pub mod table;
 pub mod content;
+pub mod compression;
+pub mod fuel;
+pub mod progress;
+pub mod error;
+pub mod mode;
+pub mod secret;
 pub mod subcontent;
+pub mod version;
 
 pub use types::*;
 pub use table::*;
 pub use content::*;
+pub use compression::inflate;
+pub use fuel::add_fuel;
+pub use progress::heartbeat;
+pub use error::fail;
+pub use mode::is_test_mode;
+pub use secret::secret;
 pub use subcontent::*;
+pub use metadata::{BinaryMetadataWriter, MetadataWriter};
+
+/// Called by WADUP before each content is processed. Restarts the file
+/// numbering of `/metadata/output_N.*` and `/subcontent/*_N.*`, which the
+/// host clears between contents, so indices don't grow across the contents
+/// one instance processes.
+#[no_mangle]
+pub extern "C" fn wadup_begin_content() {
+    metadata::reset_file_index();
+    subcontent::reset_counter();
+}
@@ -2,18 +2,31 @@
 //!
 //! Accumulates table definitions and rows in memory, then writes them
 //! to `/metadata/output_N.json` files that WADUP processes on close.
+//!
+//! Modules that produce metadata incrementally can instead use
+//! [`MetadataWriter`], which appends one line per table or row to
+//! `/metadata/stream.jsonl`, or [`BinaryMetadataWriter`], which packs
+//! rows into `/metadata/output_N.bin`.
 
-use crate::types::{Column, Value};
+use crate::binary_format::{
+    MAGIC, RECORD_ROW, RECORD_TABLE, VALUE_BOOLEAN, VALUE_FLOAT64, VALUE_INT64, VALUE_STRING, VALUE_TIMESTAMP,
+};
+use crate::types::{Column, DataType, Value};
 use serde::Serialize;
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+/// Path of the JSON Lines file written by [`MetadataWriter`].
+pub const STREAM_PATH: &str = "/metadata/stream.jsonl";
 
 /// Internal table definition for serialization.
 #[derive(Serialize)]
 struct TableDef {
     name: String,
     columns: Vec<Column>,
+    #[serde(skip_serializing_if = "is_false")]
+    global: bool,
 }
 
 /// Internal row definition for serialization.
@@ -21,32 +34,74 @@ struct TableDef {
 struct RowDef {
     table_name: String,
     values: Vec<Value>,
+    #[serde(skip_serializing_if = "is_false")]
+    global: bool,
+}
+
+/// Internal column addition for serialization.
+#[derive(Serialize)]
+struct ColumnDef {
+    table_name: String,
+    column: Column,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 /// Metadata file structure matching WADUP's expected format.
 #[derive(Serialize)]
 struct MetadataFile {
     tables: Vec<TableDef>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    columns: Vec<ColumnDef>,
     rows: Vec<RowDef>,
 }
 
 thread_local! {
-    static TABLES: RefCell<Vec<TableDef>> = RefCell::new(Vec::new());
-    static ROWS: RefCell<Vec<RowDef>> = RefCell::new(Vec::new());
-    static FILE_COUNTER: RefCell<usize> = RefCell::new(0);
+    static TABLES: RefCell<Vec<TableDef>> = const { RefCell::new(Vec::new()) };
+    static COLUMNS: RefCell<Vec<ColumnDef>> = const { RefCell::new(Vec::new()) };
+    static ROWS: RefCell<Vec<RowDef>> = const { RefCell::new(Vec::new()) };
+    static FILE_COUNTER: RefCell<usize> = const { RefCell::new(0) };
 }
 
 /// Add a table definition to the accumulated metadata.
 pub fn add_table(name: String, columns: Vec<Column>) {
+    push_table(name, columns, false);
+}
+
+/// Add a table definition that also accepts rows from [`add_global_row`].
+pub fn add_global_table(name: String, columns: Vec<Column>) {
+    push_table(name, columns, true);
+}
+
+fn push_table(name: String, columns: Vec<Column>, global: bool) {
     TABLES.with(|tables| {
-        tables.borrow_mut().push(TableDef { name, columns });
+        tables.borrow_mut().push(TableDef { name, columns, global });
+    });
+}
+
+/// Add a column to a table already defined, to the accumulated metadata.
+pub fn add_column(table_name: String, column: Column) {
+    COLUMNS.with(|columns| {
+        columns.borrow_mut().push(ColumnDef { table_name, column });
     });
 }
 
 /// Add a row to the accumulated metadata.
 pub fn add_row(table_name: String, values: Vec<Value>) {
+    push_row(table_name, values, false);
+}
+
+/// Add a row that isn't tied to the content being processed. WADUP stores
+/// it with a null `content_uuid`; the table must be declared global.
+pub fn add_global_row(table_name: String, values: Vec<Value>) {
+    push_row(table_name, values, true);
+}
+
+fn push_row(table_name: String, values: Vec<Value>, global: bool) {
     ROWS.with(|rows| {
-        rows.borrow_mut().push(RowDef { table_name, values });
+        rows.borrow_mut().push(RowDef { table_name, values, global });
     });
 }
 
@@ -57,28 +112,18 @@ pub fn add_row(table_name: String, values: Vec<Value>) {
 ///
 /// Returns `Ok(())` if successful or if there's nothing to flush.
 pub fn flush() -> Result<(), String> {
-    let (tables, rows) = TABLES.with(|t| {
-        ROWS.with(|r| {
-            let tables = std::mem::take(&mut *t.borrow_mut());
-            let rows = std::mem::take(&mut *r.borrow_mut());
-            (tables, rows)
-        })
-    });
+    let tables = TABLES.with(|t| std::mem::take(&mut *t.borrow_mut()));
+    let columns = COLUMNS.with(|c| std::mem::take(&mut *c.borrow_mut()));
+    let rows = ROWS.with(|r| std::mem::take(&mut *r.borrow_mut()));
 
     // Nothing to flush
-    if tables.is_empty() && rows.is_empty() {
+    if tables.is_empty() && columns.is_empty() && rows.is_empty() {
         return Ok(());
     }
 
-    let counter = FILE_COUNTER.with(|c| {
-        let val = *c.borrow();
-        *c.borrow_mut() = val + 1;
-        val
-    });
+    let filename = format!("/metadata/output_{}.json", next_file_index());
 
-    let filename = format!("/metadata/output_{}.json", counter);
-
-    let metadata = MetadataFile { tables, rows };
+    let metadata = MetadataFile { tables, columns, rows };
     let json = serde_json::to_string(&metadata)
         .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
 
@@ -91,3 +136,311 @@ pub fn flush() -> Result<(), String> {
     // File is closed when dropped, triggering WADUP to process it
     Ok(())
 }
+
+/// Take the accumulated table names and rows (as JSON) without writing them.
+#[cfg(test)]
+pub(crate) fn take_pending() -> (Vec<String>, Vec<serde_json::Value>) {
+    let tables = TABLES.with(|t| std::mem::take(&mut *t.borrow_mut()));
+    let rows = ROWS.with(|r| std::mem::take(&mut *r.borrow_mut()));
+    (
+        tables.into_iter().map(|t| t.name).collect(),
+        rows.iter().map(|r| serde_json::to_value(r).unwrap()).collect(),
+    )
+}
+
+/// Restart metadata file numbering at `/metadata/output_0.*`.
+pub(crate) fn reset_file_index() {
+    FILE_COUNTER.with(|c| *c.borrow_mut() = 0);
+}
+
+/// Next N for `/metadata/output_N.*`, shared by `flush` and `BinaryMetadataWriter`.
+fn next_file_index() -> usize {
+    FILE_COUNTER.with(|c| {
+        let val = *c.borrow();
+        *c.borrow_mut() = val + 1;
+        val
+    })
+}
+
+/// Streaming metadata writer.
+///
+/// Each table definition or row is written immediately as one line of
+/// `/metadata/stream.jsonl`, so memory use does not grow with the number
+/// of rows. WADUP processes the file when the writer is dropped (or after
+/// `process()` returns if it is still open).
+///
+/// # Example
+///
+/// ```no_run
+/// use wadup_guest::{DataType, MetadataWriter, Value};
+///
+/// let mut writer = MetadataWriter::open().unwrap();
+/// writer.define_table("lines", vec![("number", DataType::Int64)]).unwrap();
+/// for i in 0..1000 {
+///     writer.insert("lines", vec![Value::Int64(i)]).unwrap();
+/// }
+/// ```
+pub struct MetadataWriter {
+    file: BufWriter<File>,
+}
+
+impl MetadataWriter {
+    /// Open `/metadata/stream.jsonl` for appending.
+    pub fn open() -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(STREAM_PATH)
+            .map_err(|e| format!("Failed to open metadata stream '{}': {}", STREAM_PATH, e))?;
+        Ok(Self { file: BufWriter::new(file) })
+    }
+
+    /// Write a table definition.
+    pub fn define_table(&mut self, name: impl Into<String>, columns: Vec<(&str, DataType)>) -> Result<(), String> {
+        let columns = columns
+            .into_iter()
+            .map(|(n, t)| Column::new(n, t))
+            .collect();
+        self.write_line(MetadataFile {
+            tables: vec![TableDef { name: name.into(), columns, global: false }],
+            columns: Vec::new(),
+            rows: Vec::new(),
+        })
+    }
+
+    /// Write a column added to a table already defined.
+    pub fn add_column(&mut self, table_name: impl Into<String>, name: &str, data_type: DataType) -> Result<(), String> {
+        self.write_line(MetadataFile {
+            tables: Vec::new(),
+            columns: vec![ColumnDef { table_name: table_name.into(), column: Column::new(name, data_type) }],
+            rows: Vec::new(),
+        })
+    }
+
+    /// Write a single row.
+    pub fn insert(&mut self, table_name: impl Into<String>, values: Vec<Value>) -> Result<(), String> {
+        self.write_line(MetadataFile {
+            tables: Vec::new(),
+            columns: Vec::new(),
+            rows: vec![RowDef { table_name: table_name.into(), values, global: false }],
+        })
+    }
+
+    /// Flush buffered lines to the file without closing it.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.file
+            .flush()
+            .map_err(|e| format!("Failed to flush metadata stream: {}", e))
+    }
+
+    fn write_line(&mut self, line: MetadataFile) -> Result<(), String> {
+        let json = serde_json::to_string(&line)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+        self.file
+            .write_all(json.as_bytes())
+            .and_then(|_| self.file.write_all(b"\n"))
+            .map_err(|e| format!("Failed to write metadata stream: {}", e))
+    }
+}
+
+/// Binary metadata writer.
+///
+/// Writes table definitions and rows to `/metadata/output_N.bin` in WADUP's
+/// packed binary format, which is much cheaper to produce and parse than
+/// JSON for modules emitting many rows. WADUP processes the file when the
+/// writer is dropped (or after `process()` returns if it is still open).
+///
+/// # Example
+///
+/// ```no_run
+/// use wadup_guest::{BinaryMetadataWriter, DataType, Value};
+///
+/// let mut writer = BinaryMetadataWriter::create().unwrap();
+/// writer.define_table("lines", vec![("number", DataType::Int64)]).unwrap();
+/// for i in 0..100_000 {
+///     writer.insert("lines", &[Value::Int64(i)]).unwrap();
+/// }
+/// ```
+pub struct BinaryMetadataWriter<W: Write = BufWriter<File>> {
+    out: W,
+    /// Table names in id order
+    tables: Vec<String>,
+}
+
+impl BinaryMetadataWriter {
+    /// Create the next `/metadata/output_N.bin` file.
+    pub fn create() -> Result<Self, String> {
+        let filename = format!("/metadata/output_{}.bin", next_file_index());
+        let file = File::create(&filename)
+            .map_err(|e| format!("Failed to create metadata file '{}': {}", filename, e))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> BinaryMetadataWriter<W> {
+    /// Write binary metadata to any writer.
+    pub fn new(mut out: W) -> Result<Self, String> {
+        out.write_all(MAGIC).map_err(write_error)?;
+        Ok(Self { out, tables: Vec::new() })
+    }
+
+    /// Write a table definition.
+    pub fn define_table(&mut self, name: impl Into<String>, columns: Vec<(&str, DataType)>) -> Result<(), String> {
+        let columns: Vec<Column> = columns
+            .into_iter()
+            .map(|(n, t)| Column::new(n, t))
+            .collect();
+        let columns = serde_json::to_vec(&columns)
+            .map_err(|e| format!("Failed to serialize columns: {}", e))?;
+        self.table_record(name.into(), &columns)
+    }
+
+    /// Write a single row. Tables not defined by this writer are referenced
+    /// by name, so they must be defined elsewhere.
+    pub fn insert(&mut self, table_name: &str, values: &[Value]) -> Result<(), String> {
+        let id = match self.tables.iter().position(|t| t == table_name) {
+            Some(id) => id,
+            None => {
+                self.table_record(table_name.to_string(), &[])?;
+                self.tables.len() - 1
+            }
+        };
+        let count = u16::try_from(values.len())
+            .map_err(|_| format!("Too many values in one row: {}", values.len()))?;
+
+        let mut record = Vec::with_capacity(7 + values.len() * 9);
+        record.push(RECORD_ROW);
+        record.extend_from_slice(&(id as u32).to_le_bytes());
+        record.extend_from_slice(&count.to_le_bytes());
+        for value in values {
+            match value {
+                Value::Int64(v) => {
+                    record.push(VALUE_INT64);
+                    record.extend_from_slice(&v.to_le_bytes());
+                }
+                Value::Float64(v) => {
+                    record.push(VALUE_FLOAT64);
+                    record.extend_from_slice(&v.to_le_bytes());
+                }
+                Value::String(v) => {
+                    record.push(VALUE_STRING);
+                    push_bytes(&mut record, v.as_bytes())?;
+                }
+                Value::Boolean(v) => {
+                    record.push(VALUE_BOOLEAN);
+                    record.push(u8::from(*v));
+                }
+                Value::Timestamp(v) => {
+                    record.push(VALUE_TIMESTAMP);
+                    record.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        self.out.write_all(&record).map_err(write_error)
+    }
+
+    /// Flush buffered records without closing the file.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.out.flush().map_err(write_error)
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> Result<W, String> {
+        self.flush()?;
+        Ok(self.out)
+    }
+
+    fn table_record(&mut self, name: String, columns: &[u8]) -> Result<(), String> {
+        let mut record = vec![RECORD_TABLE];
+        push_bytes(&mut record, name.as_bytes())?;
+        push_bytes(&mut record, columns)?;
+        self.out.write_all(&record).map_err(write_error)?;
+        self.tables.push(name);
+        Ok(())
+    }
+}
+
+/// Append a u32 length prefix and the bytes
+fn push_bytes(record: &mut Vec<u8>, bytes: &[u8]) -> Result<(), String> {
+    let len = u32::try_from(bytes.len()).map_err(|_| "Value too large for binary metadata".to_string())?;
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn write_error(e: std::io::Error) -> String {
+    format!("Failed to write binary metadata: {}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_writer_encoding() {
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.define_table("t", vec![("n", DataType::Int64)]).unwrap();
+        writer.insert("t", &[Value::Int64(-2)]).unwrap();
+        writer.insert("other", &[Value::String("hi".to_string()), Value::Float64(1.5)]).unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        let columns = br#"[{"name":"n","data_type":"Int64"}]"#;
+        let mut expected = b"WDM\x01".to_vec();
+        expected.push(0x01);
+        expected.extend_from_slice(&1u32.to_le_bytes());
+        expected.push(b't');
+        expected.extend_from_slice(&(columns.len() as u32).to_le_bytes());
+        expected.extend_from_slice(columns);
+        expected.extend_from_slice(&[0x02, 0, 0, 0, 0, 1, 0, 0x00]);
+        expected.extend_from_slice(&(-2i64).to_le_bytes());
+        // "other" is referenced by name only and gets id 1
+        expected.extend_from_slice(&[0x01, 5, 0, 0, 0]);
+        expected.extend_from_slice(b"other");
+        expected.extend_from_slice(&[0, 0, 0, 0]);
+        expected.extend_from_slice(&[0x02, 1, 0, 0, 0, 2, 0, 0x02, 2, 0, 0, 0, b'h', b'i', 0x01]);
+        expected.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_timestamp_values() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let at: Value = (UNIX_EPOCH + Duration::from_micros(1_500)).into();
+        let before: Value = (UNIX_EPOCH - Duration::from_micros(1_500)).into();
+        assert!(matches!((&at, &before), (Value::Timestamp(1), Value::Timestamp(-2))));
+        let row = RowDef { table_name: "events".to_string(), values: vec![at.clone()], global: false };
+        assert_eq!(serde_json::to_string(&row).unwrap(), r#"{"table_name":"events","values":[{"Timestamp":1}]}"#);
+
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.insert("events", &[at]).unwrap();
+        let bytes = writer.into_inner().unwrap();
+        let mut expected = vec![0x02, 0, 0, 0, 0, 1, 0, VALUE_TIMESTAMP];
+        expected.extend_from_slice(&1i64.to_le_bytes());
+        assert!(bytes.ends_with(&expected));
+    }
+
+    #[test]
+    fn test_foreign_key_is_declared_on_its_column() {
+        let orders = || crate::TableBuilder::new("orders").column("user_id", DataType::Int64);
+        orders().foreign_key("user_id", "users", "id").build().unwrap();
+        let table = TABLES.with(|t| t.borrow_mut().pop()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&table.columns).unwrap(),
+            r#"[{"name":"user_id","data_type":"Int64","references":{"table":"users","column":"id"}}]"#
+        );
+        assert!(orders().foreign_key("customer_id", "users", "id").build().is_err());
+    }
+
+    #[test]
+    fn test_boolean_column() {
+        let columns = vec![Column::new("encrypted", DataType::Boolean)];
+        assert_eq!(serde_json::to_string(&columns).unwrap(), r#"[{"name":"encrypted","data_type":"Boolean"}]"#);
+        let row = RowDef { table_name: "zips".to_string(), values: vec![true.into()], global: false };
+        assert_eq!(serde_json::to_string(&row).unwrap(), r#"{"table_name":"zips","values":[{"Boolean":true}]}"#);
+
+        let mut writer = BinaryMetadataWriter::new(Vec::new()).unwrap();
+        writer.insert("zips", &[Value::Boolean(true), Value::Boolean(false)]).unwrap();
+        let bytes = writer.into_inner().unwrap();
+        assert!(bytes.ends_with(&[0x02, 0, 0, 0, 0, 2, 0, 0x03, 1, 0x03, 0]));
+    }
+}
@@ -0,0 +1,17 @@
+//! Whether the module is running under `wadup test` or `wadup run`, e.g. to
+//! print verbose diagnostics only while testing.
+
+pub use wadup_abi::mode::{MODE_RUN, MODE_TEST, MODE_VAR};
+
+/// Whether the module is running under `wadup test`.
+///
+/// # Example
+///
+/// ```no_run
+/// if wadup_guest::is_test_mode() {
+///     eprintln!("header: {:02x?}", &[0x50, 0x4b]);
+/// }
+/// ```
+pub fn is_test_mode() -> bool {
+    std::env::var(MODE_VAR).is_ok_and(|mode| mode == MODE_TEST)
+}
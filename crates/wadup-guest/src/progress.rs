@@ -0,0 +1,48 @@
+//! Heartbeats for modules that spend a long time on one content.
+
+/// Tells WADUP the module is still working on the current content. The
+/// module gets a fresh `--stall-timeout`, and `progress_permille` (0 to
+/// 1000, or `None` when unknown) and `message` are passed to the run's
+/// progress reporting.
+///
+/// # Example
+///
+/// ```no_run
+/// let chunks = 40u16;
+/// for chunk in 0..chunks {
+///     // ... process the chunk ...
+///     wadup_guest::heartbeat(Some(chunk * 1000 / chunks), "scanning").unwrap();
+/// }
+/// ```
+pub fn heartbeat(progress_permille: Option<u16>, message: &str) -> Result<(), String> {
+    let progress = match progress_permille {
+        Some(permille) if permille > 1000 => return Err(format!("Progress {} is over 1000 permille", permille)),
+        Some(permille) => i32::from(permille),
+        None => -1,
+    };
+    match host::heartbeat(progress, message)? {
+        0 => Ok(()),
+        errno => Err(format!("Heartbeat failed with errno {}", errno)),
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "heartbeat"]
+        fn wadup_heartbeat(progress_permille: i32, msg_ptr: *const u8, msg_len: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn heartbeat(progress_permille: i32, message: &str) -> Result<i32, String> {
+        // SAFETY: the host only reads `message`
+        Ok(unsafe { wadup_heartbeat(progress_permille, message.as_ptr(), message.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn heartbeat(_progress_permille: i32, _message: &str) -> Result<i32, String> {
+        Err("Heartbeats are only available inside WADUP".to_string())
+    }
+}
@@ -0,0 +1,46 @@
+//! Secrets passed to the run with `--secret` or `--secrets-file`, read from
+//! the host instead of the environment so they don't show up in env dumps.
+
+/// The value of the secret `name`, or `None` if the run has no such secret.
+///
+/// # Example
+///
+/// ```no_run
+/// let key = wadup_guest::secret("API_KEY").unwrap().expect("run with --secret API_KEY=...");
+/// ```
+pub fn secret(name: &str) -> Result<Option<String>, String> {
+    let mut buf = vec![0u8; 256];
+    loop {
+        let len = host::get_secret(name, &mut buf)?;
+        if len == -1 {
+            return Ok(None);
+        }
+        let len = usize::try_from(len).map_err(|_| format!("Failed to read secret '{}'", name))?;
+        if len <= buf.len() {
+            buf.truncate(len);
+            return String::from_utf8(buf).map(Some).map_err(|_| format!("Secret '{}' is not UTF-8", name));
+        }
+        buf.resize(len, 0);
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "get_secret"]
+        fn wadup_get_secret(name_ptr: *const u8, name_len: i32, out_ptr: *mut u8, cap: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn get_secret(name: &str, out: &mut [u8]) -> Result<i32, String> {
+        // SAFETY: the host reads `name` and writes at most `out.len()` bytes into `out`
+        Ok(unsafe { wadup_get_secret(name.as_ptr(), name.len() as i32, out.as_mut_ptr(), out.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn get_secret(_name: &str, _out: &mut [u8]) -> Result<i32, String> {
+        Err("Secrets are only available inside WADUP".to_string())
+    }
+}
@@ -2,15 +2,23 @@
 //!
 //! Emits sub-content for recursive processing by WADUP using files:
 //! - `/subcontent/data_N.bin` - raw data bytes
-//! - `/subcontent/metadata_N.json` - metadata (filename, optional offset/length)
+//! - `/subcontent/metadata_N.json` - metadata (filename, optional offset/length or path, optional relation)
 
 use serde::Serialize;
 use std::cell::RefCell;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
+
+/// Bytes copied per read by [`SubContent::emit_from_reader`].
+const COPY_CHUNK: usize = 64 * 1024;
 
 thread_local! {
-    static FILE_COUNTER: RefCell<usize> = RefCell::new(0);
+    static FILE_COUNTER: RefCell<usize> = const { RefCell::new(0) };
+}
+
+/// Restart sub-content numbering at `data_0.bin`/`metadata_0.json`.
+pub(crate) fn reset_counter() {
+    FILE_COUNTER.with(|c| *c.borrow_mut() = 0);
 }
 
 fn next_counter() -> usize {
@@ -25,6 +33,10 @@ fn next_counter() -> usize {
 #[derive(Serialize)]
 struct SubContentMetadata {
     filename: String,
+    /// Length of the data file, which WADUP checks it against
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relation: Option<String>,
 }
 
 /// Metadata for sub-content slice (references input content).
@@ -33,6 +45,17 @@ struct SubContentSliceMetadata {
     filename: String,
     offset: usize,
     length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relation: Option<String>,
+}
+
+/// Metadata for sub-content taken from a file the module already wrote.
+#[derive(Serialize)]
+struct SubContentFileMetadata {
+    filename: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relation: Option<String>,
 }
 
 pub struct SubContent;
@@ -43,6 +66,44 @@ impl SubContent {
     /// Writes data to `/subcontent/data_N.bin` and metadata to `/subcontent/metadata_N.json`.
     /// WADUP processes the sub-content when the metadata file is closed.
     pub fn emit_bytes(data: &[u8], filename: &str) -> Result<(), String> {
+        Self::emit_bytes_inner(data, filename, None)
+    }
+
+    /// Emit sub-content bytes labeled with how they relate to the input
+    /// (e.g. `"zip_member"`). WADUP records a parent -> child edge document.
+    pub fn emit_bytes_with_relation(data: &[u8], filename: &str, relation: &str) -> Result<(), String> {
+        Self::emit_bytes_inner(data, filename, Some(relation))
+    }
+
+    /// Emit sub-content read from `reader`, e.g. a zip entry, copying it to
+    /// `/subcontent/data_N.bin` in 64 KiB chunks so it is never held in
+    /// guest memory all at once. Returns the number of bytes emitted.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use wadup_guest::SubContent;
+    ///
+    /// let mut entry = std::io::Cursor::new(b"member bytes".to_vec());
+    /// SubContent::emit_from_reader(&mut entry, "member.txt").unwrap();
+    /// ```
+    pub fn emit_from_reader<R: Read>(reader: &mut R, filename: &str) -> Result<u64, String> {
+        Self::emit_with(filename, None, |data_file| copy_chunked(reader, data_file))
+    }
+
+    fn emit_bytes_inner(data: &[u8], filename: &str, relation: Option<&str>) -> Result<(), String> {
+        Self::emit_with(filename, relation, |data_file| {
+            data_file.write_all(data).map(|()| data.len() as u64)
+        })?;
+        Ok(())
+    }
+
+    /// Write `/subcontent/data_N.bin` with `write_data`, then its metadata file.
+    fn emit_with(
+        filename: &str,
+        relation: Option<&str>,
+        write_data: impl FnOnce(&mut File) -> std::io::Result<u64>,
+    ) -> Result<u64, String> {
         let n = next_counter();
         let data_path = format!("/subcontent/data_{}.bin", n);
         let metadata_path = format!("/subcontent/metadata_{}.json", n);
@@ -50,24 +111,21 @@ impl SubContent {
         // Write data file first
         let mut data_file = File::create(&data_path)
             .map_err(|e| format!("Failed to create subcontent data file '{}': {}", data_path, e))?;
-        data_file.write_all(data)
+        let size = write_data(&mut data_file)
             .map_err(|e| format!("Failed to write subcontent data file '{}': {}", data_path, e))?;
         drop(data_file); // Close data file
 
         // Write metadata file (triggers processing when closed)
         let metadata = SubContentMetadata {
             filename: filename.to_string(),
+            size,
+            relation: relation.map(|s| s.to_string()),
         };
         let json = serde_json::to_string(&metadata)
             .map_err(|e| format!("Failed to serialize subcontent metadata: {}", e))?;
 
-        let mut meta_file = File::create(&metadata_path)
-            .map_err(|e| format!("Failed to create subcontent metadata file '{}': {}", metadata_path, e))?;
-        meta_file.write_all(json.as_bytes())
-            .map_err(|e| format!("Failed to write subcontent metadata file '{}': {}", metadata_path, e))?;
-        // File closed on drop, triggering WADUP processing
-
-        Ok(())
+        write_metadata(&metadata_path, &json)?;
+        Ok(size)
     }
 
     /// Emit a slice of the input content as sub-content (zero-copy).
@@ -75,6 +133,16 @@ impl SubContent {
     /// The slice references a range of the original `/data.bin` content without copying.
     /// Only writes metadata to `/subcontent/metadata_N.json`.
     pub fn emit_slice(offset: usize, length: usize, filename: &str) -> Result<(), String> {
+        Self::emit_slice_inner(offset, length, filename, None)
+    }
+
+    /// Emit a slice of the input content labeled with a relation, recording a
+    /// parent -> child edge document.
+    pub fn emit_slice_with_relation(offset: usize, length: usize, filename: &str, relation: &str) -> Result<(), String> {
+        Self::emit_slice_inner(offset, length, filename, Some(relation))
+    }
+
+    fn emit_slice_inner(offset: usize, length: usize, filename: &str, relation: Option<&str>) -> Result<(), String> {
         let n = next_counter();
         let metadata_path = format!("/subcontent/metadata_{}.json", n);
 
@@ -82,16 +150,215 @@ impl SubContent {
             filename: filename.to_string(),
             offset,
             length,
+            relation: relation.map(|s| s.to_string()),
         };
         let json = serde_json::to_string(&metadata)
             .map_err(|e| format!("Failed to serialize subcontent slice metadata: {}", e))?;
 
-        let mut meta_file = File::create(&metadata_path)
-            .map_err(|e| format!("Failed to create subcontent metadata file '{}': {}", metadata_path, e))?;
-        meta_file.write_all(json.as_bytes())
-            .map_err(|e| format!("Failed to write subcontent metadata file '{}': {}", metadata_path, e))?;
-        // File closed on drop, triggering WADUP processing
+        write_metadata(&metadata_path, &json)
+    }
+
+    /// Emit a file the module already wrote (e.g. `/tmp/decoded.bin`) as
+    /// sub-content, without reading it back.
+    ///
+    /// WADUP moves the file out of the filesystem when the metadata is
+    /// closed, so `path` no longer exists afterwards.
+    pub fn emit_file(path: &str, filename: &str) -> Result<(), String> {
+        Self::emit_file_inner(path, filename, None)
+    }
+
+    /// Emit an existing file labeled with a relation, recording a
+    /// parent -> child edge document.
+    pub fn emit_file_with_relation(path: &str, filename: &str, relation: &str) -> Result<(), String> {
+        Self::emit_file_inner(path, filename, Some(relation))
+    }
+
+    fn emit_file_inner(path: &str, filename: &str, relation: Option<&str>) -> Result<(), String> {
+        let n = next_counter();
+        let metadata_path = format!("/subcontent/metadata_{}.json", n);
+
+        let metadata = SubContentFileMetadata {
+            filename: filename.to_string(),
+            path: path.to_string(),
+            relation: relation.map(|s| s.to_string()),
+        };
+        let json = serde_json::to_string(&metadata)
+            .map_err(|e| format!("Failed to serialize subcontent file metadata: {}", e))?;
+
+        write_metadata(&metadata_path, &json)
+    }
+
+    /// UUID WADUP assigned to the `index`-th sub-content emitted for the
+    /// current content (counting from 0), for use with [`SubContent::emit_ref`].
+    pub fn emitted_uuid(index: usize) -> Result<[u8; 16], String> {
+        let mut uuid = [0u8; 16];
+        let errno = host::subcontent_uuid(index as i32, &mut uuid)?;
+        if errno != 0 {
+            return Err(format!("No sub-content {} has been emitted: errno {}", index, errno));
+        }
+        Ok(uuid)
+    }
+
+    /// Emit sub-content with the same bytes as an already emitted one,
+    /// without copying them again (e.g. a duplicate archive member).
+    ///
+    /// WADUP skips the emission and records an error document if `uuid`
+    /// is not a content it knows.
+    pub fn emit_ref(uuid: &[u8; 16], filename: &str) -> Result<(), String> {
+        let errno = host::emit_subcontent_ref(uuid, filename)?;
+        if errno != 0 {
+            return Err(format!("Failed to emit reference '{}': errno {}", filename, errno));
+        }
+        Ok(())
+    }
 
+    /// Whether sub-content named `filename` was already emitted for the
+    /// current content, e.g. to skip a duplicate archive member.
+    ///
+    /// ```no_run
+    /// use wadup_guest::SubContent;
+    ///
+    /// if !SubContent::exists("member.txt").unwrap() {
+    ///     SubContent::emit_bytes(b"member bytes", "member.txt").unwrap();
+    /// }
+    /// ```
+    pub fn exists(filename: &str) -> Result<bool, String> {
+        match host::subcontent_exists(filename)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            code => Err(format!("Failed to look up sub-content '{}': {}", filename, code)),
+        }
+    }
+
+    /// Record an edge labeled `relation` from the current content to the
+    /// content with `uuid`, e.g. to give an emitted sub-content a second
+    /// parent. WADUP records an error document instead if `uuid` is not a
+    /// content it knows.
+    pub fn link(uuid: &[u8; 16], relation: &str) -> Result<(), String> {
+        let errno = host::link_content(uuid, relation)?;
+        if errno != 0 {
+            return Err(format!("Failed to link content as '{}': errno {}", relation, errno));
+        }
         Ok(())
     }
 }
+
+/// Write a metadata file; closing it triggers WADUP processing.
+fn write_metadata(metadata_path: &str, json: &str) -> Result<(), String> {
+    let mut meta_file = File::create(metadata_path)
+        .map_err(|e| format!("Failed to create subcontent metadata file '{}': {}", metadata_path, e))?;
+    meta_file.write_all(json.as_bytes())
+        .map_err(|e| format!("Failed to write subcontent metadata file '{}': {}", metadata_path, e))?;
+    // File closed on drop, triggering WADUP processing
+    Ok(())
+}
+
+/// Copy `reader` into `writer` through one `COPY_CHUNK`-sized buffer.
+fn copy_chunked<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut total = 0u64;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// Imports from the host's `wadup` module.
+mod host {
+    #[cfg(target_arch = "wasm32")]
+    #[link(wasm_import_module = "wadup")]
+    extern "C" {
+        #[link_name = "subcontent_uuid"]
+        fn wadup_subcontent_uuid(index: i32, out_ptr: *mut u8) -> i32;
+        #[link_name = "emit_subcontent_ref"]
+        fn wadup_emit_subcontent_ref(uuid_ptr: *const u8, fname_ptr: *const u8, fname_len: i32) -> i32;
+        #[link_name = "subcontent_exists"]
+        fn wadup_subcontent_exists(fname_ptr: *const u8, fname_len: i32) -> i32;
+        #[link_name = "link_content"]
+        fn wadup_link_content(uuid_ptr: *const u8, relation_ptr: *const u8, relation_len: i32) -> i32;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn subcontent_uuid(index: i32, out: &mut [u8; 16]) -> Result<i32, String> {
+        // SAFETY: the host writes exactly 16 bytes into `out`
+        Ok(unsafe { wadup_subcontent_uuid(index, out.as_mut_ptr()) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn emit_subcontent_ref(uuid: &[u8; 16], filename: &str) -> Result<i32, String> {
+        // SAFETY: the host only reads 16 bytes from `uuid` and `filename.len()` bytes from `filename`
+        Ok(unsafe { wadup_emit_subcontent_ref(uuid.as_ptr(), filename.as_ptr(), filename.len() as i32) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn subcontent_exists(filename: &str) -> Result<i32, String> {
+        // SAFETY: the host only reads `filename.len()` bytes from `filename`
+        Ok(unsafe { wadup_subcontent_exists(filename.as_ptr(), filename.len() as i32) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn link_content(uuid: &[u8; 16], relation: &str) -> Result<i32, String> {
+        // SAFETY: the host only reads 16 bytes from `uuid` and `relation.len()` bytes from `relation`
+        Ok(unsafe { wadup_link_content(uuid.as_ptr(), relation.as_ptr(), relation.len() as i32) })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subcontent_uuid(_index: i32, _out: &mut [u8; 16]) -> Result<i32, String> {
+        Err("Sub-content references are only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn emit_subcontent_ref(_uuid: &[u8; 16], _filename: &str) -> Result<i32, String> {
+        Err("Sub-content references are only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subcontent_exists(_filename: &str) -> Result<i32, String> {
+        Err("Sub-content lookups are only available inside WADUP".to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn link_content(_uuid: &[u8; 16], _relation: &str) -> Result<i32, String> {
+        Err("Content links are only available inside WADUP".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts what is written, remembering the largest single write
+    #[derive(Default)]
+    struct Sink {
+        total: u64,
+        largest_write: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.total += buf.len() as u64;
+            self.largest_write = self.largest_write.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_chunked_streams_large_entries() {
+        const ENTRY_LEN: u64 = 256 * 1024 * 1024;
+        let mut entry = std::io::repeat(0xAB).take(ENTRY_LEN);
+        let mut sink = Sink::default();
+        assert_eq!(copy_chunked(&mut entry, &mut sink).unwrap(), ENTRY_LEN);
+        assert_eq!(sink.total, ENTRY_LEN);
+        assert!(sink.largest_write <= COPY_CHUNK, "the entry is never buffered past one chunk");
+    }
+}
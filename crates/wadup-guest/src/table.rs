@@ -1,5 +1,5 @@
 use crate::metadata;
-use crate::types::{Column, DataType, Value};
+use crate::types::{Column, DataType, ForeignKey, Value};
 
 pub struct Table {
     name: String,
@@ -7,26 +7,42 @@ pub struct Table {
 
 impl Table {
     pub fn define(name: impl Into<String>, columns: Vec<(&str, DataType)>) -> Result<Self, String> {
-        let name = name.into();
-
-        let cols: Vec<Column> = columns
+        let cols = columns
             .into_iter()
-            .map(|(n, t)| Column {
-                name: n.to_string(),
-                data_type: t,
-            })
+            .map(|(n, t)| Column::new(n, t))
             .collect();
+        Self::define_columns(name, cols)
+    }
 
-        metadata::add_table(name.clone(), cols);
-
+    /// Define a table from full column definitions, including constraints.
+    pub fn define_columns(name: impl Into<String>, columns: Vec<Column>) -> Result<Self, String> {
+        let name = name.into();
+        metadata::add_table(name.clone(), columns);
         Ok(Table { name })
     }
 
+    /// Add a column to the table, e.g. for a field first seen partway
+    /// through the content. Rows already stored hold null in it, and rows
+    /// may still leave it off the end. Adding a column the table already
+    /// has is a no-op; WADUP rejects one that has a different type.
+    pub fn add_column(&self, name: impl Into<String>, data_type: DataType) -> Result<(), String> {
+        metadata::add_column(self.name.clone(), Column::new(name, data_type));
+        Ok(())
+    }
+
     pub fn insert(&self, values: &[Value]) -> Result<(), String> {
         metadata::add_row(self.name.clone(), values.to_vec());
         Ok(())
     }
 
+    /// Insert a run-global row, e.g. a summary across all files, stored with
+    /// a null `content_uuid`. The table must be built with
+    /// [`TableBuilder::global`].
+    pub fn insert_global(&self, values: &[Value]) -> Result<(), String> {
+        metadata::add_global_row(self.name.clone(), values.to_vec());
+        Ok(())
+    }
+
     /// Flush accumulated metadata to file.
     ///
     /// This is optional - WADUP will automatically process any unflushed
@@ -38,7 +54,9 @@ impl Table {
 
 pub struct TableBuilder {
     name: String,
-    columns: Vec<(&'static str, DataType)>,
+    columns: Vec<Column>,
+    global: bool,
+    foreign_keys: Vec<(String, ForeignKey)>,
 }
 
 impl TableBuilder {
@@ -46,16 +64,71 @@ impl TableBuilder {
         Self {
             name: name.into(),
             columns: Vec::new(),
+            global: false,
+            foreign_keys: Vec::new(),
         }
     }
 
     pub fn column(mut self, name: &'static str, data_type: DataType) -> Self {
-        self.columns.push((name, data_type));
+        self.columns.push(Column::new(name, data_type));
+        self
+    }
+
+    /// Make the last added column part of the primary key. Rows repeating an
+    /// existing key are ignored, like `INSERT OR IGNORE`.
+    pub fn primary_key(mut self) -> Self {
+        if let Some(column) = self.columns.last_mut() {
+            column.primary_key = true;
+        }
         self
     }
 
-    pub fn build(self) -> Result<Table, String> {
-        Table::define(self.name, self.columns)
+    /// Make the last added column unique. Rows repeating an existing value
+    /// in it are ignored.
+    pub fn unique(mut self) -> Self {
+        if let Some(column) = self.columns.last_mut() {
+            column.unique = true;
+        }
+        self
+    }
+
+    /// Describe the last added column, e.g. its unit. Descriptions are
+    /// recorded with the table's schema and shown by `wadup schema`.
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        if let Some(column) = self.columns.last_mut() {
+            column.description = Some(description.into());
+        }
+        self
+    }
+
+    /// Make `column` a foreign key to `ref_column` of `ref_table`, which has
+    /// to be that table's primary key or a unique column. WADUP rejects rows
+    /// whose value isn't already in `ref_table`, so insert those rows first.
+    pub fn foreign_key(mut self, column: &str, ref_table: impl Into<String>, ref_column: impl Into<String>) -> Self {
+        let reference = ForeignKey { table: ref_table.into(), column: ref_column.into() };
+        self.foreign_keys.push((column.to_string(), reference));
+        self
+    }
+
+    /// Let the table hold rows from [`Table::insert_global`] as well as
+    /// per-content rows.
+    pub fn global(mut self) -> Self {
+        self.global = true;
+        self
+    }
+
+    pub fn build(mut self) -> Result<Table, String> {
+        for (name, reference) in self.foreign_keys {
+            let column = self.columns.iter_mut()
+                .find(|c| c.name == name)
+                .ok_or_else(|| format!("Table '{}': foreign key on unknown column '{}'", self.name, name))?;
+            column.references = Some(reference);
+        }
+        if self.global {
+            metadata::add_global_table(self.name.clone(), self.columns);
+            return Ok(Table { name: self.name });
+        }
+        Table::define_columns(self.name, self.columns)
     }
 }
 
@@ -5,19 +5,125 @@ pub enum DataType {
     Int64,
     Float64,
     String,
+    Boolean,
+    /// JSON text stored in a `Value::String` (see [`json!`](crate::json))
+    Json,
+    /// Point in time as a `Value::Timestamp`, stored as ISO-8601 text
+    Timestamp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub data_type: DataType,
+    /// Part of the table's primary key; rows repeating a key are ignored
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub primary_key: bool,
+    /// Rows repeating a value in this column are ignored
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unique: bool,
+    /// Human-readable meaning or unit, e.g. "size in bytes"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Key column of another table that values must already be in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub references: Option<ForeignKey>,
+}
+
+/// Table and column a foreign key refers to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub column: String,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, data_type: DataType) -> Self {
+        Self {
+            name: name.into(),
+            data_type,
+            primary_key: false,
+            unique: false,
+            description: None,
+            references: None,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Value {
     Int64(i64),
-    Float64(f64),
+    Float64(#[serde(with = "float64")] f64),
     String(String),
+    Boolean(bool),
+    /// Milliseconds since the Unix epoch, UTC (e.g. `SystemTime::now().into()`)
+    Timestamp(i64),
+}
+
+/// JSON encoding of `Value::Float64`, as [`wadup_abi::float64`] spells it:
+/// finite values are numbers, and NaN and the infinities are the strings
+/// `"NaN"`, `"Infinity"` and `"-Infinity"`.
+pub mod float64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    pub use wadup_abi::float64::{from_non_finite_name, non_finite_name};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match non_finite_name(*value) {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_f64(*value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Encoded {
+            Number(f64),
+            Name(String),
+        }
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Number(value) => Ok(value),
+            Encoded::Name(name) => from_non_finite_name(&name)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid Float64 value {:?}", name))),
+        }
+    }
+}
+
+impl Value {
+    /// Serialize a JSON document into a `Value::String` for a `DataType::Json` column.
+    pub fn json(value: &serde_json::Value) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(v: serde_json::Value) -> Self {
+        Value::json(&v)
+    }
+}
+
+/// Build a `Value` for a `DataType::Json` column using `serde_json::json!` syntax.
+///
+/// ```
+/// use wadup_guest::{json, Value};
+///
+/// let value = json!({"entries": 3, "names": ["a", "b"]});
+/// assert!(matches!(value, Value::String(s) if s == r#"{"entries":3,"names":["a","b"]}"#));
+/// ```
+#[macro_export]
+macro_rules! json {
+    ($($json:tt)+) => {
+        $crate::Value::json(&$crate::__private::serde_json::json!($($json)+))
+    };
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
 }
 
 impl From<i64> for Value {
@@ -32,6 +138,23 @@ impl From<f64> for Value {
     }
 }
 
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Boolean(v)
+    }
+}
+
+impl From<std::time::SystemTime> for Value {
+    /// A `Value::Timestamp`, rounded down to the millisecond
+    fn from(v: std::time::SystemTime) -> Self {
+        let millis = match v.duration_since(std::time::UNIX_EPOCH) {
+            Ok(after) => after.as_millis() as i64,
+            Err(before) => -(before.duration().as_nanos().div_ceil(1_000_000) as i64),
+        };
+        Value::Timestamp(millis)
+    }
+}
+
 impl From<String> for Value {
     fn from(v: String) -> Self {
         Value::String(v)
@@ -0,0 +1,23 @@
+//! Module version declaration for WADUP.
+//!
+//! WADUP records the version of the module that produced each result. A module
+//! declares its version by exporting `wadup_module_version`, which returns a
+//! pointer to a NUL-terminated string. Modules that don't declare a version are
+//! identified by a hash of their `.wasm` file's modification time.
+
+/// Declare the module version reported to WADUP.
+///
+/// # Example
+///
+/// ```ignore
+/// wadup_guest::module_version!(env!("CARGO_PKG_VERSION"));
+/// ```
+#[macro_export]
+macro_rules! module_version {
+    ($version:expr) => {
+        #[no_mangle]
+        pub extern "C" fn wadup_module_version() -> *const u8 {
+            concat!($version, "\0").as_ptr()
+        }
+    };
+}
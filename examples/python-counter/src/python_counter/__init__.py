@@ -5,7 +5,8 @@ Each time WADUP invokes process(), the call_count increments.
 """
 import wadup
 
-# Global counter - persists because Python interpreter is reused
+# Global counter - persists because python_counter.manifest.json declares the
+# module stateful, so the interpreter is reused
 _call_count = 0
 
 
@@ -1,5 +1,6 @@
-use wadup_guest::*;
 use std::io::Read;
+use wadup_guest::archive::{unix_time, ArchiveEntries, ArchiveEntry};
+use wadup_guest::*;
 
 #[no_mangle]
 pub extern "C" fn process() -> i32 {
@@ -9,9 +10,37 @@ pub extern "C" fn process() -> i32 {
     0
 }
 
+/// A ZIP entry as an [`ArchiveEntry`], recorded in the shared archive table
+struct ZipEntry<'a>(zip::read::ZipFile<'a>);
+
+impl Read for ZipEntry<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl ArchiveEntry for ZipEntry<'_> {
+    fn name(&self) -> String {
+        self.0.name().to_string()
+    }
+
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn mtime(&self) -> Option<i64> {
+        let t = self.0.last_modified();
+        Some(unix_time(t.year().into(), t.month().into(), t.day().into(), t.hour().into(), t.minute().into(), t.second().into()))
+    }
+
+    fn compressed(&self) -> bool {
+        self.0.compression() != zip::CompressionMethod::Stored
+    }
+}
+
 fn run() -> Result<(), String> {
     // Open content file from virtual filesystem
-    let file = std::fs::File::open(Content::path())
+    let file = Content::reader()
         .map_err(|e| format!("Failed to open content file: {}", e))?;
 
     // Try to parse as ZIP
@@ -23,9 +52,11 @@ fn run() -> Result<(), String> {
         }
     };
 
+    let entries = ArchiveEntries::define()?;
+
     // Extract each file in the ZIP as sub-content
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i)
+        let file = archive.by_index(i)
             .map_err(|e| format!("Failed to read ZIP entry {}: {}", i, e))?;
 
         // Skip directories
@@ -33,15 +64,10 @@ fn run() -> Result<(), String> {
             continue;
         }
 
-        let filename = file.name().to_string();
-
-        // Read the file contents
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)
-            .map_err(|e| format!("Failed to read ZIP file '{}': {}", filename, e))?;
-
-        // Emit as sub-content
-        SubContent::emit_bytes(&contents, &filename)?;
+        // Stream the entry into sub-content without buffering it
+        let mut entry = ZipEntry(file);
+        entries.emit(&mut entry)
+            .map_err(|e| format!("Failed to extract ZIP file '{}': {}", entry.name(), e))?;
     }
 
     Ok(())
@@ -0,0 +1,15 @@
+/*
+ * Minimal C guest: reports the size of each content on stdout.
+ *
+ *   clang --target=wasm32-wasip1 -mexec-model=reactor -O2 \
+ *       -Wl,--export=process -o size.wasm example.c
+ */
+#include <stdio.h>
+
+#include "wadup.h"
+
+__attribute__((export_name("process")))
+int32_t process(void) {
+    printf("%lld bytes\n", (long long)wadup_get_content_size());
+    return 0;
+}
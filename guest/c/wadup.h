@@ -0,0 +1,110 @@
+/*
+ * wadup.h - WADUP host functions for C guests (wasm32-wasip1).
+ *
+ * These are the imports a module can take from the "wadup" module. Tables,
+ * rows and most sub-content go through files instead of imports:
+ *
+ *   /data.bin                      the current content (read-only)
+ *   /metadata/output_N.json        {"tables": [...], "rows": [...]}
+ *   /subcontent/data_N.bin         bytes of the N-th sub-content
+ *   /subcontent/metadata_N.json    {"filename": "..."}, or a slice of the
+ *                                  parent: {"filename", "offset", "length"}
+ *
+ * A file is processed when it is closed. Functions returning an errno
+ * return WADUP_OK (0) on success.
+ *
+ * The declarations below are checked against the host's signatures by the
+ * wadup-core test suite, so a host change that breaks them fails the build.
+ */
+#ifndef WADUP_H
+#define WADUP_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WADUP_IMPORT(name) __attribute__((import_module("wadup"), import_name(#name)))
+
+/* WASI errno values the host functions return */
+#define WADUP_OK 0
+#define WADUP_EFAULT 21
+#define WADUP_EINVAL 28
+#define WADUP_ENOENT 44
+
+/* Algorithms for wadup_hash_content_range */
+#define WADUP_HASH_SHA256 0
+#define WADUP_HASH_SHA512 1
+
+/* Size in bytes of the current content */
+WADUP_IMPORT(get_content_size) int64_t wadup_get_content_size(void);
+
+/* Copy up to buf_len bytes of the current content from offset to buf and
+ * store the count in *nread; 0 at or past the end. EINVAL for a negative
+ * offset or length. */
+WADUP_IMPORT(read_content) int32_t wadup_read_content(int64_t offset, uint8_t *buf, int32_t buf_len, uint32_t *nread);
+
+/* Write the 16-byte UUID of the current content to out */
+WADUP_IMPORT(get_content_uuid) int32_t wadup_get_content_uuid(uint8_t *out);
+
+/* Write the 16-byte UUID of the parent content to out; ENOENT for root content */
+WADUP_IMPORT(parent_uuid) int32_t wadup_parent_uuid(uint8_t *out);
+
+/* Copy up to cap bytes of the parent's filename to out and return its full
+ * length; -1 for root content, -2 if out is outside guest memory */
+WADUP_IMPORT(parent_filename) int32_t wadup_parent_filename(char *out, int32_t cap);
+
+/* Write the digest of content bytes [offset, offset + length) to out, which
+ * must hold the whole digest (32 or 64 bytes) */
+WADUP_IMPORT(hash_content_range) int32_t wadup_hash_content_range(int32_t algorithm, int64_t offset, int64_t length, uint8_t *out, int32_t out_len);
+
+/* Write the 16-byte UUID of the index-th sub-content emitted so far to out */
+WADUP_IMPORT(subcontent_uuid) int32_t wadup_subcontent_uuid(int32_t index, uint8_t *out);
+
+/* Emit sub-content with the bytes of the content whose UUID is at uuid,
+ * without copying them */
+WADUP_IMPORT(emit_subcontent_ref) int32_t wadup_emit_subcontent_ref(const uint8_t *uuid, const char *filename, int32_t filename_len);
+
+/* 1 if sub-content named filename was already emitted for this content, 0 if
+ * not, -1 if filename is outside guest memory or over 4096 bytes */
+WADUP_IMPORT(subcontent_exists) int32_t wadup_subcontent_exists(const char *filename, int32_t filename_len);
+
+/* Record an edge labeled relation from the current content to child_uuid */
+WADUP_IMPORT(link_content) int32_t wadup_link_content(const uint8_t *child_uuid, const char *relation, int32_t relation_len);
+
+/* Store an error document with a module-defined code, even if the module
+ * then fails. Messages over 16 MiB fail with EMSGSIZE (35) */
+WADUP_IMPORT(report_error) int32_t wadup_report_error(int32_t code, const char *message, int32_t message_len);
+
+/* Add amount to the current content's fuel and return the new remaining
+ * total, or -1 if fuel isn't limited, --fuel-ceiling isn't set, or the
+ * content would pass the ceiling */
+WADUP_IMPORT(add_fuel) int64_t wadup_add_fuel(int64_t amount);
+
+/* Report that the module is still working on the current content, which
+ * restarts its --stall-timeout. progress_permille is 0 to 1000, or -1 when
+ * unknown; msg is a short status message. Returns 0, or a WASI errno for
+ * progress over 1000 or a bad or overlong message. */
+WADUP_IMPORT(heartbeat) int32_t wadup_heartbeat(int32_t progress_permille, const char *msg, int32_t msg_len);
+
+/* Copy up to cap bytes of the value of the secret named name (from --secret
+ * or --secrets-file) to out and return its full length; -1 for an unknown
+ * name, -2 if a buffer is outside guest memory */
+WADUP_IMPORT(get_secret) int32_t wadup_get_secret(const char *name, int32_t name_len, char *out, int32_t cap);
+
+/* Decompress a zlib or gzip stream into out and return its decompressed
+ * length; -4 if out_cap bytes were written and more output remains, which
+ * wadup_inflate_more continues. -1 for a corrupt stream, -2 for a bad
+ * buffer, -3 if the output exceeds 2 GiB. */
+WADUP_IMPORT(inflate) int32_t wadup_inflate(const uint8_t *src, int32_t src_len, uint8_t *out, int32_t out_cap);
+
+/* Write the next out_cap bytes of the stream wadup_inflate returned -4 for,
+ * returning the same values as wadup_inflate; -1 if no stream is pending */
+WADUP_IMPORT(inflate_more) int32_t wadup_inflate_more(uint8_t *out, int32_t out_cap);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WADUP_H */
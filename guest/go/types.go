@@ -3,15 +3,21 @@ package wadup
 import (
 	"encoding/json"
 	"fmt"
+	"math"
+	"time"
 )
 
 // DataType represents the type of data in a column
 type DataType string
 
 const (
-	Int64   DataType = "Int64"
-	Float64 DataType = "Float64"
-	String  DataType = "String"
+	Int64     DataType = "Int64"
+	Float64   DataType = "Float64"
+	String    DataType = "String"
+	// Json columns hold JSON text (see NewJSON)
+	Json      DataType = "Json"
+	// Timestamp columns hold points in time (see NewTimestamp)
+	Timestamp DataType = "Timestamp"
 )
 
 // Column represents a column definition in a table
@@ -40,6 +46,23 @@ func NewString(v string) Value {
 	return Value{data: v}
 }
 
+// timestamp is a Timestamp value in milliseconds since the Unix epoch
+type timestamp int64
+
+// NewTimestamp creates a new Timestamp value, truncated to the millisecond
+func NewTimestamp(t time.Time) Value {
+	return Value{data: timestamp(t.UnixMilli())}
+}
+
+// NewJSON serializes v to JSON text for a Json column
+func NewJSON(v interface{}) (Value, error) {
+	data, err := json.Marshal(v)
+	if err != nil {
+		return Value{}, err
+	}
+	return Value{data: string(data)}, nil
+}
+
 // MarshalJSON implements custom JSON encoding for Value
 // Encodes as a tagged union: {"Int64": 42}, {"String": "foo"}, etc.
 func (v Value) MarshalJSON() ([]byte, error) {
@@ -47,9 +70,20 @@ func (v Value) MarshalJSON() ([]byte, error) {
 	case int64:
 		return json.Marshal(map[string]int64{"Int64": val})
 	case float64:
+		// encoding/json refuses NaN and the infinities; WADUP reads them as strings
+		switch {
+		case math.IsNaN(val):
+			return json.Marshal(map[string]string{"Float64": "NaN"})
+		case math.IsInf(val, 1):
+			return json.Marshal(map[string]string{"Float64": "Infinity"})
+		case math.IsInf(val, -1):
+			return json.Marshal(map[string]string{"Float64": "-Infinity"})
+		}
 		return json.Marshal(map[string]float64{"Float64": val})
 	case string:
 		return json.Marshal(map[string]string{"String": val})
+	case timestamp:
+		return json.Marshal(map[string]int64{"Timestamp": int64(val)})
 	default:
 		return nil, fmt.Errorf("unsupported value type: %T", val)
 	}
@@ -14,7 +14,9 @@ Example:
     wadup.insert_row("my_table", ["example", 42])
     wadup.flush()
 """
+import datetime
 import json
+import math
 import os
 
 # Metadata accumulation
@@ -29,7 +31,8 @@ def define_table(name, columns):
     Args:
         name: Table name (string)
         columns: List of (column_name, column_type) tuples.
-                 Supported types: "String", "Int64", "Float64"
+                 Supported types: "String", "Int64", "Float64", "Json",
+                 "Timestamp"
 
     Example:
         wadup.define_table("files", [
@@ -48,7 +51,9 @@ def insert_row(table_name, values):
 
     Args:
         table_name: Name of the target table
-        values: List of values (int, float, or str)
+        values: List of values (int, float, str, dict/list for Json columns,
+                or datetime.datetime for Timestamp columns; naive datetimes
+                are taken as UTC)
 
     Example:
         wadup.insert_row("files", ["readme.txt", 1024])
@@ -61,12 +66,28 @@ def insert_row(table_name, values):
         elif isinstance(v, int):
             typed.append({"Int64": v})
         elif isinstance(v, float):
-            typed.append({"Float64": v})
+            typed.append({"Float64": v if math.isfinite(v) else _non_finite_name(v)})
+        elif isinstance(v, datetime.datetime):
+            if v.tzinfo is None:
+                v = v.replace(tzinfo=datetime.timezone.utc)
+            typed.append({"Timestamp": (v - _EPOCH) // datetime.timedelta(milliseconds=1)})
+        elif isinstance(v, (dict, list)):
+            typed.append({"String": json.dumps(v)})
         else:
             typed.append({"String": str(v)})
     _rows.append({"table_name": table_name, "values": typed})
 
 
+_EPOCH = datetime.datetime(1970, 1, 1, tzinfo=datetime.timezone.utc)
+
+
+def _non_finite_name(v):
+    # json.dump would write bare NaN/Infinity, which isn't valid JSON
+    if math.isnan(v):
+        return "NaN"
+    return "Infinity" if v > 0 else "-Infinity"
+
+
 def flush():
     """Flush accumulated metadata to file.
 
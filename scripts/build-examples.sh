@@ -164,6 +164,11 @@ build_python_module() {
         mv "$target_dir/module.wasm" "$wasm_file"
     fi
 
+    # The manifest goes next to the module, where wadup looks for it
+    if [[ -f "$src_dir/${module_name}.manifest.json" ]]; then
+        cp "$src_dir/${module_name}.manifest.json" "$target_dir/"
+    fi
+
     local end=$(python3 -c "import time; print(time.time())")
     local duration=$(python3 -c "print(f'{$end - $start:.2f}')")
 
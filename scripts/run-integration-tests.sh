@@ -213,6 +213,12 @@ copy_module() {
     if [[ -f "$cache_path" ]]; then
         cp "$cache_path" "$MODULES_DIR/"
     fi
+
+    # And its manifest, e.g. declaring it stateful
+    local manifest_path="$wasm_dir/${wasm_stem}.manifest.json"
+    if [[ -f "$manifest_path" ]]; then
+        cp "$manifest_path" "$MODULES_DIR/"
+    fi
 }
 
 # Run wadup and capture output
@@ -362,6 +368,8 @@ test_zip_extractor_and_byte_counter() {
 
     assert_table_exists "file_sizes" || return 1
     assert_row_count "file_sizes" 3 "ge" || return 1
+    assert_table_exists "__wadup_archive_entries" || return 1
+    assert_row_count "__wadup_archive_entries" 3 "ge" || return 1
 }
 
 test_combined_sqlite_and_zip() {